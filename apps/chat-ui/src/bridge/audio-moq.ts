@@ -4,18 +4,22 @@ export interface AudioMoqConfig {
   relay: string;
   moqRoot: string; // e.g., "marmot/abc123"
   myPubkey: string;
-  trackLabel: string; // e.g., "alice-audio-001"
+  trackLabel: string; // exporter-derived label for our audio at the current epoch
+  /** Current exporter-derived audio label of a peer; re-read on every (re)subscribe */
+  peerTrackLabel(peerPubkey: string): Promise<string>;
 }
 
 export interface AudioMoqCallbacks {
   onReady(): void;
-  onPeerAudio(peerPubkey: string, data: Uint8Array): void;
+  onPeerAudio(peerPubkey: string, trackLabel: string, data: Uint8Array): void;
   onError(message: unknown): void;
   onClosed(): void;
 }
 
 export interface AudioMoqHandle {
   publishAudio(data: Uint8Array): void;
+  /** Move our broadcast to a new label once the epoch (and so the label) changes */
+  setTrackLabel(trackLabel: string): void;
   subscribeToPeerAudio(peerPubkey: string): void;
   close(): void;
 }
@@ -26,6 +30,9 @@ export interface AudioMoqHandle {
  * Path structure:
  * - Publish: {moqRoot}/audio/{myPubkey}/{trackLabel}
  * - Subscribe: {moqRoot}/audio/{peerPubkey}/{trackLabel}
+ *
+ * Track labels are exporter-derived per sender and epoch, so both sides
+ * follow them as commits land.
  */
 export async function createAudioMoq(
   config: AudioMoqConfig,
@@ -40,14 +47,9 @@ export async function createAudioMoq(
   const basePath = Moq.Path.from(config.moqRoot);
   const audioPath = Moq.Path.join(basePath, Moq.Path.from('audio'));
   const myAudioPath = Moq.Path.join(audioPath, Moq.Path.from(config.myPubkey));
-  const publishPath = Moq.Path.join(myAudioPath, Moq.Path.from(config.trackLabel));
-
-  console.debug('[audio-moq] publish path:', publishPath.toString());
-
-  const publisher = new Moq.Broadcast();
-  connection.publish(publishPath, publisher);
 
   let currentTrack: Moq.Track | null = null;
+  let publisher: Moq.Broadcast | null = null;
   let readyCalled = false;
 
   const callOnReady = () => {
@@ -58,16 +60,16 @@ export async function createAudioMoq(
   };
 
   // Handle track requests for our audio
-  const acquireTrack = async () => {
+  const acquireTrack = async (broadcast: Moq.Broadcast, trackLabel: string) => {
     try {
       for (;;) {
-        const request = await publisher.requested();
+        const request = await broadcast.requested();
         if (!request) break;
 
         const track = request.track as Moq.Track;
         console.debug('[audio-moq] track requested:', track.name);
 
-        if (track.name !== config.trackLabel) {
+        if (track.name !== trackLabel) {
           console.warn('[audio-moq] unexpected track name:', track.name);
           track.close();
           continue;
@@ -95,7 +97,19 @@ export async function createAudioMoq(
     }
   };
 
-  void acquireTrack();
+  // Publish: moqRoot/audio/myPubkey/trackLabel; the label changes with the epoch
+  const publishAs = (trackLabel: string) => {
+    const publishPath = Moq.Path.join(myAudioPath, Moq.Path.from(trackLabel));
+    console.debug('[audio-moq] publish path:', publishPath.toString());
+
+    publisher?.close();
+    currentTrack = null;
+    publisher = new Moq.Broadcast();
+    connection.publish(publishPath, publisher);
+    void acquireTrack(publisher, trackLabel);
+  };
+
+  publishAs(config.trackLabel);
 
   const isTransient = (error: unknown) => {
     const message =
@@ -115,14 +129,16 @@ export async function createAudioMoq(
     }
 
     const consumePeerAudio = async () => {
-      // Derive peer's track label from their pubkey (must match what they publish)
-      const peerTrackLabel = `audio-${peerPubkey.slice(0, 8)}`;
       const peerAudioPath = Moq.Path.join(audioPath, Moq.Path.from(peerPubkey));
-      const subscribePath = Moq.Path.join(peerAudioPath, Moq.Path.from(peerTrackLabel));
-      console.debug('[audio-moq] subscribing to peer audio:', peerPubkey, 'path:', subscribePath.toString());
 
       while (!closed) {
         try {
+          // Re-derive the peer's label each time: it changes with every epoch,
+          // and the peer moves its broadcast when it does
+          const peerTrackLabel = await config.peerTrackLabel(peerPubkey);
+          const subscribePath = Moq.Path.join(peerAudioPath, Moq.Path.from(peerTrackLabel));
+          console.debug('[audio-moq] subscribing to peer audio:', peerPubkey, 'path:', subscribePath.toString());
+
           const broadcast = connection.consume(subscribePath);
           const track = broadcast.subscribe(peerTrackLabel, 0);
 
@@ -134,7 +150,7 @@ export async function createAudioMoq(
             if (!frame) break;
 
             const callbackStart = performance.now();
-            callbacks.onPeerAudio(peerPubkey, frame);
+            callbacks.onPeerAudio(peerPubkey, peerTrackLabel, frame);
             const callbackEnd = performance.now();
 
             // Log timing to diagnose if callback is blocking frame reads
@@ -192,8 +208,14 @@ export async function createAudioMoq(
     callbacks.onClosed();
   };
 
+  const setTrackLabel = (trackLabel: string) => {
+    if (closed) return;
+    publishAs(trackLabel);
+  };
+
   return {
     publishAudio,
+    setTrackLabel,
     subscribeToPeerAudio,
     close,
   };
@@ -19,6 +19,7 @@ export interface MoqConnectCallbacks {
 export interface MoqHandle {
   publish(data: Uint8Array): void;
   subscribeToPeer(peerPubkey: string): void;
+  unsubscribeFromPeer(peerPubkey: string): void;
   close(): void;
 }
 export async function createMoqBridge() {
@@ -109,20 +110,28 @@ export async function createMoqBridge() {
         return /reset_stream/i.test(message) || /not found/i.test(message);
       };
 
+      // Open peer tracks, so a removed member's subscription can be torn down
+      const peerTracks = new Map<string, { active: boolean; track: Moq.Track | null }>();
+
       const consumePeerTrack = async (peerPubkey: string) => {
+        if (peerTracks.has(peerPubkey)) return;
+        const subscription = { active: true, track: null as Moq.Track | null };
+        peerTracks.set(peerPubkey, subscription);
         const subscribePath = Moq.Path.join(basePath, Moq.Path.from(peerPubkey));
         console.debug('[marmot-moq] subscribing to peer', peerPubkey, 'path:', subscribePath.toString());
 
-        while (!closed) {
+        while (!closed && subscription.active) {
           try {
             const broadcast = connection.consume(subscribePath);
             const track = broadcast.subscribe(TRACK_NAME, 0);
+            subscription.track = track;
             for (;;) {
               const frame = await track.readFrame();
-              if (!frame) break;
+              if (!frame || !subscription.active) break;
               callbacks.onFrame(frame);
             }
           } catch (err) {
+            if (!subscription.active) break;
             if (isTransient(err)) {
               console.warn('[marmot-moq] transient consume error for peer', peerPubkey, 'retrying', err);
             } else {
@@ -172,6 +181,15 @@ export async function createMoqBridge() {
         }
       };
 
+      const unsubscribeFromPeer = (peerPubkey: string) => {
+        console.debug('[marmot-moq] unsubscribeFromPeer', peerPubkey);
+        const subscription = peerTracks.get(peerPubkey);
+        if (!subscription) return;
+        subscription.active = false;
+        subscription.track?.close();
+        peerTracks.delete(peerPubkey);
+      };
+
       const close = () => {
         if (closed) return;
         closed = true;
@@ -187,6 +205,7 @@ export async function createMoqBridge() {
       return {
         publish,
         subscribeToPeer,
+        unsubscribeFromPeer,
         close,
       };
     },
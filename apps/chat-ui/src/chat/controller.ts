@@ -1,6 +1,20 @@
-import initWasm, { WasmChatController } from '../../../../tests/pkg/marmot_chat.js';
-import type { ChatMember, ChatMessage, ChatSession } from '../types';
+import initWasm, { WasmChatController, WasmMediaSession } from '../../../../tests/pkg/marmot_chat.js';
+import { getPublicKey } from 'nostr-tools';
+import type {
+  ChatMember,
+  ChatMessage,
+  ChatSession,
+  GroupMetadata,
+  PendingWelcome,
+  RelayHealth,
+  RelayState,
+} from '../types';
 import { createMoqBridge } from '../bridge/moq';
+import { hexToBytes } from '../utils';
+import { deleteSnapshot, loadSnapshot, saveSnapshot, snapshotKey } from './snapshots';
+
+/** Coalesces the snapshot writes of a burst of group changes */
+const SNAPSHOT_DELAY_MS = 1000;
 
 export type RecoveryAction = 'retry' | 'refresh' | 'check_connection' | 'none';
 
@@ -18,6 +32,9 @@ export interface ChatCallbacks {
   setRoster(members: ChatMember[]): void;
   upsertMember(member: ChatMember): void;
   removeMember(pubkey: string): void;
+  setGroupMetadata(metadata: GroupMetadata): void;
+  addPendingWelcome(welcome: PendingWelcome): void;
+  setRelayHealth(health: RelayHealth): void;
   showError(error: ErrorInfo): void;
   clearError(): void;
 }
@@ -27,10 +44,19 @@ export interface ChatHandle {
   sendMessage(content: string): void;
   rotate(): void;
   invite(pubkey: string, isAdmin: boolean): void;
+  removeMember(pubkey: string): void;
+  setMemberAdmin(pubkey: string, isAdmin: boolean): void;
+  leaveGroup(): void;
+  /** Join the group of a pending welcome; the others stay pending */
+  acceptWelcome(welcomeId: string): void;
+  updateGroupMetadata(name?: string, description?: string): void;
+  /** Encrypts and commits the image; returns the ciphertext to upload */
+  setGroupImage(image: Uint8Array): Uint8Array;
+  decryptGroupImage(ciphertext: Uint8Array): Uint8Array;
   // Media crypto methods
-  deriveMediaBaseKey(senderPubkey: string, trackLabel: string): Promise<string>;
-  encryptAudioFrame(baseKeyB64: string, plaintext: Uint8Array, frameCounter: number, aad: Uint8Array): Promise<Uint8Array>;
-  decryptAudioFrame(baseKeyB64: string, ciphertext: Uint8Array, frameCounter: number, aad: Uint8Array): Promise<Uint8Array>;
+  createMediaSession(senderPubkey: string, trackLabel: string, cipherSuite?: string): Promise<WasmMediaSession>;
+  /** Exporter-derived track label of a sender at the current epoch */
+  deriveTrackLabel(senderPubkey: string, kind: 'audio' | 'video' | 'screen'): Promise<string>;
   currentEpoch(): Promise<number>;
   groupRoot(): Promise<string>;
 }
@@ -52,6 +78,30 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
   await ensureWasm();
   await createMoqBridge();
 
+  // Snapshots are sealed under the secret key: whoever holds it can already
+  // act as this participant
+  const passphrase = session.secretHex;
+  const storageKey = snapshotKey(session.sessionId, getPublicKey(hexToBytes(session.secretHex)));
+  let controller: WasmChatController | null = null;
+  let snapshotTimer: ReturnType<typeof setTimeout> | undefined;
+
+  const persistState = () => {
+    if (!controller) return;
+    try {
+      void saveSnapshot(storageKey, controller.exportState(passphrase));
+    } catch (err) {
+      console.warn('Failed to export chat state', err);
+    }
+  };
+
+  const schedulePersist = () => {
+    if (snapshotTimer !== undefined) return;
+    snapshotTimer = setTimeout(() => {
+      snapshotTimer = undefined;
+      persistState();
+    }, SNAPSHOT_DELAY_MS);
+  };
+
   const toMember = (raw: any): ChatMember | null => {
     if (!raw || typeof raw !== 'object') return null;
     const pubkey = typeof raw.pubkey === 'string' ? raw.pubkey : String(raw.pubkey ?? '');
@@ -71,6 +121,7 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
       case 'ready':
         callbacks.clearError();
         callbacks.setReady(Boolean((event as ReadyState).ready));
+        schedulePersist();
         break;
       case 'message': {
         const payload = event as { author: string; content: string; created_at: number; local?: boolean };
@@ -85,6 +136,7 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
       }
       case 'commit':
         callbacks.setCommits(Number(event.total ?? 0));
+        schedulePersist();
         break;
       case 'roster': {
         const members = Array.isArray(event.members)
@@ -93,6 +145,7 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
               .filter((member): member is ChatMember => member !== null)
           : [];
         callbacks.setRoster(members);
+        schedulePersist();
         break;
       }
       case 'member_joined':
@@ -110,12 +163,45 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
         }
         break;
       }
+      case 'group_metadata':
+        callbacks.setGroupMetadata({
+          name: typeof event.name === 'string' ? event.name : '',
+          description: typeof event.description === 'string' ? event.description : '',
+          imageHash: typeof event.image_hash === 'string' ? event.image_hash : undefined,
+        });
+        break;
       case 'invite_generated': {
         const recipient = typeof event.recipient === 'string' ? event.recipient : 'unknown recipient';
         const adminFlag = event.is_admin ? 'admin' : 'member';
         callbacks.setStatus(`Invite ready for ${recipient} (${adminFlag})`);
         break;
       }
+      case 'welcome_pending': {
+        const welcome = (event as any).welcome ?? {};
+        if (typeof welcome.welcome_id === 'string') {
+          callbacks.addPendingWelcome({
+            welcomeId: welcome.welcome_id,
+            groupIdHex: String(welcome.group_id_hex ?? ''),
+            groupName: String(welcome.group_name ?? ''),
+            welcomer: String(welcome.welcomer ?? ''),
+            memberCount: Number(welcome.member_count ?? 0),
+          });
+        }
+        break;
+      }
+      case 'relay_health': {
+        const relay = (event as any).relay ?? {};
+        if (typeof relay.url === 'string') {
+          callbacks.setRelayHealth({
+            url: relay.url,
+            state: (relay.state ?? 'connecting') as RelayState,
+            failures: Number(relay.failures ?? 0),
+            retryInMs: typeof relay.retry_in_ms === 'number' ? relay.retry_in_ms : undefined,
+            dropped: Number(relay.dropped ?? 0),
+          });
+        }
+        break;
+      }
       case 'error': {
         const errorEvent = event as { message: string; fatal?: boolean; recovery_action?: RecoveryAction };
         const fatal = errorEvent.fatal !== false; // Default to true if undefined
@@ -144,35 +230,67 @@ export async function startChat(session: ChatSession, callbacks: ChatCallbacks):
     bootstrap_role: session.role,
     relay_url: session.relay,
     nostr_url: session.nostr,
+    nostr_relays: session.nostrRelays ?? [],
     session_id: session.sessionId,
     secret_hex: session.secretHex,
     group_id_hex: session.groupIdHex,
     admin_pubkeys: session.adminPubkeys ?? [],
     peer_pubkeys: session.peerPubkeys ?? [],
+    group_name: session.groupName,
+    group_description: session.groupDescription,
+    // Snapshots go to IndexedDB, so key packages outlive the page
+    persists_snapshots: typeof indexedDB !== 'undefined',
   };
 
-  const controller = WasmChatController.start(sessionValue, eventHandler);
+  // A stored snapshot resumes the group where the last page load left it
+  const snapshot = await loadSnapshot(storageKey);
+  if (snapshot) {
+    try {
+      controller = WasmChatController.resume(snapshot, passphrase, eventHandler);
+    } catch (err) {
+      console.warn('Failed to resume from stored state, starting fresh', err);
+      await deleteSnapshot(storageKey);
+    }
+  }
+  if (!controller) {
+    controller = WasmChatController.start(sessionValue, eventHandler);
+  }
+  const active = controller;
+  const onPageHide = () => persistState();
+  window.addEventListener('pagehide', onPageHide);
 
   return {
-    stop: () => controller.shutdown(),
-    sendMessage: (content: string) => controller.send_message(content),
-    rotate: () => controller.rotate_epoch(),
-    invite: (pubkey: string, isAdmin: boolean) => controller.inviteMember(pubkey, isAdmin),
-    // Media crypto methods
-    deriveMediaBaseKey: async (senderPubkey: string, trackLabel: string) => {
-      return controller.deriveMediaBaseKey(senderPubkey, trackLabel);
+    stop: () => {
+      window.removeEventListener('pagehide', onPageHide);
+      clearTimeout(snapshotTimer);
+      snapshotTimer = undefined;
+      persistState();
+      controller = null;
+      active.shutdown();
     },
-    encryptAudioFrame: async (baseKeyB64: string, plaintext: Uint8Array, frameCounter: number, aad: Uint8Array) => {
-      return controller.encryptAudioFrame(baseKeyB64, plaintext, frameCounter, aad);
+    sendMessage: (content: string) => active.send_message(content),
+    rotate: () => active.rotate_epoch(),
+    invite: (pubkey: string, isAdmin: boolean) => active.inviteMember(pubkey, isAdmin),
+    removeMember: (pubkey: string) => active.removeMember(pubkey),
+    setMemberAdmin: (pubkey: string, isAdmin: boolean) => active.setMemberAdmin(pubkey, isAdmin),
+    leaveGroup: () => active.leaveGroup(),
+    acceptWelcome: (welcomeId: string) => active.acceptWelcome(welcomeId),
+    updateGroupMetadata: (name?: string, description?: string) =>
+      active.updateGroupMetadata(name, description),
+    setGroupImage: (image: Uint8Array) => active.setGroupImage(image),
+    decryptGroupImage: (ciphertext: Uint8Array) => active.decryptGroupImage(ciphertext),
+    // Media crypto methods
+    createMediaSession: async (senderPubkey: string, trackLabel: string, cipherSuite?: string) => {
+      return active.createMediaSession(senderPubkey, trackLabel, cipherSuite);
     },
-    decryptAudioFrame: async (baseKeyB64: string, ciphertext: Uint8Array, frameCounter: number, aad: Uint8Array) => {
-      return controller.decryptAudioFrame(baseKeyB64, ciphertext, frameCounter, aad);
+    deriveTrackLabel: async (senderPubkey: string, kind: 'audio' | 'video' | 'screen') => {
+      return active.deriveTrackLabel(senderPubkey, kind);
     },
     currentEpoch: async () => {
-      return Number(controller.currentEpoch());
+      return Number(active.currentEpoch());
     },
     groupRoot: async () => {
-      return controller.groupRoot();
+      return active.groupRoot();
     },
   };
 }
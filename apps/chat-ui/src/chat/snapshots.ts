@@ -0,0 +1,70 @@
+// Sealed controller snapshots in IndexedDB, so a reload resumes the group
+// at its epoch instead of needing a new welcome.
+
+const DB_NAME = 'marmot-chat';
+const DB_VERSION = 1;
+const STORE = 'snapshots';
+
+let dbPromise: Promise<IDBDatabase> | null = null;
+
+function openDb(): Promise<IDBDatabase> {
+  if (!dbPromise) {
+    dbPromise = new Promise((resolve, reject) => {
+      const request = indexedDB.open(DB_NAME, DB_VERSION);
+      request.onupgradeneeded = () => {
+        if (!request.result.objectStoreNames.contains(STORE)) {
+          request.result.createObjectStore(STORE);
+        }
+      };
+      request.onsuccess = () => resolve(request.result);
+      request.onerror = () => reject(request.error);
+    });
+    dbPromise.catch(() => {
+      dbPromise = null;
+    });
+  }
+  return dbPromise;
+}
+
+async function run<T>(mode: IDBTransactionMode, op: (store: IDBObjectStore) => IDBRequest<T>): Promise<T> {
+  const db = await openDb();
+  return new Promise((resolve, reject) => {
+    const request = op(db.transaction(STORE, mode).objectStore(STORE));
+    request.onsuccess = () => resolve(request.result);
+    request.onerror = () => reject(request.error);
+  });
+}
+
+/** One snapshot per participant of a session */
+export function snapshotKey(sessionId: string, pubkey: string): string {
+  return `${sessionId}:${pubkey}`;
+}
+
+export async function loadSnapshot(key: string): Promise<Uint8Array | null> {
+  if (typeof indexedDB === 'undefined') return null;
+  try {
+    const value = await run('readonly', (store) => store.get(key));
+    return value instanceof Uint8Array ? value : null;
+  } catch (err) {
+    console.warn('Failed to load state snapshot', err);
+    return null;
+  }
+}
+
+export async function saveSnapshot(key: string, snapshot: Uint8Array): Promise<void> {
+  if (typeof indexedDB === 'undefined') return;
+  try {
+    await run('readwrite', (store) => store.put(snapshot, key));
+  } catch (err) {
+    console.warn('Failed to save state snapshot', err);
+  }
+}
+
+export async function deleteSnapshot(key: string): Promise<void> {
+  if (typeof indexedDB === 'undefined') return;
+  try {
+    await run('readwrite', (store) => store.delete(key));
+  } catch (err) {
+    console.warn('Failed to delete state snapshot', err);
+  }
+}
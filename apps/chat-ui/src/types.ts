@@ -4,11 +4,15 @@ export interface ChatSession {
   role: Role;
   relay: string;
   nostr: string;
+  /** Further handshake relays next to `nostr` */
+  nostrRelays?: string[];
   sessionId: string;
   secretHex: string;
   groupIdHex?: string;
   adminPubkeys?: string[];
   peerPubkeys?: string[];
+  groupName?: string;
+  groupDescription?: string;
 }
 
 export interface ChatMessage {
@@ -24,6 +28,33 @@ export interface ChatMember {
   isAdmin: boolean;
 }
 
+export interface GroupMetadata {
+  name: string;
+  description: string;
+  /** Hex SHA-256 of the encrypted image blob */
+  imageHash?: string;
+}
+
+/** A welcome into a group the user has not accepted yet */
+export interface PendingWelcome {
+  welcomeId: string;
+  groupIdHex: string;
+  groupName: string;
+  welcomer: string;
+  memberCount: number;
+}
+
+export type RelayState = 'connecting' | 'connected' | 'reconnecting';
+
+export interface RelayHealth {
+  url: string;
+  state: RelayState;
+  failures: number;
+  retryInMs?: number;
+  /** Messages given up on while the relay was down */
+  dropped: number;
+}
+
 export interface ChatState {
   messages: ChatMessage[];
   commits: number;
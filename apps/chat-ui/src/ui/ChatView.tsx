@@ -2,7 +2,7 @@ import { createEffect, createSignal, createMemo, onCleanup } from 'solid-js';
 import { createStore } from 'solid-js/store';
 import { For, Show } from 'solid-js';
 import { getPublicKey } from 'nostr-tools';
-import type { ChatSession, ChatMessage, ChatState } from '../types';
+import type { ChatSession, ChatMessage, ChatState, GroupMetadata, PendingWelcome, RelayHealth } from '../types';
 import type { ChatHandle, ChatCallbacks, ErrorInfo } from '../chat/controller';
 import { hexToBytes, normalizeHex } from '../utils';
 import { startAudioCapture, float32ToInt16 } from '../audio/capture';
@@ -11,6 +11,7 @@ import { createAudioMoq } from '../bridge/audio-moq';
 import type { AudioCaptureHandle } from '../audio/capture';
 import type { AudioPlaybackHandle } from '../audio/playback';
 import type { AudioMoqHandle } from '../bridge/audio-moq';
+import { encodeAad, sframeCounter, sframeKeyId, type WasmMediaSession } from '../../../../tests/pkg/marmot_chat.js';
 
 export interface ChatViewProps {
   session: ChatSession;
@@ -29,6 +30,10 @@ export function ChatView(props: ChatViewProps) {
   const [inviteError, setInviteError] = createSignal('');
   const [inviteSuccess, setInviteSuccess] = createSignal('');
   const [currentError, setCurrentError] = createSignal<ErrorInfo | null>(null);
+  const [groupMetadata, setGroupMetadata] = createSignal<GroupMetadata | null>(null);
+  const [pendingWelcomes, setPendingWelcomes] = createSignal<PendingWelcome[]>([]);
+  const [relayHealth, setRelayHealth] = createSignal<RelayHealth[]>([]);
+  const [groupNameDraft, setGroupNameDraft] = createSignal('');
   const [audioEnabled, setAudioEnabled] = createSignal(false);
   const [audioStatus, setAudioStatus] = createSignal('');
 
@@ -42,6 +47,9 @@ export function ChatView(props: ChatViewProps) {
   let audioCapture: AudioCaptureHandle | null = null;
   let audioMoq: AudioMoqHandle | null = null;
   let peerPlayback: Map<string, AudioPlaybackHandle> = new Map();
+  let mySession: WasmMediaSession | null = null;
+  // Peer sessions with the track label they were created for
+  const peerSessions = new Map<string, { label: string; session: WasmMediaSession }>();
 
   const selfPubkey = createMemo(() => {
     try {
@@ -52,13 +60,15 @@ export function ChatView(props: ChatViewProps) {
     }
   });
 
+  // Admin status comes from the group context via the roster; the creator
+  // is an admin until the roster says otherwise
   const isAdmin = createMemo(() => {
-    if (props.session.role === 'initial') {
-      return true;
-    }
     const me = selfPubkey();
-    if (!me) return false;
-    return chatState.members.some((member) => member.pubkey === me && member.isAdmin);
+    const self = me ? chatState.members.find((member) => member.pubkey === me) : undefined;
+    if (self) {
+      return self.isAdmin;
+    }
+    return props.session.role === 'initial';
   });
 
   const syncWindowState = () => {
@@ -74,6 +84,7 @@ export function ChatView(props: ChatViewProps) {
     (window as any).chatReady = ready();
     (window as any).chatStatus = status();
     (window as any).chatError = currentError()?.message ?? '';
+    (window as any).groupMetadata = groupMetadata();
     (window as any).audioStats = {
       encryptedFramesSent: encryptedFramesSent(),
       encryptedFramesReceived: encryptedFramesReceived(),
@@ -112,6 +123,19 @@ export function ChatView(props: ChatViewProps) {
     removeMember: (pubkey) => {
       setChatState('members', (current) => current.filter((member) => member.pubkey !== pubkey));
     },
+    setGroupMetadata: (metadata) => setGroupMetadata(metadata),
+    addPendingWelcome: (welcome) => {
+      setPendingWelcomes((current) =>
+        current.some((item) => item.welcomeId === welcome.welcomeId) ? current : [...current, welcome],
+      );
+    },
+    setRelayHealth: (health) => {
+      setRelayHealth((current) => {
+        const next = current.filter((item) => item.url !== health.url);
+        next.push(health);
+        return next.sort((a, b) => a.url.localeCompare(b.url));
+      });
+    },
     showError: (error) => {
       setCurrentError(error);
       if (!error.fatal) {
@@ -162,6 +186,13 @@ export function ChatView(props: ChatViewProps) {
       }
     }
     peerPlayback.clear();
+    // The controller keeps the key rings; these are just handles onto them
+    mySession?.free();
+    mySession = null;
+    for (const { session } of peerSessions.values()) {
+      session.free();
+    }
+    peerSessions.clear();
     setAudioEnabled(false);
     setAudioStatus('');
   };
@@ -176,6 +207,8 @@ export function ChatView(props: ChatViewProps) {
     setChatState({ messages: [], commits: 0, members: [] });
     setReady(false);
     setCurrentError(null);
+    setPendingWelcomes([]);
+    setRelayHealth([]);
     if (messageInput) {
       messageInput.value = '';
     }
@@ -237,6 +270,26 @@ export function ChatView(props: ChatViewProps) {
     }
   };
 
+  const handleRemoveMember = (pubkey: string) => {
+    controller?.removeMember(pubkey);
+  };
+
+  const handleToggleAdmin = (pubkey: string, isAdmin: boolean) => {
+    controller?.setMemberAdmin(pubkey, !isAdmin);
+  };
+
+  const handleLeave = () => {
+    controller?.leaveGroup();
+  };
+
+  const handleRenameSubmit = (event: Event) => {
+    event.preventDefault();
+    const name = groupNameDraft().trim();
+    if (!name) return;
+    controller?.updateGroupMetadata(name, undefined);
+    setGroupNameDraft('');
+  };
+
   const handleInviteSubmit = async (event: Event) => {
     event.preventDefault();
     if (!controller) return;
@@ -268,45 +321,26 @@ export function ChatView(props: ChatViewProps) {
 
       // Get MoQ root from controller (MLS-derived)
       const moqRoot = await controller.groupRoot();
-      const trackLabel = `audio-${myPubkey.slice(0, 8)}`;
-      const epoch = await controller.currentEpoch();
-
-      // Derive media base key for our own audio track
-      const myBaseKey = await controller.deriveMediaBaseKey(myPubkey, trackLabel);
+      // Track labels are derived from the MLS exporter per sender and epoch
+      let trackLabel = await controller.deriveTrackLabel(myPubkey, 'audio');
 
-      // Track: base keys for peers (derived when we see them)
-      const peerBaseKeys = new Map<string, string>();
-
-      // Frame counter for outgoing frames
-      let frameCounter = 0;
+      // Media session for our own audio track; a commit moves it (and the
+      // label) to the next epoch
+      mySession = await controller.createMediaSession(myPubkey, trackLabel);
+      let labelEpoch = mySession.epoch();
 
       // Track frame counters for each peer
       const peerFrameCounters = new Map<string, number>();
 
-      // Helper: build AAD for a frame (takes track label as parameter)
-      const buildAAD = (trackLabelForAAD: string, groupSeq: number, frameIdx: number, isKeyframe: boolean): Uint8Array => {
-        const encoder = new TextEncoder();
-        const version = new Uint8Array([1]);
-        const groupRootBytes = encoder.encode(moqRoot);
-        const trackLabelBytes = encoder.encode(trackLabelForAAD);
-        const epochBytes = new Uint8Array(8);
-        new DataView(epochBytes.buffer).setBigUint64(0, BigInt(epoch), false); // big-endian
-        const groupSeqBytes = new Uint8Array(8);
-        new DataView(groupSeqBytes.buffer).setBigUint64(0, BigInt(groupSeq), false);
-        const frameIdxBytes = new Uint8Array(8);
-        new DataView(frameIdxBytes.buffer).setBigUint64(0, BigInt(frameIdx), false);
-        const keyframeBytes = new Uint8Array([isKeyframe ? 1 : 0]);
-
-        const parts = [version, groupRootBytes, trackLabelBytes, epochBytes, groupSeqBytes, frameIdxBytes, keyframeBytes];
-        const totalLen = parts.reduce((sum, p) => sum + p.length, 0);
-        const aad = new Uint8Array(totalLen);
-        let offset = 0;
-        for (const part of parts) {
-          aad.set(part, offset);
-          offset += part.length;
-        }
-        return aad;
-      };
+      // Helper: build SFrame metadata for a track. The SFrame header (key id +
+      // frame counter) is authenticated separately, so only stream context goes here.
+      const buildMetadata = (trackLabelForAAD: string, epoch: bigint): Uint8Array =>
+        encodeAad({
+          version: 1,
+          group_root: moqRoot,
+          track_label: trackLabelForAAD,
+          epoch,
+        });
 
       // Create audio MoQ bridge
       const moq = await createAudioMoq(
@@ -315,40 +349,44 @@ export function ChatView(props: ChatViewProps) {
           moqRoot,
           myPubkey,
           trackLabel,
+          peerTrackLabel: (peerPubkey) => controller!.deriveTrackLabel(peerPubkey, 'audio'),
         },
         {
           onReady: () => {
             console.debug('[audio] MoQ ready');
             setAudioStatus('Audio connected (encrypted)');
           },
-          onPeerAudio: async (peerPubkey, data) => {
-            // Derive peer's base key if not already done
-            if (!peerBaseKeys.has(peerPubkey)) {
-              const peerTrackLabel = `audio-${peerPubkey.slice(0, 8)}`;
-              const peerKey = await controller!.deriveMediaBaseKey(peerPubkey, peerTrackLabel);
-              peerBaseKeys.set(peerPubkey, peerKey);
+          onPeerAudio: async (peerPubkey, peerTrackLabel, data) => {
+            // Create the peer's media session, or recreate it if rotation failed
+            // or the peer moved to a new epoch's label
+            const existing = peerSessions.get(peerPubkey);
+            if (!existing || existing.label !== peerTrackLabel || !existing.session.isCurrent()) {
+              existing?.session.free();
+              peerSessions.set(peerPubkey, {
+                label: peerTrackLabel,
+                session: await controller!.createMediaSession(peerPubkey, peerTrackLabel),
+              });
             }
 
-            // Extract frame counter from first 4 bytes (big-endian u32)
-            if (data.length < 4) {
-              console.error('[audio] Invalid frame: too small', data.length);
+            // Frame counter travels in the SFrame header
+            let peerFrameCounter: number;
+            try {
+              peerFrameCounter = Number(sframeCounter(data));
+            } catch (err) {
+              console.error('[audio] Invalid SFrame header', data.length, err);
               return;
             }
 
-            const view = new DataView(data.buffer, data.byteOffset, data.byteLength);
-            const peerFrameCounter = view.getUint32(0, false); // big-endian
-            const ciphertext = new Uint8Array(data.buffer, data.byteOffset + 4, data.byteLength - 4);
-
-            // Build AAD with PEER's track label
-            const peerTrackLabel = `audio-${peerPubkey.slice(0, 8)}`;
-            const groupSeq = 0;
-            const isKeyframe = peerFrameCounter === 0;
-            const aad = buildAAD(peerTrackLabel, groupSeq, peerFrameCounter, isKeyframe);
+            const peerSession = peerSessions.get(peerPubkey)!.session;
 
-            const peerKey = peerBaseKeys.get(peerPubkey)!;
+            // Metadata uses the PEER's track label and the epoch of the key
+            // named in the header (frames sent just before a commit still
+            // carry the previous epoch's key)
+            const frameEpoch = peerSession.epochForKeyId(sframeKeyId(data)) ?? peerSession.epoch();
+            const metadata = buildMetadata(peerTrackLabel, frameEpoch);
 
             try {
-              const plaintext = await controller!.decryptAudioFrame(peerKey, ciphertext, peerFrameCounter, aad);
+              const { plaintext } = peerSession.decryptFrame(data, metadata);
 
               // Get or create playback for this peer
               if (!peerPlayback.has(peerPubkey)) {
@@ -422,24 +460,22 @@ export function ChatView(props: ChatViewProps) {
             const int16 = float32ToInt16(pcmData);
             const plaintext = new Uint8Array(int16.buffer);
 
-            // Build AAD for this frame
-            const groupSeq = 0; // MoQ group sequence (would increment for new groups)
-            const isKeyframe = frameCounter === 0;
-            const aad = buildAAD(trackLabel, groupSeq, frameCounter, isKeyframe);
-
             try {
-              // Encrypt the frame
-              const ciphertext = await controller!.encryptAudioFrame(myBaseKey, plaintext, frameCounter, aad);
-
-              // Prepend frame counter (4 bytes big-endian u32) to encrypted payload
-              const payload = new Uint8Array(4 + ciphertext.length);
-              new DataView(payload.buffer).setUint32(0, frameCounter, false); // big-endian
-              payload.set(ciphertext, 4);
+              if (!mySession || !mySession.isCurrent() || mySession.epoch() !== labelEpoch) {
+                // New epoch: move the broadcast to this epoch's label
+                mySession?.free();
+                trackLabel = await controller!.deriveTrackLabel(myPubkey, 'audio');
+                mySession = await controller!.createMediaSession(myPubkey, trackLabel);
+                labelEpoch = mySession.epoch();
+                moq.setTrackLabel(trackLabel);
+              }
+              // SFrame: the session allocates the counter and writes it, with the
+              // key id, into the frame header
+              const payload = mySession.encryptFrame(plaintext, buildMetadata(trackLabel, mySession.epoch()));
 
               // Publish encrypted frame with metadata
               moq.publishAudio(payload);
               setEncryptedFramesSent((prev) => prev + 1);
-              frameCounter++;
             } catch (err) {
               console.error('[audio] Encrypt error', err);
             }
@@ -481,16 +517,35 @@ export function ChatView(props: ChatViewProps) {
 
   const dismissError = () => setCurrentError(null);
 
+  const acceptWelcome = (welcome: PendingWelcome) => {
+    controller?.acceptWelcome(welcome.welcomeId);
+    setPendingWelcomes([]);
+  };
+
   return (
     <main class="chat-app" id="chat-view-root">
       <header class="chat-app__header">
-        <h1>Marmot Chat</h1>
+        <h1 id="group-name">{groupMetadata()?.name || 'Marmot Chat'}</h1>
+        <Show when={groupMetadata()?.description}>
+          {(description) => <p class="group-description">{description()}</p>}
+        </Show>
         <div id="status" class="status">{status()}</div>
         <div class="info">
           <span id="role">Role: {formatRole(props.session.role)}</span>
           <span id="relay">Relay: {props.session.relay}</span>
           <span id="nostr">Nostr: {props.session.nostr}</span>
         </div>
+        <Show when={relayHealth().length > 0}>
+          <ul class="relay-health" id="relay-health">
+            <For each={relayHealth()}>
+              {(relay) => (
+                <li class={`relay-health__item relay-health__item--${relay.state}`} title={describeRelay(relay)}>
+                  {relay.url}
+                </li>
+              )}
+            </For>
+          </ul>
+        </Show>
       </header>
 
       <Show when={currentError()}>
@@ -520,6 +575,25 @@ export function ChatView(props: ChatViewProps) {
         )}
       </Show>
 
+      <Show when={!ready() && pendingWelcomes().length > 0}>
+        <section class="pending-welcomes" id="pending-welcomes">
+          <h2>Invitations</h2>
+          <For each={pendingWelcomes()}>
+            {(welcome) => (
+              <div class="pending-welcome">
+                <span>
+                  {welcome.groupName || 'Unnamed group'} · {welcome.memberCount} members · from{' '}
+                  {shortenKey(welcome.welcomer)}
+                </span>
+                <button type="button" onClick={() => acceptWelcome(welcome)}>
+                  Join
+                </button>
+              </div>
+            )}
+          </For>
+        </section>
+      </Show>
+
       <section class="chat-app__messages" id="messages" aria-live="polite">
         <For each={chatState.messages}>
           {(message) => (
@@ -553,12 +627,51 @@ export function ChatView(props: ChatViewProps) {
               >
                 <span class="member__pubkey">{shortenKey(member.pubkey)}</span>
                 {member.isAdmin && <span class="member__role">admin</span>}
+                <Show when={isAdmin() && member.pubkey !== selfPubkey()}>
+                  <button
+                    type="button"
+                    class="member__admin-toggle"
+                    data-testid="toggle-admin"
+                    onClick={() => handleToggleAdmin(member.pubkey, member.isAdmin)}
+                    disabled={sending() || !ready()}
+                  >
+                    {member.isAdmin ? 'Revoke admin' : 'Make admin'}
+                  </button>
+                  <button
+                    type="button"
+                    class="member__remove"
+                    data-testid="remove-member"
+                    onClick={() => handleRemoveMember(member.pubkey)}
+                    disabled={sending() || !ready()}
+                  >
+                    Remove
+                  </button>
+                </Show>
               </li>
             )}
           </For>
         </ul>
       </section>
 
+      <Show when={isAdmin()}>
+        <section class="chat-app__group" id="group-settings">
+          <h2>Group</h2>
+          <form onSubmit={handleRenameSubmit} autocomplete="off">
+            <label for="group-name-input">Group name</label>
+            <input
+              id="group-name-input"
+              data-testid="group-name"
+              value={groupNameDraft()}
+              onInput={(event) => setGroupNameDraft(event.currentTarget.value)}
+              placeholder={groupMetadata()?.name ?? 'Marmot Chat'}
+            />
+            <button type="submit" data-testid="group-rename" disabled={!ready() || !groupNameDraft().trim()}>
+              Rename
+            </button>
+          </form>
+        </section>
+      </Show>
+
       <Show when={isAdmin()}>
         <section class="chat-app__invite" id="invite">
           <h2>Add Participant</h2>
@@ -613,6 +726,9 @@ export function ChatView(props: ChatViewProps) {
           >
             Rotate Epoch
           </button>
+          <button type="button" id="leave-group" data-testid="leave-group" onClick={handleLeave} disabled={!ready()}>
+            Leave
+          </button>
           <button
             type="button"
             id="audio-toggle"
@@ -650,3 +766,24 @@ function formatRole(role: ChatSession['role']) {
       return role;
   }
 }
+
+function describeRelay(relay: RelayHealth) {
+  const state = describeRelayState(relay);
+  return relay.dropped > 0 ? `${state} · ${relay.dropped} unsent messages dropped` : state;
+}
+
+function describeRelayState(relay: RelayHealth) {
+  switch (relay.state) {
+    case 'connected':
+      return 'Connected';
+    case 'connecting':
+      return 'Connecting…';
+    case 'reconnecting': {
+      if (relay.retryInMs === undefined) return `Reconnecting… (${relay.failures} failed)`;
+      const seconds = Math.ceil(relay.retryInMs / 1000);
+      return `Reconnecting in ${seconds}s (${relay.failures} failed)`;
+    }
+    default:
+      return relay.state;
+  }
+}
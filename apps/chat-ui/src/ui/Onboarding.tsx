@@ -111,7 +111,7 @@ export function Onboarding(props: OnboardingProps) {
       setPeerPub(peer);
       const session = crypto.randomUUID().replace(/-/g, '');
       setSessionId(session);
-      const invitePayload = { session, relay: relayUrl(), nostr: nostrUrl() };
+      const invitePayload = { session, relay: relayUrl(), nostr: nostrUrl(), inviter: pubkey() };
       const encoded = encodeURIComponent(JSON.stringify(invitePayload));
       const link = `${window.location.origin}${window.location.pathname}?invite=${encoded}`;
       setInviteLink(link);
@@ -139,7 +139,7 @@ export function Onboarding(props: OnboardingProps) {
       sessionId: parsed.session,
       secretHex: secretHex(),
       adminPubkeys: [],
-      peerPubkeys: [],
+      peerPubkeys: parsed.inviter ? [parsed.inviter] : [],
     };
     props.onComplete({ session });
   };
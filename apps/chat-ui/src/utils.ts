@@ -42,6 +42,8 @@ export interface InvitePayload {
   session: string;
   relay: string;
   nostr: string;
+  /** Pubkey of the member who created the invite; handshakes are addressed to it */
+  inviter?: string;
 }
 
 export function parseInvite(raw: string): InvitePayload | null {
@@ -65,8 +67,9 @@ export function parseInvite(raw: string): InvitePayload | null {
       const session = String((parsed as any).session ?? '').trim();
       const relay = String((parsed as any).relay ?? '').trim();
       const nostr = String((parsed as any).nostr ?? '').trim();
+      const inviter = String((parsed as any).inviter ?? '').trim();
       if (session && relay && nostr) {
-        return { session, relay, nostr };
+        return inviter ? { session, relay, nostr, inviter } : { session, relay, nostr };
       }
     }
   } catch (err) {
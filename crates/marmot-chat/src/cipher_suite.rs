@@ -0,0 +1,191 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Result};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+
+/// AEAD used for a media track
+///
+/// Chosen by the publisher and announced in the track's directory entry, so
+/// every subscriber decrypts with the same suite. All suites use 96-bit
+/// nonces and 128-bit tags; only the key size differs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CipherSuite {
+    #[default]
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes128Gcm,
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+    ];
+
+    /// Name used in directory messages
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::Aes128Gcm => "aes-128-gcm",
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|suite| suite.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("unsupported media cipher suite {name:?}"))
+    }
+
+    /// Pick the first of the publisher's `offered` suites we support
+    pub fn negotiate(offered: &[CipherSuite], supported: &[CipherSuite]) -> Option<Self> {
+        offered
+            .iter()
+            .copied()
+            .find(|suite| supported.contains(suite))
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            CipherSuite::Aes128Gcm => 16,
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        12
+    }
+
+    pub fn tag_len(self) -> usize {
+        16
+    }
+
+    /// SFrame cipher suite id (RFC 9605, section 4.5)
+    ///
+    /// `AES_128_GCM_SHA256_128` and `AES_256_GCM_SHA512_128`. ChaCha20-
+    /// Poly1305 is not registered; it takes 0xF001 from the private use
+    /// range and derives its keys with HKDF-SHA256.
+    pub fn sframe_id(self) -> u16 {
+        match self {
+            CipherSuite::Aes128Gcm => 0x0004,
+            CipherSuite::Aes256Gcm => 0x0005,
+            CipherSuite::ChaCha20Poly1305 => 0xF001,
+        }
+    }
+
+    /// Suffix of the SFrame KID label, so one base key gets a KID per suite
+    ///
+    /// Empty for AES-128-GCM, keeping its KIDs unchanged from before suites
+    /// were negotiable.
+    pub(crate) fn hkdf_label(self) -> &'static [u8] {
+        match self {
+            CipherSuite::Aes128Gcm => b"",
+            CipherSuite::Aes256Gcm => b"aes256gcm",
+            CipherSuite::ChaCha20Poly1305 => b"chacha20poly1305",
+        }
+    }
+
+    pub(crate) fn seal(self, key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.check_lengths(key, nonce)?;
+        let nonce = Nonce::from_slice(nonce);
+        let payload = Payload { msg, aad };
+        let sealed = match self {
+            CipherSuite::Aes128Gcm => Aes128Gcm::new(key.into()).encrypt(nonce, payload),
+            CipherSuite::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce, payload),
+            CipherSuite::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into()).encrypt(nonce, payload)
+            }
+        };
+        sealed.map_err(|e| anyhow!("AEAD encryption failed: {e}"))
+    }
+
+    pub(crate) fn open(self, key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.check_lengths(key, nonce)?;
+        let nonce = Nonce::from_slice(nonce);
+        let payload = Payload { msg, aad };
+        let opened = match self {
+            CipherSuite::Aes128Gcm => Aes128Gcm::new(key.into()).decrypt(nonce, payload),
+            CipherSuite::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(nonce, payload),
+            CipherSuite::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into()).decrypt(nonce, payload)
+            }
+        };
+        opened.map_err(|e| anyhow!("AEAD decryption failed: {e}"))
+    }
+
+    fn check_lengths(self, key: &[u8], nonce: &[u8]) -> Result<()> {
+        if key.len() != self.key_len() || nonce.len() != self.nonce_len() {
+            return Err(anyhow!(
+                "{} expects a {}-byte key and {}-byte nonce",
+                self.name(),
+                self.key_len(),
+                self.nonce_len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_roundtrip_for_every_suite() {
+        for suite in CipherSuite::ALL {
+            let key = vec![7u8; suite.key_len()];
+            let nonce = vec![1u8; suite.nonce_len()];
+            let sealed = suite.seal(&key, &nonce, b"frame", b"aad").unwrap();
+            assert_eq!(sealed.len(), b"frame".len() + suite.tag_len());
+            assert_eq!(suite.open(&key, &nonce, &sealed, b"aad").unwrap(), b"frame");
+            assert!(suite.open(&key, &nonce, &sealed, b"other").is_err());
+        }
+    }
+
+    #[test]
+    fn test_suite_names_match_serde() {
+        for suite in CipherSuite::ALL {
+            let json = serde_json::to_string(&suite).unwrap();
+            assert_eq!(json, format!("\"{}\"", suite.name()));
+            assert_eq!(CipherSuite::from_name(suite.name()).unwrap(), suite);
+        }
+        assert!(CipherSuite::from_name("aes-512-gcm").is_err());
+    }
+
+    #[test]
+    fn test_negotiate_prefers_publisher_order() {
+        let offered = [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes128Gcm];
+        assert_eq!(
+            CipherSuite::negotiate(&offered, &CipherSuite::ALL),
+            Some(CipherSuite::ChaCha20Poly1305)
+        );
+        assert_eq!(
+            CipherSuite::negotiate(&offered, &[CipherSuite::Aes128Gcm]),
+            Some(CipherSuite::Aes128Gcm)
+        );
+        assert_eq!(
+            CipherSuite::negotiate(&offered, &[CipherSuite::Aes256Gcm]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_wrong_key_length_rejected() {
+        let nonce = [0u8; 12];
+        assert!(CipherSuite::Aes256Gcm
+            .seal(&[0u8; 16], &nonce, b"x", b"")
+            .is_err());
+    }
+}
@@ -14,6 +14,9 @@ pub enum ErrorStage {
     Handshake,
     Messaging,
     Invite,
+    Membership,
+    /// Group name, description or image
+    Metadata,
 }
 
 impl fmt::Display for ErrorStage {
@@ -22,6 +25,8 @@ impl fmt::Display for ErrorStage {
             ErrorStage::Handshake => write!(f, "handshake"),
             ErrorStage::Messaging => write!(f, "messaging"),
             ErrorStage::Invite => write!(f, "invite"),
+            ErrorStage::Membership => write!(f, "membership"),
+            ErrorStage::Metadata => write!(f, "metadata"),
         }
     }
 }
@@ -91,6 +96,8 @@ fn default_user_message(stage: ErrorStage) -> &'static str {
             "Failed to process encrypted message. Refresh or request a new invite."
         }
         ErrorStage::Invite => "Invite request failed. Verify the participant key and try again.",
+        ErrorStage::Membership => "Membership change failed. Try again.",
+        ErrorStage::Metadata => "Group settings change failed. Try again.",
     }
 }
 
@@ -99,5 +106,7 @@ fn default_recovery_action(stage: ErrorStage) -> RecoveryAction {
         ErrorStage::Handshake => RecoveryAction::Refresh,
         ErrorStage::Messaging => RecoveryAction::Refresh,
         ErrorStage::Invite => RecoveryAction::Retry,
+        ErrorStage::Membership => RecoveryAction::Retry,
+        ErrorStage::Metadata => RecoveryAction::Retry,
     }
 }
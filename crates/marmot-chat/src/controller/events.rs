@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::secret::SecretString;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum RecoveryAction {
@@ -50,8 +52,11 @@ pub struct SessionParams {
     pub bootstrap_role: SessionRole,
     pub relay_url: String,
     pub nostr_url: String,
+    /// Further relays the handshake uses alongside `nostr_url`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nostr_relays: Vec<String>,
     pub session_id: String,
-    pub secret_hex: String,
+    pub secret_hex: SecretString,
     #[serde(default)]
     pub peer_pubkeys: Vec<String>,
     #[serde(default)]
@@ -63,6 +68,34 @@ pub struct SessionParams {
     /// MLS-derived MoQ root path (replaces session_id for MoQ transport after group establishment)
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub moq_root: Option<String>,
+    /// How the MoQ root is chosen once the group is established
+    #[serde(default)]
+    pub root_mode: GroupRootMode,
+    /// Name for a group this session creates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    /// Description for a group this session creates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_description: Option<String>,
+    /// The host keeps sealed snapshots of the state and resumes from them,
+    /// so an in-memory identity may publish key packages
+    #[serde(default)]
+    pub persists_snapshots: bool,
+}
+
+/// MoQ root selection
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupRootMode {
+    /// Stay on `marmot/<hex(mls_group_id)>`
+    Legacy,
+    /// Start on the legacy root, announce both roots, and move to the
+    /// exporter root once every member has announced it
+    #[default]
+    Migrate,
+    /// Use the exporter-derived root of the epoch the group was created at
+    /// from the start
+    Exporter,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -72,6 +105,44 @@ pub struct MemberInfo {
     pub is_admin: bool,
 }
 
+/// A welcome waiting for the user to accept it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PendingWelcome {
+    /// Id of the kind 444 rumor, passed back to accept it
+    pub welcome_id: String,
+    pub group_id_hex: String,
+    pub group_name: String,
+    /// Pubkey of whoever added us
+    pub welcomer: String,
+    pub member_count: u32,
+}
+
+/// Where a handshake relay connection stands
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayState {
+    Connecting,
+    Connected,
+    /// Dropped or unreachable; another attempt is scheduled
+    Reconnecting,
+}
+
+/// Health of one relay in the handshake pool
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RelayHealth {
+    pub url: String,
+    pub state: RelayState,
+    /// Attempts that failed since the relay was last connected
+    pub failures: u32,
+    /// Delay before the next attempt while reconnecting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u64>,
+    /// Messages given up on because too many piled up while the relay was
+    /// down
+    #[serde(default)]
+    pub dropped: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ChatEvent {
@@ -107,6 +178,11 @@ pub enum ChatEvent {
         recipient: String,
         is_admin: bool,
     },
+    /// A welcome this session did not ask for; join it with
+    /// `ChatController::accept_welcome`
+    WelcomePending {
+        welcome: PendingWelcome,
+    },
     Error {
         message: String,
         #[serde(default = "default_true")]
@@ -117,6 +193,18 @@ pub enum ChatEvent {
     Handshake {
         phase: HandshakePhase,
     },
+    /// A handshake relay connected, dropped or is being retried
+    RelayHealth {
+        relay: RelayHealth,
+    },
+    /// Group name, description and image from the group context
+    GroupMetadata {
+        name: String,
+        description: String,
+        /// Hex SHA-256 of the encrypted image blob, if the group has one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image_hash: Option<String>,
+    },
     /// Directory update received from a participant
     DirectoryUpdate {
         sender: String,
@@ -125,6 +213,20 @@ pub enum ChatEvent {
     },
 }
 
+/// A [`ChatEvent`] from one of several groups sharing an identity
+///
+/// Serialized flat: the event's own fields plus `session_id` and, once the
+/// group exists, `group_id_hex`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupEvent {
+    /// Session the group was joined through
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id_hex: Option<String>,
+    #[serde(flatten)]
+    pub event: ChatEvent,
+}
+
 /// Track information for UI consumption
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TrackInfo {
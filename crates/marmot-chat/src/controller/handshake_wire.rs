@@ -0,0 +1,723 @@
+//! Handshake events on the wire
+//!
+//! Each handshake message is addressed to one peer and gift-wrapped per
+//! NIP-59: the payload travels as an unsigned kind 44501 rumor, sealed to
+//! the recipient with NIP-44 under the sender's key, and the seal is
+//! wrapped again under a throwaway key. Relays and onlookers only see a
+//! kind 1059 event tagged with the recipient's pubkey; the session, the
+//! sender and the payload are inside. Clients subscribe to the wraps
+//! addressed to their own key and route them to a session after opening
+//! them. The browser and native Nostr services both speak this format.
+//!
+//! Welcomes travel the standard Marmot way instead: the kind 444 rumor MDK
+//! produces is wrapped as it is, so it names no session and the invitee
+//! can pick it up whenever they next come online.
+//!
+//! Opening a wrap checks that the seal author wrote the rumor and any
+//! pubkey the payload claims, and that a handshake payload is recent.
+//! Services keep a [`SeenEvents`] so relayed copies are handled once.
+
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::{anyhow, Context, Result};
+use nostr::nips::nip44::{self, Version};
+use nostr::prelude::*;
+use nostr::JsonUtil;
+use serde_json::{json, Value as JsonValue};
+
+use super::events::SessionRole;
+use super::services::{HandshakeMessage, HandshakeMessageBody, HandshakeMessageType};
+
+/// Kind of the rumor carrying a handshake payload
+pub const HANDSHAKE_KIND: u16 = 44501;
+/// How far seals and wraps backdate `created_at` so it says nothing about
+/// when the message was sent, as NIP-59 recommends
+const TIMESTAMP_TWEAK_SECS: u64 = 2 * 24 * 60 * 60;
+/// Oldest handshake payload still acted on; relays hold requests for
+/// invitees who come online later, but not forever
+const MAX_HANDSHAKE_AGE_SECS: u64 = 24 * 60 * 60;
+/// How far a sender's clock may run ahead of ours
+const MAX_CLOCK_SKEW_SECS: u64 = 10 * 60;
+/// Event ids a [`SeenEvents`] remembers before forgetting the oldest
+const SEEN_CAPACITY: usize = 4096;
+
+/// Subscription id a client uses for the wraps addressed to `pubkey_hex`
+pub fn subscription_id(pubkey_hex: &str) -> String {
+    format!("marmot-{pubkey_hex}")
+}
+
+/// `REQ` for the gift wraps addressed to `pubkey_hex`, optionally only
+/// those dated from `since`
+pub fn subscription_request(pubkey_hex: &str, since: Option<Timestamp>) -> String {
+    let mut filter = json!({
+        "kinds": [Kind::GiftWrap.as_u16()],
+        "#p": [pubkey_hex],
+    });
+    if let Some(since) = since {
+        filter["since"] = json!(since.as_u64());
+    }
+    format!("[\"REQ\",\"{}\",{}]", subscription_id(pubkey_hex), filter)
+}
+
+/// `since` for resubscribing to a relay that dropped at `dropped_at`
+///
+/// Wraps sent after the drop may be backdated by up to
+/// [`TIMESTAMP_TWEAK_SECS`], so the window reaches back that far.
+pub fn resume_since(dropped_at: Timestamp) -> Timestamp {
+    Timestamp::from(dropped_at.as_u64().saturating_sub(TIMESTAMP_TWEAK_SECS))
+}
+
+/// `CLOSE` for the subscription opened by [`subscription_request`]
+pub fn close_request(pubkey_hex: &str) -> String {
+    format!("[\"CLOSE\",\"{}\"]", subscription_id(pubkey_hex))
+}
+
+/// Seal `message` from `keys` as `role` in `session` and gift-wrap it for
+/// `message.peer`
+///
+/// A welcome is wrapped as the kind 444 rumor it carries; everything else
+/// becomes a kind 44501 handshake payload.
+pub fn build_handshake_event(
+    keys: &Keys,
+    session: &str,
+    role: SessionRole,
+    message: &HandshakeMessage,
+) -> Result<Event> {
+    let recipient = PublicKey::from_hex(&message.peer).context("parse handshake recipient")?;
+    let rumor = match &message.data {
+        HandshakeMessageBody::Welcome { welcome, .. } => {
+            let rumor = UnsignedEvent::from_json(welcome).context("parse welcome rumor")?;
+            if rumor.kind != Kind::MlsWelcome || rumor.pubkey != keys.public_key() {
+                return Err(anyhow!("welcome rumor is not a kind 444 event of ours"));
+            }
+            rumor
+        }
+        _ => {
+            let content = serde_json::to_string(&handshake_payload(session, role, message))
+                .context("serialize handshake payload")?;
+            EventBuilder::new(Kind::from(HANDSHAKE_KIND), content).build(keys.public_key())
+        }
+    };
+    gift_wrap(keys, &recipient, rumor)
+}
+
+/// Seal `rumor` from `keys` with NIP-44 and wrap the seal for `recipient`
+fn gift_wrap(keys: &Keys, recipient: &PublicKey, mut rumor: UnsignedEvent) -> Result<Event> {
+    rumor.ensure_id();
+    let sealed = nip44::encrypt(keys.secret_key(), recipient, rumor.as_json(), Version::V2)
+        .context("seal handshake rumor")?;
+    let seal = EventBuilder::new(Kind::Seal, sealed)
+        .custom_created_at(tweaked_timestamp())
+        .sign_with_keys(keys)
+        .context("sign handshake seal")?;
+
+    let wrap_keys = Keys::generate();
+    let wrapped = nip44::encrypt(
+        wrap_keys.secret_key(),
+        recipient,
+        seal.as_json(),
+        Version::V2,
+    )
+    .context("wrap handshake seal")?;
+    EventBuilder::new(Kind::GiftWrap, wrapped)
+        .tag(Tag::public_key(*recipient))
+        .custom_created_at(tweaked_timestamp())
+        .sign_with_keys(&wrap_keys)
+        .context("sign handshake gift wrap")
+}
+
+/// `EVENT` message publishing `event`
+pub fn event_message(event: &Event) -> String {
+    format!("[\"EVENT\",{}]", event.as_json())
+}
+
+/// Relays named by `message` that are not among `connected`, which a
+/// service has to reach separately for the recipient to see it
+pub fn unconnected_inbox_relays<'a>(
+    message: &'a HandshakeMessage,
+    connected: &[&str],
+) -> Vec<&'a str> {
+    let HandshakeMessageBody::Welcome { relays, .. } = &message.data else {
+        return Vec::new();
+    };
+    let normalize = |url: &str| url.trim_end_matches('/').to_ascii_lowercase();
+    let mut missing: Vec<&str> = Vec::new();
+    for url in relays {
+        let known = connected
+            .iter()
+            .chain(missing.iter())
+            .any(|other| normalize(other) == normalize(url));
+        if !known {
+            missing.push(url);
+        }
+    }
+    missing
+}
+
+/// Relay messages the Nostr services act on
+#[derive(Debug)]
+pub enum RelayMessage {
+    /// `EVENT` delivered on `subscription`
+    Event { subscription: String, event: Event },
+    /// `EOSE`: stored events for `subscription` are done, live ones follow
+    EndOfStoredEvents(String),
+}
+
+/// Parse an `EVENT` or `EOSE` message from a relay
+pub fn parse_relay_message(text: &str) -> Option<RelayMessage> {
+    let parsed: JsonValue = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(err) => {
+            log::warn!("failed to parse relay message: {err}");
+            return None;
+        }
+    };
+    let array = parsed.as_array()?;
+    let subscription = array.get(1).and_then(|v| v.as_str())?.to_string();
+    match array.first().and_then(|v| v.as_str())? {
+        "EVENT" => match Event::from_json(array.get(2)?.to_string()) {
+            Ok(event) => Some(RelayMessage::Event {
+                subscription,
+                event,
+            }),
+            Err(err) => {
+                log::warn!("failed to decode nostr event: {err}");
+                None
+            }
+        },
+        "EOSE" => Some(RelayMessage::EndOfStoredEvents(subscription)),
+        _ => None,
+    }
+}
+
+/// Pull the event out of a relay `EVENT` message
+pub fn event_from_relay_message(text: &str) -> Option<Event> {
+    match parse_relay_message(text)? {
+        RelayMessage::Event { event, .. } => Some(event),
+        RelayMessage::EndOfStoredEvents(_) => None,
+    }
+}
+
+/// A handshake opened from a gift wrap, with the session and role it names
+///
+/// `message.peer` is the key that signed the seal, i.e. the sender.
+/// Welcomes name neither session nor role.
+pub struct InboundHandshake {
+    /// Id of the rumor, the same however often it is wrapped or relayed
+    pub id: EventId,
+    pub session: Option<String>,
+    pub from: Option<SessionRole>,
+    pub message: HandshakeMessage,
+}
+
+impl InboundHandshake {
+    /// Whether a session acting as `role` should hear this
+    ///
+    /// Welcomes go to every invitee session of the recipient, which sort
+    /// out among themselves whose welcome it is.
+    pub fn is_for(&self, session: &str, role: SessionRole) -> bool {
+        match (&self.session, self.from) {
+            (Some(named), Some(from)) => named == session && from != role,
+            _ => role == SessionRole::Invitee,
+        }
+    }
+}
+
+/// Event ids already handled, forgetting the oldest past a bound
+#[derive(Default)]
+pub struct SeenEvents {
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+}
+
+impl SeenEvents {
+    /// Record `id`, returning whether it is new
+    pub fn insert(&mut self, id: EventId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.order.clear();
+    }
+}
+
+/// Open a gift wrap addressed to `keys`
+///
+/// Returns `None` for other events and for wraps that are not for `keys`,
+/// do not hold a well-formed handshake, misstate their author or are
+/// stale.
+pub fn decode_handshake_event(keys: &Keys, event: &Event) -> Option<InboundHandshake> {
+    if event.kind != Kind::GiftWrap {
+        return None;
+    }
+    match open_gift_wrap(keys, event, Timestamp::now()) {
+        Ok(inbound) => Some(inbound),
+        Err(err) => {
+            log::debug!("skipping gift wrap {}: {err:#}", event.id);
+            None
+        }
+    }
+}
+
+fn open_gift_wrap(keys: &Keys, event: &Event, now: Timestamp) -> Result<InboundHandshake> {
+    event.verify().context("verify wrap")?;
+    let seal_json =
+        nip44::decrypt(keys.secret_key(), &event.pubkey, &event.content).context("open wrap")?;
+    let seal = Event::from_json(seal_json).context("parse seal")?;
+    if seal.kind != Kind::Seal {
+        return Err(anyhow!("wrapped event is not a seal"));
+    }
+    seal.verify().context("verify seal")?;
+
+    let rumor_json =
+        nip44::decrypt(keys.secret_key(), &seal.pubkey, &seal.content).context("open seal")?;
+    let mut rumor = UnsignedEvent::from_json(rumor_json).context("parse rumor")?;
+    // The seal signature only vouches for the rumor if they name one author
+    if rumor.pubkey != seal.pubkey {
+        return Err(anyhow!("rumor author does not match seal"));
+    }
+    // Ours to compute, so a sender cannot pass one rumor off as another
+    rumor.id = None;
+    rumor.ensure_id();
+    let id = rumor.id.ok_or_else(|| anyhow!("rumor without id"))?;
+    // Welcomes wait on relays until the invitee comes online, and MLS
+    // refuses any it has already processed
+    if rumor.kind == Kind::MlsWelcome {
+        return Ok(InboundHandshake {
+            id,
+            session: None,
+            from: None,
+            message: HandshakeMessage {
+                message_type: HandshakeMessageType::Welcome,
+                peer: seal.pubkey.to_hex(),
+                data: HandshakeMessageBody::Welcome {
+                    welcome: rumor.as_json(),
+                    recipient: Some(keys.public_key().to_hex()),
+                    relays: Vec::new(),
+                    wrap_id: Some(event.id),
+                },
+            },
+        });
+    }
+    if rumor.kind != Kind::from(HANDSHAKE_KIND) {
+        return Err(anyhow!("rumor is not a handshake"));
+    }
+    check_fresh(rumor.created_at, now)?;
+
+    let payload: JsonValue =
+        serde_json::from_str(&rumor.content).context("parse handshake payload")?;
+    let session = payload
+        .get("session")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("handshake payload missing session"))?
+        .to_string();
+    let from = payload
+        .get("from")
+        .and_then(|v| v.as_str())
+        .and_then(SessionRole::from_str)
+        .ok_or_else(|| anyhow!("handshake payload missing role"))?;
+    let message = handshake_from_payload(&payload, seal.pubkey.to_hex())
+        .ok_or_else(|| anyhow!("malformed handshake payload"))?;
+    // A key package speaks for whoever it names, so only its author may
+    if let HandshakeMessageBody::KeyPackage {
+        pubkey: Some(claimed),
+        ..
+    } = &message.data
+    {
+        if *claimed != message.peer {
+            return Err(anyhow!("key package claimed for another pubkey"));
+        }
+    }
+    Ok(InboundHandshake {
+        id,
+        session: Some(session),
+        from: Some(from),
+        message,
+    })
+}
+
+/// Whether a rumor written at `created_at` is recent enough at `now`
+fn check_fresh(created_at: Timestamp, now: Timestamp) -> Result<()> {
+    let (created_at, now) = (created_at.as_u64(), now.as_u64());
+    if created_at > now.saturating_add(MAX_CLOCK_SKEW_SECS) {
+        return Err(anyhow!("handshake dated {created_at} is in the future"));
+    }
+    if created_at.saturating_add(MAX_HANDSHAKE_AGE_SECS) < now {
+        return Err(anyhow!("handshake dated {created_at} is stale"));
+    }
+    Ok(())
+}
+
+/// A moment up to [`TIMESTAMP_TWEAK_SECS`] in the past
+fn tweaked_timestamp() -> Timestamp {
+    let mut bytes = [0u8; 8];
+    let offset = match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u64::from_le_bytes(bytes) % TIMESTAMP_TWEAK_SECS,
+        Err(_) => 0,
+    };
+    Timestamp::from(Timestamp::now().as_u64().saturating_sub(offset))
+}
+
+fn handshake_payload(session: &str, role: SessionRole, message: &HandshakeMessage) -> JsonValue {
+    let mut base = json!({
+        "type": message.message_type.as_str(),
+        "session": session,
+        "from": role.as_str(),
+        "created_at": Timestamp::now().as_u64(),
+    });
+    match &message.data {
+        // Welcomes are wrapped as their own rumor, never as a payload
+        HandshakeMessageBody::None | HandshakeMessageBody::Welcome { .. } => {}
+        HandshakeMessageBody::Request { pubkey, is_admin } => {
+            if let Some(obj) = base.as_object_mut() {
+                if let Some(pubkey) = pubkey {
+                    obj.insert("pubkey".to_string(), json!(pubkey));
+                }
+                if let Some(is_admin) = is_admin {
+                    obj.insert("isAdmin".to_string(), json!(is_admin));
+                }
+            }
+        }
+        HandshakeMessageBody::KeyPackage { event, pubkey } => {
+            if let Some(obj) = base.as_object_mut() {
+                obj.insert("event".to_string(), json!(event));
+                if let Some(pubkey) = pubkey {
+                    obj.insert("pubkey".to_string(), json!(pubkey));
+                }
+            }
+        }
+    }
+    base
+}
+
+fn handshake_from_payload(payload: &JsonValue, peer: String) -> Option<HandshakeMessage> {
+    let ty = payload.get("type")?.as_str()?;
+    let message_type = HandshakeMessageType::from_str(ty)?;
+    let data = match message_type {
+        HandshakeMessageType::RequestKeyPackage | HandshakeMessageType::RequestWelcome => {
+            let pubkey = payload
+                .get("pubkey")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
+            let is_admin = payload.get("isAdmin").and_then(|v| v.as_bool());
+            HandshakeMessageBody::Request { pubkey, is_admin }
+        }
+        HandshakeMessageType::KeyPackage => {
+            let event = payload.get("event")?.as_str()?.to_string();
+            let pubkey = payload
+                .get("pubkey")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string());
+            HandshakeMessageBody::KeyPackage { event, pubkey }
+        }
+        // Welcomes only arrive as kind 444 rumors
+        HandshakeMessageType::Welcome => return None,
+    };
+    Some(HandshakeMessage {
+        message_type,
+        peer,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender() -> Keys {
+        Keys::new(SecretKey::from_hex(&"42".repeat(32)).unwrap())
+    }
+
+    fn recipient() -> Keys {
+        Keys::new(SecretKey::from_hex(&"43".repeat(32)).unwrap())
+    }
+
+    fn relay_echo(keys: &Keys, event: &Event) -> Option<InboundHandshake> {
+        let pubkey = keys.public_key().to_hex();
+        let relayed = format!(
+            "[\"EVENT\",\"{}\",{}]",
+            subscription_id(&pubkey),
+            event.as_json()
+        );
+        event_from_relay_message(&relayed)
+            .as_ref()
+            .and_then(|event| decode_handshake_event(keys, event))
+    }
+
+    fn key_package_claiming(pubkey: String) -> HandshakeMessage {
+        HandshakeMessage {
+            message_type: HandshakeMessageType::KeyPackage,
+            peer: recipient().public_key().to_hex(),
+            data: HandshakeMessageBody::KeyPackage {
+                event: "{}".to_string(),
+                pubkey: Some(pubkey),
+            },
+        }
+    }
+
+    fn key_package_to_recipient() -> HandshakeMessage {
+        key_package_claiming(sender().public_key().to_hex())
+    }
+
+    /// A handshake rumor written at `created_at`
+    fn rumor_dated(message: &HandshakeMessage, created_at: u64) -> UnsignedEvent {
+        let content =
+            serde_json::to_string(&handshake_payload("s1", SessionRole::Invitee, message)).unwrap();
+        EventBuilder::new(Kind::from(HANDSHAKE_KIND), content)
+            .custom_created_at(created_at.into())
+            .build(sender().public_key())
+    }
+
+    fn wrap(rumor: UnsignedEvent) -> Event {
+        gift_wrap(&sender(), &recipient().public_key(), rumor).unwrap()
+    }
+
+    #[test]
+    fn test_key_package_roundtrip() {
+        let message = key_package_to_recipient();
+        let event = build_handshake_event(&sender(), "s1", SessionRole::Invitee, &message).unwrap();
+        assert_eq!(event.kind, Kind::GiftWrap);
+        assert!(event.verify().is_ok());
+
+        let inbound = relay_echo(&recipient(), &event).unwrap();
+        assert_eq!(inbound.session.as_deref(), Some("s1"));
+        assert_eq!(inbound.from, Some(SessionRole::Invitee));
+        assert!(inbound.is_for("s1", SessionRole::Initial));
+        assert!(!inbound.is_for("s1", SessionRole::Invitee));
+        assert!(!inbound.is_for("s2", SessionRole::Initial));
+        assert_eq!(inbound.message.peer, sender().public_key().to_hex());
+        match inbound.message.data {
+            HandshakeMessageBody::KeyPackage { pubkey, .. } => {
+                assert_eq!(pubkey, Some(sender().public_key().to_hex()));
+            }
+            other => panic!("unexpected body {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_key_package_for_someone_else_rejected() {
+        let message = key_package_claiming("ab".repeat(32));
+        let event = build_handshake_event(&sender(), "s1", SessionRole::Invitee, &message).unwrap();
+        assert!(decode_handshake_event(&recipient(), &event).is_none());
+    }
+
+    #[test]
+    fn test_only_recent_handshakes_accepted() {
+        let now = Timestamp::now().as_u64();
+        let message = key_package_to_recipient();
+        let open = |created_at| {
+            let event = wrap(rumor_dated(&message, created_at));
+            open_gift_wrap(&recipient(), &event, now.into()).is_ok()
+        };
+        assert!(open(now));
+        assert!(open(now - MAX_HANDSHAKE_AGE_SECS + 60));
+        assert!(!open(now - MAX_HANDSHAKE_AGE_SECS - 60));
+        assert!(open(now + 60));
+        assert!(!open(now + MAX_CLOCK_SKEW_SECS + 60));
+    }
+
+    #[test]
+    fn test_rewrapped_rumor_keeps_its_id() {
+        let rumor = rumor_dated(&key_package_to_recipient(), Timestamp::now().as_u64());
+        let (first, second) = (wrap(rumor.clone()), wrap(rumor));
+        assert_ne!(first.id, second.id);
+        let first = relay_echo(&recipient(), &first).unwrap();
+        let second = relay_echo(&recipient(), &second).unwrap();
+        assert_eq!(first.id, second.id);
+
+        let mut seen = SeenEvents::default();
+        assert!(seen.insert(first.id));
+        assert!(!seen.insert(second.id));
+    }
+
+    #[test]
+    fn test_seen_events_forget_the_oldest() {
+        let mut seen = SeenEvents::default();
+        let ids: Vec<EventId> = (0..=SEEN_CAPACITY)
+            .map(|index| EventId::from_byte_array(id_bytes(index)))
+            .collect();
+        for id in &ids {
+            assert!(seen.insert(*id));
+        }
+        assert!(!seen.insert(ids[SEEN_CAPACITY]));
+        assert!(seen.insert(ids[0]));
+        seen.clear();
+        assert!(seen.insert(ids[SEEN_CAPACITY]));
+    }
+
+    fn id_bytes(index: usize) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(index as u64).to_le_bytes());
+        bytes
+    }
+
+    fn welcome_to_recipient(rumor: &UnsignedEvent) -> HandshakeMessage {
+        HandshakeMessage {
+            message_type: HandshakeMessageType::Welcome,
+            peer: recipient().public_key().to_hex(),
+            data: HandshakeMessageBody::Welcome {
+                welcome: rumor.as_json(),
+                recipient: None,
+                relays: Vec::new(),
+                wrap_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_welcome_travels_as_its_rumor() {
+        let mut rumor = EventBuilder::new(Kind::MlsWelcome, "welcome")
+            .tag(Tag::event(EventId::all_zeros()))
+            .build(sender().public_key());
+        rumor.ensure_id();
+        let event = build_handshake_event(
+            &sender(),
+            "s1",
+            SessionRole::Initial,
+            &welcome_to_recipient(&rumor),
+        )
+        .unwrap();
+        let inbound = relay_echo(&recipient(), &event).unwrap();
+        assert_eq!(inbound.session, None);
+        assert_eq!(inbound.from, None);
+        assert!(inbound.is_for("any", SessionRole::Invitee));
+        assert!(!inbound.is_for("any", SessionRole::Initial));
+        assert_eq!(inbound.message.peer, sender().public_key().to_hex());
+        match inbound.message.data {
+            HandshakeMessageBody::Welcome {
+                welcome,
+                recipient: to,
+                wrap_id,
+                ..
+            } => {
+                assert_eq!(UnsignedEvent::from_json(welcome).unwrap().id, rumor.id);
+                assert_eq!(to, Some(recipient().public_key().to_hex()));
+                assert_eq!(wrap_id, Some(event.id));
+            }
+            other => panic!("unexpected body {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_welcome_must_be_our_kind_444() {
+        let note = EventBuilder::text_note("hi").build(sender().public_key());
+        let foreign =
+            EventBuilder::new(Kind::MlsWelcome, "welcome").build(recipient().public_key());
+        for rumor in [note, foreign] {
+            let message = welcome_to_recipient(&rumor);
+            assert!(
+                build_handshake_event(&sender(), "s1", SessionRole::Initial, &message).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_inbox_relays_beyond_the_connected_ones() {
+        let mut message =
+            welcome_to_recipient(&EventBuilder::text_note("").build(sender().public_key()));
+        if let HandshakeMessageBody::Welcome { relays, .. } = &mut message.data {
+            *relays = vec![
+                "wss://home.test/".to_string(),
+                "wss://inbox.test".to_string(),
+                "WSS://INBOX.test/".to_string(),
+            ];
+        }
+        assert_eq!(
+            unconnected_inbox_relays(&message, &["wss://home.test"]),
+            vec!["wss://inbox.test"]
+        );
+        assert!(unconnected_inbox_relays(&key_package_to_recipient(), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_wrap_hides_session_and_sender() {
+        let message = key_package_to_recipient();
+        let event = build_handshake_event(&sender(), "s1", SessionRole::Invitee, &message).unwrap();
+        assert_ne!(event.pubkey, sender().public_key());
+        assert!(!event.content.contains("s1"));
+        assert!(!event.content.contains("keyPackage"));
+        let tags: Vec<Vec<String>> = event.tags.iter().map(|tag| tag.clone().to_vec()).collect();
+        assert_eq!(
+            tags,
+            vec![vec!["p".to_string(), recipient().public_key().to_hex()]]
+        );
+        assert!(event.created_at <= Timestamp::now());
+    }
+
+    #[test]
+    fn test_only_the_recipient_can_open() {
+        let message = key_package_to_recipient();
+        let event = build_handshake_event(&sender(), "s1", SessionRole::Invitee, &message).unwrap();
+        assert!(decode_handshake_event(&sender(), &event).is_none());
+        assert!(decode_handshake_event(&Keys::generate(), &event).is_none());
+        assert!(decode_handshake_event(&recipient(), &event).is_some());
+    }
+
+    #[test]
+    fn test_other_messages_ignored() {
+        assert!(event_from_relay_message("[\"EOSE\",\"marmot-s1\"]").is_none());
+        assert!(event_from_relay_message("not json").is_none());
+        let note = EventBuilder::text_note("hi")
+            .sign_with_keys(&sender())
+            .unwrap();
+        assert!(decode_handshake_event(&recipient(), &note).is_none());
+    }
+
+    #[test]
+    fn test_relay_messages_keep_their_subscription() {
+        let note = EventBuilder::text_note("hi")
+            .sign_with_keys(&sender())
+            .unwrap();
+        let text = format!("[\"EVENT\",\"sub\",{}]", note.as_json());
+        match parse_relay_message(&text) {
+            Some(RelayMessage::Event {
+                subscription,
+                event,
+            }) => {
+                assert_eq!(subscription, "sub");
+                assert_eq!(event.id, note.id);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(matches!(
+            parse_relay_message("[\"EOSE\",\"sub\"]"),
+            Some(RelayMessage::EndOfStoredEvents(subscription)) if subscription == "sub"
+        ));
+        assert!(parse_relay_message("[\"NOTICE\",\"slow down\"]").is_none());
+    }
+
+    #[test]
+    fn test_subscription_filters_on_recipient() {
+        let pubkey = recipient().public_key().to_hex();
+        let request: JsonValue =
+            serde_json::from_str(&subscription_request(&pubkey, None)).unwrap();
+        assert_eq!(request[0], "REQ");
+        assert_eq!(request[1], format!("marmot-{pubkey}"));
+        assert_eq!(request[2]["kinds"][0], 1059);
+        assert_eq!(request[2]["#p"][0], pubkey.as_str());
+        assert!(request[2].get("#t").is_none());
+        assert!(request[2].get("since").is_none());
+    }
+
+    #[test]
+    fn test_resubscription_reaches_back_past_backdated_wraps() {
+        let pubkey = recipient().public_key().to_hex();
+        let dropped_at = Timestamp::from(10 * TIMESTAMP_TWEAK_SECS);
+        let since = resume_since(dropped_at);
+        assert_eq!(since.as_u64(), 9 * TIMESTAMP_TWEAK_SECS);
+        let request: JsonValue =
+            serde_json::from_str(&subscription_request(&pubkey, Some(since))).unwrap();
+        assert_eq!(request[2]["since"], 9 * TIMESTAMP_TWEAK_SECS);
+        assert_eq!(resume_since(Timestamp::from(5)).as_u64(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Context, Result};
+use mdk_memory_storage::MdkMemoryStorage;
+use mdk_storage_traits::MdkStorageProvider;
+use nostr::{Keys, SecretKey};
+
+use super::events::{ChatEvent, GroupEvent, SessionParams};
+use super::services::{IdentityHandle, MoqService, NostrService};
+use super::state::ControllerConfig;
+use super::ChatController;
+
+pub type GroupEventCallback = Rc<dyn Fn(GroupEvent)>;
+
+/// Several group sessions on one identity
+///
+/// Each joined session runs its own [`ChatController`] over an identity
+/// handle scoped to its group, and its events reach the hub callback tagged
+/// with the session and group. The caller supplies each session's Nostr
+/// service; giving every session a view of the same connection keeps one
+/// relay socket for the whole identity.
+pub struct ChatHub<S: MdkStorageProvider = MdkMemoryStorage> {
+    identity: IdentityHandle<S>,
+    callback: GroupEventCallback,
+    groups: BTreeMap<String, ChatController<S>>,
+}
+
+impl<S: MdkStorageProvider + 'static> ChatHub<S> {
+    pub fn new(identity: IdentityHandle<S>, callback: GroupEventCallback) -> Self {
+        Self {
+            identity,
+            callback,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn identity(&self) -> &IdentityHandle<S> {
+        &self.identity
+    }
+
+    /// Start a session and its controller
+    ///
+    /// Resumes the stored group when `session.group_id_hex` names one this
+    /// identity belongs to; otherwise the session creates or joins a new
+    /// group through its handshake.
+    pub fn join(
+        &mut self,
+        session: SessionParams,
+        nostr: Rc<dyn NostrService>,
+        moq: Rc<dyn MoqService>,
+    ) -> Result<()> {
+        if self.groups.contains_key(&session.session_id) {
+            return Err(anyhow!("session {} already joined", session.session_id));
+        }
+        let session_keys = SecretKey::from_hex(session.secret_hex.expose())
+            .map(Keys::new)
+            .context("parse session secret")?;
+        if session_keys.public_key().to_hex() != self.identity.public_key_hex() {
+            return Err(anyhow!("session secret belongs to another identity"));
+        }
+
+        let identity = match &session.group_id_hex {
+            Some(group_id_hex) if self.identity.list_groups()?.contains(group_id_hex) => {
+                self.identity.for_group(group_id_hex)?
+            }
+            _ => self.identity.new_scope(),
+        };
+
+        let session_id = session.session_id.clone();
+        let scope = identity.clone();
+        let hub_callback = self.callback.clone();
+        let tag_session = session_id.clone();
+        let callback = Rc::new(move |event: ChatEvent| {
+            hub_callback(GroupEvent {
+                session_id: tag_session.clone(),
+                group_id_hex: scope.group_id_hex(),
+                event,
+            });
+        });
+
+        let controller = ChatController::new(ControllerConfig {
+            identity,
+            session,
+            nostr,
+            moq,
+            callback,
+        });
+        controller.start();
+        self.groups.insert(session_id, controller);
+        Ok(())
+    }
+
+    pub fn group(&self, session_id: &str) -> Option<&ChatController<S>> {
+        self.groups.get(session_id)
+    }
+
+    pub fn sessions(&self) -> Vec<String> {
+        self.groups.keys().cloned().collect()
+    }
+
+    /// Shut one session down; the identity and other sessions stay up
+    pub fn close(&mut self, session_id: &str) -> bool {
+        match self.groups.remove(session_id) {
+            Some(controller) => {
+                controller.shutdown();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        for (_, controller) in std::mem::take(&mut self.groups) {
+            controller.shutdown();
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Published key packages (kind 443)
+//!
+//! Members advertise MLS key packages as kind 443 events on their relays
+//! so they can be invited while offline. Each carries a NIP-40
+//! `expiration` tag; inviters look up an author's events, drop expired or
+//! forged ones and use the newest that is left. The Nostr services issue
+//! the queries built here and hand back whatever the relays return. The
+//! `relays` tag names where the owner reads, so welcomes go there too.
+//! Key packages that are replaced, expire or get used up are withdrawn
+//! with a NIP-09 kind 5 deletion.
+
+use nostr::prelude::*;
+use serde_json::json;
+
+use super::handshake_wire::event_message;
+
+/// How long a published key package stays valid
+pub const KEY_PACKAGE_LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+/// Most events asked for per author; relays return the newest first
+const FETCH_LIMIT: usize = 20;
+
+/// Subscription id for looking up the key packages of `pubkey_hex`
+pub fn subscription_id(pubkey_hex: &str) -> String {
+    format!("marmot-kp-{pubkey_hex}")
+}
+
+/// `REQ` for the key packages published by `pubkey_hex`
+pub fn fetch_request(pubkey_hex: &str) -> String {
+    let filter = json!({
+        "kinds": [Kind::MlsKeyPackage.as_u16()],
+        "authors": [pubkey_hex],
+        "limit": FETCH_LIMIT,
+    });
+    format!("[\"REQ\",\"{}\",{}]", subscription_id(pubkey_hex), filter)
+}
+
+/// `CLOSE` for the lookup opened by [`fetch_request`]
+pub fn close_request(pubkey_hex: &str) -> String {
+    format!("[\"CLOSE\",\"{}\"]", subscription_id(pubkey_hex))
+}
+
+/// `EVENT` message publishing a signed key package, or `None` if
+/// `event_json` is not one
+pub fn publish_message(event_json: &str) -> Option<String> {
+    let event = Event::from_json(event_json).ok()?;
+    (event.kind == Kind::MlsKeyPackage).then(|| event_message(&event))
+}
+
+/// `EVENT` message publishing a signed deletion of key packages, or
+/// `None` if `event_json` is not one
+pub fn deletion_message(event_json: &str) -> Option<String> {
+    let event = Event::from_json(event_json).ok()?;
+    (event.kind == Kind::EventDeletion).then(|| event_message(&event))
+}
+
+/// The pubkey a key package lookup was for, from its subscription id
+pub fn fetched_pubkey(subscription: &str) -> Option<&str> {
+    subscription.strip_prefix("marmot-kp-")
+}
+
+/// Whether `event` has expired at `now` (unix seconds)
+pub fn is_expired(event: &Event, now: u64) -> bool {
+    event
+        .tags
+        .expiration()
+        .is_some_and(|expiration| expiration.as_u64() <= now)
+}
+
+/// Signed, unexpired kind 443 events by `pubkey_hex`, newest first
+pub fn usable_key_packages(pubkey_hex: &str, events: &[String], now: u64) -> Vec<Event> {
+    let mut usable: Vec<Event> = events
+        .iter()
+        .filter_map(|event_json| Event::from_json(event_json).ok())
+        .filter(|event| {
+            event.kind == Kind::MlsKeyPackage
+                && event.pubkey.to_hex() == pubkey_hex
+                && !is_expired(event, now)
+                && event.verify().is_ok()
+        })
+        .collect();
+    usable.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    usable.dedup_by_key(|event| event.id);
+    usable
+}
+
+/// Relays a key package's `relays` tag asks welcomes to be left on
+pub fn inbox_relays(event_json: &str) -> Vec<String> {
+    let Ok(event) = Event::from_json(event_json) else {
+        return Vec::new();
+    };
+    event
+        .tags
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [kind, urls @ ..] if kind == "relays" => Some(urls.to_vec()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Keys {
+        Keys::new(SecretKey::from_hex(&"44".repeat(32)).unwrap())
+    }
+
+    fn key_package(keys: &Keys, created_at: u64, expiration: Option<u64>) -> String {
+        let mut builder =
+            EventBuilder::new(Kind::MlsKeyPackage, "kp").custom_created_at(created_at.into());
+        if let Some(expiration) = expiration {
+            builder = builder.tag(Tag::expiration(expiration.into()));
+        }
+        builder.sign_with_keys(keys).unwrap().as_json()
+    }
+
+    #[test]
+    fn test_newest_unexpired_key_package_first() {
+        let pubkey = keys().public_key().to_hex();
+        let old = key_package(&keys(), 100, None);
+        let newest = key_package(&keys(), 300, Some(1_000));
+        let expired = key_package(&keys(), 400, Some(500));
+        let events = vec![old.clone(), expired, newest.clone(), newest.clone()];
+
+        let usable = usable_key_packages(&pubkey, &events, 600);
+        let usable: Vec<String> = usable.iter().map(|event| event.as_json()).collect();
+        assert_eq!(usable, vec![newest, old]);
+    }
+
+    #[test]
+    fn test_other_authors_and_forgeries_skipped() {
+        let pubkey = keys().public_key().to_hex();
+        let stranger = key_package(&Keys::generate(), 100, None);
+        let note = EventBuilder::text_note("hi")
+            .sign_with_keys(&keys())
+            .unwrap()
+            .as_json();
+        let forged = key_package(&keys(), 100, None).replace("\"kp\"", "\"xx\"");
+        let usable = usable_key_packages(&pubkey, &[stranger, note, forged], 0);
+        assert!(usable.is_empty());
+    }
+
+    #[test]
+    fn test_lookup_round_trip() {
+        let pubkey = keys().public_key().to_hex();
+        let request: serde_json::Value = serde_json::from_str(&fetch_request(&pubkey)).unwrap();
+        assert_eq!(request[2]["kinds"][0], 443);
+        assert_eq!(request[2]["authors"][0], pubkey.as_str());
+        assert_eq!(
+            fetched_pubkey(request[1].as_str().unwrap()),
+            Some(pubkey.as_str())
+        );
+        assert!(publish_message(&key_package(&keys(), 1, None)).is_some());
+        assert!(publish_message("{}").is_none());
+        let deletion = EventBuilder::delete(EventDeletionRequest::new().id(EventId::all_zeros()))
+            .sign_with_keys(&keys())
+            .unwrap()
+            .as_json();
+        assert!(deletion_message(&deletion).is_some());
+        assert!(deletion_message(&key_package(&keys(), 1, None)).is_none());
+    }
+
+    #[test]
+    fn test_inbox_relays_from_relays_tag() {
+        let event = EventBuilder::new(Kind::MlsKeyPackage, "kp")
+            .tag(Tag::custom(
+                TagKind::Relays,
+                ["wss://a.test", "wss://b.test"],
+            ))
+            .sign_with_keys(&keys())
+            .unwrap()
+            .as_json();
+        assert_eq!(inbox_relays(&event), vec!["wss://a.test", "wss://b.test"]);
+        assert!(inbox_relays(&key_package(&keys(), 1, None)).is_empty());
+        assert!(inbox_relays("{}").is_empty());
+    }
+}
@@ -1,29 +1,43 @@
 mod error;
 pub mod events;
+pub mod handshake_wire;
+mod hub;
+pub mod key_packages;
+pub mod reconnect;
 pub mod services;
+pub mod snapshot;
 mod state;
 
+pub use hub::{ChatHub, GroupEventCallback};
+#[cfg(all(not(target_arch = "wasm32"), feature = "native"))]
+pub use local::drive;
+#[cfg(not(target_arch = "wasm32"))]
+pub use local::run_until_stalled;
 pub use state::{ControllerConfig, ControllerState};
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use crate::cipher_suite::CipherSuite;
+use crate::media_crypto::MediaSession;
 use error::{ControllerError, ErrorSeverity, ErrorStage};
-use events::{ChatEvent, RecoveryAction, SessionParams};
+use events::{ChatEvent, RecoveryAction, RelayHealth, SessionParams};
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::StreamExt;
 use log::warn;
+use mdk_memory_storage::MdkMemoryStorage;
+use mdk_storage_traits::MdkStorageProvider;
 use state::Operation;
 
-use services::{HandshakeListener, HandshakeMessage, MoqListener};
+use services::{HandshakeListener, HandshakeMessage, KeyPackageListener, MoqListener};
 
-pub struct ChatController {
-    state: Rc<RefCell<ControllerState>>,
+pub struct ChatController<S: MdkStorageProvider = MdkMemoryStorage> {
+    state: Rc<RefCell<ControllerState<S>>>,
     op_tx: UnboundedSender<Operation>,
 }
 
-impl ChatController {
-    pub fn new(config: ControllerConfig) -> Self {
+impl<S: MdkStorageProvider + 'static> ChatController<S> {
+    pub fn new(config: ControllerConfig<S>) -> Self {
         let state = Rc::new(RefCell::new(ControllerState::new(config)));
         let (op_tx, op_rx) = unbounded();
         let runtime = ChatRuntime::new(state.clone(), op_tx.clone());
@@ -47,6 +61,12 @@ impl ChatController {
         let _ = self.op_tx.unbounded_send(Operation::RotateEpoch);
     }
 
+    /// Commit a self-update and move the group to a fresh MoQ root (admins
+    /// only); members switch once all of them have announced it
+    pub fn rotate_moq_root(&self) {
+        let _ = self.op_tx.unbounded_send(Operation::RotateMoqRoot);
+    }
+
     pub fn shutdown(&self) {
         let _ = self.op_tx.unbounded_send(Operation::Shutdown);
     }
@@ -57,19 +77,87 @@ impl ChatController {
             .unbounded_send(Operation::InviteMember { pubkey, is_admin });
     }
 
+    /// Remove a member from the group (admins only)
+    pub fn remove_member(&self, pubkey: String) {
+        let _ = self.op_tx.unbounded_send(Operation::RemoveMember(pubkey));
+    }
+
+    /// Propose our removal; the group is left once an admin commits it
+    pub fn leave_group(&self) {
+        let _ = self.op_tx.unbounded_send(Operation::LeaveGroup);
+    }
+
+    /// Join the group of a pending welcome, as announced by
+    /// [`ChatEvent::WelcomePending`]; other pending welcomes stay pending
+    pub fn accept_welcome(&self, welcome_id: String) {
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::AcceptWelcome(welcome_id));
+    }
+
+    /// Promote or demote a member through the group context (admins only)
+    pub fn set_member_admin(&self, pubkey: String, is_admin: bool) {
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::SetMemberAdmin { pubkey, is_admin });
+    }
+
+    /// Rename the group and/or change its description (admins only)
+    pub fn update_group_metadata(&self, name: Option<String>, description: Option<String>) {
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::UpdateGroupMetadata { name, description });
+    }
+
+    /// Encrypt a new group image and commit its reference (admins only)
+    ///
+    /// Returns the ciphertext, which the caller uploads wherever members
+    /// fetch the image from; the group context only carries its hash and key.
+    /// Fails right away for non-admins, before anything is encrypted.
+    pub fn set_group_image(&self, image: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.state.borrow().ensure_metadata_admin()?;
+        let (ciphertext, image_ref) = crate::group_image::encrypt_group_image(image)?;
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::SetGroupImage(Some(image_ref)));
+        Ok(ciphertext)
+    }
+
+    pub fn clear_group_image(&self) {
+        let _ = self.op_tx.unbounded_send(Operation::SetGroupImage(None));
+    }
+
+    /// Decrypt a group image blob fetched by the caller
+    pub fn decrypt_group_image(&self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.state.borrow().decrypt_group_image(ciphertext)
+    }
+
+    /// Media session for a sender's track on the controller's shared key
+    /// ring; see [`ControllerState::create_media_session`]
+    pub fn create_media_session(
+        &self,
+        sender_pubkey: &str,
+        track_label: &str,
+        suite: CipherSuite,
+    ) -> anyhow::Result<MediaSession> {
+        self.state
+            .borrow()
+            .create_media_session(sender_pubkey, track_label, suite)
+    }
+
     #[allow(dead_code)]
-    pub(crate) fn state(&self) -> Rc<RefCell<ControllerState>> {
+    pub(crate) fn state(&self) -> Rc<RefCell<ControllerState<S>>> {
         self.state.clone()
     }
 }
 
-struct ChatRuntime {
-    state: Rc<RefCell<ControllerState>>,
+struct ChatRuntime<S: MdkStorageProvider> {
+    state: Rc<RefCell<ControllerState<S>>>,
     op_tx: UnboundedSender<Operation>,
 }
 
-impl ChatRuntime {
-    fn new(state: Rc<RefCell<ControllerState>>, op_tx: UnboundedSender<Operation>) -> Self {
+impl<S: MdkStorageProvider + 'static> ChatRuntime<S> {
+    fn new(state: Rc<RefCell<ControllerState<S>>>, op_tx: UnboundedSender<Operation>) -> Self {
         Self { state, op_tx }
     }
 
@@ -78,7 +166,7 @@ impl ChatRuntime {
         wasm_bindgen_futures::spawn_local(self.run(op_rx));
 
         #[cfg(not(target_arch = "wasm32"))]
-        futures::executor::LocalPool::new().run_until(self.run(op_rx));
+        local::spawn(self.run(op_rx));
     }
 
     async fn run(mut self, mut op_rx: UnboundedReceiver<Operation>) {
@@ -113,6 +201,29 @@ impl ChatRuntime {
                         err,
                         "Handshake message failed. Refresh the page or request a new invite.",
                     ));
+                } else {
+                    let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                }
+            }
+            Operation::FetchKeyPackages(pubkey) => {
+                let listener: Box<dyn KeyPackageListener> =
+                    Box::new(ControllerKeyPackageListener {
+                        op_tx: self.op_tx.clone(),
+                    });
+                self.state
+                    .borrow()
+                    .nostr
+                    .fetch_key_packages(&pubkey, listener);
+            }
+            Operation::KeyPackagesFetched { pubkey, events } => {
+                if let Err(err) =
+                    self.state
+                        .borrow_mut()
+                        .on_key_packages_fetched(&self.op_tx, pubkey, events)
+                {
+                    self.emit_error(self.classify_invite_error(err));
+                } else {
+                    let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
                 }
             }
             Operation::ConnectMoq => {
@@ -158,6 +269,8 @@ impl ChatRuntime {
                         for event in events {
                             let _ = self.op_tx.unbounded_send(Operation::Emit(event));
                         }
+                        let _ = self.op_tx.unbounded_send(Operation::CommitOverdueLeaves);
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
                     }
                     Err(err) => self.emit_error(
                         ControllerError::fatal(ErrorStage::Messaging, err).with_user_message(
@@ -171,6 +284,43 @@ impl ChatRuntime {
             }
             Operation::Ready => {
                 self.state.borrow_mut().on_ready(&self.op_tx);
+                let _ = self.op_tx.unbounded_send(Operation::CommitOverdueLeaves);
+                let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+            }
+            Operation::CommitOverdueLeaves => {
+                let result = self.state.borrow_mut().commit_overdue_leaves(&self.op_tx);
+                if let Err(err) = result {
+                    self.emit_error(
+                        ControllerError::transient(ErrorStage::Membership, err).with_user_message(
+                            "Could not commit a member's leave; it stays pending.",
+                        ),
+                    );
+                }
+            }
+            Operation::SyncMoqRoot => {
+                if let Err(err) = self.state.borrow_mut().sync_moq_root(&self.op_tx) {
+                    self.emit_error(
+                        ControllerError::transient(ErrorStage::Messaging, err).with_user_message(
+                            "Failed to update the media root; staying on the current one.",
+                        ),
+                    );
+                }
+            }
+            Operation::RotateMoqRoot => {
+                let result = self.state.borrow_mut().rotate_moq_root(&self.op_tx);
+                match result {
+                    Ok(events) => {
+                        for event in events {
+                            let _ = self.op_tx.unbounded_send(Operation::Emit(event));
+                        }
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(
+                        ControllerError::transient(ErrorStage::Messaging, err).with_user_message(
+                            "Could not rotate the media root; staying on the current one.",
+                        ),
+                    ),
+                }
             }
             Operation::Emit(event) => {
                 (self.state.borrow().callback)(event);
@@ -197,6 +347,8 @@ impl ChatRuntime {
                         for event in events {
                             let _ = self.op_tx.unbounded_send(Operation::Emit(event));
                         }
+                        // A root move under way re-announces at the new epoch
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
                     }
                     Err(err) => self.emit_error(
                         ControllerError::fatal(ErrorStage::Messaging, err).with_user_message(
@@ -214,6 +366,74 @@ impl ChatRuntime {
                     self.emit_error(self.classify_invite_error(err));
                 }
             }
+            Operation::RemoveMember(pubkey) => {
+                let result = self.state.borrow_mut().remove_member(&self.op_tx, pubkey);
+                match result {
+                    // A root move under way re-announces at the new epoch
+                    Ok(()) => {
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(self.classify_membership_error(err)),
+                }
+            }
+            Operation::SetMemberAdmin { pubkey, is_admin } => {
+                let result =
+                    self.state
+                        .borrow_mut()
+                        .change_member_admin(&self.op_tx, pubkey, is_admin);
+                match result {
+                    Ok(()) => {
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(self.classify_membership_error(err)),
+                }
+            }
+            Operation::UpdateGroupMetadata { name, description } => {
+                let result =
+                    self.state
+                        .borrow_mut()
+                        .update_group_metadata(&self.op_tx, name, description);
+                match result {
+                    Ok(()) => {
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(self.classify_metadata_error(err)),
+                }
+            }
+            Operation::SetGroupImage(image) => {
+                let result = self.state.borrow_mut().set_group_image(&self.op_tx, image);
+                match result {
+                    Ok(()) => {
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(self.classify_metadata_error(err)),
+                }
+            }
+            Operation::LeaveGroup => {
+                let result = self.state.borrow_mut().leave_group(&self.op_tx);
+                match result {
+                    Ok(()) => {
+                        // Handing admin over before leaving commits
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(self.classify_membership_error(err)),
+                }
+            }
+            Operation::AcceptWelcome(welcome_id) => {
+                let result = self
+                    .state
+                    .borrow_mut()
+                    .accept_pending_welcome(&self.op_tx, &welcome_id);
+                match result {
+                    Ok(()) => {
+                        let _ = self.op_tx.unbounded_send(Operation::SyncMoqRoot);
+                    }
+                    Err(err) => self.emit_error(
+                        ControllerError::transient(ErrorStage::Handshake, err)
+                            .with_user_message("Could not join that group. Ask for a new invite."),
+                    ),
+                }
+            }
             Operation::Shutdown => {
                 self.state.borrow().moq.shutdown();
                 self.state.borrow().nostr.shutdown();
@@ -275,6 +495,56 @@ impl ChatRuntime {
         }
     }
 
+    fn classify_membership_error(&self, err: anyhow::Error) -> ControllerError {
+        let lower = err.to_string().to_lowercase();
+
+        let user_message = if lower.contains("pubkey required") {
+            "Please enter the pubkey of the member to remove."
+        } else if lower.contains("parse member pubkey") {
+            "Member pubkey is invalid. Use the participant's hex or npub key."
+        } else if lower.contains("only admins can remove") {
+            "Only group admins can remove members."
+        } else if lower.contains("only admins can change") {
+            "Only group admins can promote or demote members."
+        } else if lower.contains("at least one admin") {
+            "The group needs at least one admin."
+        } else if lower.contains("cannot remove self") {
+            "Use Leave to remove yourself from the group."
+        } else if lower.contains("not a member") {
+            "That participant is not in the group."
+        } else if lower.contains("already left") {
+            "You already left this group."
+        } else if lower.contains("leave already requested") {
+            "Your leave is still waiting for an admin to commit it."
+        } else if lower.contains("only member") {
+            "You are the only member; there is nobody to hand the group to."
+        } else {
+            return ControllerError::fatal(ErrorStage::Membership, err);
+        };
+        ControllerError::transient(ErrorStage::Membership, err)
+            .with_user_message(user_message)
+            .with_recovery_action(RecoveryAction::None)
+    }
+
+    fn classify_metadata_error(&self, err: anyhow::Error) -> ControllerError {
+        let lower = err.to_string().to_lowercase();
+
+        let user_message = if lower.contains("only admins can edit group metadata") {
+            "Only group admins can change the group name, description or image."
+        } else if lower.contains("group name required") {
+            "Please enter a group name."
+        } else if lower.contains("group has no image") {
+            "This group has no image."
+        } else if lower.contains("already left") {
+            "You already left this group."
+        } else {
+            return ControllerError::fatal(ErrorStage::Metadata, err);
+        };
+        ControllerError::transient(ErrorStage::Metadata, err)
+            .with_user_message(user_message)
+            .with_recovery_action(RecoveryAction::None)
+    }
+
     fn emit_error(&self, err: ControllerError) {
         let (severity, stage, message, recovery_action, detail) = err.into_parts();
         match severity {
@@ -312,6 +582,113 @@ impl ChatRuntime {
     }
 }
 
+/// Thread-local executor for native builds
+///
+/// Controllers never depend on an ambient async runtime: the caller drives
+/// them with [`run_until_stalled`], or with the `native` feature awaits
+/// [`drive`] on the thread that created them.
+#[cfg(not(target_arch = "wasm32"))]
+mod local {
+    use std::cell::RefCell;
+    use std::future::Future;
+
+    use futures::executor::{LocalPool, LocalSpawner};
+    use futures::task::LocalSpawnExt;
+
+    thread_local! {
+        static POOL: RefCell<LocalPool> = RefCell::new(LocalPool::new());
+        static SPAWNER: LocalSpawner = POOL.with(|pool| pool.borrow().spawner());
+    }
+
+    pub(super) fn spawn(future: impl Future<Output = ()> + 'static) {
+        #[cfg(feature = "native")]
+        let future = driver::Notifying(Box::pin(future));
+        SPAWNER.with(|spawner| {
+            if let Err(err) = spawner.spawn_local(future) {
+                log::error!("controller runtime failed to spawn: {err}");
+            }
+        });
+    }
+
+    /// Run the controllers created on this thread until none can progress
+    ///
+    /// Without an async driver the caller steps the controllers: call this
+    /// after issuing commands or delivering transport events. Calls made
+    /// from inside a controller callback return without doing anything.
+    pub fn run_until_stalled() {
+        // Create the spawner before the pool is borrowed for running
+        SPAWNER.with(|_| {});
+        POOL.with(|pool| {
+            if let Ok(mut pool) = pool.try_borrow_mut() {
+                pool.run_until_stalled();
+            }
+        });
+    }
+
+    #[cfg(feature = "native")]
+    pub use driver::drive;
+
+    #[cfg(feature = "native")]
+    mod driver {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Waker};
+
+        use futures::future::poll_fn;
+        use futures::task::{waker, ArcWake, AtomicWaker};
+
+        thread_local! {
+            /// The [`drive`] future of this thread, if one is running
+            static DRIVER: Arc<AtomicWaker> = Arc::new(AtomicWaker::new());
+        }
+
+        /// Run the controllers created on this thread for as long as it is
+        /// polled
+        ///
+        /// Await it (or `spawn_local` it) on the thread that creates the
+        /// controllers, inside the tokio `LocalSet` the
+        /// [`native`](crate::native) services need. It never completes.
+        pub async fn drive() {
+            poll_fn(|cx: &mut Context<'_>| {
+                DRIVER.with(|driver| driver.register(cx.waker()));
+                super::run_until_stalled();
+                Poll::<()>::Pending
+            })
+            .await
+        }
+
+        /// Controller task that also wakes its thread's [`drive`] future
+        pub(in crate::controller) struct Notifying(
+            pub(in crate::controller) Pin<Box<dyn Future<Output = ()>>>,
+        );
+
+        struct Notify {
+            task: Waker,
+            driver: Arc<AtomicWaker>,
+        }
+
+        impl ArcWake for Notify {
+            fn wake_by_ref(notify: &Arc<Self>) {
+                notify.task.wake_by_ref();
+                notify.driver.wake();
+            }
+        }
+
+        impl Future for Notifying {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                let notify = waker(Arc::new(Notify {
+                    task: cx.waker().clone(),
+                    driver: DRIVER.with(Arc::clone),
+                }));
+                self.0.as_mut().poll(&mut Context::from_waker(&notify))
+            }
+        }
+    }
+}
+
 struct ControllerHandshakeListener {
     op_tx: UnboundedSender<Operation>,
 }
@@ -322,6 +699,24 @@ impl HandshakeListener for ControllerHandshakeListener {
             .op_tx
             .unbounded_send(Operation::IncomingHandshake(message));
     }
+
+    fn on_relay_health(&self, health: RelayHealth) {
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::Emit(ChatEvent::RelayHealth { relay: health }));
+    }
+}
+
+struct ControllerKeyPackageListener {
+    op_tx: UnboundedSender<Operation>,
+}
+
+impl KeyPackageListener for ControllerKeyPackageListener {
+    fn on_key_packages(&self, pubkey: String, events: Vec<String>) {
+        let _ = self
+            .op_tx
+            .unbounded_send(Operation::KeyPackagesFetched { pubkey, events });
+    }
 }
 
 struct ControllerMoqListener {
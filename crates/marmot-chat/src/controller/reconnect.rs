@@ -0,0 +1,95 @@
+//! Reconnect delays for relay connections
+//!
+//! Exponential backoff with jitter: the ceiling doubles with every failed
+//! attempt up to [`MAX_DELAY_MS`] and the delay is drawn from its upper
+//! half, so clients dropped by the same relay do not all return at once.
+
+use super::events::{RelayHealth, RelayState};
+
+/// Ceiling of the first delay
+const BASE_DELAY_MS: u64 = 500;
+/// Longest wait between two attempts
+const MAX_DELAY_MS: u64 = 60_000;
+
+/// Failed attempts of one connection since it was last up
+#[derive(Debug, Default, Clone)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Count a failed or dropped connection and pick the delay before the
+    /// next attempt, in milliseconds
+    pub fn next_delay_ms(&mut self) -> u64 {
+        self.failures = self.failures.saturating_add(1);
+        let mut bytes = [0u8; 8];
+        let random = match getrandom::getrandom(&mut bytes) {
+            Ok(()) => u64::from_le_bytes(bytes),
+            Err(_) => 0,
+        };
+        delay_ms(self.failures, random)
+    }
+
+    /// The connection is up; the next failure starts over
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Health of the relay at `url` as of this backoff
+    pub fn health(&self, url: &str, state: RelayState, retry_in_ms: Option<u64>) -> RelayHealth {
+        RelayHealth {
+            url: url.to_string(),
+            state,
+            failures: self.failures,
+            retry_in_ms,
+            dropped: 0,
+        }
+    }
+}
+
+fn delay_ms(failures: u32, random: u64) -> u64 {
+    let doublings = failures.saturating_sub(1).min(16);
+    let ceiling = (BASE_DELAY_MS << doublings).min(MAX_DELAY_MS);
+    let floor = ceiling / 2;
+    floor + random % (ceiling - floor + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_double_up_to_the_cap() {
+        let floors: Vec<u64> = (1..=10).map(|failures| delay_ms(failures, 0)).collect();
+        assert_eq!(floors[..3], [250, 500, 1_000]);
+        assert!(floors.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(floors[9], MAX_DELAY_MS / 2);
+        assert_eq!(delay_ms(u32::MAX, 0), MAX_DELAY_MS / 2);
+        assert!(delay_ms(u32::MAX, u64::MAX) <= MAX_DELAY_MS);
+    }
+
+    #[test]
+    fn test_jitter_stays_in_the_upper_half() {
+        for random in [0, 1, 7, 999, u64::MAX / 3] {
+            let delay = delay_ms(3, random);
+            assert!((1_000..=2_000).contains(&delay), "{delay}");
+        }
+    }
+
+    #[test]
+    fn test_reset_after_connecting() {
+        let mut backoff = Backoff::default();
+        backoff.next_delay_ms();
+        backoff.next_delay_ms();
+        assert_eq!(backoff.failures(), 2);
+        let health = backoff.health("wss://a.test", RelayState::Reconnecting, Some(10));
+        assert_eq!(health.failures, 2);
+        backoff.reset();
+        assert_eq!(backoff.failures(), 0);
+        assert!(backoff.next_delay_ms() <= BASE_DELAY_MS);
+    }
+}
@@ -1,28 +1,80 @@
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use anyhow::{anyhow, Context, Result};
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine as _;
 use mdk_core::{
-    groups::{NostrGroupConfigData, UpdateGroupResult},
+    groups::{NostrGroupConfigData, NostrGroupDataUpdate, UpdateGroupResult},
     messages::MessageProcessingResult,
     MDK,
 };
 use mdk_memory_storage::MdkMemoryStorage;
-use mdk_storage_traits::{groups::types::Group, GroupId};
-use nostr::{Event, EventBuilder, JsonUtil, Kind, PublicKey, SecretKey, Timestamp};
+#[cfg(not(target_arch = "wasm32"))]
+use mdk_sqlite_storage::MdkSqliteStorage;
+use mdk_storage_traits::groups::{
+    types::{GroupExporterSecret, GroupState},
+    GroupStorage,
+};
+use mdk_storage_traits::welcomes::types::{Welcome, WelcomeState};
+use mdk_storage_traits::{GroupId, MdkStorageProvider};
+use nostr::nips::nip09::EventDeletionRequest;
+use nostr::{
+    Alphabet, Event, EventBuilder, EventId, JsonUtil, Kind, PublicKey, SecretKey, SingleLetterTag,
+    Tag, TagKind, Timestamp,
+};
 use openmls::prelude::{KeyPackageBundle, OpenMlsProvider};
-use openmls_traits::storage::StorageProvider;
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
 
-use crate::messages::{DirectoryMessage, TrackEntry, WrapperFrame, WrapperKind};
+use crate::group_image::GroupImageRef;
+use crate::media_crypto::MediaBaseKey;
+use crate::messages::{
+    DirectoryMessage, RootAnnouncement, TrackEntry, TrackKind, WrapperFrame, WrapperKind,
+};
+use crate::secret::SecretString;
 
-use super::events::SessionRole;
+use super::events::{PendingWelcome, RelayHealth, SessionParams, SessionRole};
+use super::key_packages;
+use super::snapshot::{
+    self, ExporterSecretImage, GroupImage, MemoryImage, StateSnapshot, StorageImage,
+};
 
 const DEFAULT_IMAGE_HASH: Option<[u8; 32]> = None;
 const DEFAULT_IMAGE_KEY: Option<[u8; 32]> = None;
 const DEFAULT_IMAGE_NONCE: Option<[u8; 12]> = None;
+/// Welcome rumor tag naming the MoQ root the group is pinned to
+const MOQ_ROOT_TAG: &str = "moq-root";
+
+/// Name and description a group is created with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupProfile {
+    pub name: String,
+    pub description: String,
+}
+
+impl Default for GroupProfile {
+    fn default() -> Self {
+        Self {
+            name: "Marmot Chat".to_string(),
+            description: "MoQ/MLS demo".to_string(),
+        }
+    }
+}
+
+/// Metadata held in the group's Marmot data extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMetadata {
+    pub name: String,
+    pub description: String,
+    pub image: Option<GroupImageRef>,
+}
 
 #[derive(Debug, Clone)]
 pub struct GroupArtifacts {
@@ -42,17 +94,93 @@ pub struct AddMembersArtifacts {
     pub welcomes: Vec<WelcomeArtifact>,
 }
 
-pub struct IdentityHandle {
+/// Nostr identity plus its MDK state, scoped to one group
+///
+/// Group operations act on the handle's group. One identity can take part
+/// in several groups: [`IdentityHandle::for_group`] and
+/// [`IdentityHandle::new_scope`] hand out further handles that share the
+/// keys and MLS store but track their own group. Clones share the scope.
+///
+/// Generic over the MDK storage backend. The default keeps everything in
+/// memory; a persistent backend (see [`IdentityService::open`]) lets a
+/// restarted client pick its group back up with [`IdentityHandle::restore_group`].
+pub struct IdentityHandle<S: MdkStorageProvider = MdkMemoryStorage> {
     pub(crate) keys: nostr::Keys,
-    pub(crate) mdk: MDK<MdkMemoryStorage>,
+    pub(crate) mdk: Rc<MDK<S>>,
     pub(crate) group_id: Rc<RefCell<Option<GroupId>>>,
+    /// Key package events published by this identity
+    pub(crate) key_packages: Rc<RefCell<Vec<String>>>,
+    /// Those of `key_packages` that survive being used by a welcome; at
+    /// most one
+    pub(crate) last_resort: Rc<RefCell<BTreeSet<String>>>,
+    /// Key packages replaced, expired or used up since the last
+    /// [`IdentityHandle::key_package_deletion`]
+    pub(crate) retired: Rc<RefCell<Vec<EventId>>>,
+    /// MoQ root pinned per group, by group id hex
+    pub(crate) group_roots: Rc<RefCell<BTreeMap<String, String>>>,
+    /// Epoch each group was loaded from storage at, by group id hex; our
+    /// own media keys for it stay locked until a commit moves past it
+    pub(crate) restored_epochs: Rc<RefCell<BTreeMap<String, u64>>>,
+    /// Database behind a SQLite-backed identity
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) storage_path: Option<PathBuf>,
+    /// Whether the MLS state outlives this process, in a SQLite store or
+    /// in snapshots the caller keeps
+    pub(crate) persisted: bool,
 }
 
-impl IdentityHandle {
+impl<S: MdkStorageProvider> Clone for IdentityHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            mdk: self.mdk.clone(),
+            group_id: self.group_id.clone(),
+            key_packages: self.key_packages.clone(),
+            last_resort: self.last_resort.clone(),
+            retired: self.retired.clone(),
+            group_roots: self.group_roots.clone(),
+            restored_epochs: self.restored_epochs.clone(),
+            #[cfg(not(target_arch = "wasm32"))]
+            storage_path: self.storage_path.clone(),
+            persisted: self.persisted,
+        }
+    }
+}
+
+impl<S: MdkStorageProvider> IdentityHandle<S> {
     pub fn public_key_hex(&self) -> String {
         self.keys.public_key().to_hex()
     }
 
+    /// A handle on the same identity with no group yet, for creating or
+    /// joining another group
+    pub fn new_scope(&self) -> Self {
+        Self {
+            group_id: Rc::new(RefCell::new(None)),
+            ..self.clone()
+        }
+    }
+
+    /// A handle on the same identity scoped to an existing group
+    pub fn for_group(&self, group_id_hex: &str) -> Result<Self> {
+        if !self.list_groups()?.iter().any(|id| id == group_id_hex) {
+            return Err(anyhow!("no active group {group_id_hex}"));
+        }
+        let scoped = self.new_scope();
+        scoped.set_group_id_hex(group_id_hex)?;
+        Ok(scoped)
+    }
+
+    /// Ids of every active group this identity belongs to
+    pub fn list_groups(&self) -> Result<Vec<String>> {
+        let groups = self.mdk.get_groups().context("load stored groups")?;
+        Ok(groups
+            .into_iter()
+            .filter(|group| group.state == GroupState::Active)
+            .map(|group| hex::encode(group.mls_group_id.as_slice()))
+            .collect())
+    }
+
     pub fn group_id_hex(&self) -> Option<String> {
         self.group_id
             .borrow()
@@ -93,6 +221,15 @@ impl IdentityHandle {
     }
 
     pub fn create_key_package(&self, relays: &[String]) -> Result<KeyPackageExport> {
+        self.create_key_package_with(relays, &KeyPackageOptions::default())
+    }
+
+    /// Create a key package event, expiring and/or reusable per `options`
+    pub fn create_key_package_with(
+        &self,
+        relays: &[String],
+        options: &KeyPackageOptions,
+    ) -> Result<KeyPackageExport> {
         let relays = relays
             .iter()
             .map(|url| nostr::RelayUrl::parse(url))
@@ -102,18 +239,148 @@ impl IdentityHandle {
             .mdk
             .create_key_package_for_event(&self.keys.public_key(), relays)
             .context("create key package")?;
-        let event = EventBuilder::new(Kind::MlsKeyPackage, encoded)
-            .tags(tags)
+        let mut builder = EventBuilder::new(Kind::MlsKeyPackage, encoded).tags(tags);
+        if let Some(expires_at) = options.expires_at {
+            builder = builder.tag(Tag::expiration(Timestamp::from(expires_at)));
+        }
+        let event = builder
             .build(self.keys.public_key())
             .sign_with_keys(&self.keys)
             .context("sign key package")?;
         let bundle = self.export_key_package_bundle(&event.as_json())?;
+        if options.last_resort {
+            // Only the newest reusable key package stays valid
+            let replaced = std::mem::take(&mut *self.last_resort.borrow_mut());
+            self.key_packages
+                .borrow_mut()
+                .retain(|event_json| !replaced.contains(event_json));
+            for event_json in &replaced {
+                self.forget_key_package(event_json)?;
+            }
+            self.last_resort.borrow_mut().insert(event.as_json());
+        }
+        self.key_packages.borrow_mut().push(event.as_json());
         Ok(KeyPackageExport {
             event_json: event.as_json(),
             bundle,
         })
     }
 
+    /// Forget our key packages that expired by `now`, private halves included
+    ///
+    /// Returns how many were dropped.
+    pub fn prune_expired_key_packages(&self, now: u64) -> Result<usize> {
+        let (expired, kept): (Vec<String>, Vec<String>) = self
+            .key_packages
+            .borrow_mut()
+            .drain(..)
+            .partition(|event_json| {
+                Event::from_json(event_json)
+                    .map(|event| key_packages::is_expired(&event, now))
+                    .unwrap_or(true)
+            });
+        *self.key_packages.borrow_mut() = kept;
+        for event_json in &expired {
+            self.last_resort.borrow_mut().remove(event_json);
+            self.forget_key_package(event_json)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Delete the private half of a key package and retire its event
+    fn forget_key_package(&self, event_json: &str) -> Result<()> {
+        let Ok(event) = Event::from_json(event_json) else {
+            return Ok(());
+        };
+        self.retired.borrow_mut().push(event.id);
+        let Ok(key_package) = self.mdk.parse_key_package(&event) else {
+            return Ok(());
+        };
+        let hash_ref = key_package
+            .hash_ref(self.mdk.provider.crypto())
+            .context("hash key package")?;
+        self.mdk
+            .provider
+            .storage()
+            .delete_key_package(&hash_ref)
+            .map_err(|e| anyhow!("delete key package bundle: {:?}", e))
+    }
+
+    /// Retire the one-shot key packages a welcome used up
+    fn retire_consumed_key_packages(&self) {
+        let last_resort = self.last_resort.borrow();
+        let (consumed, kept): (Vec<String>, Vec<String>) = self
+            .key_packages
+            .borrow_mut()
+            .drain(..)
+            .partition(|event_json| {
+                !last_resort.contains(event_json)
+                    && self.export_key_package_bundle(event_json).is_err()
+            });
+        *self.key_packages.borrow_mut() = kept;
+        self.retired.borrow_mut().extend(
+            consumed
+                .iter()
+                .filter_map(|event_json| Event::from_json(event_json).ok())
+                .map(|event| event.id),
+        );
+    }
+
+    /// Signed kind 5 deletion (NIP-09) of the key packages retired since
+    /// the last call, so inviters stop picking them; `None` if there are
+    /// none
+    pub fn key_package_deletion(&self) -> Result<Option<String>> {
+        let retired = std::mem::take(&mut *self.retired.borrow_mut());
+        if retired.is_empty() {
+            return Ok(None);
+        }
+        let event = EventBuilder::delete(EventDeletionRequest::new().ids(retired))
+            .tag(Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::K)),
+                [Kind::MlsKeyPackage.as_u16().to_string()],
+            ))
+            .sign_with_keys(&self.keys)
+            .context("sign key package deletion")?;
+        Ok(Some(event.as_json()))
+    }
+
+    /// Whether key packages of this identity may be published
+    ///
+    /// Only once its state is persisted: otherwise the private halves are
+    /// gone with the page or process, and a welcome sent to a published key
+    /// package could never be opened.
+    pub fn publishes_key_packages(&self) -> bool {
+        self.persisted
+    }
+
+    /// Declare that the caller keeps sealed [`IdentityHandle::export_state`]
+    /// snapshots of this identity and resumes from them
+    ///
+    /// Browsers keep them in IndexedDB; key packages are published from then
+    /// on, as their private halves outlive the page.
+    pub fn set_snapshots_persisted(&mut self) {
+        self.persisted = true;
+    }
+
+    /// Newest of `events` that is a valid, unexpired key package by `pubkey_hex`
+    pub fn newest_key_package(
+        &self,
+        pubkey_hex: &str,
+        events: &[String],
+        now: u64,
+    ) -> Option<String> {
+        key_packages::usable_key_packages(pubkey_hex, events, now)
+            .into_iter()
+            .find(|event| match self.mdk.parse_key_package(event) {
+                Ok(_) => true,
+                Err(err) => {
+                    log::debug!("skipping key package {}: {err}", event.id);
+                    false
+                }
+            })
+            .map(|event| event.as_json())
+    }
+
     pub fn export_key_package_bundle(&self, event_json: &str) -> Result<String> {
         let event = Event::from_json(event_json).context("parse key package event")?;
         let key_package = self
@@ -139,9 +406,28 @@ impl IdentityHandle {
         invitee_event: &str,
         invitee_pub: &str,
         admin_pubkeys: &[String],
+    ) -> Result<GroupArtifacts> {
+        self.create_group_with_profile(
+            invitee_event,
+            invitee_pub,
+            admin_pubkeys,
+            &GroupProfile::default(),
+        )
+    }
+
+    pub fn create_group_with_profile(
+        &self,
+        invitee_event: &str,
+        invitee_pub: &str,
+        admin_pubkeys: &[String],
+        profile: &GroupProfile,
     ) -> Result<GroupArtifacts> {
         let invitee = Event::from_json(invitee_event).context("parse invitee event")?;
         let invitee_pubkey = PublicKey::from_hex(invitee_pub).context("parse invitee pubkey")?;
+        if invitee.pubkey != invitee_pubkey {
+            return Err(anyhow!("key package is not signed by the invitee"));
+        }
+        // The invitee is an admin only when listed in `admin_pubkeys`
         let mut admins = vec![self.keys.public_key()];
         for hex in admin_pubkeys {
             if let Ok(pk) = PublicKey::from_hex(hex) {
@@ -150,12 +436,9 @@ impl IdentityHandle {
                 }
             }
         }
-        if !admins.iter().any(|pk| pk == &invitee_pubkey) {
-            admins.push(invitee_pubkey);
-        }
         let config = NostrGroupConfigData::new(
-            "Marmot Chat".to_string(),
-            "MoQ/MLS demo".to_string(),
+            profile.name.clone(),
+            profile.description.clone(),
             DEFAULT_IMAGE_HASH,
             DEFAULT_IMAGE_KEY,
             DEFAULT_IMAGE_NONCE,
@@ -205,11 +488,13 @@ impl IdentityHandle {
             .unwrap_or_default()
             .into_iter()
             .zip(events.iter())
-            .map(|(rumor, event)| WelcomeArtifact {
-                welcome: rumor.as_json(),
-                recipient: event.pubkey.to_hex(),
+            .map(|(rumor, event)| {
+                Ok(WelcomeArtifact {
+                    welcome: self.pinned_welcome(&rumor.as_json())?,
+                    recipient: event.pubkey.to_hex(),
+                })
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
         Ok(AddMembersArtifacts {
             commit: commit_frame,
@@ -217,33 +502,117 @@ impl IdentityHandle {
         })
     }
 
+    /// Process `welcome_json` and join its group
     pub fn accept_welcome(&self, welcome_json: &str) -> Result<String> {
-        use nostr::{EventId, UnsignedEvent};
+        let welcome = self.stage_welcome(None, welcome_json)?;
+        self.join_welcome(&welcome)
+    }
 
-        let welcome_unsigned = UnsignedEvent::from_json(welcome_json.as_bytes())
-            .context("parse welcome unsigned event")?;
+    /// Process `welcome_json` without joining; it waits among
+    /// [`IdentityHandle::pending_welcomes`] until accepted
+    ///
+    /// `None` if the welcome was accepted or declined before. For a welcome
+    /// handed over directly; one from the relays goes through
+    /// [`IdentityHandle::process_wrapped_welcome`].
+    pub fn process_welcome(&self, welcome_json: &str) -> Result<Option<PendingWelcome>> {
+        let welcome = self.stage_welcome(None, welcome_json)?;
+        Ok((welcome.state == WelcomeState::Pending).then(|| PendingWelcome::from(&welcome)))
+    }
 
+    /// [`IdentityHandle::process_welcome`] for a welcome that arrived in
+    /// the gift wrap `wrap_id`
+    pub fn process_wrapped_welcome(
+        &self,
+        wrap_id: &EventId,
+        welcome_json: &str,
+    ) -> Result<Option<PendingWelcome>> {
+        let welcome = self.stage_welcome(Some(wrap_id), welcome_json)?;
+        Ok((welcome.state == WelcomeState::Pending).then(|| PendingWelcome::from(&welcome)))
+    }
+
+    /// Welcomes processed but neither accepted nor declined yet
+    pub fn pending_welcomes(&self) -> Result<Vec<PendingWelcome>> {
+        Ok(self
+            .mdk
+            .get_pending_welcomes()
+            .context("load pending welcomes")?
+            .iter()
+            .map(PendingWelcome::from)
+            .collect())
+    }
+
+    /// Join the group of one pending welcome, leaving the others pending
+    pub fn accept_pending_welcome(&self, welcome_id: &str) -> Result<String> {
+        let welcome = self.pending_welcome(welcome_id)?;
+        self.join_welcome(&welcome)
+    }
+
+    fn pending_welcome(&self, welcome_id: &str) -> Result<Welcome> {
+        let id = EventId::from_hex(welcome_id).context("parse welcome id")?;
         self.mdk
-            .process_welcome(&EventId::all_zeros(), &welcome_unsigned)
-            .context("process welcome")?;
+            .get_welcome(&id)
+            .context("load welcome")?
+            .filter(|welcome| welcome.state == WelcomeState::Pending)
+            .ok_or_else(|| anyhow!("no pending welcome {welcome_id}"))
+    }
 
-        let mut accepted_group: Option<Group> = None;
-        if let Ok(mut welcomes) = self.mdk.get_pending_welcomes() {
-            for welcome in welcomes.iter() {
-                self.mdk.accept_welcome(welcome).context("accept welcome")?;
-            }
-            if let Some(latest) = welcomes.pop() {
-                if let Ok(groups) = self.mdk.get_groups() {
-                    accepted_group = groups
-                        .into_iter()
-                        .find(|group| group.mls_group_id == latest.mls_group_id);
-                }
-            }
+    /// Process a welcome rumor into MDK, or find it if it was already
+    ///
+    /// MDK records processed welcomes by the gift wrap they came in; a
+    /// welcome handed over without one is recorded by its own id.
+    fn stage_welcome(&self, wrap_id: Option<&EventId>, welcome_json: &str) -> Result<Welcome> {
+        use nostr::UnsignedEvent;
+
+        let mut welcome_unsigned = UnsignedEvent::from_json(welcome_json.as_bytes())
+            .context("parse welcome unsigned event")?;
+        welcome_unsigned.ensure_id();
+        let id = welcome_unsigned.id.context("welcome rumor id")?;
+        // Relays hand the same welcome back on every reconnect
+        if let Some(known) = self.mdk.get_welcome(&id).context("load welcome")? {
+            return Ok(known);
         }
 
-        let group =
-            accepted_group.ok_or_else(|| anyhow!("accepted welcome but group not found"))?;
+        // Processing a welcome consumes the key package it was sent to, so
+        // hold on to the reusable ones and put them back afterwards
+        let last_resort: Vec<String> = self
+            .last_resort
+            .borrow()
+            .iter()
+            .filter_map(|event_json| self.export_key_package_bundle(event_json).ok())
+            .collect();
+        let welcome = self
+            .mdk
+            .process_welcome(wrap_id.unwrap_or(&id), &welcome_unsigned)
+            .context("process welcome")?;
+        for bundle in &last_resort {
+            self.import_key_package_bundle(bundle)?;
+        }
+        self.retire_consumed_key_packages();
+        Ok(welcome)
+    }
+
+    /// Accept only `welcome`: other scopes of the identity may have their
+    /// own welcomes pending
+    fn join_welcome(&self, welcome: &Welcome) -> Result<String> {
+        self.mdk.accept_welcome(welcome).context("accept welcome")?;
+
+        let group = self
+            .mdk
+            .get_groups()
+            .context("load stored groups")?
+            .into_iter()
+            .find(|group| group.mls_group_id == welcome.mls_group_id)
+            .ok_or_else(|| anyhow!("accepted welcome but group not found"))?;
         *self.group_id.borrow_mut() = Some(group.mls_group_id.clone());
+        let root = welcome
+            .event
+            .tags
+            .iter()
+            .find(|tag| tag.kind() == TagKind::custom(MOQ_ROOT_TAG))
+            .and_then(|tag| tag.content());
+        if let Some(root) = root {
+            self.pin_group_root(root)?;
+        }
         Ok(hex::encode(group.mls_group_id.as_slice()))
     }
 
@@ -267,6 +636,12 @@ impl IdentityHandle {
                         directory,
                         created_at,
                     })
+                } else if let Ok(announcement) = serde_json::from_str::<RootAnnouncement>(&content)
+                {
+                    Ok(WrapperOutcome::RootAnnouncement {
+                        author,
+                        announcement,
+                    })
                 } else {
                     // Fall back to regular text message
                     Ok(WrapperOutcome::Application {
@@ -277,12 +652,115 @@ impl IdentityHandle {
                 }
             }
             MessageProcessingResult::Commit => Ok(WrapperOutcome::Commit),
-            MessageProcessingResult::Proposal(_)
-            | MessageProcessingResult::ExternalJoinProposal => Ok(WrapperOutcome::None),
+            // As an admin, MDK commits the proposal (e.g. a member leaving)
+            // right away. Every admin does, so all but one drop their commit;
+            // the committer merges it and hands it back for publishing
+            MessageProcessingResult::Proposal(update) => {
+                if !self.commits_proposals()? {
+                    let removed = self.pending_removals()?;
+                    self.discard_pending_commit()?;
+                    return Ok(WrapperOutcome::ProposalDeferred(removed));
+                }
+                self.merge_pending_commit()?;
+                Ok(WrapperOutcome::ProposalCommitted(WrapperFrame {
+                    bytes: update.evolution_event.as_json().into_bytes(),
+                    kind: WrapperKind::Commit,
+                }))
+            }
+            MessageProcessingResult::ExternalJoinProposal => Ok(WrapperOutcome::None),
             MessageProcessingResult::Unprocessable => Ok(WrapperOutcome::None),
         }
     }
 
+    /// Resume the active group `group_id_hex` held in storage
+    ///
+    /// Returns false when storage holds no such active group (fresh store,
+    /// or the group was left). The MLS state comes back at the epoch it was
+    /// persisted at, so no new welcome is needed. The controller calls this
+    /// on start for the session's group, then commits a self-update so our
+    /// media keys are ones the stored process never used.
+    pub fn restore_group(&self, group_id_hex: &str) -> Result<bool> {
+        let groups = self.mdk.get_groups().context("load stored groups")?;
+        let Some(group) = groups.into_iter().find(|group| {
+            group.state == GroupState::Active
+                && hex::encode(group.mls_group_id.as_slice()) == group_id_hex
+        }) else {
+            return Ok(false);
+        };
+        *self.group_id.borrow_mut() = Some(group.mls_group_id);
+        Ok(true)
+    }
+
+    /// Record the epoch of every stored group, locking our own media keys
+    /// for it until the group moves on
+    fn lock_restored_media(&self) -> Result<()> {
+        for group_id_hex in self.list_groups()? {
+            let epoch = self.for_group(&group_id_hex)?.current_epoch()?;
+            self.restored_epochs
+                .borrow_mut()
+                .insert(group_id_hex, epoch);
+        }
+        Ok(())
+    }
+
+    /// Seal the identity, its MLS state and `session` under `passphrase`
+    ///
+    /// Key packages a welcome has already consumed are left out. A SQLite
+    /// identity carries its database; any other backend carries its groups
+    /// as read through the storage traits.
+    pub fn export_state(
+        &self,
+        passphrase: &str,
+        session: Option<&SessionParams>,
+    ) -> Result<Vec<u8>> {
+        let storage = self.storage_image()?;
+        let memory = match storage {
+            Some(_) => None,
+            None => Some(memory_image(&self.mdk, &self.key_packages.borrow())?),
+        };
+        let key_packages = self
+            .key_packages
+            .borrow()
+            .iter()
+            .filter_map(|event_json| self.export_key_package_bundle(event_json).ok())
+            .collect();
+        let state = StateSnapshot {
+            secret_hex: SecretString::new(self.keys.secret_key().to_secret_hex()),
+            group_id_hex: self.group_id_hex(),
+            key_packages,
+            storage,
+            memory,
+            group_roots: self.group_roots.borrow().clone(),
+            session: session.cloned(),
+        };
+        snapshot::seal(&state, passphrase)
+    }
+
+    /// Consistent copy of the SQLite store
+    ///
+    /// `VACUUM INTO` reads the database in one transaction, write-ahead log
+    /// included, so the image never mixes pages from before and after a
+    /// concurrent write the way copying the files could.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn storage_image(&self) -> Result<Option<StorageImage>> {
+        let Some(path) = &self.storage_path else {
+            return Ok(None);
+        };
+        let copy = export_path(path)?;
+        let result = vacuum_into(path, &copy);
+        let _ = std::fs::remove_file(&copy);
+        let database = Zeroizing::new(result?);
+        Ok(Some(StorageImage {
+            database: SecretString::new(BASE64.encode(database.as_slice())),
+            wal: None,
+        }))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn storage_image(&self) -> Result<Option<StorageImage>> {
+        Ok(None)
+    }
+
     pub fn merge_pending_commit(&self) -> Result<()> {
         let group_id = self.group_id()?;
         self.mdk
@@ -290,12 +768,214 @@ impl IdentityHandle {
             .context("merge pending commit")
     }
 
+    /// Whether we are the admin that commits proposals from other members
+    ///
+    /// The first of [`Self::proposal_committers`]; one committer keeps
+    /// competing commits from forking the epoch.
+    fn commits_proposals(&self) -> Result<bool> {
+        let committers = self.proposal_committers(&self.pending_removals()?)?;
+        Ok(committers.first() == Some(&self.public_key_hex()))
+    }
+
+    /// Admins in the order they commit a proposal removing `removed`
+    ///
+    /// Admins that stay in the group, by pubkey. The first commits right
+    /// away; the others only step in when it does not (see
+    /// [`WrapperOutcome::ProposalDeferred`]).
+    pub fn proposal_committers(&self, removed: &BTreeSet<String>) -> Result<Vec<String>> {
+        let members: BTreeSet<String> = self.list_members()?.into_iter().collect();
+        Ok(self
+            .group_admins()?
+            .into_iter()
+            .filter(|admin| members.contains(admin) && !removed.contains(admin))
+            .collect())
+    }
+
+    /// Members the staged pending commit removes
+    fn pending_removals(&self) -> Result<BTreeSet<String>> {
+        use openmls::group::MlsGroup;
+        use openmls::prelude::BasicCredential;
+
+        let group_id = self.group_id()?;
+        let mls_group = MlsGroup::load(self.mdk.provider.storage(), group_id.inner())
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+        let Some(commit) = mls_group.pending_commit() else {
+            return Ok(BTreeSet::new());
+        };
+        commit
+            .remove_proposals()
+            .map(|proposal| {
+                let leaf = proposal.remove_proposal().removed();
+                let member = mls_group
+                    .member_at(leaf)
+                    .ok_or_else(|| anyhow!("removed leaf {leaf:?} is not in the group"))?;
+                let credential =
+                    BasicCredential::try_from(member.credential).context("member credential")?;
+                let pubkey =
+                    PublicKey::from_slice(credential.identity()).context("member pubkey")?;
+                Ok(pubkey.to_hex())
+            })
+            .collect()
+    }
+
+    /// Drop a commit MDK staged but we are not going to publish
+    fn discard_pending_commit(&self) -> Result<()> {
+        use openmls::group::MlsGroup;
+
+        let group_id = self.group_id()?;
+        let mut mls_group = MlsGroup::load(self.mdk.provider.storage(), group_id.inner())
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+        mls_group
+            .clear_pending_commit(self.mdk.provider.storage())
+            .map_err(|e| anyhow!("clear pending commit: {e:?}"))
+    }
+
     pub fn list_members(&self) -> Result<Vec<String>> {
         let group_id = self.group_id()?;
         let members = self.mdk.get_members(&group_id).context("get members")?;
         Ok(members.into_iter().map(|pk| pk.to_hex()).collect())
     }
 
+    /// Commit the removal of `pubkeys` and merge it locally
+    pub fn remove_members(&self, pubkeys: &[String]) -> Result<WrapperFrame> {
+        let group_id = self.group_id()?;
+        let pubkeys = pubkeys
+            .iter()
+            .map(|hex| PublicKey::from_hex(hex).context("parse member pubkey"))
+            .collect::<Result<Vec<_>>>()?;
+        let update = self
+            .mdk
+            .remove_members(&group_id, &pubkeys)
+            .context("remove members")?;
+        self.apply_own_commit(update)
+    }
+
+    /// Commit the removals of members whose leave proposal went uncommitted
+    ///
+    /// Drops the queued proposals first so the removals are not committed
+    /// twice; the commit removes the same members the proposals did.
+    pub fn commit_removals(&self, pubkeys: &[String]) -> Result<WrapperFrame> {
+        use openmls::group::MlsGroup;
+
+        let group_id = self.group_id()?;
+        let mut mls_group = MlsGroup::load(self.mdk.provider.storage(), group_id.inner())
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+        mls_group
+            .clear_pending_proposals(self.mdk.provider.storage())
+            .map_err(|e| anyhow!("clear pending proposals: {e:?}"))?;
+        self.remove_members(pubkeys)
+    }
+
+    /// Admins recorded in the group's Marmot data extension
+    ///
+    /// MDK refreshes this from the group context whenever a commit or
+    /// welcome is processed, so every member derives the same set.
+    pub fn group_admins(&self) -> Result<BTreeSet<String>> {
+        let group_id = self.group_id()?;
+        let group = self
+            .mdk
+            .get_group(&group_id)
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+        Ok(group.admin_pubkeys.iter().map(|pk| pk.to_hex()).collect())
+    }
+
+    /// Commit `admins` as the group's admin set and merge it locally
+    pub fn set_group_admins(&self, admins: &BTreeSet<String>) -> Result<WrapperFrame> {
+        let group_id = self.group_id()?;
+        let admins = admins
+            .iter()
+            .map(|hex| PublicKey::from_hex(hex).context("parse admin pubkey"))
+            .collect::<Result<Vec<_>>>()?;
+        let update = self
+            .mdk
+            .update_group_data(&group_id, NostrGroupDataUpdate::new().admins(admins))
+            .context("update group admins")?;
+        self.apply_own_commit(update)
+    }
+
+    pub fn group_metadata(&self) -> Result<GroupMetadata> {
+        let group_id = self.group_id()?;
+        let group = self
+            .mdk
+            .get_group(&group_id)
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+        let image = match (group.image_hash, group.image_key, group.image_nonce) {
+            (Some(hash), Some(key), Some(nonce)) => Some(GroupImageRef { hash, key, nonce }),
+            _ => None,
+        };
+        Ok(GroupMetadata {
+            name: group.name,
+            description: group.description,
+            image,
+        })
+    }
+
+    /// Commit a new name and/or description
+    pub fn update_group_profile(
+        &self,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> Result<WrapperFrame> {
+        let group_id = self.group_id()?;
+        let mut update = NostrGroupDataUpdate::new();
+        if let Some(name) = name {
+            update = update.name(name);
+        }
+        if let Some(description) = description {
+            update = update.description(description);
+        }
+        let update = self
+            .mdk
+            .update_group_data(&group_id, update)
+            .context("update group profile")?;
+        self.apply_own_commit(update)
+    }
+
+    /// Commit the reference to a new encrypted group image, or clear it
+    pub fn set_group_image(&self, image: Option<&GroupImageRef>) -> Result<WrapperFrame> {
+        let group_id = self.group_id()?;
+        let update = NostrGroupDataUpdate::new()
+            .image_hash(image.map(|image| image.hash))
+            .image_key(image.map(|image| image.key))
+            .image_nonce(image.map(|image| image.nonce));
+        let update = self
+            .mdk
+            .update_group_data(&group_id, update)
+            .context("update group image")?;
+        self.apply_own_commit(update)
+    }
+
+    fn apply_own_commit(&self, update: UpdateGroupResult) -> Result<WrapperFrame> {
+        let json = update.evolution_event.as_json();
+        let _ = self.ingest_wrapper(json.as_bytes())?;
+        self.merge_pending_commit()?;
+        Ok(WrapperFrame {
+            bytes: json.into_bytes(),
+            kind: WrapperKind::Commit,
+        })
+    }
+
+    /// Propose our own removal
+    ///
+    /// MLS members cannot commit their own removal; the committing admin
+    /// (see [`WrapperOutcome::ProposalCommitted`]) commits the proposal when
+    /// it arrives.
+    pub fn leave_group(&self) -> Result<WrapperFrame> {
+        let group_id = self.group_id()?;
+        let UpdateGroupResult {
+            evolution_event, ..
+        } = self.mdk.leave_group(&group_id).context("leave group")?;
+        Ok(WrapperFrame {
+            bytes: evolution_event.as_json().into_bytes(),
+            kind: WrapperKind::Proposal,
+        })
+    }
+
     pub fn create_message(&self, content: &str) -> Result<WrapperFrame> {
         let rumor = EventBuilder::new(Kind::TextNote, content)
             .custom_created_at(Timestamp::now())
@@ -329,10 +1009,80 @@ impl IdentityHandle {
         })
     }
 
+    /// Derive the MoQ root for the current epoch
+    ///
+    /// Per spec: root = marmot/hex(MLS-Exporter("moq-group-root-v1", mls_group_id, 16)).
+    /// Relays cannot link the path to the group id. The exporter changes
+    /// every epoch, so a group pins the root it derives once (see
+    /// [`IdentityHandle::pin_group_root`]) rather than follow it.
     pub fn derive_group_root(&self) -> Result<String> {
+        use openmls::group::MlsGroup;
+
+        let group_id = self.group_id()?;
+        let mls_group = MlsGroup::load(self.mdk.provider.storage(), group_id.inner())
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+
+        let exported = mls_group
+            .export_secret(
+                self.mdk.provider.crypto(),
+                "moq-group-root-v1",
+                group_id.as_slice(),
+                16,
+            )
+            .context("export group root")?;
+        Ok(format!("marmot/{}", hex::encode(exported)))
+    }
+
+    /// MoQ root pinned for the handle's group, if any
+    pub fn pinned_group_root(&self) -> Result<Option<String>> {
+        let group_id_hex = self
+            .group_id_hex()
+            .ok_or_else(|| anyhow!("group not established"))?;
+        Ok(self.group_roots.borrow().get(&group_id_hex).cloned())
+    }
+
+    /// Pin `root` as the MoQ root of the handle's group
+    ///
+    /// The root stays put through commits until pinned again. A SQLite
+    /// identity keeps it in its database, so it survives restarts and
+    /// travels in snapshots with the MLS state.
+    pub fn pin_group_root(&self, root: &str) -> Result<()> {
+        let group_id_hex = self
+            .group_id_hex()
+            .ok_or_else(|| anyhow!("group not established"))?;
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(path) = &self.storage_path {
+            store_group_root(path, &group_id_hex, root)?;
+        }
+        self.group_roots
+            .borrow_mut()
+            .insert(group_id_hex, root.to_string());
+        Ok(())
+    }
+
+    /// `welcome_json` tagged with the group's pinned MoQ root, so the
+    /// invitee connects where the members are; unchanged while none is
+    pub fn pinned_welcome(&self, welcome_json: &str) -> Result<String> {
+        let Some(root) = self.pinned_group_root()? else {
+            return Ok(welcome_json.to_string());
+        };
+        let mut rumor =
+            nostr::UnsignedEvent::from_json(welcome_json).context("parse welcome rumor")?;
+        rumor
+            .tags
+            .push(Tag::custom(TagKind::custom(MOQ_ROOT_TAG), [root]));
+        rumor.id = None;
+        rumor.ensure_id();
+        Ok(rumor.as_json())
+    }
+
+    /// Pre-exporter MoQ root, `marmot/<hex(mls_group_id)>`
+    ///
+    /// Stable across epochs but linkable; only used while migrating members
+    /// that have not moved to exporter-derived roots yet.
+    pub fn derive_legacy_group_root(&self) -> Result<String> {
         let group_id = self.group_id()?;
-        // Use the stable group ID (not epoch-specific export) as MoQ path root
-        // This ensures all members at any epoch use the same path
         Ok(format!("marmot/{}", hex::encode(group_id.as_slice())))
     }
 
@@ -345,6 +1095,28 @@ impl IdentityHandle {
         Ok(mls_group.epoch().as_u64())
     }
 
+    pub fn create_root_announcement(&self, roots: Vec<String>) -> Result<WrapperFrame> {
+        let announcement = RootAnnouncement {
+            sender: self.public_key_hex(),
+            epoch: self.current_epoch()?,
+            roots,
+        };
+        let content =
+            serde_json::to_string(&announcement).context("serialize root announcement")?;
+        let rumor = EventBuilder::new(Kind::TextNote, content)
+            .custom_created_at(Timestamp::now())
+            .build(self.keys.public_key());
+        let group_id = self.group_id()?;
+        let wrapper = self
+            .mdk
+            .create_message(&group_id, rumor)
+            .context("create root announcement")?;
+        Ok(WrapperFrame {
+            bytes: wrapper.as_json().into_bytes(),
+            kind: WrapperKind::RootAnnouncement(announcement),
+        })
+    }
+
     pub fn create_directory_message(&self, tracks: Vec<TrackEntry>) -> Result<WrapperFrame> {
         let epoch = self.current_epoch()?;
         let directory = DirectoryMessage {
@@ -371,6 +1143,11 @@ impl IdentityHandle {
     ///
     /// Per spec: base = MLS-Exporter("moq-media-base-v1", sender_leaf || track_label || epoch_bytes, 32)
     ///
+    /// Frame counters live in memory only, so our own keys for a group
+    /// loaded from storage are refused until a commit (see
+    /// [`IdentityHandle::self_update`]) moves it past the epoch it was
+    /// loaded at; the process that stored it may have used them already.
+    ///
     /// # Arguments
     /// * `sender_pubkey_hex` - Sender's public key in hex
     /// * `track_label` - Track label (already derived from exporter in directory)
@@ -378,7 +1155,7 @@ impl IdentityHandle {
         &self,
         sender_pubkey_hex: &str,
         track_label: &str,
-    ) -> Result<[u8; 32]> {
+    ) -> Result<MediaBaseKey> {
         use openmls::group::MlsGroup;
 
         let group_id = self.group_id()?;
@@ -387,6 +1164,16 @@ impl IdentityHandle {
             .ok_or_else(|| anyhow!("group not found"))?;
 
         let epoch = mls_group.epoch().as_u64();
+        if sender_pubkey_hex == self.public_key_hex() {
+            let group_id_hex = hex::encode(group_id.as_slice());
+            if let Some(restored) = self.restored_epochs.borrow().get(&group_id_hex) {
+                if epoch <= *restored {
+                    return Err(anyhow!(
+                        "own media keys wait for a commit past restored epoch {restored}"
+                    ));
+                }
+            }
+        }
 
         // Construct context: sender_pubkey_hex || track_label || epoch_bytes
         let mut context = Vec::new();
@@ -394,33 +1181,681 @@ impl IdentityHandle {
         context.extend_from_slice(track_label.as_bytes());
         context.extend_from_slice(&epoch.to_be_bytes());
 
+        let exported = Zeroizing::new(
+            mls_group
+                .export_secret(
+                    self.mdk.provider.crypto(),
+                    "moq-media-base-v1",
+                    &context,
+                    32,
+                )
+                .context("export media base key")?,
+        );
+        MediaBaseKey::from_slice(&exported)
+    }
+
+    /// Derive the label for `sender_pubkey_hex`'s track of `kind` at the
+    /// current epoch
+    ///
+    /// Per spec: label = hex(MLS-Exporter("moq-track-lbl-v1", sender_leaf || kind || epoch, 16)).
+    /// As with media base keys, the sender's pubkey hex stands in for the leaf.
+    pub fn derive_track_label(&self, sender_pubkey_hex: &str, kind: &TrackKind) -> Result<String> {
+        use openmls::group::MlsGroup;
+
+        let group_id = self.group_id()?;
+        let mls_group = MlsGroup::load(self.mdk.provider.storage(), group_id.inner())
+            .context("load group")?
+            .ok_or_else(|| anyhow!("group not found"))?;
+
+        let epoch = mls_group.epoch().as_u64();
+
+        let mut context = Vec::new();
+        context.extend_from_slice(sender_pubkey_hex.as_bytes());
+        context.extend_from_slice(kind.as_str().as_bytes());
+        context.extend_from_slice(&epoch.to_be_bytes());
+
         let exported = mls_group
-            .export_secret(
-                self.mdk.provider.crypto(),
-                "moq-media-base-v1",
-                &context,
-                32,
-            )
-            .context("export media base key")?;
+            .export_secret(self.mdk.provider.crypto(), "moq-track-lbl-v1", &context, 16)
+            .context("export track label")?;
+        Ok(hex::encode(exported))
+    }
 
-        let mut base_key = [0u8; 32];
-        base_key.copy_from_slice(&exported);
-        Ok(base_key)
+    /// Check that a directory entry's label is the one `sender_pubkey_hex`
+    /// owns for its kind at `epoch`
+    ///
+    /// Labels can only be recomputed at the current epoch, so directories
+    /// announced for any other epoch fail with [`TrackLabelError::EpochMismatch`].
+    /// The controller retains labels for recent epochs and checks older
+    /// directories against those.
+    pub fn verify_track_label(
+        &self,
+        sender_pubkey_hex: &str,
+        epoch: u64,
+        track: &TrackEntry,
+    ) -> Result<()> {
+        let current = self.current_epoch()?;
+        if epoch != current {
+            return Err(TrackLabelError::EpochMismatch {
+                announced: epoch,
+                current,
+            }
+            .into());
+        }
+        let expected = self.derive_track_label(sender_pubkey_hex, &track.kind)?;
+        if track.label != expected {
+            return Err(TrackLabelError::LabelMismatch {
+                label: track.label.clone(),
+                expected,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Why a directory track label was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackLabelError {
+    /// The directory was announced for an epoch other than ours whose
+    /// labels are not (or no longer) retained
+    EpochMismatch { announced: u64, current: u64 },
+    /// The label is not the one derived for the sender, kind and epoch
+    LabelMismatch { label: String, expected: String },
+    /// The sender was not a member at the retained epoch announced
+    NotMember { epoch: u64 },
+}
+
+impl fmt::Display for TrackLabelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackLabelError::EpochMismatch { announced, current } => write!(
+                f,
+                "directory announced for epoch {announced}, group is at epoch {current}"
+            ),
+            TrackLabelError::LabelMismatch { label, expected } => {
+                write!(
+                    f,
+                    "track label {label} does not match derived label {expected}"
+                )
+            }
+            TrackLabelError::NotMember { epoch } => {
+                write!(f, "sender was not a member at epoch {epoch}")
+            }
+        }
     }
 }
 
+impl std::error::Error for TrackLabelError {}
+
 pub struct IdentityService;
 
+/// Identity and session recovered from a state snapshot
+pub struct ImportedState<S: MdkStorageProvider = MdkMemoryStorage> {
+    pub identity: IdentityHandle<S>,
+    pub session: Option<SessionParams>,
+}
+
 impl IdentityService {
+    /// Create an identity whose MLS state lives only in memory
+    ///
+    /// [`IdentityHandle::export_state`] still carries its groups, so a
+    /// snapshot is the way to keep them across reloads.
     pub fn create(secret_hex: &str) -> Result<IdentityHandle> {
+        Self::create_with_storage(secret_hex, MdkMemoryStorage::default())
+    }
+
+    /// Create an identity on top of any MDK storage backend
+    ///
+    /// Its key packages are not published unless the backend is known to
+    /// persist (see [`IdentityHandle::publishes_key_packages`]).
+    pub fn create_with_storage<S: MdkStorageProvider>(
+        secret_hex: &str,
+        storage: S,
+    ) -> Result<IdentityHandle<S>> {
         let secret = SecretKey::from_hex(secret_hex).context("parse secret hex")?;
         let keys = nostr::Keys::new(secret);
         Ok(IdentityHandle {
             keys,
-            mdk: MDK::new(MdkMemoryStorage::default()),
+            mdk: Rc::new(MDK::new(storage)),
             group_id: Rc::new(RefCell::new(None)),
+            key_packages: Rc::new(RefCell::new(Vec::new())),
+            last_resort: Rc::new(RefCell::new(BTreeSet::new())),
+            retired: Rc::new(RefCell::new(Vec::new())),
+            group_roots: Rc::new(RefCell::new(BTreeMap::new())),
+            restored_epochs: Rc::new(RefCell::new(BTreeMap::new())),
+            #[cfg(not(target_arch = "wasm32"))]
+            storage_path: None,
+            persisted: false,
+        })
+    }
+
+    /// Import a snapshot into an in-memory identity
+    ///
+    /// Restores the groups of a snapshot taken from memory; ones exported
+    /// from a SQLite store go through [`IdentityService::import_state_to`].
+    pub fn import_state(bytes: &[u8], passphrase: &str) -> Result<ImportedState> {
+        let state = snapshot::open(bytes, passphrase)?;
+        if state.storage.is_some() {
+            return Err(anyhow!(
+                "snapshot carries persisted group state; import it into a persistent store"
+            ));
+        }
+        let identity = Self::create(state.secret_hex.expose())?;
+        if let Some(image) = &state.memory {
+            restore_memory_image(&identity.mdk, image)?;
+        }
+        identity.lock_restored_media()?;
+        for bundle in &state.key_packages {
+            identity.import_key_package_bundle(bundle)?;
+        }
+        if let Some(group_id_hex) = &state.group_id_hex {
+            if !identity.list_groups()?.contains(group_id_hex) {
+                return Err(anyhow!("snapshot group state does not match its group id"));
+            }
+            identity.set_group_id_hex(group_id_hex)?;
+        }
+        restore_group_roots(&identity, &state.group_roots)?;
+        Ok(ImportedState {
+            identity,
+            session: state.session,
         })
     }
+
+    /// Open an identity backed by the SQLite database at `path`
+    ///
+    /// The database is created on first use. Groups, epoch secrets and key
+    /// packages survive restarts; a controller started with the group's id
+    /// in its session (or [`IdentityHandle::restore_group`]) resumes it.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(
+        secret_hex: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<IdentityHandle<MdkSqliteStorage>> {
+        let path = path.as_ref();
+        let storage = MdkSqliteStorage::new(path)
+            .with_context(|| format!("open MDK storage at {}", path.display()))?;
+        let mut identity = Self::create_with_storage(secret_hex, storage)?;
+        identity.storage_path = Some(path.to_path_buf());
+        identity.persisted = true;
+        *identity.group_roots.borrow_mut() = load_group_roots(path)?;
+        identity.lock_restored_media()?;
+        Ok(identity)
+    }
+
+    /// Import a snapshot into a new SQLite database at `path`
+    ///
+    /// Restores the stored group, so the identity resumes at the epoch the
+    /// snapshot was taken at. Refuses to overwrite an existing database.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn import_state_to(
+        bytes: &[u8],
+        passphrase: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<ImportedState<MdkSqliteStorage>> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(anyhow!("{} already exists", path.display()));
+        }
+        let state = snapshot::open(bytes, passphrase)?;
+        if let Some(image) = &state.storage {
+            let database = Zeroizing::new(
+                BASE64
+                    .decode(image.database.expose())
+                    .context("invalid storage image encoding")?,
+            );
+            std::fs::write(path, database.as_slice())
+                .with_context(|| format!("write MDK storage to {}", path.display()))?;
+            if let Some(wal) = &image.wal {
+                let wal = Zeroizing::new(
+                    BASE64
+                        .decode(wal.expose())
+                        .context("invalid write-ahead log encoding")?,
+                );
+                std::fs::write(wal_path(path), wal.as_slice())
+                    .context("write MDK write-ahead log")?;
+            }
+        }
+
+        let identity = Self::open(state.secret_hex.expose(), path)?;
+        for bundle in &state.key_packages {
+            identity.import_key_package_bundle(bundle)?;
+        }
+        if let Some(group_id_hex) = &state.group_id_hex {
+            if !identity.list_groups()?.contains(group_id_hex) {
+                return Err(anyhow!("snapshot group state does not match its group id"));
+            }
+            identity.set_group_id_hex(group_id_hex)?;
+        }
+        restore_group_roots(&identity, &state.group_roots)?;
+        Ok(ImportedState {
+            identity,
+            session: state.session,
+        })
+    }
+}
+
+/// Pin the snapshot's MoQ roots for the groups `identity` holds
+fn restore_group_roots<S: MdkStorageProvider>(
+    identity: &IdentityHandle<S>,
+    roots: &BTreeMap<String, String>,
+) -> Result<()> {
+    let groups = identity.list_groups()?;
+    for (group_id_hex, root) in roots {
+        if groups.contains(group_id_hex) {
+            identity.for_group(group_id_hex)?.pin_group_root(root)?;
+        }
+    }
+    Ok(())
+}
+
+/// SQLite keeps uncheckpointed pages next to the database as `<path>-wal`
+#[cfg(not(target_arch = "wasm32"))]
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+/// Copy the database at `path` to `copy` and read the copy back
+#[cfg(not(target_arch = "wasm32"))]
+fn vacuum_into(path: &Path, copy: &Path) -> Result<Vec<u8>> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open MDK storage at {}", path.display()))?;
+    let target = copy
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid UTF-8", copy.display()))?;
+    connection
+        .execute("VACUUM INTO ?1", [target])
+        .context("copy MDK storage")?;
+    std::fs::read(copy).context("read MDK storage copy")
+}
+
+/// Unused file next to the database for `VACUUM INTO` to write
+#[cfg(not(target_arch = "wasm32"))]
+fn export_path(path: &Path) -> Result<PathBuf> {
+    let mut suffix = [0u8; 8];
+    getrandom::getrandom(&mut suffix).map_err(|e| anyhow!("export file name: {e}"))?;
+    let mut copy = path.as_os_str().to_owned();
+    copy.push(format!(".export-{}", hex::encode(suffix)));
+    Ok(PathBuf::from(copy))
+}
+
+/// Table of pinned MoQ roots, kept next to MDK's own in its database
+#[cfg(not(target_arch = "wasm32"))]
+const GROUP_ROOTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS marmot_chat_group_roots \
+     (group_id TEXT PRIMARY KEY NOT NULL, root TEXT NOT NULL)";
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_group_roots(path: &Path) -> Result<rusqlite::Connection> {
+    let connection = rusqlite::Connection::open(path)
+        .with_context(|| format!("open MDK storage at {}", path.display()))?;
+    connection
+        .busy_timeout(std::time::Duration::from_secs(5))
+        .context("set busy timeout")?;
+    connection
+        .execute(GROUP_ROOTS_TABLE, [])
+        .context("create group root table")?;
+    Ok(connection)
+}
+
+/// MoQ roots pinned in the database at `path`, by group id hex
+#[cfg(not(target_arch = "wasm32"))]
+fn load_group_roots(path: &Path) -> Result<BTreeMap<String, String>> {
+    let connection = open_group_roots(path)?;
+    let mut statement = connection
+        .prepare("SELECT group_id, root FROM marmot_chat_group_roots")
+        .context("read group roots")?;
+    let rows = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context("read group roots")?;
+    rows.collect::<rusqlite::Result<_>>()
+        .context("read group roots")
+}
+
+/// Pin `root` for `group_id_hex` in the database at `path`
+#[cfg(not(target_arch = "wasm32"))]
+fn store_group_root(path: &Path, group_id_hex: &str, root: &str) -> Result<()> {
+    open_group_roots(path)?
+        .execute(
+            "INSERT OR REPLACE INTO marmot_chat_group_roots (group_id, root) VALUES (?1, ?2)",
+            [group_id_hex, root],
+        )
+        .context("store group root")?;
+    Ok(())
+}
+
+/// An OpenMLS record as the JSON it is stored as
+///
+/// Lets the snapshot code read and write group records through
+/// [`StorageProvider`] without naming the OpenMLS types behind them, most
+/// of which are private to OpenMLS.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct StoredRecord(serde_json::Value);
+
+impl Entity<CURRENT_VERSION> for StoredRecord {}
+impl Key<CURRENT_VERSION> for StoredRecord {}
+impl traits::MlsGroupJoinConfig<CURRENT_VERSION> for StoredRecord {}
+impl traits::LeafNode<CURRENT_VERSION> for StoredRecord {}
+impl traits::ProposalRef<CURRENT_VERSION> for StoredRecord {}
+impl traits::QueuedProposal<CURRENT_VERSION> for StoredRecord {}
+impl traits::TreeSync<CURRENT_VERSION> for StoredRecord {}
+impl traits::GroupContext<CURRENT_VERSION> for StoredRecord {}
+impl traits::InterimTranscriptHash<CURRENT_VERSION> for StoredRecord {}
+impl traits::ConfirmationTag<CURRENT_VERSION> for StoredRecord {}
+impl traits::GroupState<CURRENT_VERSION> for StoredRecord {}
+impl traits::MessageSecrets<CURRENT_VERSION> for StoredRecord {}
+impl traits::ResumptionPskStore<CURRENT_VERSION> for StoredRecord {}
+impl traits::LeafNodeIndex<CURRENT_VERSION> for StoredRecord {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for StoredRecord {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for StoredRecord {}
+
+fn seal_record<T: Serialize>(record: &T) -> Result<SecretString> {
+    serde_json::to_string(record)
+        .map(SecretString::new)
+        .context("serialize MLS record")
+}
+
+fn open_record<T: serde::de::DeserializeOwned>(
+    records: &BTreeMap<String, SecretString>,
+    name: &str,
+) -> Result<Option<T>> {
+    records
+        .get(name)
+        .map(|record| serde_json::from_str(record.expose()))
+        .transpose()
+        .with_context(|| format!("parse MLS record {name}"))
+}
+
+/// Exporter secrets kept per group, so messages from just before the
+/// snapshot still decrypt after it is restored
+const IMAGE_SECRET_EPOCHS: u64 = 3;
+
+/// Group records copied one to one, by their name in [`StorageProvider`]
+const MLS_GROUP_RECORDS: [&str; 10] = [
+    "mls_group_join_config",
+    "tree",
+    "group_context",
+    "interim_transcript_hash",
+    "confirmation_tag",
+    "group_state",
+    "message_secrets",
+    "resumption_psk_store",
+    "own_leaf_index",
+    "group_epoch_secrets",
+];
+
+/// Copy the groups of a store out through the OpenMLS and MDK storage
+/// traits, with the signing keys of the groups and of `key_packages`
+fn memory_image<S: MdkStorageProvider>(
+    mdk: &MDK<S>,
+    key_packages: &[String],
+) -> Result<MemoryImage> {
+    use openmls::group::MlsGroup;
+
+    let mls_storage = mdk.provider.storage();
+    let storage = mdk.storage();
+    let mut signature_keys = BTreeMap::new();
+    let mut groups = Vec::new();
+    for group in mdk.get_groups().context("load stored groups")? {
+        let mls_group = MlsGroup::load(mls_storage, group.mls_group_id.inner())
+            .map_err(|e| anyhow!("load group: {e:?}"))?
+            .ok_or_else(|| anyhow!("MLS state of a stored group is missing"))?;
+        let own_leaf = mls_group
+            .own_leaf_node()
+            .ok_or_else(|| anyhow!("group has no own leaf"))?;
+        let signer = SignatureKeyPair::read(
+            mls_storage,
+            own_leaf.signature_key().as_slice(),
+            mls_group.ciphersuite().signature_algorithm(),
+        )
+        .ok_or_else(|| anyhow!("group signing key missing"))?;
+        signature_keys.insert(signer.public().to_vec(), seal_record(&signer)?);
+
+        let relays = mdk
+            .get_relays(&group.mls_group_id)
+            .context("load group relays")?
+            .into_iter()
+            .map(|relay| relay.to_string())
+            .collect();
+        let mut exporter_secrets = Vec::new();
+        for epoch in group.epoch.saturating_sub(IMAGE_SECRET_EPOCHS - 1)..=group.epoch {
+            let secret = storage
+                .get_group_exporter_secret(&group.mls_group_id, epoch)
+                .map_err(|e| anyhow!("load exporter secret: {e:?}"))?;
+            if let Some(secret) = secret {
+                exporter_secrets.push(ExporterSecretImage {
+                    epoch,
+                    secret: SecretString::new(hex::encode(secret.secret)),
+                });
+            }
+        }
+        groups.push(GroupImage {
+            mls: mls_group_image(mls_storage, &mls_group)?,
+            group,
+            relays,
+            exporter_secrets,
+        });
+    }
+
+    // Key packages a welcome may still use need their signing key too
+    for event_json in key_packages {
+        let Ok(event) = Event::from_json(event_json) else {
+            continue;
+        };
+        let Ok(key_package) = mdk.parse_key_package(&event) else {
+            continue;
+        };
+        let signer = SignatureKeyPair::read(
+            mls_storage,
+            key_package.leaf_node().signature_key().as_slice(),
+            key_package.ciphersuite().signature_algorithm(),
+        );
+        if let Some(signer) = signer {
+            signature_keys.insert(signer.public().to_vec(), seal_record(&signer)?);
+        }
+    }
+    Ok(MemoryImage {
+        groups,
+        signature_keys: signature_keys.into_values().collect(),
+    })
+}
+
+/// Records OpenMLS keeps for `mls_group`, by their name in [`StorageProvider`]
+fn mls_group_image<P: StorageProvider<CURRENT_VERSION>>(
+    storage: &P,
+    mls_group: &openmls::group::MlsGroup,
+) -> Result<BTreeMap<String, SecretString>> {
+    let group_id = mls_group.group_id();
+    let read = |e: P::Error| anyhow!("read MLS group state: {e:?}");
+    let singles: [Option<StoredRecord>; 10] = [
+        storage.mls_group_join_config(group_id).map_err(read)?,
+        storage.tree(group_id).map_err(read)?,
+        storage.group_context(group_id).map_err(read)?,
+        storage.interim_transcript_hash(group_id).map_err(read)?,
+        storage.confirmation_tag(group_id).map_err(read)?,
+        storage.group_state(group_id).map_err(read)?,
+        storage.message_secrets(group_id).map_err(read)?,
+        storage.resumption_psk_store(group_id).map_err(read)?,
+        storage.own_leaf_index(group_id).map_err(read)?,
+        storage.group_epoch_secrets(group_id).map_err(read)?,
+    ];
+    let mut records = BTreeMap::new();
+    for (name, record) in MLS_GROUP_RECORDS.into_iter().zip(singles) {
+        if let Some(record) = record {
+            records.insert(name.to_string(), seal_record(&record)?);
+        }
+    }
+
+    let own_leaf_nodes: Vec<StoredRecord> = storage.own_leaf_nodes(group_id).map_err(read)?;
+    let queued_proposals: Vec<(StoredRecord, StoredRecord)> =
+        storage.queued_proposals(group_id).map_err(read)?;
+    let epoch_key_pairs: Vec<StoredRecord> = storage
+        .encryption_epoch_key_pairs(
+            group_id,
+            &mls_group.epoch(),
+            mls_group.own_leaf_index().u32(),
+        )
+        .map_err(read)?;
+    records.insert("own_leaf_nodes".to_string(), seal_record(&own_leaf_nodes)?);
+    records.insert(
+        "queued_proposals".to_string(),
+        seal_record(&queued_proposals)?,
+    );
+    records.insert(
+        "encryption_epoch_key_pairs".to_string(),
+        seal_record(&epoch_key_pairs)?,
+    );
+    Ok(records)
+}
+
+/// Write a [`memory_image`] back through the storage traits
+fn restore_memory_image<S: MdkStorageProvider>(mdk: &MDK<S>, image: &MemoryImage) -> Result<()> {
+    let mls_storage = mdk.provider.storage();
+    for signer in &image.signature_keys {
+        let signer: SignatureKeyPair =
+            serde_json::from_str(signer.expose()).context("parse signing key")?;
+        signer
+            .store(mls_storage)
+            .map_err(|e| anyhow!("store signing key: {e:?}"))?;
+    }
+
+    let storage = mdk.storage();
+    for entry in &image.groups {
+        let group_id = entry.group.mls_group_id.clone();
+        restore_mls_group(mls_storage, group_id.inner(), &entry.mls)?;
+        storage
+            .save_group(entry.group.clone())
+            .map_err(|e| anyhow!("store group: {e:?}"))?;
+        let relays = entry
+            .relays
+            .iter()
+            .map(|url| nostr::RelayUrl::parse(url))
+            .collect::<Result<BTreeSet<_>, _>>()
+            .context("parse group relays")?;
+        storage
+            .replace_group_relays(&group_id, relays)
+            .map_err(|e| anyhow!("store group relays: {e:?}"))?;
+        for exporter in &entry.exporter_secrets {
+            let secret: [u8; 32] = hex::decode(exporter.secret.expose())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| anyhow!("invalid exporter secret"))?;
+            storage
+                .save_group_exporter_secret(GroupExporterSecret {
+                    mls_group_id: group_id.clone(),
+                    epoch: exporter.epoch,
+                    secret,
+                })
+                .map_err(|e| anyhow!("store exporter secret: {e:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the records of a [`mls_group_image`] for `group_id`
+fn restore_mls_group<P: StorageProvider<CURRENT_VERSION>>(
+    storage: &P,
+    group_id: &openmls::group::GroupId,
+    records: &BTreeMap<String, SecretString>,
+) -> Result<()> {
+    use openmls::group::MlsGroup;
+
+    let write = |e: P::Error| anyhow!("write MLS group state: {e:?}");
+    for name in MLS_GROUP_RECORDS {
+        let Some(record) = open_record::<StoredRecord>(records, name)? else {
+            continue;
+        };
+        match name {
+            "mls_group_join_config" => storage.write_mls_join_config(group_id, &record),
+            "tree" => storage.write_tree(group_id, &record),
+            "group_context" => storage.write_context(group_id, &record),
+            "interim_transcript_hash" => storage.write_interim_transcript_hash(group_id, &record),
+            "confirmation_tag" => storage.write_confirmation_tag(group_id, &record),
+            "group_state" => storage.write_group_state(group_id, &record),
+            "message_secrets" => storage.write_message_secrets(group_id, &record),
+            "resumption_psk_store" => storage.write_resumption_psk_store(group_id, &record),
+            "own_leaf_index" => storage.write_own_leaf_index(group_id, &record),
+            "group_epoch_secrets" => storage.write_group_epoch_secrets(group_id, &record),
+            _ => unreachable!("every group record has a writer"),
+        }
+        .map_err(write)?;
+    }
+    let own_leaf_nodes: Vec<StoredRecord> =
+        open_record(records, "own_leaf_nodes")?.unwrap_or_default();
+    for leaf_node in &own_leaf_nodes {
+        storage
+            .append_own_leaf_node(group_id, leaf_node)
+            .map_err(write)?;
+    }
+    let queued_proposals: Vec<(StoredRecord, StoredRecord)> =
+        open_record(records, "queued_proposals")?.unwrap_or_default();
+    for (proposal_ref, proposal) in &queued_proposals {
+        storage
+            .queue_proposal(group_id, proposal_ref, proposal)
+            .map_err(write)?;
+    }
+
+    // Epoch key pairs are keyed by the epoch and leaf the records above hold
+    let mls_group = MlsGroup::load(storage, group_id)
+        .map_err(|e| anyhow!("load restored group: {e:?}"))?
+        .ok_or_else(|| anyhow!("restored MLS group state is incomplete"))?;
+    let epoch_key_pairs: Vec<StoredRecord> =
+        open_record(records, "encryption_epoch_key_pairs")?.unwrap_or_default();
+    storage
+        .write_encryption_epoch_key_pairs(
+            group_id,
+            &mls_group.epoch(),
+            mls_group.own_leaf_index().u32(),
+            &epoch_key_pairs,
+        )
+        .map_err(write)?;
+    Ok(())
+}
+
+/// How a new key package may be used
+#[derive(Debug, Clone, Default)]
+pub struct KeyPackageOptions {
+    /// Keep the private half after a welcome uses it, so one published key
+    /// package can bring us into several groups
+    pub last_resort: bool,
+    /// Unix time after which inviters must not use it (NIP-40 `expiration`)
+    pub expires_at: Option<u64>,
+}
+
+impl KeyPackageOptions {
+    /// Good for one welcome and valid for
+    /// [`key_packages::KEY_PACKAGE_LIFETIME_SECS`] from `now`, as published
+    /// key packages are
+    pub fn published(now: u64) -> Self {
+        Self {
+            last_resort: false,
+            expires_at: Some(now + key_packages::KEY_PACKAGE_LIFETIME_SECS),
+        }
+    }
+
+    /// Like [`KeyPackageOptions::published`] but reusable; creating one
+    /// replaces the identity's previous last-resort key package
+    pub fn last_resort(now: u64) -> Self {
+        Self {
+            last_resort: true,
+            ..Self::published(now)
+        }
+    }
+}
+
+impl From<&Welcome> for PendingWelcome {
+    fn from(welcome: &Welcome) -> Self {
+        Self {
+            welcome_id: welcome.id.to_hex(),
+            group_id_hex: hex::encode(welcome.mls_group_id.as_slice()),
+            group_name: welcome.group_name.clone(),
+            welcomer: welcome.welcomer.to_hex(),
+            member_count: welcome.member_count,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -441,30 +1876,64 @@ pub enum WrapperOutcome {
         directory: DirectoryMessage,
         created_at: u64,
     },
+    RootAnnouncement {
+        author: String,
+        announcement: RootAnnouncement,
+    },
     Commit,
+    /// We committed a pending proposal; the commit must be published
+    ///
+    /// Only the lowest remaining admin pubkey commits; the other admins
+    /// drop their staged commit and see [`WrapperOutcome::ProposalDeferred`].
+    ProposalCommitted(WrapperFrame),
+    /// Another admin is due to commit a proposal removing these members
+    ///
+    /// The proposal stays queued; if its commit never arrives we commit the
+    /// removals ourselves with [`IdentityHandle::commit_removals`].
+    ProposalDeferred(BTreeSet<String>),
     None,
 }
 
 pub trait HandshakeListener {
     fn on_message(&self, message: HandshakeMessage);
+    /// A relay of the service changed state
+    fn on_relay_health(&self, _health: RelayHealth) {}
+}
+
+pub trait KeyPackageListener {
+    /// Kind 443 events the relays returned for `pubkey`, unchecked
+    fn on_key_packages(&self, pubkey: String, events: Vec<String>);
 }
 
 pub trait NostrService {
     fn connect(&self, params: HandshakeConnectParams, listener: Box<dyn HandshakeListener>);
     fn send(&self, payload: HandshakeMessage);
+    /// Publish a signed kind 443 key package to the relays in use
+    fn publish_key_package(&self, event_json: String);
+    /// Publish a signed kind 5 deletion of our key packages to the relays
+    /// in use
+    fn delete_key_packages(&self, deletion_json: String);
+    /// Look up the key packages `pubkey` published; the listener is called
+    /// once, with an empty list if the relays had none
+    fn fetch_key_packages(&self, pubkey: &str, listener: Box<dyn KeyPackageListener>);
     fn shutdown(&self);
 }
 
 pub struct HandshakeConnectParams {
     pub url: String,
+    /// Relays to use alongside `url`
+    pub relays: Vec<String>,
     pub session: String,
     pub role: SessionRole,
-    pub secret_hex: String,
+    pub secret_hex: SecretString,
 }
 
 #[derive(Debug, Clone)]
 pub struct HandshakeMessage {
     pub message_type: HandshakeMessageType,
+    /// Hex pubkey of the other side: the recipient when sending, the
+    /// verified sender when received
+    pub peer: String,
     pub data: HandshakeMessageBody,
 }
 
@@ -507,13 +1976,17 @@ pub enum HandshakeMessageBody {
     },
     KeyPackage {
         event: String,
-        bundle: Option<String>,
         pubkey: Option<String>,
     },
     Welcome {
+        /// Kind 444 rumor from MDK
         welcome: String,
-        group_id_hex: Option<String>,
         recipient: Option<String>,
+        /// Inbox relays from the recipient's key package; the welcome is
+        /// published there as well as on the relays in use
+        relays: Vec<String>,
+        /// Gift wrap the welcome arrived in; `None` when sending
+        wrap_id: Option<EventId>,
     },
 }
 
@@ -534,6 +2007,7 @@ pub trait MoqService {
         listener: Box<dyn MoqListener>,
     );
     fn subscribe_to_peer(&self, peer_pubkey: &str);
+    fn unsubscribe_from_peer(&self, peer_pubkey: &str);
     fn publish_wrapper(&self, bytes: &[u8]);
     fn shutdown(&self);
 }
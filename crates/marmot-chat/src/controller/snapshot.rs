@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use mdk_storage_traits::groups::types::Group;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::cipher_suite::CipherSuite;
+use crate::secret::SecretString;
+
+use super::events::SessionParams;
+
+/// Leading bytes of every sealed snapshot
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"MCSS";
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const SNAPSHOT_SUITE: CipherSuite = CipherSuite::ChaCha20Poly1305;
+/// Limits on the Argon2 costs an untrusted header may ask for: 1 GiB of
+/// memory, 16 passes and 16 lanes, well above what [`seal`] writes
+const MAX_M_COST_KIB: u32 = 1 << 20;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+/// Everything needed to resume a participant on another device
+///
+/// Sealed with [`seal`] before it leaves the process; the plaintext form
+/// only exists in memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// Nostr secret key in hex
+    pub secret_hex: SecretString,
+    #[serde(default)]
+    pub group_id_hex: Option<String>,
+    /// Base64 key package bundles that have not been consumed by a welcome
+    #[serde(default)]
+    pub key_packages: Vec<String>,
+    /// MLS group state from a persistent store
+    #[serde(default)]
+    pub storage: Option<StorageImage>,
+    /// MLS group state of any other store, read through its storage traits
+    #[serde(default)]
+    pub memory: Option<MemoryImage>,
+    /// MoQ root pinned per group, by group id hex
+    #[serde(default)]
+    pub group_roots: BTreeMap<String, String>,
+    /// Session parameters, including the admin set
+    #[serde(default)]
+    pub session: Option<SessionParams>,
+}
+
+/// Raw image of a SQLite-backed MDK store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageImage {
+    /// Base64 database file, taken with `VACUUM INTO` so it is consistent
+    /// on its own
+    pub database: SecretString,
+    /// Base64 write-ahead log; only snapshots that copied the database
+    /// files carry one, and it is still restored on import
+    #[serde(default)]
+    pub wal: Option<SecretString>,
+}
+
+/// Groups of an MDK store without a database image, read through the
+/// OpenMLS and MDK storage traits
+///
+/// Messages and welcomes already processed are not kept; the groups resume
+/// without them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryImage {
+    #[serde(default)]
+    pub groups: Vec<GroupImage>,
+    /// JSON signing key pairs of the groups and of unused key packages
+    #[serde(default)]
+    pub signature_keys: Vec<SecretString>,
+}
+
+/// MDK's record of a group, with what it needs to keep decrypting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupImage {
+    pub group: Group,
+    #[serde(default)]
+    pub relays: Vec<String>,
+    /// Exporter secrets of the latest epochs, for the outer message layer
+    #[serde(default)]
+    pub exporter_secrets: Vec<ExporterSecretImage>,
+    /// OpenMLS group records as stored JSON, by their `StorageProvider`
+    /// name
+    pub mls: BTreeMap<String, SecretString>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExporterSecretImage {
+    pub epoch: u64,
+    /// Hex secret
+    pub secret: SecretString,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    UnsupportedVersion(u8),
+    Malformed(String),
+    /// Wrong passphrase, or the snapshot was modified
+    Decrypt,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported state snapshot version {version}")
+            }
+            SnapshotError::Malformed(reason) => write!(f, "malformed state snapshot: {reason}"),
+            SnapshotError::Decrypt => {
+                write!(f, "wrong passphrase or corrupted state snapshot")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Argon2id cost parameters stored in the snapshot header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Reject costs that would let a crafted header pin the CPU or memory
+    fn check_limits(&self) -> Result<(), SnapshotError> {
+        let limits = [
+            ("memory cost", self.m_cost, MAX_M_COST_KIB),
+            ("time cost", self.t_cost, MAX_T_COST),
+            ("parallelism", self.p_cost, MAX_P_COST),
+        ];
+        for (name, value, limit) in limits {
+            if value > limit {
+                return Err(SnapshotError::Malformed(format!(
+                    "Argon2 {name} {value} exceeds the limit of {limit}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn derive_key(&self, passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|e| anyhow!("invalid Argon2 parameters: {e}"))?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+            .map_err(|e| anyhow!("derive snapshot key: {e}"))?;
+        Ok(key)
+    }
+}
+
+/// Encrypt a snapshot under a passphrase
+///
+/// ```text
+/// magic(4) | version(1) | m_cost(4) | t_cost(4) | p_cost(4) | salt(16) | nonce(12) | ciphertext
+/// ```
+///
+/// The key comes from Argon2id over the passphrase and salt; the payload is
+/// ChaCha20-Poly1305 with the whole header as associated data.
+pub fn seal(snapshot: &StateSnapshot, passphrase: &str) -> Result<Vec<u8>> {
+    seal_with(snapshot, passphrase, KdfParams::default())
+}
+
+pub(crate) fn seal_with(
+    snapshot: &StateSnapshot,
+    passphrase: &str,
+    kdf: KdfParams,
+) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = vec![0u8; SNAPSHOT_SUITE.nonce_len()];
+    getrandom::getrandom(&mut salt).map_err(|e| anyhow!("snapshot salt: {e}"))?;
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("snapshot nonce: {e}"))?;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(SNAPSHOT_MAGIC);
+    out.push(SNAPSHOT_VERSION);
+    out.extend_from_slice(&kdf.m_cost.to_be_bytes());
+    out.extend_from_slice(&kdf.t_cost.to_be_bytes());
+    out.extend_from_slice(&kdf.p_cost.to_be_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+
+    let key = kdf.derive_key(passphrase, &salt)?;
+    let plaintext =
+        Zeroizing::new(serde_json::to_vec(snapshot).context("serialize state snapshot")?);
+    let ciphertext = SNAPSHOT_SUITE.seal(key.as_ref(), &nonce, &plaintext, &out)?;
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a snapshot produced by [`seal`]
+pub fn open(bytes: &[u8], passphrase: &str) -> Result<StateSnapshot> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::Malformed("not a state snapshot".to_string()).into());
+    }
+    let version = reader.take(1)?[0];
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version).into());
+    }
+    let kdf = KdfParams {
+        m_cost: reader.u32()?,
+        t_cost: reader.u32()?,
+        p_cost: reader.u32()?,
+    };
+    kdf.check_limits()?;
+    let salt = reader.take(SALT_LEN)?;
+    let nonce = reader.take(SNAPSHOT_SUITE.nonce_len())?;
+    let (header, ciphertext) = bytes.split_at(reader.offset);
+
+    let key = kdf.derive_key(passphrase, salt)?;
+    let plaintext = Zeroizing::new(
+        SNAPSHOT_SUITE
+            .open(key.as_ref(), nonce, ciphertext, header)
+            .map_err(|_| SnapshotError::Decrypt)?,
+    );
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| SnapshotError::Malformed(format!("invalid payload: {e}")).into())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let slice = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| SnapshotError::Malformed("truncated header".to_string()))?;
+        self.offset += len;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters so tests do not spend seconds in Argon2
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn sample() -> StateSnapshot {
+        StateSnapshot {
+            secret_hex: SecretString::from("11".repeat(32)),
+            group_id_hex: Some("abcd".to_string()),
+            key_packages: vec!["YnVuZGxl".to_string()],
+            storage: None,
+            memory: None,
+            group_roots: BTreeMap::new(),
+            session: None,
+        }
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let sealed = seal_with(&sample(), "hunter2", TEST_KDF).unwrap();
+        assert!(sealed.starts_with(SNAPSHOT_MAGIC));
+        let opened = open(&sealed, "hunter2").unwrap();
+        assert_eq!(opened.secret_hex, sample().secret_hex);
+        assert_eq!(opened.group_id_hex.as_deref(), Some("abcd"));
+        assert_eq!(opened.key_packages, sample().key_packages);
+    }
+
+    #[test]
+    fn test_wrong_passphrase_rejected() {
+        let sealed = seal_with(&sample(), "hunter2", TEST_KDF).unwrap();
+        let err = open(&sealed, "hunter3").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(&SnapshotError::Decrypt)
+        );
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let mut sealed = seal_with(&sample(), "hunter2", TEST_KDF).unwrap();
+        // Flip a salt byte: the derived key changes and the tag no longer matches
+        sealed[SNAPSHOT_MAGIC.len() + 1 + 12] ^= 1;
+        let err = open(&sealed, "hunter2").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SnapshotError>(),
+            Some(&SnapshotError::Decrypt)
+        );
+    }
+
+    #[test]
+    fn test_oversized_kdf_costs_rejected() {
+        let sealed = seal_with(&sample(), "hunter2", TEST_KDF).unwrap();
+        // m_cost, t_cost and p_cost follow the magic and version byte
+        for field in 0..3 {
+            let mut crafted = sealed.clone();
+            let at = SNAPSHOT_MAGIC.len() + 1 + field * 4;
+            crafted[at..at + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+            assert!(matches!(
+                open(&crafted, "hunter2")
+                    .unwrap_err()
+                    .downcast_ref::<SnapshotError>(),
+                Some(SnapshotError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_version_and_truncation_rejected() {
+        let mut sealed = seal_with(&sample(), "hunter2", TEST_KDF).unwrap();
+        assert!(matches!(
+            open(&sealed[..10], "hunter2")
+                .unwrap_err()
+                .downcast_ref::<SnapshotError>(),
+            Some(SnapshotError::Malformed(_))
+        ));
+        sealed[SNAPSHOT_MAGIC.len()] = 2;
+        assert_eq!(
+            open(&sealed, "hunter2")
+                .unwrap_err()
+                .downcast_ref::<SnapshotError>(),
+            Some(&SnapshotError::UnsupportedVersion(2))
+        );
+    }
+}
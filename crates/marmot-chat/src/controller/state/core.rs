@@ -1,11 +1,17 @@
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use anyhow::Result;
+use log::warn;
+use mdk_storage_traits::MdkStorageProvider;
 
 use crate::controller::events::{ChatEvent, HandshakePhase, SessionRole};
+use crate::media_crypto::EpochWatch;
 
 use super::types::{ControllerConfig, ControllerState, HandshakeState};
 
-impl ControllerState {
-    pub fn new(config: ControllerConfig) -> Self {
+impl<S: MdkStorageProvider> ControllerState<S> {
+    pub fn new(config: ControllerConfig<S>) -> Self {
         let role = config.session.bootstrap_role;
         let handshake = match role {
             SessionRole::Initial => HandshakeState::WaitingForKeyPackage,
@@ -27,11 +33,22 @@ impl ControllerState {
             ready: false,
             outgoing_queue: VecDeque::new(),
             pending_incoming: VecDeque::new(),
+            seen_wrappers: VecDeque::new(),
             key_package_cache: None,
             welcome_json: None,
             admin_pubkeys,
             pending_invites: BTreeMap::new(),
+            announced_welcomes: BTreeSet::new(),
             subscribed_peers: BTreeSet::new(),
+            epoch_watch: EpochWatch::default(),
+            media_keys: RefCell::new(HashMap::new()),
+            track_labels: RefCell::new(VecDeque::new()),
+            root_migration: None,
+            group_root: RefCell::new(None),
+            left_group: false,
+            leave_requested: None,
+            pending_leaves: BTreeMap::new(),
+            group_metadata: None,
         }
     }
 
@@ -43,6 +60,28 @@ impl ControllerState {
         (self.callback)(ChatEvent::Handshake { phase });
     }
 
+    /// Publish the group's current MLS epoch to outstanding media sessions,
+    /// rotate their key rings onto it and retain its track labels
+    pub(super) fn refresh_epoch_watch(&self) {
+        self.group_root.take();
+        match self.identity.current_epoch() {
+            Ok(epoch) => {
+                self.epoch_watch.set(epoch);
+                self.rotate_media_keys(epoch);
+                self.retain_track_labels(epoch);
+            }
+            Err(err) => warn!("Failed to read epoch for media sessions: {err:#}"),
+        }
+    }
+
+    /// Seal the identity and session, carrying the live admin set and group
+    pub fn export_state(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let mut session = self.session.clone();
+        session.admin_pubkeys = self.admin_pubkeys.iter().cloned().collect();
+        session.group_id_hex = self.identity.group_id_hex();
+        self.identity.export_state(passphrase, Some(&session))
+    }
+
     pub fn handshake_phase(&self) -> HandshakePhase {
         match self.handshake {
             HandshakeState::WaitingForKeyPackage => HandshakePhase::WaitingForKeyPackage,
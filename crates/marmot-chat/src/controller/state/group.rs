@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::UnboundedSender;
+use log::{info, warn};
+use mdk_storage_traits::MdkStorageProvider;
+
+use crate::controller::events::ChatEvent;
+use crate::group_image::{self, GroupImageRef};
+use crate::messages::WrapperFrame;
+
+use super::types::{ControllerState, Operation};
+use super::utils::schedule;
+
+impl<S: MdkStorageProvider> ControllerState<S> {
+    pub fn update_group_metadata(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> Result<()> {
+        let name = name.map(|name| name.trim().to_string());
+        if name.as_deref() == Some("") {
+            return Err(anyhow!("group name required"));
+        }
+        if name.is_none() && description.is_none() {
+            return Ok(());
+        }
+        self.ensure_metadata_admin()?;
+        info!("controller: updating group metadata");
+        let frame = self.identity.update_group_profile(name, description)?;
+        self.publish_metadata_commit(tx, frame)
+    }
+
+    pub fn set_group_image(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        image: Option<GroupImageRef>,
+    ) -> Result<()> {
+        self.ensure_metadata_admin()?;
+        info!(
+            "controller: setting group image {}",
+            image
+                .as_ref()
+                .map(|image| image.hash_hex())
+                .unwrap_or_else(|| "(none)".to_string())
+        );
+        let frame = self.identity.set_group_image(image.as_ref())?;
+        self.publish_metadata_commit(tx, frame)
+    }
+
+    /// Decrypt a downloaded group image against the current group context
+    pub fn decrypt_group_image(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let image = self
+            .identity
+            .group_metadata()?
+            .image
+            .ok_or_else(|| anyhow!("group has no image"))?;
+        group_image::decrypt_group_image(ciphertext, &image)
+    }
+
+    /// Emit `GroupMetadata` when the group context differs from what we last saw
+    pub(super) fn sync_group_metadata(&mut self) {
+        let metadata = match self.identity.group_metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                warn!("Failed to read group metadata: {err:#}");
+                return;
+            }
+        };
+        if self.group_metadata.as_ref() == Some(&metadata) {
+            return;
+        }
+        (self.callback)(ChatEvent::GroupMetadata {
+            name: metadata.name.clone(),
+            description: metadata.description.clone(),
+            image_hash: metadata.image.as_ref().map(GroupImageRef::hash_hex),
+        });
+        self.group_metadata = Some(metadata);
+    }
+
+    /// Also checked up front by [`ChatController::set_group_image`], so a
+    /// non-admin learns before the image is encrypted
+    ///
+    /// [`ChatController::set_group_image`]: crate::controller::ChatController::set_group_image
+    pub(crate) fn ensure_metadata_admin(&self) -> Result<()> {
+        if !self.admin_pubkeys.contains(&self.identity.public_key_hex()) {
+            return Err(anyhow!("only admins can edit group metadata"));
+        }
+        Ok(())
+    }
+
+    fn publish_metadata_commit(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        frame: WrapperFrame,
+    ) -> Result<()> {
+        schedule(tx, Operation::PublishWrapper(frame.bytes));
+        self.commits += 1;
+        self.refresh_epoch_watch();
+        self.sync_members_from_identity()?;
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::Commit {
+                total: self.commits,
+            }),
+        );
+        Ok(())
+    }
+}
@@ -1,19 +1,21 @@
 use anyhow::{anyhow, Context, Result};
 use futures::channel::mpsc::UnboundedSender;
-use log::{debug, info};
+use log::{debug, info, warn};
 
+use mdk_storage_traits::MdkStorageProvider;
 use nostr::prelude::*;
 
-use crate::controller::events::{ChatEvent, HandshakePhase, SessionRole};
+use crate::controller::events::{ChatEvent, HandshakePhase, PendingWelcome, SessionRole};
+use crate::controller::key_packages;
 use crate::controller::services::{
-    GroupArtifacts, HandshakeConnectParams, HandshakeListener, HandshakeMessage,
-    HandshakeMessageBody, HandshakeMessageType, KeyPackageExport,
+    GroupArtifacts, GroupProfile, HandshakeConnectParams, HandshakeListener, HandshakeMessage,
+    HandshakeMessageBody, HandshakeMessageType, KeyPackageOptions,
 };
 
 use super::types::{ControllerState, HandshakeState, Operation, PendingInvite};
-use super::utils::{relay_relays_url, schedule, short_key};
+use super::utils::{now_timestamp, schedule, short_key};
 
-impl ControllerState {
+impl<S: MdkStorageProvider> ControllerState<S> {
     pub fn request_invite(
         &mut self,
         tx: &UnboundedSender<Operation>,
@@ -68,34 +70,82 @@ impl ControllerState {
             self.pending_invites.keys().collect::<Vec<_>>()
         );
 
-        if is_admin {
-            self.update_member_admin(&pubkey, true);
-        }
+        self.emit_status(format!("Looking up key package for {}", short_key(&pubkey)));
 
-        self.emit_status(format!(
-            "Requesting key package from {}",
-            short_key(&pubkey)
-        ));
+        // Published key packages let us add people who are offline; the
+        // direct request is the fallback if they have none
+        schedule(tx, Operation::FetchKeyPackages(pubkey.clone()));
+
+        info!(
+            "controller: request_invite queued lookup pubkey={} is_admin={} pending_invites={}",
+            pubkey,
+            is_admin,
+            self.pending_invites.len()
+        );
+
+        Ok(())
+    }
 
+    /// Ask `pubkey` for a key package over the handshake channel
+    fn request_key_package(&self, tx: &UnboundedSender<Operation>, pubkey: &str) {
+        let is_admin = self
+            .pending_invites
+            .get(pubkey)
+            .map(|invite| invite.is_admin);
+        self.emit_status(format!("Requesting key package from {}", short_key(pubkey)));
         schedule(
             tx,
             Operation::OutgoingHandshake(HandshakeMessage {
                 message_type: HandshakeMessageType::RequestKeyPackage,
+                peer: pubkey.to_string(),
                 data: HandshakeMessageBody::Request {
-                    pubkey: Some(pubkey.clone()),
-                    is_admin: Some(is_admin),
+                    pubkey: Some(pubkey.to_string()),
+                    is_admin,
                 },
             }),
         );
+    }
+
+    /// Use the newest valid published key package of `pubkey`, or fall
+    /// back to asking for one
+    pub fn on_key_packages_fetched(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        pubkey: String,
+        events: Vec<String>,
+    ) -> Result<()> {
+        let established = self.handshake == HandshakeState::Established;
+        let wanted = self.pending_invites.contains_key(&pubkey)
+            && (established || self.session.bootstrap_role == SessionRole::Initial);
+        if !wanted {
+            debug!(
+                "controller: key package lookup for {} no longer needed",
+                short_key(&pubkey)
+            );
+            return Ok(());
+        }
 
+        let Some(event) = self
+            .identity
+            .newest_key_package(&pubkey, &events, now_timestamp())
+        else {
+            info!(
+                "controller: no usable published key package for {} among {}",
+                short_key(&pubkey),
+                events.len()
+            );
+            self.request_key_package(tx, &pubkey);
+            return Ok(());
+        };
         info!(
-            "controller: request_invite queued handshake pubkey={} is_admin={} pending_invites={}",
-            pubkey,
-            is_admin,
-            self.pending_invites.len()
+            "controller: using published key package for {}",
+            short_key(&pubkey)
         );
-
-        Ok(())
+        if established {
+            self.handle_member_addition(tx, pubkey, event)
+        } else {
+            self.create_group_for(tx, pubkey, event)
+        }
     }
 
     fn handle_member_addition(
@@ -103,7 +153,6 @@ impl ControllerState {
         tx: &UnboundedSender<Operation>,
         invitee_pub: String,
         event_json: String,
-        _bundle: Option<String>,
     ) -> Result<()> {
         info!(
             "controller: handle_member_addition pubkey={} pending_admin={} handshake_state={:?}",
@@ -136,29 +185,32 @@ impl ControllerState {
         );
 
         self.commits += 1;
+        self.refresh_epoch_watch();
 
         schedule(
             tx,
             Operation::PublishWrapper(artifacts.commit.bytes.clone()),
         );
 
-        let group_hex = self.identity.group_id_hex().unwrap_or_default();
+        let relays = key_packages::inbox_relays(&event_json);
         for welcome in artifacts.welcomes {
             schedule(
                 tx,
                 Operation::OutgoingHandshake(HandshakeMessage {
                     message_type: HandshakeMessageType::Welcome,
+                    peer: welcome.recipient.clone(),
                     data: HandshakeMessageBody::Welcome {
                         welcome: welcome.welcome.clone(),
-                        group_id_hex: Some(group_hex.clone()),
                         recipient: Some(welcome.recipient.clone()),
+                        relays: relays.clone(),
+                        wrap_id: None,
                     },
                 }),
             );
             (self.callback)(ChatEvent::InviteGenerated {
                 welcome: welcome.welcome,
                 recipient: welcome.recipient.clone(),
-                is_admin: self.admin_pubkeys.contains(&welcome.recipient),
+                is_admin: requested_admin && welcome.recipient == invitee_pub,
             });
         }
 
@@ -167,9 +219,10 @@ impl ControllerState {
         // Sync members from MDK to update roster and subscribe to new peer's MoQ track
         self.sync_members_from_identity()?;
 
-        // Apply admin status after member is created
+        // Promote in a commit of its own once the add went through, so a
+        // failed add never leaves an admin who is not a member
         if requested_admin {
-            self.update_member_admin(&invitee_pub, true);
+            self.set_member_admin(tx, &invitee_pub, true)?;
         }
 
         self.flush_pending_incoming(tx)?;
@@ -177,6 +230,59 @@ impl ControllerState {
         Ok(())
     }
 
+    /// Create the group with `invitee_pub` as its first member
+    fn create_group_for(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        invitee_pub: String,
+        event_json: String,
+    ) -> Result<()> {
+        let mut profile = GroupProfile::default();
+        if let Some(name) = &self.session.group_name {
+            profile.name = name.clone();
+        }
+        if let Some(description) = &self.session.group_description {
+            profile.description = description.clone();
+        }
+        let GroupArtifacts { welcome, .. } = self
+            .identity
+            .create_group_with_profile(
+                &event_json,
+                &invitee_pub,
+                &self.session.admin_pubkeys,
+                &profile,
+            )
+            .map_err(|err| anyhow!("create_group failed: {err}"))?;
+        self.pending_invites.remove(&invitee_pub);
+        self.emit_status("Group created; sending welcome…");
+        self.handshake = HandshakeState::Established;
+        self.refresh_epoch_watch();
+        self.emit_handshake_phase(HandshakePhase::Finalizing);
+        // Pick the MoQ root before the welcome goes out, so it can name it
+        self.select_moq_root()?;
+        let welcome = self.identity.pinned_welcome(&welcome)?;
+        self.welcome_json = Some(welcome.clone());
+        schedule(
+            tx,
+            Operation::OutgoingHandshake(HandshakeMessage {
+                message_type: HandshakeMessageType::Welcome,
+                peer: invitee_pub.clone(),
+                data: HandshakeMessageBody::Welcome {
+                    welcome: welcome.clone(),
+                    recipient: Some(invitee_pub.clone()),
+                    relays: key_packages::inbox_relays(&event_json),
+                    wrap_id: None,
+                },
+            }),
+        );
+        schedule(tx, Operation::ConnectMoq);
+        let self_pub = self.identity.public_key_hex();
+        self.notify_new_member(&self_pub);
+        self.sync_members_from_identity()?;
+        self.flush_pending_incoming(tx)?;
+        Ok(())
+    }
+
     pub fn on_start(
         &mut self,
         tx: &UnboundedSender<Operation>,
@@ -185,33 +291,73 @@ impl ControllerState {
         self.emit_status("Connecting handshake relay…");
         let params = HandshakeConnectParams {
             url: self.session.nostr_url.clone(),
+            relays: self.session.nostr_relays.clone(),
             session: self.session.session_id.clone(),
             role: self.session.bootstrap_role,
             secret_hex: self.session.secret_hex.clone(),
         };
         self.nostr.connect(params, listener);
+
+        // A group already in storage picks up at its stored epoch, without
+        // another handshake
+        if self.restore_stored_group()? {
+            return self.resume_group(tx);
+        }
         self.emit_handshake_phase(self.handshake_phase());
 
         match self.session.bootstrap_role {
-            SessionRole::Initial => {
-                self.emit_status("Requesting key package…");
-                schedule(
-                    tx,
-                    Operation::OutgoingHandshake(HandshakeMessage {
-                        message_type: HandshakeMessageType::RequestKeyPackage,
-                        data: HandshakeMessageBody::Request {
-                            pubkey: self.session.peer_pubkeys.first().cloned(),
-                            is_admin: None,
-                        },
-                    }),
-                );
-            }
+            SessionRole::Initial => match self.session.peer_pubkeys.first().cloned() {
+                Some(peer) => {
+                    // Only key packages from invitees we named are taken
+                    let is_admin = self.session.admin_pubkeys.contains(&peer);
+                    self.pending_invites
+                        .insert(peer.clone(), PendingInvite { is_admin });
+                    self.emit_status("Looking up key package…");
+                    schedule(tx, Operation::FetchKeyPackages(peer));
+                }
+                None => self.emit_status("Invite someone to start the group"),
+            },
             SessionRole::Invitee => {
                 self.emit_status("Generating key package…");
-                let relays = vec![relay_relays_url(&self.session.relay_url)];
-                let export = self.identity.create_key_package(&relays)?;
+                let now = now_timestamp();
+                let pruned = self.identity.prune_expired_key_packages(now)?;
+                if pruned > 0 {
+                    debug!("controller: dropped {pruned} expired key packages");
+                }
+                // The `relays` tag tells inviters where to leave our welcome
+                let mut relays = vec![self.session.nostr_url.clone()];
+                relays.extend(self.session.nostr_relays.iter().cloned());
+                let export = self
+                    .identity
+                    .create_key_package_with(&relays, &KeyPackageOptions::published(now))?;
+                // Published so later invites work while we are offline, as
+                // long as its private half outlives this session
+                if self.identity.publishes_key_packages() {
+                    self.nostr.publish_key_package(export.event_json.clone());
+                }
+                self.retract_retired_key_packages();
+                // Handshakes are addressed, so offer the key package to the
+                // inviter up front rather than waiting to be asked
+                if let Some(inviter) = self.session.peer_pubkeys.first() {
+                    schedule(
+                        tx,
+                        Operation::OutgoingHandshake(HandshakeMessage {
+                            message_type: HandshakeMessageType::KeyPackage,
+                            peer: inviter.clone(),
+                            data: HandshakeMessageBody::KeyPackage {
+                                event: export.event_json.clone(),
+                                pubkey: Some(self.identity.public_key_hex()),
+                            },
+                        }),
+                    );
+                }
                 self.key_package_cache = Some(export);
                 self.emit_handshake_phase(HandshakePhase::WaitingForWelcome);
+                // Welcomes held from earlier runs; new ones come in from
+                // the relays as stored gift wraps
+                for welcome in self.identity.pending_welcomes()? {
+                    self.announce_welcome(welcome);
+                }
             }
         }
 
@@ -236,82 +382,62 @@ impl ControllerState {
     ) -> Result<()> {
         match message.message_type {
             HandshakeMessageType::KeyPackage => {
-                let (event, bundle, pubkey) = match message.data {
-                    HandshakeMessageBody::KeyPackage {
-                        event,
-                        bundle,
-                        pubkey,
-                    } => (event, bundle, pubkey),
+                let event = match message.data {
+                    HandshakeMessageBody::KeyPackage { event, .. } => event,
                     _ => return Err(anyhow!("missing key package payload")),
                 };
-                let invitee_pub =
-                    match pubkey.or_else(|| self.session.peer_pubkeys.first().cloned()) {
-                        Some(key) => key,
-                        None => return Err(anyhow!("invitee pubkey missing")),
-                    };
-
-                if self.handshake == HandshakeState::Established {
-                    return self.handle_member_addition(tx, invitee_pub, event, bundle);
+                // The seal author, not the self-reported pubkey, is who we add
+                let invitee_pub = message.peer;
+
+                // Only key packages we asked for, so repeats, strangers and
+                // unsolicited offers from members are dropped
+                if !self.pending_invites.contains_key(&invitee_pub) {
+                    debug!(
+                        "controller: ignoring unrequested key package from {}",
+                        short_key(&invitee_pub)
+                    );
+                    return Ok(());
                 }
+                // It has to be signed by the invitee and still valid
+                let Some(event) = self.identity.newest_key_package(
+                    &invitee_pub,
+                    std::slice::from_ref(&event),
+                    now_timestamp(),
+                ) else {
+                    log::warn!(
+                        "controller: rejecting key package from {}: not theirs or no longer valid",
+                        short_key(&invitee_pub)
+                    );
+                    return Ok(());
+                };
 
-                if self.admin_pubkeys.contains(&invitee_pub) {
-                    self.update_member_admin(&invitee_pub, true);
+                if self.handshake == HandshakeState::Established {
+                    return self.handle_member_addition(tx, invitee_pub, event);
                 }
-                self.key_package_cache = Some(KeyPackageExport {
-                    event_json: event.clone(),
-                    bundle: bundle.clone().unwrap_or_default(),
-                });
-                let GroupArtifacts {
-                    group_id_hex,
-                    welcome,
-                } = self
-                    .identity
-                    .create_group(&event, &invitee_pub, &self.session.admin_pubkeys)
-                    .map_err(|err| anyhow!("create_group failed: {err}"))?;
-                self.welcome_json = Some(welcome.clone());
-                self.emit_status("Group created; sending welcome…");
-                schedule(
-                    tx,
-                    Operation::OutgoingHandshake(HandshakeMessage {
-                        message_type: HandshakeMessageType::Welcome,
-                        data: HandshakeMessageBody::Welcome {
-                            welcome: welcome.clone(),
-                            group_id_hex: Some(group_id_hex.clone()),
-                            recipient: Some(invitee_pub.clone()),
-                        },
-                    }),
-                );
-                self.handshake = HandshakeState::Established;
-                self.emit_handshake_phase(HandshakePhase::Finalizing);
-                // Derive MLS-based MoQ root path and store in session
-                let moq_root = self
-                    .identity
-                    .derive_group_root()
-                    .map_err(|err| anyhow!("failed to derive group root: {err}"))?;
-                info!("controller: derived moq_root={}", moq_root);
-                self.session.moq_root = Some(moq_root);
-                schedule(tx, Operation::ConnectMoq);
-                let self_pub = self.identity.public_key_hex();
-                self.notify_new_member(&self_pub);
-                self.sync_members_from_identity()?;
-                self.flush_pending_incoming(tx)?;
-                Ok(())
+                self.create_group_for(tx, invitee_pub, event)
             }
             HandshakeMessageType::RequestWelcome => {
-                let target_pub = match message.data.clone() {
-                    HandshakeMessageBody::Request { pubkey, .. } => pubkey,
-                    _ => None,
-                };
+                // The welcome is only ever for the member it added
+                let member = self
+                    .identity
+                    .list_members()
+                    .map(|members| members.contains(&message.peer))
+                    .unwrap_or(false);
+                if !member {
+                    return Ok(());
+                }
                 if let Some(welcome) = self.welcome_json.clone() {
-                    let group_id_hex = self.identity.group_id_hex().unwrap_or_default();
+                    // They are online and asking, so our own relays will do
                     schedule(
                         tx,
                         Operation::OutgoingHandshake(HandshakeMessage {
                             message_type: HandshakeMessageType::Welcome,
+                            peer: message.peer.clone(),
                             data: HandshakeMessageBody::Welcome {
                                 welcome,
-                                group_id_hex: Some(group_id_hex),
-                                recipient: target_pub,
+                                recipient: Some(message.peer),
+                                relays: Vec::new(),
+                                wrap_id: None,
                             },
                         }),
                     );
@@ -328,61 +454,59 @@ impl ControllerState {
         message: HandshakeMessage,
     ) -> Result<()> {
         match message.message_type {
+            HandshakeMessageType::Welcome if self.handshake == HandshakeState::Established => {
+                debug!("controller: ignoring welcome after joining");
+                Ok(())
+            }
             HandshakeMessageType::Welcome => {
-                let (welcome, group_id_hex) = match message.data {
+                let (welcome, wrap_id) = match message.data {
                     HandshakeMessageBody::Welcome {
                         welcome,
-                        group_id_hex,
                         recipient,
+                        wrap_id,
+                        ..
                     } => {
                         if let Some(recipient) = recipient {
                             if recipient != self.identity.public_key_hex() {
                                 return Ok(());
                             }
                         }
-                        (welcome, group_id_hex)
+                        (welcome, wrap_id)
                     }
                     _ => return Err(anyhow!("missing welcome payload")),
                 };
-                if let Some(export) = self.key_package_cache.clone() {
-                    if !export.bundle.is_empty() {
-                        let _ = self.identity.import_key_package_bundle(&export.bundle);
+                let expected = self.expects_welcome(&message.peer, &welcome);
+                // Relays replay every welcome ever left for us, so ones we
+                // cannot open or already handled are history, not errors
+                let processed = match &wrap_id {
+                    Some(wrap_id) => self.identity.process_wrapped_welcome(wrap_id, &welcome),
+                    None => self.identity.process_welcome(&welcome),
+                };
+                self.retract_retired_key_packages();
+                let pending = match processed {
+                    Ok(Some(pending)) => pending,
+                    Ok(None) => {
+                        debug!("controller: welcome already handled");
+                        return Ok(());
                     }
-                }
-                self.emit_status("Accepting welcome…");
-                let accepted_group = self.identity.accept_welcome(&welcome)?;
-                let self_pub = self.identity.public_key_hex();
-                self.notify_new_member(&self_pub);
-                self.sync_members_from_identity()?;
-
-                if let Some(provided) = group_id_hex {
-                    if provided != accepted_group {
+                    Err(err) => {
                         log::warn!(
-                            "Provided group id {} differs from accepted {}",
-                            provided,
-                            accepted_group
+                            "controller: skipping welcome from {}: {err:#}",
+                            short_key(&message.peer)
                         );
+                        if expected {
+                            self.emit_status("Could not open the welcome; still waiting…");
+                        }
+                        return Ok(());
                     }
+                };
+                if !expected {
+                    self.announce_welcome(pending);
+                    return Ok(());
                 }
-                self.handshake = HandshakeState::Established;
-                self.emit_handshake_phase(HandshakePhase::Finalizing);
-                // Derive MLS-based MoQ root path and store in session
-                let moq_root = self
-                    .identity
-                    .derive_group_root()
-                    .map_err(|err| anyhow!("failed to derive group root: {err}"))?;
-                info!("controller: derived moq_root={}", moq_root);
-                self.session.moq_root = Some(moq_root);
-                schedule(tx, Operation::ConnectMoq);
-                schedule(
-                    tx,
-                    Operation::Emit(ChatEvent::status(format!(
-                        "Joined group {}",
-                        self.identity.group_id_hex().unwrap_or_default()
-                    ))),
-                );
-                self.flush_pending_incoming(tx)?;
-                Ok(())
+                self.emit_status("Accepting welcome…");
+                self.identity.accept_pending_welcome(&pending.welcome_id)?;
+                self.finish_join(tx)
             }
             HandshakeMessageType::RequestKeyPackage => {
                 let target_pub = match message.data.clone() {
@@ -399,9 +523,9 @@ impl ControllerState {
                         tx,
                         Operation::OutgoingHandshake(HandshakeMessage {
                             message_type: HandshakeMessageType::KeyPackage,
+                            peer: message.peer.clone(),
                             data: HandshakeMessageBody::KeyPackage {
                                 event: export.event_json.clone(),
-                                bundle: Some(export.bundle.clone()),
                                 pubkey: Some(self.identity.public_key_hex()),
                             },
                         }),
@@ -412,4 +536,132 @@ impl ControllerState {
             _ => Ok(()),
         }
     }
+
+    /// Join the group of a pending welcome the user picked
+    pub fn accept_pending_welcome(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        welcome_id: &str,
+    ) -> Result<()> {
+        if self.handshake == HandshakeState::Established {
+            return Err(anyhow!("session already joined a group"));
+        }
+        self.emit_status("Accepting welcome…");
+        self.identity.accept_pending_welcome(welcome_id)?;
+        self.finish_join(tx)
+    }
+
+    /// Whether a welcome answers this session's own handshake: it comes
+    /// from the inviter the session names or uses the key package we made
+    fn expects_welcome(&self, sender: &str, welcome_json: &str) -> bool {
+        if self.session.peer_pubkeys.iter().any(|peer| peer == sender) {
+            return true;
+        }
+        let Some(export) = &self.key_package_cache else {
+            return false;
+        };
+        match (
+            UnsignedEvent::from_json(welcome_json),
+            Event::from_json(&export.event_json),
+        ) {
+            (Ok(rumor), Ok(offered)) => rumor.tags.event_ids().any(|id| *id == offered.id),
+            _ => false,
+        }
+    }
+
+    /// Withdraw the key packages the identity replaced, let expire or used
+    /// up from the relays
+    fn retract_retired_key_packages(&self) {
+        match self.identity.key_package_deletion() {
+            Ok(Some(deletion)) if self.identity.publishes_key_packages() => {
+                self.nostr.delete_key_packages(deletion);
+            }
+            Ok(_) => {}
+            Err(err) => log::warn!("controller: could not withdraw key packages: {err:#}"),
+        }
+    }
+
+    /// Tell the UI about a welcome it can accept, once per welcome
+    fn announce_welcome(&mut self, welcome: PendingWelcome) {
+        if !self.announced_welcomes.insert(welcome.welcome_id.clone()) {
+            return;
+        }
+        info!(
+            "controller: welcome to {} from {} held for the user",
+            welcome.group_id_hex,
+            short_key(&welcome.welcomer)
+        );
+        (self.callback)(ChatEvent::WelcomePending { welcome });
+    }
+
+    /// Scope the identity to the session's group if storage holds it
+    ///
+    /// The group comes from the identity handle (a hub session scoped to a
+    /// stored group) or the session's `group_id_hex` (a resumed snapshot or
+    /// a restart over a persistent store).
+    fn restore_stored_group(&self) -> Result<bool> {
+        let wanted = self
+            .identity
+            .group_id_hex()
+            .or_else(|| self.session.group_id_hex.clone());
+        let Some(group_id_hex) = wanted else {
+            return Ok(false);
+        };
+        if self.identity.restore_group(&group_id_hex)? {
+            return Ok(true);
+        }
+        warn!("controller: group {group_id_hex} is not in storage; running the handshake");
+        Ok(false)
+    }
+
+    /// Pick a restored group back up: members, admins, epoch and MoQ root
+    /// all come from the stored MLS state
+    ///
+    /// Frame counters did not survive the restart, so a self-update moves
+    /// the group to an epoch whose media keys nobody used yet; it goes out
+    /// once MoQ connects.
+    fn resume_group(&mut self, tx: &UnboundedSender<Operation>) -> Result<()> {
+        self.sync_members_from_identity()?;
+        self.handshake = HandshakeState::Established;
+        self.refresh_epoch_watch();
+        self.emit_handshake_phase(HandshakePhase::Finalizing);
+        let (commit, events) = self.handle_self_update()?;
+        schedule(tx, Operation::PublishWrapper(commit));
+        for event in events {
+            schedule(tx, Operation::Emit(event));
+        }
+        self.select_moq_root()?;
+        schedule(tx, Operation::ConnectMoq);
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::status(format!(
+                "Resumed group {} at epoch {}",
+                self.identity.group_id_hex().unwrap_or_default(),
+                self.identity.current_epoch()?
+            ))),
+        );
+        Ok(())
+    }
+
+    /// Switch to the group the identity just joined
+    fn finish_join(&mut self, tx: &UnboundedSender<Operation>) -> Result<()> {
+        let self_pub = self.identity.public_key_hex();
+        self.notify_new_member(&self_pub);
+        self.sync_members_from_identity()?;
+        self.handshake = HandshakeState::Established;
+        self.refresh_epoch_watch();
+        self.emit_handshake_phase(HandshakePhase::Finalizing);
+        // Derive MLS-based MoQ root path and store in session
+        self.select_moq_root()?;
+        schedule(tx, Operation::ConnectMoq);
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::status(format!(
+                "Joined group {}",
+                self.identity.group_id_hex().unwrap_or_default()
+            ))),
+        );
+        self.flush_pending_incoming(tx)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,156 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use anyhow::Result;
+use log::warn;
+use mdk_storage_traits::MdkStorageProvider;
+
+use crate::cipher_suite::CipherSuite;
+use crate::controller::services::TrackLabelError;
+use crate::media_crypto::{MediaKeyRing, MediaSession};
+use crate::messages::{TrackEntry, TrackKind};
+
+use super::types::{ControllerState, RetainedTrackLabels};
+
+/// Epochs whose track labels stay verifiable, counting the current one
+pub(super) const RETAINED_LABEL_EPOCHS: usize = 3;
+
+impl<S: MdkStorageProvider> ControllerState<S> {
+    /// Derive a media session for `sender_pubkey`'s `track_label` at the
+    /// current epoch, using the `suite` announced in the track's directory
+    /// entry.
+    ///
+    /// Sessions for the same (sender, track, suite) share one key ring,
+    /// which is rotated after every merged commit so frames from the
+    /// previous epoch still decrypt during the grace period. The controller
+    /// owns the ring, so dropping every session and creating a new one
+    /// within an epoch picks up the same keys rather than starting them
+    /// over. A session under another suite gets a ring (and KID) of its own.
+    pub fn create_media_session(
+        &self,
+        sender_pubkey: &str,
+        track_label: &str,
+        suite: CipherSuite,
+    ) -> Result<MediaSession> {
+        let epoch = self.identity.current_epoch()?;
+        let base_key = self
+            .identity
+            .derive_media_base_key(sender_pubkey, track_label)?;
+        self.epoch_watch.set(epoch);
+
+        let key = (sender_pubkey.to_string(), track_label.to_string(), suite);
+        let mut registry = self.media_keys.borrow_mut();
+        let keys = match registry.get(&key).cloned() {
+            Some(keys) => {
+                keys.borrow_mut().rotate(epoch, base_key);
+                keys
+            }
+            None => {
+                let keys = Rc::new(RefCell::new(MediaKeyRing::with_suite(
+                    epoch, base_key, suite,
+                )));
+                registry.insert(key, keys.clone());
+                keys
+            }
+        };
+        Ok(MediaSession::with_keys(keys, self.epoch_watch.clone()))
+    }
+
+    /// Move the media key rings onto `epoch`
+    ///
+    /// Rings no session holds any more are dropped instead, now that the
+    /// keys they were kept for are superseded.
+    pub(super) fn rotate_media_keys(&self, epoch: u64) {
+        let mut registry = self.media_keys.borrow_mut();
+        registry.retain(|(sender, label, _), keys| {
+            if keys.borrow().current_epoch() >= epoch {
+                return true;
+            }
+            if Rc::strong_count(keys) == 1 {
+                return false;
+            }
+            match self.identity.derive_media_base_key(sender, label) {
+                Ok(base_key) => keys.borrow_mut().rotate(epoch, base_key),
+                Err(err) => warn!("Failed to rotate media key for {label}: {err:#}"),
+            }
+            true
+        });
+    }
+
+    /// Derive every member's track labels at `epoch` and keep them for the
+    /// next few epochs
+    ///
+    /// Labels come from the MLS exporter and can only be derived at the
+    /// current epoch, so this runs whenever the epoch changes.
+    pub(super) fn retain_track_labels(&self, epoch: u64) {
+        let mut retained = self.track_labels.borrow_mut();
+        if retained.back().is_some_and(|labels| labels.epoch == epoch) {
+            return;
+        }
+        let members = match self.identity.list_members() {
+            Ok(members) => members,
+            Err(err) => {
+                warn!("Failed to list members for track labels: {err:#}");
+                return;
+            }
+        };
+        let mut labels = HashMap::new();
+        for member in members {
+            for kind in [TrackKind::Audio, TrackKind::Video, TrackKind::Screen] {
+                match self.identity.derive_track_label(&member, &kind) {
+                    Ok(label) => {
+                        labels.insert((member.clone(), kind.as_str()), label);
+                    }
+                    Err(err) => warn!("Failed to derive {} label: {err:#}", kind.as_str()),
+                }
+            }
+        }
+        retained.push_back(RetainedTrackLabels { epoch, labels });
+        while retained.len() > RETAINED_LABEL_EPOCHS {
+            retained.pop_front();
+        }
+    }
+
+    /// Check a directory entry's label against the one `sender_pubkey` owns
+    /// for its kind at `epoch`
+    ///
+    /// Unlike [`IdentityHandle::verify_track_label`], directories announced
+    /// for one of the last few epochs still verify, so a commit racing a
+    /// directory does not drop its tracks.
+    ///
+    /// [`IdentityHandle::verify_track_label`]: crate::controller::services::IdentityHandle::verify_track_label
+    pub(super) fn verify_track_label(
+        &self,
+        sender_pubkey: &str,
+        epoch: u64,
+        track: &TrackEntry,
+    ) -> Result<()> {
+        let current = self.identity.current_epoch()?;
+        if epoch == current {
+            return self
+                .identity
+                .verify_track_label(sender_pubkey, epoch, track);
+        }
+        let retained = self.track_labels.borrow();
+        let Some(labels) = retained.iter().find(|labels| labels.epoch == epoch) else {
+            return Err(TrackLabelError::EpochMismatch {
+                announced: epoch,
+                current,
+            }
+            .into());
+        };
+        match labels
+            .labels
+            .get(&(sender_pubkey.to_string(), track.kind.as_str()))
+        {
+            Some(expected) if *expected == track.label => Ok(()),
+            Some(expected) => Err(TrackLabelError::LabelMismatch {
+                label: track.label.clone(),
+                expected: expected.clone(),
+            }
+            .into()),
+            None => Err(TrackLabelError::NotMember { epoch }.into()),
+        }
+    }
+}
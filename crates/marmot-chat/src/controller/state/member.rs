@@ -1,12 +1,22 @@
-use anyhow::Result;
+use std::collections::BTreeSet;
+
+use anyhow::{anyhow, Context, Result};
+use futures::channel::mpsc::UnboundedSender;
 use log::{info, warn};
 
+use mdk_storage_traits::MdkStorageProvider;
+use nostr::prelude::*;
+
 use crate::controller::events::{ChatEvent, MemberInfo};
 
-use super::types::ControllerState;
-use super::utils::short_key;
+use super::types::{ControllerState, Operation, PendingLeave};
+use super::utils::{now_timestamp, schedule, short_key};
+
+/// How long each admin in line waits for the one before it to commit a
+/// leave proposal, in seconds
+const LEAVE_COMMIT_TIMEOUT_SECS: u64 = 30;
 
-impl ControllerState {
+impl<S: MdkStorageProvider> ControllerState<S> {
     pub(super) fn emit_roster(&self) {
         let all_members = match self.identity.list_members() {
             Ok(members) => members,
@@ -38,29 +48,284 @@ impl ControllerState {
         self.emit_roster();
     }
 
-    pub(super) fn update_member_admin(&mut self, pubkey: &str, is_admin: bool) {
-        if is_admin {
-            self.admin_pubkeys.insert(pubkey.to_string());
-        } else {
-            self.admin_pubkeys.remove(pubkey);
-        }
-
-        match self.identity.list_members() {
-            Ok(members) => {
-                if members.iter().any(|member| member == pubkey) {
-                    let member = MemberInfo {
-                        pubkey: pubkey.to_string(),
-                        is_admin,
-                    };
-                    (self.callback)(ChatEvent::MemberUpdated {
-                        member: member.clone(),
-                    });
-                    self.emit_roster();
-                }
+    /// Commit the removal of a member; only admins may remove others
+    pub fn remove_member(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        pubkey_input: String,
+    ) -> Result<()> {
+        let trimmed = pubkey_input.trim();
+        if trimmed.is_empty() {
+            return Err(anyhow!("pubkey required"));
+        }
+        let pubkey = PublicKey::from_hex(trimmed)
+            .or_else(|_| PublicKey::from_bech32(trimmed))
+            .context("parse member pubkey")?
+            .to_hex();
+
+        let own_pubkey = self.identity.public_key_hex();
+        if !self.admin_pubkeys.contains(&own_pubkey) {
+            return Err(anyhow!("only admins can remove members"));
+        }
+        if pubkey == own_pubkey {
+            return Err(anyhow!("cannot remove self"));
+        }
+        if !self.identity.list_members()?.contains(&pubkey) {
+            return Err(anyhow!("not a member"));
+        }
+
+        info!("controller: removing member {}", short_key(&pubkey));
+        let frame = self
+            .identity
+            .remove_members(std::slice::from_ref(&pubkey))?;
+        schedule(tx, Operation::PublishWrapper(frame.bytes));
+        self.commits += 1;
+        self.refresh_epoch_watch();
+        self.sync_members_from_identity()?;
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::Commit {
+                total: self.commits,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Publish a proposal removing ourselves
+    ///
+    /// We keep listening until the commit removing us arrives, which is
+    /// when the proposal is known to have reached an admin; tearing down
+    /// earlier could drop it from the outgoing queue. The group's only
+    /// admin first hands admin to the lowest other member, since nobody
+    /// else could commit the proposal.
+    pub fn leave_group(&mut self, tx: &UnboundedSender<Operation>) -> Result<()> {
+        if self.left_group {
+            return Err(anyhow!("already left the group"));
+        }
+        if self.leave_requested.is_some() {
+            return Err(anyhow!("leave already requested"));
+        }
+        let own_pubkey = self.identity.public_key_hex();
+        let members = self.identity.list_members()?;
+        let Some(successor) = members.iter().filter(|pubkey| **pubkey != own_pubkey).min() else {
+            return Err(anyhow!("cannot leave a group we are the only member of"));
+        };
+        let other_admin = members
+            .iter()
+            .any(|pubkey| *pubkey != own_pubkey && self.admin_pubkeys.contains(pubkey));
+        if self.admin_pubkeys.contains(&own_pubkey) && !other_admin {
+            info!(
+                "controller: handing admin to {} before leaving",
+                short_key(successor)
+            );
+            let mut admins = self.identity.group_admins()?;
+            admins.remove(&own_pubkey);
+            admins.insert(successor.clone());
+            self.commit_admins(tx, &admins)?;
+        }
+
+        let frame = self.identity.leave_group()?;
+        schedule(tx, Operation::PublishWrapper(frame.bytes));
+        self.leave_requested = Some(self.identity.current_epoch()?);
+        self.emit_status("Leaving the group; waiting for an admin to commit it");
+        Ok(())
+    }
+
+    /// Note leave proposals another admin is due to commit
+    pub(super) fn defer_leaves(&mut self, removed: BTreeSet<String>) -> Result<()> {
+        let epoch = self.identity.current_epoch()?;
+        let since = now_timestamp();
+        for pubkey in removed {
+            self.pending_leaves
+                .entry(pubkey)
+                .or_insert(PendingLeave { epoch, since });
+        }
+        Ok(())
+    }
+
+    /// Commit leave proposals the admins ahead of us left hanging
+    ///
+    /// Admins line up by pubkey as in `IdentityHandle::proposal_committers`;
+    /// each one takes over [`LEAVE_COMMIT_TIMEOUT_SECS`] after the one
+    /// before it. Runs on incoming traffic, as the controller keeps no
+    /// timers.
+    pub fn commit_overdue_leaves(&mut self, tx: &UnboundedSender<Operation>) -> Result<()> {
+        if self.pending_leaves.is_empty() || self.left_group {
+            return Ok(());
+        }
+        // A commit that moved the epoch dropped the proposals with it
+        let epoch = self.identity.current_epoch()?;
+        let members = self.identity.list_members()?;
+        self.pending_leaves
+            .retain(|pubkey, leave| leave.epoch == epoch && members.contains(pubkey));
+
+        let own_pubkey = self.identity.public_key_hex();
+        let now = now_timestamp();
+        let mut overdue = Vec::new();
+        for (pubkey, leave) in &self.pending_leaves {
+            let removed = BTreeSet::from([pubkey.clone()]);
+            let committers = self.identity.proposal_committers(&removed)?;
+            let Some(position) = committers.iter().position(|admin| *admin == own_pubkey) else {
+                continue;
+            };
+            if now >= leave.since + position as u64 * LEAVE_COMMIT_TIMEOUT_SECS {
+                overdue.push(pubkey.clone());
             }
+        }
+        if overdue.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "controller: committing {} overdue leave proposal(s)",
+            overdue.len()
+        );
+        let frame = self.identity.commit_removals(&overdue)?;
+        schedule(tx, Operation::PublishWrapper(frame.bytes));
+        self.pending_leaves.clear();
+        self.commits += 1;
+        self.refresh_epoch_watch();
+        self.sync_members_from_identity()?;
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::Commit {
+                total: self.commits,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Stop all group traffic once we are no longer a member
+    pub fn finish_leave(&mut self, status: &str) {
+        if self.left_group {
+            return;
+        }
+        self.left_group = true;
+        for peer in std::mem::take(&mut self.subscribed_peers) {
+            self.moq.unsubscribe_from_peer(&peer);
+        }
+        self.media_keys.borrow_mut().clear();
+        self.track_labels.borrow_mut().clear();
+        self.moq.shutdown();
+        let event = self.mark_ready(false);
+        (self.callback)(event);
+        (self.callback)(ChatEvent::MemberLeft {
+            pubkey: self.identity.public_key_hex(),
+        });
+        self.emit_status(status);
+    }
+
+    fn notify_member_left(&mut self, pubkey: &str) {
+        info!("controller: member {} left", short_key(pubkey));
+        self.pending_leaves.remove(pubkey);
+        self.moq.unsubscribe_from_peer(pubkey);
+        self.subscribed_peers.remove(pubkey);
+        self.admin_pubkeys.remove(pubkey);
+        self.media_keys
+            .borrow_mut()
+            .retain(|(sender, _, _), _| sender != pubkey);
+        (self.callback)(ChatEvent::MemberLeft {
+            pubkey: pubkey.to_string(),
+        });
+        self.emit_roster();
+    }
+
+    /// Promote or demote a member by committing a new admin set
+    pub(super) fn set_member_admin(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        pubkey: &str,
+        is_admin: bool,
+    ) -> Result<()> {
+        let own_pubkey = self.identity.public_key_hex();
+        if !self.admin_pubkeys.contains(&own_pubkey) {
+            return Err(anyhow!("only admins can change admin status"));
+        }
+        let mut admins = self.identity.group_admins()?;
+        let changed = if is_admin {
+            admins.insert(pubkey.to_string())
+        } else {
+            admins.remove(pubkey)
+        };
+        if !changed {
+            return Ok(());
+        }
+        if admins.is_empty() {
+            return Err(anyhow!("group needs at least one admin"));
+        }
+
+        info!(
+            "controller: setting admin={} for {}",
+            is_admin,
+            short_key(pubkey)
+        );
+        self.commit_admins(tx, &admins)
+    }
+
+    /// Commit a new admin set
+    fn commit_admins(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        admins: &BTreeSet<String>,
+    ) -> Result<()> {
+        let frame = self.identity.set_group_admins(admins)?;
+        schedule(tx, Operation::PublishWrapper(frame.bytes));
+        self.commits += 1;
+        self.refresh_epoch_watch();
+        self.sync_members_from_identity()?;
+        schedule(
+            tx,
+            Operation::Emit(ChatEvent::Commit {
+                total: self.commits,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Promote or demote an existing member
+    pub fn change_member_admin(
+        &mut self,
+        tx: &UnboundedSender<Operation>,
+        pubkey_input: String,
+        is_admin: bool,
+    ) -> Result<()> {
+        let pubkey = PublicKey::from_hex(pubkey_input.trim())
+            .or_else(|_| PublicKey::from_bech32(pubkey_input.trim()))
+            .context("parse member pubkey")?
+            .to_hex();
+        if !self.identity.list_members()?.contains(&pubkey) {
+            return Err(anyhow!("not a member"));
+        }
+        self.set_member_admin(tx, &pubkey, is_admin)
+    }
+
+    /// Replace the local admin view with the group context's admin set
+    fn sync_admins_from_group(&mut self, members: &[String]) {
+        let admins = match self.identity.group_admins() {
+            Ok(admins) => admins,
             Err(err) => {
-                warn!("Failed to list members while updating admin: {err:#}");
+                warn!("Failed to read group admins: {err:#}");
+                return;
             }
+        };
+        if admins == self.admin_pubkeys {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.admin_pubkeys, admins);
+        let changed: Vec<&String> = members
+            .iter()
+            .filter(|pubkey| previous.contains(*pubkey) != self.admin_pubkeys.contains(*pubkey))
+            .collect();
+        for pubkey in &changed {
+            (self.callback)(ChatEvent::MemberUpdated {
+                member: MemberInfo {
+                    pubkey: pubkey.to_string(),
+                    is_admin: self.admin_pubkeys.contains(*pubkey),
+                },
+            });
+        }
+        if !changed.is_empty() {
+            self.emit_roster();
         }
     }
 
@@ -73,6 +338,38 @@ impl ControllerState {
             }
         };
         let own_pubkey = self.identity.public_key_hex();
+        if !members.contains(&own_pubkey) {
+            let status = if self.leave_requested.is_some() {
+                "You left the group"
+            } else {
+                "You were removed from the group"
+            };
+            self.finish_leave(status);
+            return Ok(());
+        }
+        // Our leave proposal only holds for the epoch it was made in
+        if let Some(epoch) = self.leave_requested {
+            if self.identity.current_epoch()? != epoch {
+                self.leave_requested = None;
+                self.emit_status(
+                    "The group moved on before anyone committed your leave; leave again",
+                );
+            }
+        }
+
+        self.sync_admins_from_group(&members);
+        self.sync_group_metadata();
+
+        let departed: Vec<String> = self
+            .subscribed_peers
+            .iter()
+            .filter(|pubkey| !members.contains(pubkey))
+            .cloned()
+            .collect();
+        for pubkey in departed {
+            self.notify_member_left(&pubkey);
+        }
+
         for pubkey in members {
             if pubkey != own_pubkey && !self.subscribed_peers.contains(&pubkey) {
                 info!(
@@ -2,7 +2,9 @@ use std::collections::VecDeque;
 
 use anyhow::Result;
 use futures::channel::mpsc::UnboundedSender;
-use log::warn;
+use log::{debug, warn};
+use mdk_storage_traits::MdkStorageProvider;
+use sha2::{Digest, Sha256};
 
 use crate::controller::events::ChatEvent;
 
@@ -10,11 +12,34 @@ use super::types::{ControllerState, Operation, PendingIncomingFrame};
 use super::utils::{now_timestamp, schedule};
 
 const MAX_PENDING_INCOMING_ATTEMPTS: u8 = 5;
+/// How many ingested wrappers to remember for dropping redeliveries
+const MAX_SEEN_WRAPPERS: usize = 512;
 
-impl ControllerState {
+fn wrapper_digest(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+impl<S: MdkStorageProvider> ControllerState<S> {
     pub fn handle_incoming_frame(&mut self, bytes: Vec<u8>) -> Result<Vec<ChatEvent>> {
+        // Relays may deliver a wrapper twice, and MLS cannot open it again
+        let digest = wrapper_digest(&bytes);
+        if self.seen_wrappers.contains(&digest) {
+            debug!("controller: dropping redelivered wrapper");
+            return Ok(Vec::new());
+        }
+        // A copy that failed before waits in the retry queue; opening this
+        // one now would leave that copy to fail forever once it succeeds
+        if self
+            .pending_incoming
+            .iter()
+            .any(|frame| wrapper_digest(&frame.bytes) == digest)
+        {
+            debug!("controller: wrapper already queued for retry");
+            return Ok(Vec::new());
+        }
         match self.ingest_wrapper_bytes(&bytes) {
             Ok(mut events) => {
+                self.remember_wrapper(digest);
                 let mut retried = self.retry_pending_incoming()?;
                 events.append(&mut retried);
                 Ok(events)
@@ -45,6 +70,7 @@ impl ControllerState {
     pub fn handle_self_update(&mut self) -> Result<(Vec<u8>, Vec<ChatEvent>)> {
         let frame = self.identity.self_update()?;
         self.commits += 1;
+        self.refresh_epoch_watch();
         Ok((
             frame.bytes,
             vec![ChatEvent::Commit {
@@ -83,10 +109,31 @@ impl ControllerState {
                 directory,
                 created_at: _,
             } => {
-                // Convert directory message to UI-friendly format
+                if directory.sender != author {
+                    warn!(
+                        "controller: dropping directory from {author} claiming sender {}",
+                        directory.sender
+                    );
+                    return Ok(Vec::new());
+                }
+
+                // Convert directory message to UI-friendly format, skipping
+                // labels that don't belong to this sender and epoch
                 let tracks = directory
                     .tracks
                     .into_iter()
+                    .filter(|track| {
+                        match self.verify_track_label(&author, directory.epoch, track) {
+                            Ok(()) => true,
+                            Err(err) => {
+                                warn!(
+                                    "controller: dropping track {} from {author}: {err:#}",
+                                    track.label
+                                );
+                                false
+                            }
+                        }
+                    })
                     .map(|track| TrackInfo {
                         label: track.label,
                         kind: match track.kind {
@@ -104,14 +151,32 @@ impl ControllerState {
                     tracks,
                 }])
             }
+            crate::controller::services::WrapperOutcome::RootAnnouncement {
+                author,
+                announcement,
+            } => self.handle_root_announcement(author, announcement),
             crate::controller::services::WrapperOutcome::Commit => {
                 self.identity.merge_pending_commit()?;
                 self.commits += 1;
+                self.refresh_epoch_watch();
                 self.sync_members_from_identity()?;
                 Ok(vec![ChatEvent::Commit {
                     total: self.commits,
                 }])
             }
+            crate::controller::services::WrapperOutcome::ProposalCommitted(commit) => {
+                self.publish_or_queue(commit.bytes);
+                self.commits += 1;
+                self.refresh_epoch_watch();
+                self.sync_members_from_identity()?;
+                Ok(vec![ChatEvent::Commit {
+                    total: self.commits,
+                }])
+            }
+            crate::controller::services::WrapperOutcome::ProposalDeferred(removed) => {
+                self.defer_leaves(removed)?;
+                Ok(Vec::new())
+            }
             crate::controller::services::WrapperOutcome::None => Ok(Vec::new()),
         }
     }
@@ -150,8 +215,12 @@ impl ControllerState {
         let mut remaining = VecDeque::new();
 
         while let Some(mut frame) = self.pending_incoming.pop_front() {
+            if self.seen_wrappers.contains(&wrapper_digest(&frame.bytes)) {
+                continue;
+            }
             match self.ingest_wrapper_bytes(&frame.bytes) {
                 Ok(mut events) => {
+                    self.remember_wrapper(wrapper_digest(&frame.bytes));
                     produced.append(&mut events);
                 }
                 Err(err) => {
@@ -178,6 +247,13 @@ impl ControllerState {
         Ok(produced)
     }
 
+    fn remember_wrapper(&mut self, digest: [u8; 32]) {
+        if self.seen_wrappers.len() == MAX_SEEN_WRAPPERS {
+            self.seen_wrappers.pop_front();
+        }
+        self.seen_wrappers.push_back(digest);
+    }
+
     fn should_retry_ingest(&self, err: &anyhow::Error) -> bool {
         err.chain().any(|cause| {
             let message = cause.to_string();
@@ -235,6 +311,25 @@ mod tests {
         assert_eq!(state.pending_incoming[0].attempts, 1); // Actually doesn't increment in current impl
     }
 
+    #[test]
+    fn test_redelivered_pending_wrapper_is_not_queued_twice() {
+        let mut state = create_test_state();
+        let bytes = b"not a wrapper yet".to_vec();
+        let err = anyhow::anyhow!("Failed to process message");
+        state.queue_pending_incoming(bytes.clone(), &err);
+
+        assert!(state
+            .handle_incoming_frame(bytes.clone())
+            .unwrap()
+            .is_empty());
+        assert_eq!(state.pending_incoming.len(), 1);
+
+        // Once a copy got through, the queued one is dropped, not retried
+        state.remember_wrapper(wrapper_digest(&bytes));
+        assert!(state.retry_pending_incoming().unwrap().is_empty());
+        assert!(state.pending_incoming.is_empty());
+    }
+
     #[test]
     fn test_pending_incoming_max_attempts() {
         // This test documents that pending frames fail after MAX_PENDING_INCOMING_ATTEMPTS (5)
@@ -242,6 +337,95 @@ mod tests {
         assert_eq!(MAX_PENDING_INCOMING_ATTEMPTS, 5);
     }
 
+    #[test]
+    fn test_seen_wrappers_are_bounded() {
+        let mut state = create_test_state();
+        for index in 0..MAX_SEEN_WRAPPERS + 3 {
+            state.remember_wrapper(wrapper_digest(&index.to_be_bytes()));
+        }
+        assert_eq!(state.seen_wrappers.len(), MAX_SEEN_WRAPPERS);
+        assert!(!state
+            .seen_wrappers
+            .contains(&wrapper_digest(&0usize.to_be_bytes())));
+        assert!(state
+            .seen_wrappers
+            .contains(&wrapper_digest(&(MAX_SEEN_WRAPPERS + 2).to_be_bytes())));
+    }
+
+    #[test]
+    fn test_redelivered_wrapper_is_dropped() {
+        let mut state = create_test_state();
+        let bytes = b"not a wrapper".to_vec();
+        state.remember_wrapper(wrapper_digest(&bytes));
+        assert!(state.handle_incoming_frame(bytes).unwrap().is_empty());
+        assert!(state.pending_incoming.is_empty());
+    }
+
+    #[test]
+    fn test_directory_labels_verify_for_retained_epochs() {
+        use crate::controller::services::{IdentityService, TrackLabelError};
+        use crate::messages::{CodecInfo, TrackEntry, TrackKind};
+
+        use super::super::media::RETAINED_LABEL_EPOCHS;
+
+        let state = create_test_state();
+        let bob = IdentityService::create(
+            "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+        )
+        .unwrap();
+        let bob_pubkey = bob.public_key_hex();
+        let relays = vec!["ws://localhost:8880".to_string()];
+        let bob_kp = bob.create_key_package(&relays).unwrap();
+        state
+            .identity
+            .create_group(&bob_kp.event_json, &bob_pubkey, &[])
+            .unwrap();
+        state.refresh_epoch_watch();
+
+        let own = state.identity.public_key_hex();
+        let epoch = state.identity.current_epoch().unwrap();
+        let track = TrackEntry {
+            label: state
+                .identity
+                .derive_track_label(&own, &TrackKind::Audio)
+                .unwrap(),
+            kind: TrackKind::Audio,
+            codec: CodecInfo {
+                name: "opus".to_string(),
+                clock_rate: Some(48000),
+                channels: Some(1),
+                params: vec![],
+            },
+            cipher_suite: Default::default(),
+            simulcast: vec![],
+        };
+        let advance = || {
+            state.identity.self_update().unwrap();
+            state.refresh_epoch_watch();
+        };
+
+        // A directory from a few epochs back still checks out...
+        for _ in 1..RETAINED_LABEL_EPOCHS {
+            advance();
+        }
+        state.verify_track_label(&own, epoch, &track).unwrap();
+        let err = state
+            .verify_track_label(&bob_pubkey, epoch, &track)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TrackLabelError>(),
+            Some(TrackLabelError::LabelMismatch { .. })
+        ));
+
+        // ...until its labels fall out of the window
+        advance();
+        let err = state.verify_track_label(&own, epoch, &track).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TrackLabelError>(),
+            Some(TrackLabelError::EpochMismatch { .. })
+        ));
+    }
+
     fn create_test_state() -> ControllerState {
         use std::collections::{BTreeMap, BTreeSet, VecDeque};
         use std::rc::Rc;
@@ -257,6 +441,17 @@ mod tests {
 
             fn send(&self, _payload: crate::controller::services::HandshakeMessage) {}
 
+            fn publish_key_package(&self, _event_json: String) {}
+
+            fn delete_key_packages(&self, _deletion_json: String) {}
+
+            fn fetch_key_packages(
+                &self,
+                _pubkey: &str,
+                _listener: Box<dyn crate::controller::services::KeyPackageListener>,
+            ) {
+            }
+
             fn shutdown(&self) {}
         }
 
@@ -274,6 +469,8 @@ mod tests {
 
             fn subscribe_to_peer(&self, _peer_pubkey: &str) {}
 
+            fn unsubscribe_from_peer(&self, _peer_pubkey: &str) {}
+
             fn publish_wrapper(&self, _bytes: &[u8]) {}
 
             fn shutdown(&self) {}
@@ -287,13 +484,18 @@ mod tests {
             bootstrap_role: crate::controller::events::SessionRole::Initial,
             relay_url: String::new(),
             nostr_url: String::new(),
+            nostr_relays: Vec::new(),
             session_id: String::new(),
-            secret_hex: String::new(),
+            secret_hex: crate::secret::SecretString::default(),
             peer_pubkeys: vec![],
             group_id_hex: None,
             admin_pubkeys: vec![],
             local_transport_id: None,
             moq_root: None,
+            root_mode: Default::default(),
+            group_name: None,
+            group_description: None,
+            persists_snapshots: false,
         };
         let nostr: Rc<dyn crate::controller::services::NostrService> = Rc::new(NoopNostr);
         let moq: Rc<dyn crate::controller::services::MoqService> = Rc::new(NoopMoq);
@@ -309,11 +511,22 @@ mod tests {
             ready: false,
             outgoing_queue: VecDeque::new(),
             pending_incoming: VecDeque::new(),
+            seen_wrappers: VecDeque::new(),
             key_package_cache: None,
             welcome_json: None,
             admin_pubkeys: BTreeSet::new(),
             pending_invites: BTreeMap::new(),
+            announced_welcomes: BTreeSet::new(),
             subscribed_peers: BTreeSet::new(),
+            epoch_watch: crate::media_crypto::EpochWatch::default(),
+            media_keys: Default::default(),
+            track_labels: Default::default(),
+            root_migration: None,
+            group_root: Default::default(),
+            left_group: false,
+            leave_requested: None,
+            pending_leaves: BTreeMap::new(),
+            group_metadata: None,
         }
     }
 }
@@ -1,8 +1,11 @@
 mod core;
+mod group;
 mod handshake;
+mod media;
 mod member;
 mod message;
 mod ready;
+mod root;
 mod types;
 mod utils;
 
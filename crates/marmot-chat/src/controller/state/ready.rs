@@ -1,11 +1,12 @@
 use futures::channel::mpsc::UnboundedSender;
+use mdk_storage_traits::MdkStorageProvider;
 
 use crate::controller::events::ChatEvent;
 
 use super::types::{ControllerState, Operation};
 use super::utils::schedule;
 
-impl ControllerState {
+impl<S: MdkStorageProvider> ControllerState<S> {
     pub fn enqueue_outgoing(&mut self, bytes: Vec<u8>) {
         self.outgoing_queue.push_back(bytes);
     }
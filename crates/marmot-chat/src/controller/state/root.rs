@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::UnboundedSender;
+use log::info;
+use mdk_storage_traits::MdkStorageProvider;
+
+use crate::controller::events::{ChatEvent, GroupRootMode};
+use crate::messages::RootAnnouncement;
+
+use super::types::{ControllerState, HandshakeState, Operation, RootMigration};
+use super::utils::{schedule, short_key};
+
+impl<S: MdkStorageProvider> ControllerState<S> {
+    /// Pick the MoQ root for a freshly established group per `root_mode`
+    ///
+    /// A root pinned for the group, by us or by the member whose welcome
+    /// we joined through, wins. In exporter mode a group without one pins
+    /// the exporter root of the current epoch.
+    pub(super) fn select_moq_root(&mut self) -> Result<()> {
+        let pinned = self.identity.pinned_group_root()?;
+        let moq_root = match (self.session.root_mode, pinned) {
+            (GroupRootMode::Legacy, _) => self.identity.derive_legacy_group_root(),
+            (_, Some(root)) => Ok(root),
+            (GroupRootMode::Migrate, None) => {
+                self.root_migration = Some(RootMigration::default());
+                self.identity.derive_legacy_group_root()
+            }
+            (GroupRootMode::Exporter, None) => self.exporter_root().and_then(|root| {
+                self.identity.pin_group_root(&root)?;
+                Ok(root)
+            }),
+        }
+        .map_err(|err| anyhow!("failed to derive group root: {err}"))?;
+        info!("controller: derived moq_root={}", moq_root);
+        self.session.moq_root = Some(moq_root);
+        Ok(())
+    }
+
+    /// Carry a move to a new MoQ root forward
+    ///
+    /// The root stays pinned through commits; it only changes when the
+    /// group moves off the legacy root or an admin rotates it. While
+    /// moving, (re-)announce the current and target roots whenever the
+    /// epoch moves, so members added since hear about it, and stay on the
+    /// current root until every member has announced the target.
+    pub fn sync_moq_root(&mut self, tx: &UnboundedSender<Operation>) -> Result<()> {
+        if self.handshake != HandshakeState::Established
+            || self.left_group
+            || self.session.root_mode == GroupRootMode::Legacy
+        {
+            return Ok(());
+        }
+        let Some(current) = self.session.moq_root.clone() else {
+            return Ok(());
+        };
+        if self
+            .root_migration
+            .as_ref()
+            .is_some_and(|migration| migration.target.is_none())
+        {
+            let proposed = self.exporter_root()?;
+            if let Some(migration) = self.root_migration.as_mut() {
+                migration.target = Some(proposed);
+            }
+        }
+        let Some(migration) = self.root_migration.as_mut() else {
+            return Ok(());
+        };
+        let Some(target) = migration.target.clone() else {
+            return Ok(());
+        };
+
+        let epoch = self.identity.current_epoch()?;
+        if migration.epoch != Some(epoch) {
+            // Re-announce so members added since the last one hear about it
+            migration.epoch = Some(epoch);
+            migration.moved.insert(self.identity.public_key_hex());
+            let frame = self
+                .identity
+                .create_root_announcement(vec![current.clone(), target.clone()])?;
+            schedule(tx, Operation::PublishWrapper(frame.bytes));
+        }
+
+        let members = self.identity.list_members()?;
+        if !members
+            .iter()
+            .all(|member| migration.moved.contains(member))
+        {
+            return Ok(());
+        }
+        self.root_migration = None;
+        self.identity.pin_group_root(&target)?;
+        if current != target {
+            self.switch_moq_root(tx, target);
+        }
+        Ok(())
+    }
+
+    /// Commit a self-update and move the group to the exporter root of the
+    /// epoch it starts (admins only)
+    ///
+    /// The commit goes out on the current root, which everyone keeps using
+    /// until all members have announced the new one.
+    pub fn rotate_moq_root(&mut self, tx: &UnboundedSender<Operation>) -> Result<Vec<ChatEvent>> {
+        if self.handshake != HandshakeState::Established {
+            return Err(anyhow!("group not established"));
+        }
+        if self.session.root_mode == GroupRootMode::Legacy {
+            return Err(anyhow!("legacy groups stay on their root"));
+        }
+        if !self.admin_pubkeys.contains(&self.identity.public_key_hex()) {
+            return Err(anyhow!("only admins can rotate the media root"));
+        }
+        let (bytes, events) = self.handle_self_update()?;
+        schedule(tx, Operation::PublishWrapper(bytes));
+        self.root_migration = Some(RootMigration {
+            target: Some(self.exporter_root()?),
+            ..RootMigration::default()
+        });
+        Ok(events)
+    }
+
+    /// Follow the move to a new root that `announcement` proposes
+    ///
+    /// An announcement names the root its sender is on, then the one it
+    /// moves to, and only counts while we are on that first root. Anyone
+    /// may propose moving off the legacy root; moving off a pinned root
+    /// takes an admin. When proposals race, every member settles on the
+    /// lowest target.
+    pub(super) fn handle_root_announcement(
+        &mut self,
+        author: String,
+        announcement: RootAnnouncement,
+    ) -> Result<Vec<ChatEvent>> {
+        if self.session.root_mode == GroupRootMode::Legacy || announcement.sender != author {
+            return Ok(Vec::new());
+        }
+        let [from, to] = announcement.roots.as_slice() else {
+            return Ok(Vec::new());
+        };
+        if from == to || self.session.moq_root.as_deref() != Some(from.as_str()) {
+            return Ok(Vec::new());
+        }
+        let may_propose = *from == self.identity.derive_legacy_group_root()?
+            || self.admin_pubkeys.contains(&author);
+        let adopt = match self
+            .root_migration
+            .as_ref()
+            .and_then(|migration| migration.target.as_ref())
+        {
+            Some(target) => may_propose && to < target,
+            None => may_propose,
+        };
+        if adopt {
+            self.root_migration = Some(RootMigration {
+                target: Some(to.clone()),
+                ..RootMigration::default()
+            });
+        }
+        let Some(migration) = self.root_migration.as_mut() else {
+            return Ok(Vec::new());
+        };
+        if migration.target.as_ref() != Some(to) {
+            return Ok(Vec::new());
+        }
+        if migration.moved.insert(author.clone()) {
+            return Ok(vec![ChatEvent::status(format!(
+                "{} is ready to move to the new media root",
+                short_key(&author)
+            ))]);
+        }
+        Ok(Vec::new())
+    }
+
+    /// Root the MoQ connection uses now, or the one it will use once
+    /// established
+    pub fn active_moq_root(&self) -> Result<String> {
+        match &self.session.moq_root {
+            Some(root) => Ok(root.clone()),
+            None => self.exporter_root(),
+        }
+    }
+
+    /// Exporter root of the current epoch, derived once per epoch
+    fn exporter_root(&self) -> Result<String> {
+        if let Some(root) = self.group_root.borrow().as_ref() {
+            return Ok(root.clone());
+        }
+        let root = self.identity.derive_group_root()?;
+        *self.group_root.borrow_mut() = Some(root.clone());
+        Ok(root)
+    }
+
+    fn switch_moq_root(&mut self, tx: &UnboundedSender<Operation>, root: String) {
+        info!("controller: switching moq_root to {}", root);
+        self.moq.shutdown();
+        self.session.moq_root = Some(root);
+        let event = self.mark_ready(false);
+        schedule(tx, Operation::Emit(event));
+        schedule(tx, Operation::ConnectMoq);
+    }
+}
@@ -1,23 +1,35 @@
-use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::rc::Rc;
 
+use mdk_memory_storage::MdkMemoryStorage;
+use mdk_storage_traits::MdkStorageProvider;
+
+use crate::cipher_suite::CipherSuite;
 use crate::controller::events::{ChatEvent, SessionParams};
 use crate::controller::services::{
-    HandshakeMessage, IdentityHandle, KeyPackageExport, MoqService, NostrService,
+    GroupMetadata, HandshakeMessage, IdentityHandle, KeyPackageExport, MoqService, NostrService,
 };
+use crate::group_image::GroupImageRef;
+use crate::media_crypto::{EpochWatch, MediaKeyRing};
 
 pub type EventCallback = Rc<dyn Fn(ChatEvent)>;
 
-pub struct ControllerConfig {
-    pub identity: IdentityHandle,
+/// What a controller runs on
+///
+/// Generic over the identity's MDK storage backend like [`IdentityHandle`];
+/// with a persistent backend the controller resumes the session's stored
+/// group on start instead of running the handshake again.
+pub struct ControllerConfig<S: MdkStorageProvider = MdkMemoryStorage> {
+    pub identity: IdentityHandle<S>,
     pub session: SessionParams,
     pub nostr: Rc<dyn NostrService>,
     pub moq: Rc<dyn MoqService>,
     pub callback: EventCallback,
 }
 
-pub struct ControllerState {
-    pub identity: IdentityHandle,
+pub struct ControllerState<S: MdkStorageProvider = MdkMemoryStorage> {
+    pub identity: IdentityHandle<S>,
     pub session: SessionParams,
     pub nostr: Rc<dyn NostrService>,
     pub moq: Rc<dyn MoqService>,
@@ -27,11 +39,66 @@ pub struct ControllerState {
     pub ready: bool,
     pub outgoing_queue: VecDeque<Vec<u8>>,
     pub pending_incoming: VecDeque<PendingIncomingFrame>,
+    /// SHA-256 of recently ingested wrappers, oldest first
+    pub seen_wrappers: VecDeque<[u8; 32]>,
     pub key_package_cache: Option<KeyPackageExport>,
     pub welcome_json: Option<String>,
     pub admin_pubkeys: BTreeSet<String>,
     pub pending_invites: BTreeMap<String, PendingInvite>,
+    /// Pending welcomes already reported to the UI, by rumor id
+    pub announced_welcomes: BTreeSet<String>,
     pub subscribed_peers: BTreeSet<String>,
+    pub epoch_watch: EpochWatch,
+    /// Key rings behind media sessions, keyed by (sender, track label,
+    /// suite); held until the epoch moves on even if every session is dropped
+    pub media_keys: RefCell<HashMap<(String, String, CipherSuite), Rc<RefCell<MediaKeyRing>>>>,
+    /// Track labels of every member at recent epochs, oldest first, so
+    /// directories announced just before a commit still verify after it
+    pub track_labels: RefCell<VecDeque<RetainedTrackLabels>>,
+    /// Set while the group moves to a new MoQ root: off the legacy root,
+    /// or to a fresh exporter root an admin asked for
+    pub root_migration: Option<RootMigration>,
+    /// Exporter-derived MoQ root of the current epoch, cleared whenever the
+    /// epoch moves on; only read when a new root is pinned
+    pub group_root: RefCell<Option<String>>,
+    /// Set once we left or were removed from the group
+    pub left_group: bool,
+    /// Epoch our leave proposal went out at; we stay until an admin commits
+    /// it, or ask again once the epoch moves on without us leaving
+    pub leave_requested: Option<u64>,
+    /// Leave proposals another admin is due to commit, by leaving member
+    pub pending_leaves: BTreeMap<String, PendingLeave>,
+    /// Group context metadata last reported to the UI
+    pub group_metadata: Option<GroupMetadata>,
+}
+
+/// Track labels derived for every member at one epoch
+#[derive(Debug, Clone)]
+pub struct RetainedTrackLabels {
+    pub epoch: u64,
+    /// Label by (sender pubkey, track kind)
+    pub labels: HashMap<(String, &'static str), String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RootMigration {
+    /// Root the group is moving to; `None` until we propose one or hear
+    /// another member's proposal
+    pub target: Option<String>,
+    /// Members that announced `target`; kept across epochs, since the
+    /// target does not change with them
+    pub moved: BTreeSet<String>,
+    /// Epoch our own latest announcement was made at
+    pub epoch: Option<u64>,
+}
+
+/// A member's leave proposal waiting for its commit
+#[derive(Debug, Clone)]
+pub struct PendingLeave {
+    /// Epoch the proposal was made at; it means nothing after
+    pub epoch: u64,
+    /// When we saw the proposal, in seconds
+    pub since: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +119,12 @@ pub enum Operation {
     Emit(ChatEvent),
     OutgoingHandshake(HandshakeMessage),
     IncomingHandshake(HandshakeMessage),
+    /// Look up the key packages a pubkey published
+    FetchKeyPackages(String),
+    KeyPackagesFetched {
+        pubkey: String,
+        events: Vec<String>,
+    },
     ConnectMoq,
     IncomingFrame(Vec<u8>),
     PublishWrapper(Vec<u8>),
@@ -59,7 +132,28 @@ pub enum Operation {
     Shutdown,
     SendText(String),
     RotateEpoch,
-    InviteMember { pubkey: String, is_admin: bool },
+    InviteMember {
+        pubkey: String,
+        is_admin: bool,
+    },
+    RemoveMember(String),
+    SetMemberAdmin {
+        pubkey: String,
+        is_admin: bool,
+    },
+    LeaveGroup,
+    /// Join through one of the identity's pending welcomes, by rumor id
+    AcceptWelcome(String),
+    SyncMoqRoot,
+    /// Move the group to a fresh exporter root (admins only)
+    RotateMoqRoot,
+    /// Commit leave proposals the admins ahead of us did not
+    CommitOverdueLeaves,
+    UpdateGroupMetadata {
+        name: Option<String>,
+        description: Option<String>,
+    },
+    SetGroupImage(Option<GroupImageRef>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
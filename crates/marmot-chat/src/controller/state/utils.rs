@@ -30,9 +30,3 @@ pub(super) fn short_key(key: &str) -> String {
         format!("{}…{}", &key[..6], &key[key.len() - 4..])
     }
 }
-
-pub(super) fn relay_relays_url(url: &str) -> String {
-    url.parse::<url::Url>()
-        .map(|parsed| format!("wss://{}", parsed.host_str().unwrap_or("localhost")))
-        .unwrap_or_else(|_| "wss://localhost".to_string())
-}
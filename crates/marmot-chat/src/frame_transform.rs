@@ -0,0 +1,612 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+
+use crate::media_crypto::MediaCrypto;
+use crate::messages::CodecInfo;
+use crate::sframe::SframeHeader;
+
+/// NAL header of the unit carrying the trailer of an encrypted H.264
+/// frame: nal_ref_idc 0, type 30 (unspecified, ignored by decoders)
+const H264_TRAILER_NAL: u8 = 0x1e;
+
+/// Appended to every encrypted H.264 payload before escaping, like the
+/// RBSP stop bit, so an escaped payload never ends in a zero byte
+const H264_PAYLOAD_STOP: u8 = 0x80;
+
+/// Codec family, as far as partial encryption is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecKind {
+    Opus,
+    Vp8,
+    Vp9,
+    H264,
+    Av1,
+    /// Anything unrecognised is encrypted in full
+    Other,
+}
+
+impl CodecKind {
+    /// Map a codec name (`CodecInfo::name` or a WebCodecs codec string)
+    pub fn from_name(name: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "opus" => CodecKind::Opus,
+            "vp8" => CodecKind::Vp8,
+            "vp9" => CodecKind::Vp9,
+            "h264" | "avc" => CodecKind::H264,
+            "av1" => CodecKind::Av1,
+            _ if name.starts_with("vp09") => CodecKind::Vp9,
+            _ if name.starts_with("avc1") || name.starts_with("avc3") => CodecKind::H264,
+            _ if name.starts_with("av01") => CodecKind::Av1,
+            _ => CodecKind::Other,
+        }
+    }
+}
+
+/// Codec-aware frame encryption per MOQ_MARMOT_AV_SPEC.md
+///
+/// Leaves the bytes decoders and SFUs need in plaintext and encrypts the
+/// rest in place, so the frame keeps its bitstream structure:
+/// - VP8: 1 byte (delta) or 10 bytes (keyframe) per RFC 7741
+/// - H.264 (Annex B): start codes, non-VCL NAL units and VCL NAL headers
+/// - AV1: OBU headers, extensions and size fields; sequence headers,
+///   temporal delimiters and padding stay fully clear
+/// - Opus, VP9, others: fully encrypted
+///
+/// Encrypted frames are `frame || tag || sframe_header || header_len`.
+/// The clear bytes are bound into the AAD, together with the caller's
+/// metadata and the SFrame header.
+///
+/// H.264 ciphertext gets emulation prevention bytes, as in the bitstream
+/// itself, so it never forms a start code; the trailer travels escaped in
+/// a NAL unit of its own, after the frame's units.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameTransformer {
+    codec: CodecKind,
+}
+
+impl FrameTransformer {
+    pub fn new(codec: CodecKind) -> Self {
+        Self { codec }
+    }
+
+    pub fn for_codec(codec: &CodecInfo) -> Self {
+        Self::new(CodecKind::from_name(&codec.name))
+    }
+
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    pub fn encrypt(
+        &self,
+        crypto: &mut MediaCrypto,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<Vec<u8>> {
+        let ranges = self.encrypted_ranges(frame)?;
+        let clear = clear_bytes(frame, &ranges);
+        let plaintext: Vec<u8> = ranges
+            .iter()
+            .flat_map(|range| frame[range.clone()].iter().copied())
+            .collect();
+
+        let key_id = crypto.key_id();
+        let (frame_counter, sealed) = crypto.encrypt_next(&plaintext, |counter| {
+            Ok(build_aad(
+                &SframeHeader::new(key_id, counter.into()),
+                metadata,
+                &clear,
+            ))
+        })?;
+        let header = SframeHeader::new(key_id, frame_counter.into()).encode();
+        let tag_len = crypto.cipher_suite().tag_len();
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - tag_len);
+        let mut trailer = tag.to_vec();
+        trailer.extend_from_slice(&header);
+        trailer.push(header.len() as u8);
+
+        if self.codec == CodecKind::H264 {
+            return Ok(h264_assemble(frame, &ranges, ciphertext, &trailer));
+        }
+        let mut output = frame.to_vec();
+        let mut offset = 0;
+        for range in &ranges {
+            output[range.clone()].copy_from_slice(&ciphertext[offset..offset + range.len()]);
+            offset += range.len();
+        }
+        output.extend_from_slice(&trailer);
+        Ok(output)
+    }
+
+    pub fn decrypt(
+        &self,
+        crypto: &mut MediaCrypto,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<Vec<u8>> {
+        let tag_len = crypto.cipher_suite().tag_len();
+        let (body, trailer) = if self.codec == CodecKind::H264 {
+            h264_split_trailer(frame)?
+        } else {
+            let trailer_start = frame
+                .last()
+                .and_then(|&header_len| frame.len().checked_sub(usize::from(header_len) + 1))
+                .and_then(|header_start| header_start.checked_sub(tag_len))
+                .ok_or_else(|| anyhow!("truncated frame trailer"))?;
+            (
+                frame[..trailer_start].to_vec(),
+                frame[trailer_start..].to_vec(),
+            )
+        };
+        let (&header_len, rest) = trailer
+            .split_last()
+            .ok_or_else(|| anyhow!("empty encrypted frame"))?;
+        let header_start = rest
+            .len()
+            .checked_sub(usize::from(header_len))
+            .filter(|start| *start == tag_len)
+            .ok_or_else(|| anyhow!("truncated frame trailer"))?;
+        let (header, parsed_len) = SframeHeader::parse(&rest[header_start..])?;
+        if parsed_len != usize::from(header_len) {
+            return Err(anyhow!("malformed frame trailer"));
+        }
+        if header.kid != crypto.key_id() {
+            return Err(anyhow!(
+                "frame key id {:#x} does not match session key id {:#x}",
+                header.kid,
+                crypto.key_id()
+            ));
+        }
+        let frame_counter = u32::try_from(header.ctr)
+            .map_err(|_| anyhow!("frame counter {:#x} exceeds 32 bits", header.ctr))?;
+        let tag = &rest[..header_start];
+
+        let ranges = self.encrypted_ranges(&body)?;
+        let clear = clear_bytes(&body, &ranges);
+        let payloads = ranges
+            .iter()
+            .map(|range| match self.codec {
+                CodecKind::H264 => h264_unescape_payload(&body[range.clone()]),
+                _ => Ok(body[range.clone()].to_vec()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut sealed = payloads.concat();
+        sealed.extend_from_slice(tag);
+
+        let aad = build_aad(&header, metadata, &clear);
+        let plaintext = crypto.decrypt(&sealed, frame_counter, &aad)?;
+
+        let mut output = Vec::with_capacity(body.len());
+        let mut cursor = 0;
+        let mut offset = 0;
+        for (range, payload) in ranges.iter().zip(&payloads) {
+            output.extend_from_slice(&body[cursor..range.start]);
+            output.extend_from_slice(&plaintext[offset..offset + payload.len()]);
+            cursor = range.end;
+            offset += payload.len();
+        }
+        output.extend_from_slice(&body[cursor..]);
+        Ok(output)
+    }
+
+    /// Byte ranges of `frame` that get encrypted; everything else stays clear
+    ///
+    /// Only depends on bytes that stay clear, so the receiver recovers the
+    /// same ranges from the encrypted frame.
+    pub fn encrypted_ranges(&self, frame: &[u8]) -> Result<Vec<Range<usize>>> {
+        let ranges = match self.codec {
+            CodecKind::Vp8 => vp8_ranges(frame),
+            CodecKind::H264 => h264_ranges(frame)?,
+            CodecKind::Av1 => av1_ranges(frame)?,
+            CodecKind::Opus | CodecKind::Vp9 | CodecKind::Other => vec![Range {
+                start: 0,
+                end: frame.len(),
+            }],
+        };
+        Ok(ranges
+            .into_iter()
+            .filter(|range| !range.is_empty())
+            .collect())
+    }
+}
+
+fn clear_bytes(frame: &[u8], encrypted: &[Range<usize>]) -> Vec<u8> {
+    let mut clear = Vec::new();
+    let mut cursor = 0;
+    for range in encrypted {
+        clear.extend_from_slice(&frame[cursor..range.start]);
+        cursor = range.end;
+    }
+    clear.extend_from_slice(&frame[cursor..]);
+    clear
+}
+
+fn build_aad(header: &SframeHeader, metadata: &[u8], clear: &[u8]) -> Vec<u8> {
+    let mut aad = header.encode();
+    aad.extend_from_slice(metadata);
+    aad.extend_from_slice(clear);
+    aad
+}
+
+/// RFC 7741: keep the frame tag (and the keyframe start code + dimensions)
+fn vp8_ranges(frame: &[u8]) -> Vec<Range<usize>> {
+    let Some(first) = frame.first() else {
+        return Vec::new();
+    };
+    // P bit (bit 0 of the frame tag) is 0 for keyframes
+    let clear = if first & 0x01 == 0 { 10 } else { 1 };
+    vec![Range {
+        start: clear.min(frame.len()),
+        end: frame.len(),
+    }]
+}
+
+/// NAL units of an Annex B stream as (header offset, end) pairs
+fn h264_nal_units(frame: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= frame.len() {
+        if frame[i] == 0 && frame[i + 1] == 0 && frame[i + 2] == 1 {
+            starts.push((i, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(idx, &(_, header))| {
+            let mut end = starts
+                .get(idx + 1)
+                .map(|&(code, _)| code)
+                .unwrap_or(frame.len());
+            // Zero bytes before the next start code belong to it
+            if idx + 1 < starts.len() {
+                while end > header && frame[end - 1] == 0 {
+                    end -= 1;
+                }
+            }
+            (header, end)
+        })
+        .collect()
+}
+
+/// Encrypt VCL NAL payloads (types 1-5); keep non-VCL units and headers
+fn h264_ranges(frame: &[u8]) -> Result<Vec<Range<usize>>> {
+    let units = h264_nal_units(frame);
+    if units.is_empty() && !frame.is_empty() {
+        return Err(anyhow!("H.264 frame is not in Annex B format"));
+    }
+    Ok(units
+        .into_iter()
+        .filter(|&(header, end)| header < end && matches!(frame[header] & 0x1f, 1..=5))
+        .map(|(header, end)| header + 1..end)
+        .collect())
+}
+
+/// Replace each encrypted payload of `frame` with its escaped ciphertext
+/// and append the escaped trailer as a unit of its own
+fn h264_assemble(
+    frame: &[u8],
+    ranges: &[Range<usize>],
+    ciphertext: &[u8],
+    trailer: &[u8],
+) -> Vec<u8> {
+    let mut output = Vec::with_capacity(frame.len() + trailer.len() + 16);
+    let mut cursor = 0;
+    let mut offset = 0;
+    for range in ranges {
+        output.extend_from_slice(&frame[cursor..range.start]);
+        let mut payload = ciphertext[offset..offset + range.len()].to_vec();
+        payload.push(H264_PAYLOAD_STOP);
+        h264_escape_into(&mut output, &payload);
+        cursor = range.end;
+        offset += range.len();
+    }
+    output.extend_from_slice(&frame[cursor..]);
+    output.extend_from_slice(&[0, 0, 0, 1, H264_TRAILER_NAL]);
+    h264_escape_into(&mut output, trailer);
+    output
+}
+
+/// Split an encrypted H.264 frame into its units and the unescaped trailer
+fn h264_split_trailer(frame: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let &(header, end) = h264_nal_units(frame)
+        .last()
+        .filter(|&&(header, _)| header >= 4 && frame[header] == H264_TRAILER_NAL)
+        .ok_or_else(|| anyhow!("H.264 frame has no trailer unit"))?;
+    if frame[header - 4] != 0 {
+        return Err(anyhow!("malformed H.264 trailer start code"));
+    }
+    Ok((
+        frame[..header - 4].to_vec(),
+        h264_unescape(&frame[header + 1..end]),
+    ))
+}
+
+/// Insert an emulation prevention byte after every `00 00` followed by a
+/// byte up to `03`, so `payload` cannot form a start code
+fn h264_escape_into(output: &mut Vec<u8>, payload: &[u8]) {
+    let mut zeros = 0;
+    for &byte in payload {
+        if zeros >= 2 && byte <= 3 {
+            output.push(3);
+            zeros = 0;
+        }
+        output.push(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+}
+
+/// Drop the emulation prevention byte from every `00 00 03`
+fn h264_unescape(escaped: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(escaped.len());
+    let mut zeros = 0;
+    for &byte in escaped {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        output.push(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+    output
+}
+
+/// Unescape an encrypted payload and strip its stop byte
+fn h264_unescape_payload(escaped: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = h264_unescape(escaped);
+    if payload.pop() != Some(H264_PAYLOAD_STOP) {
+        return Err(anyhow!("H.264 payload lost its stop byte"));
+    }
+    Ok(payload)
+}
+
+/// Keep OBU headers, extension bytes and leb128 size fields clear
+fn av1_ranges(frame: &[u8]) -> Result<Vec<Range<usize>>> {
+    const OBU_SEQUENCE_HEADER: u8 = 1;
+    const OBU_TEMPORAL_DELIMITER: u8 = 2;
+    const OBU_PADDING: u8 = 15;
+
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < frame.len() {
+        let header = frame[offset];
+        let obu_type = (header >> 3) & 0x0f;
+        let has_extension = header & 0x04 != 0;
+        let has_size = header & 0x02 != 0;
+        offset += 1;
+        if has_extension {
+            offset += 1;
+        }
+        // The last OBU may omit its size and run to the end of the frame
+        let payload_len = if has_size {
+            let (size, read) = read_leb128(frame.get(offset..).unwrap_or_default())?;
+            offset += read;
+            usize::try_from(size).map_err(|_| anyhow!("AV1 OBU size overflow"))?
+        } else {
+            frame.len().saturating_sub(offset)
+        };
+        let end = offset
+            .checked_add(payload_len)
+            .filter(|end| *end <= frame.len())
+            .ok_or_else(|| anyhow!("AV1 OBU exceeds frame length"))?;
+        if !matches!(
+            obu_type,
+            OBU_SEQUENCE_HEADER | OBU_TEMPORAL_DELIMITER | OBU_PADDING
+        ) {
+            ranges.push(offset..end);
+        }
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+fn read_leb128(bytes: &[u8]) -> Result<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().take(8).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(anyhow!("invalid AV1 leb128 size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher_suite::CipherSuite;
+
+    fn roundtrip(codec: CodecKind, frame: &[u8]) -> Vec<u8> {
+        let transformer = FrameTransformer::new(codec);
+        let mut sender = MediaCrypto::new([21u8; 32]);
+        let mut receiver = MediaCrypto::new([21u8; 32]);
+
+        let encrypted = transformer.encrypt(&mut sender, frame, b"meta").unwrap();
+        let decrypted = transformer
+            .decrypt(&mut receiver, &encrypted, b"meta")
+            .unwrap();
+        assert_eq!(decrypted, frame);
+        encrypted
+    }
+
+    #[test]
+    fn test_codec_kind_from_name() {
+        assert_eq!(CodecKind::from_name("VP8"), CodecKind::Vp8);
+        assert_eq!(CodecKind::from_name("avc1.42E01F"), CodecKind::H264);
+        assert_eq!(CodecKind::from_name("av01.0.04M.08"), CodecKind::Av1);
+        assert_eq!(CodecKind::from_name("vp09.00.10.08"), CodecKind::Vp9);
+        assert_eq!(CodecKind::from_name("opus"), CodecKind::Opus);
+        assert_eq!(CodecKind::from_name("flac"), CodecKind::Other);
+    }
+
+    #[test]
+    fn test_vp8_keeps_frame_tag_clear() {
+        let keyframe: Vec<u8> = (0..64).map(|i| (i as u8) << 1).collect();
+        let encrypted = roundtrip(CodecKind::Vp8, &keyframe);
+        assert_eq!(&encrypted[..10], &keyframe[..10]);
+        assert_ne!(&encrypted[10..64], &keyframe[10..]);
+
+        let delta: Vec<u8> = (0..64).map(|i| (i as u8) | 0x01).collect();
+        let encrypted = roundtrip(CodecKind::Vp8, &delta);
+        assert_eq!(encrypted[0], delta[0]);
+        assert_ne!(&encrypted[1..64], &delta[1..]);
+    }
+
+    #[test]
+    fn test_h264_keeps_non_vcl_units_and_headers_clear() {
+        let sps = [0x67, 0x42, 0xe0, 0x1f, 0x8d];
+        let pps = [0x68, 0xce, 0x3c, 0x80];
+        let idr: Vec<u8> = std::iter::once(0x65)
+            .chain((1..200).map(|i| i as u8 | 1))
+            .collect();
+        let mut frame = Vec::new();
+        for nal in [&sps[..], &pps[..], &idr[..]] {
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(nal);
+        }
+
+        let encrypted = roundtrip(CodecKind::H264, &frame);
+        let idr_header = frame.len() - idr.len();
+        assert_eq!(&encrypted[..=idr_header], &frame[..=idr_header]);
+        assert_ne!(&encrypted[idr_header + 1..frame.len()], &idr[1..]);
+        let types = |bytes: &[u8]| -> Vec<u8> {
+            h264_nal_units(bytes)
+                .into_iter()
+                .map(|(header, _)| bytes[header] & 0x1f)
+                .collect()
+        };
+        assert_eq!(types(&encrypted), vec![7, 8, 5, H264_TRAILER_NAL]);
+    }
+
+    #[test]
+    fn test_h264_ciphertext_never_forms_start_codes() {
+        let frame = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x65, 1, 2, 3, 4, 5];
+        let ranges = FrameTransformer::new(CodecKind::H264)
+            .encrypted_ranges(&frame)
+            .unwrap();
+        // Ciphertext and trailer full of would-be start codes and zeros
+        let ciphertext = [0, 0, 1, 0, 0];
+        let trailer = [0, 0, 0, 1, 0, 0, 3, 2];
+        let output = h264_assemble(&frame, &ranges, &ciphertext, &trailer);
+
+        let (body, unescaped) = h264_split_trailer(&output).unwrap();
+        assert_eq!(unescaped, trailer);
+        assert_eq!(h264_nal_units(&output).len(), 3);
+        let escaped = FrameTransformer::new(CodecKind::H264)
+            .encrypted_ranges(&body)
+            .unwrap();
+        assert_eq!(
+            h264_unescape_payload(&body[escaped[0].clone()]).unwrap(),
+            ciphertext
+        );
+    }
+
+    #[test]
+    fn test_h264_frames_roundtrip() {
+        // Some of these ciphertexts end in a zero byte
+        let transformer = FrameTransformer::new(CodecKind::H264);
+        let mut sender = MediaCrypto::new([25u8; 32]);
+        let mut receiver = MediaCrypto::new([25u8; 32]);
+        for len in 0..512usize {
+            let mut frame = vec![0, 0, 0, 1, 0x67, 0x42, 0x00, 0x00, 0x03, 0x01];
+            frame.extend_from_slice(&[0, 0, 1, 0x41]);
+            frame.extend((0..len).map(|i| (i * 7 + len) as u8 | 1));
+            frame.push(0);
+
+            let encrypted = transformer.encrypt(&mut sender, &frame, b"").unwrap();
+            assert_eq!(h264_nal_units(&encrypted).len(), 3, "frame {len}");
+            assert_eq!(
+                transformer.decrypt(&mut receiver, &encrypted, b"").unwrap(),
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn test_h264_escaping_roundtrips() {
+        for payload in [
+            &[0, 0, 0][..],
+            &[0, 0, 1, 0, 0, 2],
+            &[0, 0, 3, 0, 0],
+            &[7, 0, 0, 0, 0, 3, 3],
+        ] {
+            let mut escaped = Vec::new();
+            h264_escape_into(&mut escaped, payload);
+            assert!(!escaped
+                .windows(3)
+                .any(|window| window[0] == 0 && window[1] == 0 && window[2] < 3));
+            assert_eq!(h264_unescape(&escaped), payload);
+        }
+    }
+
+    #[test]
+    fn test_h264_requires_annex_b() {
+        let transformer = FrameTransformer::new(CodecKind::H264);
+        let mut crypto = MediaCrypto::new([22u8; 32]);
+        assert!(transformer
+            .encrypt(&mut crypto, &[0x00, 0x00, 0x00, 0x05, 0x65, 0x88], b"")
+            .is_err());
+    }
+
+    #[test]
+    fn test_av1_keeps_obu_headers_clear() {
+        let mut frame = vec![0x12, 0x00]; // temporal delimiter, size 0
+        frame.extend_from_slice(&[0x0a, 0x03, 0xaa, 0xbb, 0xcc]); // sequence header
+        frame.extend_from_slice(&[0x32, 0x05, 1, 2, 3, 4, 5]); // frame OBU, size 5
+        frame.extend_from_slice(&[0x30, 9, 8, 7]); // last OBU without size field
+
+        let encrypted = roundtrip(CodecKind::Av1, &frame);
+        assert_eq!(&encrypted[..9], &frame[..9]);
+        assert_ne!(&encrypted[9..14], &frame[9..14]);
+        assert_eq!(encrypted[14], frame[14]);
+        assert_ne!(&encrypted[15..18], &frame[15..18]);
+    }
+
+    #[test]
+    fn test_opus_fully_encrypted() {
+        let frame = vec![0x78u8; 40];
+        let encrypted = roundtrip(CodecKind::Opus, &frame);
+        assert!(encrypted[..40].iter().zip(&frame).any(|(a, b)| a != b));
+    }
+
+    #[test]
+    fn test_trailer_uses_session_suite() {
+        let transformer = FrameTransformer::new(CodecKind::Vp8);
+        let suite = CipherSuite::ChaCha20Poly1305;
+        let mut sender = MediaCrypto::with_suite([24u8; 32], suite);
+        let mut receiver = MediaCrypto::with_suite([24u8; 32], suite);
+        let frame: Vec<u8> = (0..32).map(|i| (i as u8) << 1).collect();
+
+        let encrypted = transformer.encrypt(&mut sender, &frame, b"meta").unwrap();
+        let header_len = usize::from(*encrypted.last().unwrap());
+        assert_eq!(
+            encrypted.len(),
+            frame.len() + suite.tag_len() + header_len + 1
+        );
+        assert_eq!(
+            transformer
+                .decrypt(&mut receiver, &encrypted, b"meta")
+                .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn test_tampered_clear_header_rejected() {
+        let transformer = FrameTransformer::new(CodecKind::Vp8);
+        let mut sender = MediaCrypto::new([23u8; 32]);
+        let mut receiver = MediaCrypto::new([23u8; 32]);
+        let frame: Vec<u8> = (0..32).map(|i| (i as u8) << 1).collect();
+
+        let mut encrypted = transformer.encrypt(&mut sender, &frame, b"meta").unwrap();
+        encrypted[3] ^= 0x80;
+        assert!(transformer
+            .decrypt(&mut receiver, &encrypted, b"meta")
+            .is_err());
+    }
+}
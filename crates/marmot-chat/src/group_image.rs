@@ -0,0 +1,137 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::cipher_suite::CipherSuite;
+
+const IMAGE_SUITE: CipherSuite = CipherSuite::ChaCha20Poly1305;
+
+/// What the group context records about the group image
+///
+/// The image itself is stored encrypted outside the group (e.g. on a blob
+/// server) under `hash`; `key` and `nonce` let members decrypt it.
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct GroupImageRef {
+    /// SHA-256 of the encrypted image
+    pub hash: [u8; 32],
+    pub key: [u8; 32],
+    pub nonce: [u8; 12],
+}
+
+impl GroupImageRef {
+    pub fn hash_hex(&self) -> String {
+        hex::encode(self.hash)
+    }
+}
+
+impl fmt::Debug for GroupImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupImageRef")
+            .field("hash", &self.hash_hex())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupImageError {
+    /// The blob is not the one the group context points at
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    Decrypt,
+}
+
+impl fmt::Display for GroupImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupImageError::HashMismatch { expected, actual } => {
+                write!(f, "group image hash {actual} does not match {expected}")
+            }
+            GroupImageError::Decrypt => write!(f, "group image failed to decrypt"),
+        }
+    }
+}
+
+impl std::error::Error for GroupImageError {}
+
+/// Encrypt an image under a fresh key and nonce
+///
+/// Returns the ciphertext to upload and the reference to commit to the
+/// group context.
+pub fn encrypt_group_image(image: &[u8]) -> Result<(Vec<u8>, GroupImageRef)> {
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    getrandom::getrandom(&mut key).map_err(|e| anyhow!("group image key: {e}"))?;
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("group image nonce: {e}"))?;
+    let ciphertext = IMAGE_SUITE.seal(&key, &nonce, image, &[])?;
+    let image_ref = GroupImageRef {
+        hash: Sha256::digest(&ciphertext).into(),
+        key,
+        nonce,
+    };
+    key.zeroize();
+    Ok((ciphertext, image_ref))
+}
+
+/// Check a downloaded blob against the group context and decrypt it
+pub fn decrypt_group_image(ciphertext: &[u8], image_ref: &GroupImageRef) -> Result<Vec<u8>> {
+    let actual: [u8; 32] = Sha256::digest(ciphertext).into();
+    if actual != image_ref.hash {
+        return Err(GroupImageError::HashMismatch {
+            expected: image_ref.hash_hex(),
+            actual: hex::encode(actual),
+        }
+        .into());
+    }
+    IMAGE_SUITE
+        .open(&image_ref.key, &image_ref.nonce, ciphertext, &[])
+        .map_err(|_| GroupImageError::Decrypt.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_image_roundtrip() {
+        let (ciphertext, image_ref) = encrypt_group_image(b"\x89PNG fake image").unwrap();
+        assert_ne!(ciphertext.as_slice(), b"\x89PNG fake image");
+        assert_eq!(
+            decrypt_group_image(&ciphertext, &image_ref).unwrap(),
+            b"\x89PNG fake image"
+        );
+    }
+
+    #[test]
+    fn test_swapped_blob_rejected() {
+        let (_, image_ref) = encrypt_group_image(b"first").unwrap();
+        let (other, _) = encrypt_group_image(b"second").unwrap();
+        let err = decrypt_group_image(&other, &image_ref).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GroupImageError>(),
+            Some(GroupImageError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        let (ciphertext, mut image_ref) = encrypt_group_image(b"image").unwrap();
+        image_ref.key[0] ^= 1;
+        let err = decrypt_group_image(&ciphertext, &image_ref).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GroupImageError>(),
+            Some(&GroupImageError::Decrypt)
+        );
+    }
+
+    #[test]
+    fn test_debug_hides_key() {
+        let (_, image_ref) = encrypt_group_image(b"image").unwrap();
+        let debug = format!("{image_ref:?}");
+        assert!(debug.contains(&image_ref.hash_hex()));
+        assert!(!debug.contains(&hex::encode(image_ref.key)));
+    }
+}
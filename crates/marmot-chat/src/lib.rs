@@ -1,6 +1,13 @@
+pub mod cipher_suite;
 pub mod controller;
+pub mod frame_transform;
+pub mod group_image;
 pub mod media_crypto;
 pub mod messages;
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub mod native;
+pub mod secret;
+pub mod sframe;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm;
@@ -1,134 +1,122 @@
-use aes_gcm::{
-    aead::{Aead, KeyInit, Payload},
-    Aes128Gcm, Nonce,
-};
 use anyhow::{anyhow, Result};
 use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
-#[cfg(not(target_arch = "wasm32"))]
-use std::time::{Duration, Instant};
+use crate::cipher_suite::CipherSuite;
+use crate::frame_transform::FrameTransformer;
+use crate::sframe::{SframeHeader, SframeKeys};
 
 /// Media encryption key manager
 ///
 /// Per MOQ_MARMOT_AV_SPEC.md:
 /// - base = MLS-Exporter("moq-media-base-v1", sender_leaf || track_label || epoch_bytes, 32)
-/// - K_gen, N_salt = HKDF(base, "k"/"n" || gen)
-/// - Generation = MSB of 32-bit frame counter; cache ~10s
+/// - KID = HKDF-Expand(base, "sframe-kid" || suite label, 8)
+/// - key and salt from base and KID per RFC 9605 section 4.4 (see
+///   [`crate::sframe`])
+/// - AEAD and key size follow the track's [`CipherSuite`]
+///
+/// One instance covers a single (sender, track, epoch) base key, so the
+/// replay window it carries is naturally per sender and per track.
 pub struct MediaCrypto {
-    base_key: [u8; 32],
-    key_cache: HashMap<u8, CachedGeneration>,
-    #[cfg(not(target_arch = "wasm32"))]
-    cache_ttl: Duration,
-}
-
-struct CachedGeneration {
-    aead_key: [u8; 16],
-    nonce_salt: [u8; 12],
-    #[cfg(not(target_arch = "wasm32"))]
-    created_at: Instant,
+    suite: CipherSuite,
+    key_id: u64,
+    keys: SframeKeys,
+    replay_window: ReplayWindow,
 }
 
 impl MediaCrypto {
     /// Create new MediaCrypto with base key from MLS exporter
-    pub fn new(base_key: [u8; 32]) -> Self {
-        Self {
-            base_key,
-            key_cache: HashMap::new(),
-            #[cfg(not(target_arch = "wasm32"))]
-            cache_ttl: Duration::from_secs(10),
-        }
+    pub fn new(base_key: impl Into<MediaBaseKey>) -> Self {
+        Self::with_replay_window(base_key, DEFAULT_REPLAY_WINDOW)
     }
 
-    /// Derive AEAD key and nonce salt for a given generation using HKDF
-    fn derive_generation_keys(&self, generation: u8) -> Result<([u8; 16], [u8; 12])> {
-        let hkdf = Hkdf::<Sha256>::new(None, &self.base_key);
-
-        // Derive AEAD key: HKDF(base, "k" || gen)
-        let mut aead_key = [0u8; 16];
-        let k_info = [b'k', generation];
-        hkdf.expand(&k_info, &mut aead_key)
-            .map_err(|_| anyhow!("HKDF expand failed for key"))?;
-
-        // Derive nonce salt: HKDF(base, "n" || gen)
-        let mut nonce_salt = [0u8; 12];
-        let n_info = [b'n', generation];
-        hkdf.expand(&n_info, &mut nonce_salt)
-            .map_err(|_| anyhow!("HKDF expand failed for nonce"))?;
+    /// Create new MediaCrypto using the given AEAD suite
+    pub fn with_suite(base_key: impl Into<MediaBaseKey>, suite: CipherSuite) -> Self {
+        Self::build(base_key.into(), suite, DEFAULT_REPLAY_WINDOW)
+    }
 
-        Ok((aead_key, nonce_salt))
+    /// Create new MediaCrypto with a custom anti-replay window size
+    ///
+    /// `window_size` is the number of frame counters behind the highest
+    /// accepted counter that may still arrive out of order.
+    pub fn with_replay_window(base_key: impl Into<MediaBaseKey>, window_size: u32) -> Self {
+        Self::build(base_key.into(), CipherSuite::default(), window_size)
     }
 
-    /// Get or derive keys for a generation, with caching
-    fn get_generation_keys(&mut self, generation: u8) -> Result<(&[u8; 16], &[u8; 12])> {
-        // Evict expired cache entries (not available in WASM)
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            let now = Instant::now();
-            self.key_cache
-                .retain(|_, cached| now.duration_since(cached.created_at) < self.cache_ttl);
+    fn build(base_key: MediaBaseKey, suite: CipherSuite, window_size: u32) -> Self {
+        let key_id = derive_key_id(&base_key, suite);
+        Self {
+            suite,
+            key_id,
+            keys: SframeKeys::derive(base_key.as_bytes(), key_id, suite),
+            replay_window: ReplayWindow::new(window_size),
         }
+    }
 
-        // Get or create cache entry
-        if !self.key_cache.contains_key(&generation) {
-            let (aead_key, nonce_salt) = self.derive_generation_keys(generation)?;
-            self.key_cache.insert(
-                generation,
-                CachedGeneration {
-                    aead_key,
-                    nonce_salt,
-                    #[cfg(not(target_arch = "wasm32"))]
-                    created_at: Instant::now(),
-                },
-            );
-        }
+    /// SFrame key id for this base key
+    pub fn key_id(&self) -> u64 {
+        self.key_id
+    }
 
-        let cached = self.key_cache.get(&generation).unwrap();
-        Ok((&cached.aead_key, &cached.nonce_salt))
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.suite
     }
 
-    /// Construct nonce from frame counter and nonce salt
-    /// Frame counter is 32-bit, MSB becomes generation
-    fn construct_nonce(nonce_salt: &[u8; 12], frame_counter: u32) -> [u8; 12] {
-        let mut nonce = *nonce_salt;
-        // XOR the last 4 bytes with frame counter (big-endian)
-        for (i, byte) in frame_counter.to_be_bytes().iter().enumerate() {
-            nonce[8 + i] ^= byte;
-        }
-        nonce
+    /// Encrypt media frame under the next allocated frame counter
+    ///
+    /// The counter comes from the [`FrameCounter`] shared by every instance
+    /// over the same base key, so nonces never repeat under one key however
+    /// many sessions use it. `aad` receives the
+    /// counter so callers can bind it (e.g. as the frame index); if it fails
+    /// the counter is left for the next frame.
+    ///
+    /// Returns the counter alongside the ciphertext; fails with
+    /// [`CounterExhausted`] once the 32-bit space is used up, at which
+    /// point the base key must be refreshed.
+    pub fn encrypt_next<F>(&mut self, plaintext: &[u8], aad: F) -> Result<(u32, Vec<u8>)>
+    where
+        F: FnOnce(u32) -> Result<Vec<u8>>,
+    {
+        let frame_counter = with_frame_counter(self.key_id, |counter| counter.peek())?;
+        let aad = aad(frame_counter)?;
+        let ciphertext = self.encrypt(plaintext, frame_counter, &aad)?;
+        Ok((frame_counter, ciphertext))
     }
 
-    /// Encrypt media frame
+    /// Encrypt media frame under `frame_counter`
+    ///
+    /// Callers outside this module go through [`MediaCrypto::encrypt_next`],
+    /// so a counter is never picked by hand; the shared allocator still
+    /// skips past any counter used here.
     ///
     /// # Arguments
     /// * `plaintext` - Raw media payload to encrypt
-    /// * `frame_counter` - 32-bit frame counter (MSB = generation)
+    /// * `frame_counter` - 32-bit frame counter, the SFrame CTR
     /// * `aad` - Additional authenticated data (track label, epoch, etc.)
-    pub fn encrypt(&mut self, plaintext: &[u8], frame_counter: u32, aad: &[u8]) -> Result<Vec<u8>> {
-        // Extract generation from MSB of frame counter
-        let generation = (frame_counter >> 24) as u8;
-
-        let (aead_key, nonce_salt) = self.get_generation_keys(generation)?;
-        let nonce_bytes = Self::construct_nonce(nonce_salt, frame_counter);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-
-        let cipher = Aes128Gcm::new(aead_key.into());
-        let payload = Payload {
-            msg: plaintext,
-            aad,
-        };
+    fn encrypt(&mut self, plaintext: &[u8], frame_counter: u32, aad: &[u8]) -> Result<Vec<u8>> {
+        with_frame_counter(self.key_id, |counter| counter.skip_past(frame_counter));
 
-        cipher
-            .encrypt(nonce, payload)
-            .map_err(|e| anyhow!("AEAD encryption failed: {e}"))
+        let nonce = self.keys.nonce(frame_counter);
+        self.suite.seal(&self.keys.key, &nonce, plaintext, aad)
     }
 
     /// Decrypt media frame
     ///
+    /// Frames whose counter was already accepted, or that fall behind the
+    /// replay window, are rejected with a [`ReplayError`] before any AEAD
+    /// work. The window only advances after authentication succeeds, so
+    /// forged counters cannot push legitimate frames out of it.
+    ///
     /// # Arguments
     /// * `ciphertext` - Encrypted media payload
-    /// * `frame_counter` - 32-bit frame counter (MSB = generation)
+    /// * `frame_counter` - 32-bit frame counter, the SFrame CTR
     /// * `aad` - Additional authenticated data (must match encryption)
     pub fn decrypt(
         &mut self,
@@ -136,81 +124,1053 @@ impl MediaCrypto {
         frame_counter: u32,
         aad: &[u8],
     ) -> Result<Vec<u8>> {
-        // Extract generation from MSB of frame counter
-        let generation = (frame_counter >> 24) as u8;
+        self.replay_window.check(frame_counter)?;
+
+        let nonce = self.keys.nonce(frame_counter);
+        let plaintext = self.suite.open(&self.keys.key, &nonce, ciphertext, aad)?;
+
+        self.replay_window.accept(frame_counter);
+        Ok(plaintext)
+    }
+
+    /// Encrypt media frame into SFrame wire format
+    ///
+    /// Output is `header || ciphertext`, where the header carries this base
+    /// key's KID and the allocated counter. As in RFC 9605 the AEAD AAD is
+    /// the header followed by `metadata`, so receivers only need the frame
+    /// and the same metadata to decrypt.
+    pub fn encrypt_sframe(&mut self, plaintext: &[u8], metadata: &[u8]) -> Result<Vec<u8>> {
+        let key_id = self.key_id;
+        let (frame_counter, ciphertext) = self.encrypt_next(plaintext, |counter| {
+            let mut aad = SframeHeader::new(key_id, counter.into()).encode();
+            aad.extend_from_slice(metadata);
+            Ok(aad)
+        })?;
+
+        let mut frame = SframeHeader::new(key_id, frame_counter.into()).encode();
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
+    }
+
+    /// Decrypt a frame produced by [`MediaCrypto::encrypt_sframe`]
+    ///
+    /// Recovers KID and counter from the header; frames for another key id
+    /// are rejected before any AEAD work.
+    pub fn decrypt_sframe(&mut self, frame: &[u8], metadata: &[u8]) -> Result<Vec<u8>> {
+        let (header, header_len) = SframeHeader::parse(frame)?;
+        if header.kid != self.key_id {
+            return Err(anyhow!(
+                "SFrame key id {:#x} does not match session key id {:#x}",
+                header.kid,
+                self.key_id
+            ));
+        }
+        let frame_counter = u32::try_from(header.ctr)
+            .map_err(|_| anyhow!("SFrame counter {:#x} exceeds 32 bits", header.ctr))?;
+
+        let mut aad = frame[..header_len].to_vec();
+        aad.extend_from_slice(metadata);
+        self.decrypt(&frame[header_len..], frame_counter, &aad)
+    }
+}
+
+/// Media base key exported from MLS for one (sender, track, epoch)
+///
+/// Wiped on drop and redacted in `Debug`. Converting from a `[u8; 32]`
+/// copies the bytes; callers should zeroize their own copy.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct MediaBaseKey([u8; 32]);
+
+impl MediaBaseKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("media base key must be 32 bytes, got {}", bytes.len()))?;
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// SFrame key id of this key under `suite`; safe to expose
+    pub fn key_id(&self, suite: CipherSuite) -> u64 {
+        derive_key_id(self, suite)
+    }
+}
+
+impl From<[u8; 32]> for MediaBaseKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// Constant-time comparison, so equality checks do not leak key bytes
+impl PartialEq for MediaBaseKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl Eq for MediaBaseKey {}
+
+impl fmt::Debug for MediaBaseKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MediaBaseKey(<redacted>, kid={:#x})",
+            self.key_id(CipherSuite::default())
+        )
+    }
+}
+
+/// SFrame KID: HKDF(base, "sframe-kid" || suite label), so it is bound to
+/// the same (sender, track, epoch) as the base key without revealing any of
+/// them, and one base key used under two suites gets two KIDs (and two
+/// frame counters)
+fn derive_key_id(base_key: &MediaBaseKey, suite: CipherSuite) -> u64 {
+    let hkdf = Hkdf::<Sha256>::new(None, base_key.as_bytes());
+    let mut kid = [0u8; 8];
+    hkdf.expand_multi_info(&[b"sframe-kid", suite.hkdf_label()], &mut kid)
+        .expect("8 bytes is a valid HKDF-SHA256 output length");
+    u64::from_be_bytes(kid)
+}
+
+/// Shared view of the MLS epoch that media sessions check against
+///
+/// The controller bumps this after merging a commit; a [`MediaSession`]
+/// whose key ring has not caught up then refuses to encrypt.
+#[derive(Clone, Debug, Default)]
+pub struct EpochWatch {
+    epoch: Rc<Cell<u64>>,
+}
+
+impl EpochWatch {
+    pub fn new(epoch: u64) -> Self {
+        Self {
+            epoch: Rc::new(Cell::new(epoch)),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.epoch.get()
+    }
+
+    pub fn set(&self, epoch: u64) {
+        self.epoch.set(epoch);
+    }
+}
+
+/// Number of superseded epochs a [`MediaKeyRing`] keeps by default
+pub const DEFAULT_RETAINED_EPOCHS: usize = 2;
+
+/// How long superseded epoch keys stay usable for decryption by default
+pub const DEFAULT_EPOCH_GRACE: Duration = Duration::from_secs(5);
+
+/// Media base keys for one (sender, track) across recent epochs
+///
+/// Per spec, receivers "briefly accept prior gen/epoch keys" after a
+/// commit. Encryption always uses the current epoch; decryption falls back
+/// to up to `max_previous` superseded epochs, each for `grace` after it
+/// was replaced.
+pub struct MediaKeyRing {
+    suite: CipherSuite,
+    current: EpochKeys,
+    previous: VecDeque<RetiredKeys>,
+    max_previous: usize,
+    grace: Duration,
+}
+
+struct EpochKeys {
+    epoch: u64,
+    crypto: MediaCrypto,
+}
+
+struct RetiredKeys {
+    keys: EpochKeys,
+    retired_at_ms: u64,
+}
+
+/// Plaintext of a decrypted frame and the epoch whose key opened it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptedFrame {
+    pub epoch: u64,
+    pub plaintext: Vec<u8>,
+}
+
+impl MediaKeyRing {
+    pub fn new(epoch: u64, base_key: impl Into<MediaBaseKey>) -> Self {
+        Self::with_suite(epoch, base_key, CipherSuite::default())
+    }
+
+    /// Create a ring whose keys all use `suite`, as announced for the track
+    pub fn with_suite(epoch: u64, base_key: impl Into<MediaBaseKey>, suite: CipherSuite) -> Self {
+        Self::build(
+            epoch,
+            base_key.into(),
+            suite,
+            DEFAULT_RETAINED_EPOCHS,
+            DEFAULT_EPOCH_GRACE,
+        )
+    }
+
+    /// Create a ring keeping at most `max_previous` superseded epochs, each
+    /// for `grace` after rotation
+    pub fn with_retention(
+        epoch: u64,
+        base_key: impl Into<MediaBaseKey>,
+        max_previous: usize,
+        grace: Duration,
+    ) -> Self {
+        Self::build(
+            epoch,
+            base_key.into(),
+            CipherSuite::default(),
+            max_previous,
+            grace,
+        )
+    }
+
+    fn build(
+        epoch: u64,
+        base_key: MediaBaseKey,
+        suite: CipherSuite,
+        max_previous: usize,
+        grace: Duration,
+    ) -> Self {
+        Self {
+            suite,
+            current: EpochKeys {
+                epoch,
+                crypto: MediaCrypto::with_suite(base_key, suite),
+            },
+            previous: VecDeque::new(),
+            max_previous,
+            grace,
+        }
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Epoch used for encryption
+    pub fn current_epoch(&self) -> u64 {
+        self.current.epoch
+    }
+
+    /// Epochs whose keys are still accepted, newest first
+    pub fn retained_epochs(&mut self) -> Vec<u64> {
+        self.prune(now_millis());
+        std::iter::once(self.current.epoch)
+            .chain(self.previous.iter().map(|retired| retired.keys.epoch))
+            .collect()
+    }
+
+    /// Install the base key for a newer epoch, retiring the current one
+    ///
+    /// Rotating to the current or an older epoch is a no-op.
+    pub fn rotate(&mut self, epoch: u64, base_key: impl Into<MediaBaseKey>) {
+        self.rotate_at(epoch, base_key.into(), now_millis());
+    }
+
+    fn rotate_at(&mut self, epoch: u64, base_key: MediaBaseKey, now_ms: u64) {
+        if epoch <= self.current.epoch {
+            return;
+        }
+        let retired = std::mem::replace(
+            &mut self.current,
+            EpochKeys {
+                epoch,
+                crypto: MediaCrypto::with_suite(base_key, self.suite),
+            },
+        );
+        self.previous.push_front(RetiredKeys {
+            keys: retired,
+            retired_at_ms: now_ms,
+        });
+        self.prune(now_ms);
+    }
+
+    /// Keys for the current epoch
+    pub fn current_mut(&mut self) -> &mut MediaCrypto {
+        &mut self.current.crypto
+    }
+
+    /// Run `decrypt` against the current key, then each retained epoch
+    /// newest first, returning the first success
+    ///
+    /// If every key fails, the current epoch's error is returned.
+    pub fn decrypt_with<F>(&mut self, decrypt: F) -> Result<DecryptedFrame>
+    where
+        F: FnMut(&mut MediaCrypto) -> Result<Vec<u8>>,
+    {
+        self.decrypt_with_at(decrypt, now_millis())
+    }
+
+    fn decrypt_with_at<F>(&mut self, mut decrypt: F, now_ms: u64) -> Result<DecryptedFrame>
+    where
+        F: FnMut(&mut MediaCrypto) -> Result<Vec<u8>>,
+    {
+        self.prune(now_ms);
+        let current_err = match decrypt(&mut self.current.crypto) {
+            Ok(plaintext) => {
+                return Ok(DecryptedFrame {
+                    epoch: self.current.epoch,
+                    plaintext,
+                })
+            }
+            Err(err) => err,
+        };
+        for retired in self.previous.iter_mut() {
+            if let Ok(plaintext) = decrypt(&mut retired.keys.crypto) {
+                return Ok(DecryptedFrame {
+                    epoch: retired.keys.epoch,
+                    plaintext,
+                });
+            }
+        }
+        Err(current_err)
+    }
+
+    /// Decrypt an SFrame with whichever retained key its KID names
+    pub fn decrypt_sframe(&mut self, frame: &[u8], metadata: &[u8]) -> Result<DecryptedFrame> {
+        self.decrypt_sframe_at(frame, metadata, now_millis())
+    }
+
+    fn decrypt_sframe_at(
+        &mut self,
+        frame: &[u8],
+        metadata: &[u8],
+        now_ms: u64,
+    ) -> Result<DecryptedFrame> {
+        let (header, _) = SframeHeader::parse(frame)?;
+        self.prune(now_ms);
+        let keys = self
+            .keys_mut()
+            .find(|keys| keys.crypto.key_id() == header.kid)
+            .ok_or_else(|| anyhow!("no retained media key for SFrame key id {:#x}", header.kid))?;
+        Ok(DecryptedFrame {
+            epoch: keys.epoch,
+            plaintext: keys.crypto.decrypt_sframe(frame, metadata)?,
+        })
+    }
+
+    /// Epoch of the retained key with SFrame key id `key_id`
+    pub fn epoch_for_key_id(&mut self, key_id: u64) -> Option<u64> {
+        self.prune(now_millis());
+        self.keys_mut()
+            .find(|keys| keys.crypto.key_id() == key_id)
+            .map(|keys| keys.epoch)
+    }
+
+    fn keys_mut(&mut self) -> impl Iterator<Item = &mut EpochKeys> {
+        std::iter::once(&mut self.current)
+            .chain(self.previous.iter_mut().map(|retired| &mut retired.keys))
+    }
+
+    fn prune(&mut self, now_ms: u64) {
+        let grace_ms = u64::try_from(self.grace.as_millis()).unwrap_or(u64::MAX);
+        self.previous.truncate(self.max_previous);
+        self.previous
+            .retain(|retired| now_ms.saturating_sub(retired.retired_at_ms) < grace_ms);
+    }
+}
+
+/// Wall-clock milliseconds for grace periods (`Instant` is unavailable in WASM)
+fn now_millis() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now() as u64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+/// Long-lived media crypto state for one (sender, track)
+///
+/// Owns a [`MediaKeyRing`] so the derived keys and replay window persist
+/// across frames. The ring may be shared with the controller, which
+/// rotates it after each commit; encryption fails with [`SessionExpired`]
+/// if the ring falls behind the shared [`EpochWatch`].
+pub struct MediaSession {
+    keys: Rc<RefCell<MediaKeyRing>>,
+    watch: EpochWatch,
+}
+
+impl MediaSession {
+    pub fn new(base_key: impl Into<MediaBaseKey>, epoch: u64, watch: EpochWatch) -> Self {
+        Self::with_keys(
+            Rc::new(RefCell::new(MediaKeyRing::new(epoch, base_key))),
+            watch,
+        )
+    }
+
+    /// Session over an existing (possibly shared) key ring
+    pub fn with_keys(keys: Rc<RefCell<MediaKeyRing>>, watch: EpochWatch) -> Self {
+        Self { keys, watch }
+    }
+
+    /// Epoch the session currently encrypts under
+    pub fn epoch(&self) -> u64 {
+        self.keys.borrow().current_epoch()
+    }
+
+    /// Whether the session's current key matches the group's epoch
+    pub fn is_current(&self) -> bool {
+        self.watch.get() == self.epoch()
+    }
+
+    fn ensure_current(&self) -> Result<()> {
+        if self.is_current() {
+            Ok(())
+        } else {
+            Err(SessionExpired {
+                session_epoch: self.epoch(),
+                current_epoch: self.watch.get(),
+            }
+            .into())
+        }
+    }
+
+    /// SFrame key id of the session's current base key
+    pub fn key_id(&self) -> u64 {
+        self.keys.borrow_mut().current_mut().key_id()
+    }
+
+    /// Epoch of a still-accepted key, e.g. to rebuild an incoming frame's AAD
+    pub fn epoch_for_key_id(&self, key_id: u64) -> Option<u64> {
+        self.keys.borrow_mut().epoch_for_key_id(key_id)
+    }
+
+    /// Encrypt into SFrame wire format; see [`MediaCrypto::encrypt_sframe`]
+    pub fn encrypt_sframe(&mut self, plaintext: &[u8], metadata: &[u8]) -> Result<Vec<u8>> {
+        self.ensure_current()?;
+        self.keys
+            .borrow_mut()
+            .current_mut()
+            .encrypt_sframe(plaintext, metadata)
+    }
+
+    /// Decrypt an SFrame with the current or a retained epoch key
+    pub fn decrypt_sframe(&mut self, frame: &[u8], metadata: &[u8]) -> Result<DecryptedFrame> {
+        self.keys.borrow_mut().decrypt_sframe(frame, metadata)
+    }
+
+    /// Encrypt with codec-aware partial encryption; see [`FrameTransformer`]
+    pub fn encrypt_codec_frame(
+        &mut self,
+        transformer: &FrameTransformer,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.ensure_current()?;
+        transformer.encrypt(self.keys.borrow_mut().current_mut(), frame, metadata)
+    }
+
+    /// Decrypt a frame produced by [`MediaSession::encrypt_codec_frame`]
+    pub fn decrypt_codec_frame(
+        &mut self,
+        transformer: &FrameTransformer,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<DecryptedFrame> {
+        self.keys
+            .borrow_mut()
+            .decrypt_with(|crypto| transformer.decrypt(crypto, frame, metadata))
+    }
+
+    /// Encrypt under the next allocated frame counter; see
+    /// [`MediaCrypto::encrypt_next`]
+    pub fn encrypt_next<F>(&mut self, plaintext: &[u8], aad: F) -> Result<(u32, Vec<u8>)>
+    where
+        F: FnOnce(u32) -> Result<Vec<u8>>,
+    {
+        self.ensure_current()?;
+        self.keys
+            .borrow_mut()
+            .current_mut()
+            .encrypt_next(plaintext, aad)
+    }
+
+    pub fn decrypt(
+        &mut self,
+        ciphertext: &[u8],
+        frame_counter: u32,
+        aad: &[u8],
+    ) -> Result<DecryptedFrame> {
+        self.keys
+            .borrow_mut()
+            .decrypt_with(|crypto| crypto.decrypt(ciphertext, frame_counter, aad))
+    }
+}
+
+/// A [`MediaSession`] was used after the group moved to a new epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionExpired {
+    pub session_epoch: u64,
+    pub current_epoch: u64,
+}
+
+impl fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "media session for epoch {} expired (group at epoch {})",
+            self.session_epoch, self.current_epoch
+        )
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+/// Publisher-side frame counter allocator
+///
+/// Hands out strictly increasing 32-bit counters. The generation byte (MSB)
+/// advances on its own when the low 24 bits wrap; after `u32::MAX` the
+/// allocator is exhausted for the current base key.
+#[derive(Debug, Default)]
+pub struct FrameCounter {
+    next: u64,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate the next unused counter
+    pub fn allocate(&mut self) -> std::result::Result<u32, CounterExhausted> {
+        let counter = self.peek()?;
+        self.next += 1;
+        Ok(counter)
+    }
+
+    /// The counter [`FrameCounter::allocate`] would hand out next
+    pub fn peek(&self) -> std::result::Result<u32, CounterExhausted> {
+        u32::try_from(self.next).map_err(|_| CounterExhausted)
+    }
+
+    /// Generation the next allocated counter will fall in
+    pub fn generation(&self) -> Option<u8> {
+        u32::try_from(self.next)
+            .ok()
+            .map(|counter| (counter >> 24) as u8)
+    }
+
+    /// Make sure `counter` is never handed out again
+    fn skip_past(&mut self, counter: u32) {
+        self.next = self.next.max(u64::from(counter) + 1);
+    }
+}
+
+thread_local! {
+    static FRAME_COUNTERS: RefCell<HashMap<u64, FrameCounter>> = RefCell::new(HashMap::new());
+}
+
+/// Run `f` on the frame counter of the base key with SFrame key id `key_id`
+///
+/// There is one allocator per derived key for the life of the thread (the
+/// page, in WASM), no matter how many [`MediaCrypto`] instances are built
+/// over the key or when they are dropped; entries are never evicted, since
+/// forgetting one would hand out used counters again. Counters are not
+/// persisted; a restarted process never gets the keys it used before, as
+/// identities loaded from storage refuse their own media keys until a
+/// commit moves the group on (see
+/// [`IdentityHandle::derive_media_base_key`](crate::controller::services::IdentityHandle::derive_media_base_key)).
+fn with_frame_counter<R>(key_id: u64, f: impl FnOnce(&mut FrameCounter) -> R) -> R {
+    FRAME_COUNTERS.with(|counters| f(counters.borrow_mut().entry(key_id).or_default()))
+}
+
+/// Every 32-bit frame counter has been used under the current base key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterExhausted;
+
+impl fmt::Display for CounterExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame counter space exhausted; media key refresh required"
+        )
+    }
+}
+
+impl std::error::Error for CounterExhausted {}
+
+/// Default anti-replay window, in frame counters (~2.5s of 20ms audio)
+pub const DEFAULT_REPLAY_WINDOW: u32 = 128;
+
+/// Reason a frame was rejected by the anti-replay window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// Counter was already accepted inside the window
+    Replayed { frame_counter: u32 },
+    /// Counter is older than the window can track
+    TooOld { frame_counter: u32, highest: u32 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Replayed { frame_counter } => {
+                write!(f, "replayed frame counter {frame_counter:#010x}")
+            }
+            ReplayError::TooOld {
+                frame_counter,
+                highest,
+            } => write!(
+                f,
+                "frame counter {frame_counter:#010x} too old (highest {highest:#010x})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Sliding anti-replay window over the full 32-bit frame counter
+///
+/// SRTP/SFrame style: tracks the highest accepted counter plus a bitmap of
+/// the `size` counters below it. Operating on the whole counter (not the low
+/// 24 bits) keeps ordering intact across generation rollover.
+struct ReplayWindow {
+    size: u32,
+    highest: Option<u32>,
+    bitmap: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new(size: u32) -> Self {
+        let size = size.max(1);
+        let words = size.div_ceil(64) as usize;
+        Self {
+            size,
+            highest: None,
+            bitmap: vec![0; words],
+        }
+    }
+
+    fn bit(&self, counter: u32) -> (usize, u64) {
+        let slot = counter as usize % (self.bitmap.len() * 64);
+        (slot / 64, 1u64 << (slot % 64))
+    }
+
+    /// Verify a counter without recording it
+    fn check(&self, counter: u32) -> std::result::Result<(), ReplayError> {
+        let Some(highest) = self.highest else {
+            return Ok(());
+        };
+        if counter > highest {
+            return Ok(());
+        }
+        if highest - counter >= self.size {
+            return Err(ReplayError::TooOld {
+                frame_counter: counter,
+                highest,
+            });
+        }
+        let (word, mask) = self.bit(counter);
+        if self.bitmap[word] & mask != 0 {
+            return Err(ReplayError::Replayed {
+                frame_counter: counter,
+            });
+        }
+        Ok(())
+    }
+
+    /// Record a counter that passed `check` and authenticated
+    fn accept(&mut self, counter: u32) {
+        match self.highest {
+            Some(highest) if counter <= highest => {}
+            Some(highest) => {
+                // Clear the slots the window slides over
+                let advance = counter - highest;
+                if advance as usize >= self.bitmap.len() * 64 {
+                    self.bitmap.iter_mut().for_each(|word| *word = 0);
+                } else {
+                    for skipped in highest + 1..=counter {
+                        let (word, mask) = self.bit(skipped);
+                        self.bitmap[word] &= !mask;
+                    }
+                }
+                self.highest = Some(counter);
+            }
+            None => self.highest = Some(counter),
+        }
+        let (word, mask) = self.bit(counter);
+        self.bitmap[word] |= mask;
+    }
+}
+
+/// Encoding version written as the first byte of every AAD
+pub const AAD_ENCODING_VERSION: u8 = 1;
+
+/// Fields an AAD can bind, in canonical (tag) order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AadField {
+    Version,
+    GroupRoot,
+    TrackLabel,
+    Epoch,
+    GroupSequence,
+    FrameIndex,
+    Keyframe,
+    Codec,
+    TemporalLayer,
+    SpatialLayer,
+    Discardable,
+}
 
-        let (aead_key, nonce_salt) = self.get_generation_keys(generation)?;
-        let nonce_bytes = Self::construct_nonce(nonce_salt, frame_counter);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+impl AadField {
+    const ALL: [AadField; 11] = [
+        AadField::Version,
+        AadField::GroupRoot,
+        AadField::TrackLabel,
+        AadField::Epoch,
+        AadField::GroupSequence,
+        AadField::FrameIndex,
+        AadField::Keyframe,
+        AadField::Codec,
+        AadField::TemporalLayer,
+        AadField::SpatialLayer,
+        AadField::Discardable,
+    ];
+
+    fn tag(self) -> u8 {
+        self as u8 + 1
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(tag).checked_sub(1)?).copied()
+    }
+}
 
-        let cipher = Aes128Gcm::new(aead_key.into());
-        let payload = Payload {
-            msg: ciphertext,
-            aad,
+impl fmt::Display for AadField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AadField::Version => "version",
+            AadField::GroupRoot => "group_root",
+            AadField::TrackLabel => "track_label",
+            AadField::Epoch => "epoch",
+            AadField::GroupSequence => "group_sequence",
+            AadField::FrameIndex => "frame_index",
+            AadField::Keyframe => "keyframe",
+            AadField::Codec => "codec",
+            AadField::TemporalLayer => "temporal_layer",
+            AadField::SpatialLayer => "spatial_layer",
+            AadField::Discardable => "discardable",
         };
+        f.write_str(name)
+    }
+}
 
-        cipher
-            .decrypt(nonce, payload)
-            .map_err(|e| anyhow!("AEAD decryption failed: {e}"))
+/// Decoded AAD contents; absent fields were not bound
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AadFields {
+    #[serde(default)]
+    pub version: Option<u8>,
+    #[serde(default)]
+    pub group_root: Option<String>,
+    #[serde(default)]
+    pub track_label: Option<String>,
+    #[serde(default)]
+    pub epoch: Option<u64>,
+    #[serde(default)]
+    pub group_sequence: Option<u64>,
+    #[serde(default)]
+    pub frame_index: Option<u64>,
+    #[serde(default)]
+    pub keyframe: Option<bool>,
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub temporal_layer: Option<u8>,
+    #[serde(default)]
+    pub spatial_layer: Option<u8>,
+    #[serde(default)]
+    pub discardable: Option<bool>,
+}
+
+impl AadFields {
+    /// Encode as `encoding_version || (tag || u16_be(len) || value)*`
+    ///
+    /// Fields are written in tag order, so the output is canonical and no
+    /// two distinct field sets share an encoding. A value longer than a
+    /// `u16` length can express is rejected rather than truncated.
+    pub fn encode(&self) -> std::result::Result<Vec<u8>, AadError> {
+        let mut out = vec![AAD_ENCODING_VERSION];
+        for field in AadField::ALL {
+            if let Some(value) = self.value_bytes(field) {
+                let len = u16::try_from(value.len()).map_err(|_| AadError::FieldTooLong {
+                    field,
+                    len: value.len(),
+                })?;
+                out.push(field.tag());
+                out.extend_from_slice(&len.to_be_bytes());
+                out.extend_from_slice(&value);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Parse an AAD produced by [`AadFields::encode`]
+    pub fn parse(aad: &[u8]) -> std::result::Result<Self, AadError> {
+        let (&encoding, mut rest) = aad
+            .split_first()
+            .ok_or_else(|| AadError::Malformed("empty AAD".into()))?;
+        if encoding != AAD_ENCODING_VERSION {
+            return Err(AadError::UnsupportedEncoding(encoding));
+        }
+
+        let mut fields = AadFields::default();
+        let mut previous: Option<AadField> = None;
+        while let Some((&tag, tail)) = rest.split_first() {
+            let field = AadField::from_tag(tag)
+                .ok_or_else(|| AadError::Malformed(format!("unknown field tag {tag}")))?;
+            if previous.is_some_and(|prev| prev >= field) {
+                return Err(AadError::Malformed(format!(
+                    "field {field} out of order or repeated"
+                )));
+            }
+            if tail.len() < 2 {
+                return Err(AadError::Malformed(format!("truncated length for {field}")));
+            }
+            let len = usize::from(u16::from_be_bytes([tail[0], tail[1]]));
+            let value = tail
+                .get(2..2 + len)
+                .ok_or_else(|| AadError::Malformed(format!("truncated value for {field}")))?;
+            fields.set_value(field, value)?;
+            previous = Some(field);
+            rest = &tail[2 + len..];
+        }
+        Ok(fields)
+    }
+
+    /// Compare against a received AAD, naming the first field that differs
+    pub fn verify(&self, aad: &[u8]) -> std::result::Result<(), AadError> {
+        let actual = Self::parse(aad)?;
+        for field in AadField::ALL {
+            let expected_value = self.display_value(field);
+            let actual_value = actual.display_value(field);
+            if expected_value != actual_value {
+                return Err(AadError::Mismatch {
+                    field,
+                    expected: expected_value,
+                    actual: actual_value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn value_bytes(&self, field: AadField) -> Option<Vec<u8>> {
+        match field {
+            AadField::Version => self.version.map(|v| vec![v]),
+            AadField::GroupRoot => self.group_root.as_ref().map(|v| v.as_bytes().to_vec()),
+            AadField::TrackLabel => self.track_label.as_ref().map(|v| v.as_bytes().to_vec()),
+            AadField::Epoch => self.epoch.map(|v| v.to_be_bytes().to_vec()),
+            AadField::GroupSequence => self.group_sequence.map(|v| v.to_be_bytes().to_vec()),
+            AadField::FrameIndex => self.frame_index.map(|v| v.to_be_bytes().to_vec()),
+            AadField::Keyframe => self.keyframe.map(|v| vec![u8::from(v)]),
+            AadField::Codec => self.codec.as_ref().map(|v| v.as_bytes().to_vec()),
+            AadField::TemporalLayer => self.temporal_layer.map(|v| vec![v]),
+            AadField::SpatialLayer => self.spatial_layer.map(|v| vec![v]),
+            AadField::Discardable => self.discardable.map(|v| vec![u8::from(v)]),
+        }
+    }
+
+    fn set_value(&mut self, field: AadField, value: &[u8]) -> std::result::Result<(), AadError> {
+        let invalid = || AadError::Malformed(format!("invalid value for {field}"));
+        let byte = || match value {
+            [b] => Ok(*b),
+            _ => Err(invalid()),
+        };
+        let flag = || match value {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(invalid()),
+        };
+        let number = || {
+            <[u8; 8]>::try_from(value)
+                .map(u64::from_be_bytes)
+                .map_err(|_| invalid())
+        };
+        let text = || {
+            std::str::from_utf8(value)
+                .map(str::to_string)
+                .map_err(|_| invalid())
+        };
+        match field {
+            AadField::Version => self.version = Some(byte()?),
+            AadField::GroupRoot => self.group_root = Some(text()?),
+            AadField::TrackLabel => self.track_label = Some(text()?),
+            AadField::Epoch => self.epoch = Some(number()?),
+            AadField::GroupSequence => self.group_sequence = Some(number()?),
+            AadField::FrameIndex => self.frame_index = Some(number()?),
+            AadField::Keyframe => self.keyframe = Some(flag()?),
+            AadField::Codec => self.codec = Some(text()?),
+            AadField::TemporalLayer => self.temporal_layer = Some(byte()?),
+            AadField::SpatialLayer => self.spatial_layer = Some(byte()?),
+            AadField::Discardable => self.discardable = Some(flag()?),
+        }
+        Ok(())
+    }
+
+    fn display_value(&self, field: AadField) -> Option<String> {
+        match field {
+            AadField::Version => self.version.map(|v| v.to_string()),
+            AadField::GroupRoot => self.group_root.clone(),
+            AadField::TrackLabel => self.track_label.clone(),
+            AadField::Epoch => self.epoch.map(|v| v.to_string()),
+            AadField::GroupSequence => self.group_sequence.map(|v| v.to_string()),
+            AadField::FrameIndex => self.frame_index.map(|v| v.to_string()),
+            AadField::Keyframe => self.keyframe.map(|v| v.to_string()),
+            AadField::Codec => self.codec.clone(),
+            AadField::TemporalLayer => self.temporal_layer.map(|v| v.to_string()),
+            AadField::SpatialLayer => self.spatial_layer.map(|v| v.to_string()),
+            AadField::Discardable => self.discardable.map(|v| v.to_string()),
+        }
+    }
+}
+
+/// Why a received AAD was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AadError {
+    UnsupportedEncoding(u8),
+    Malformed(String),
+    /// A value exceeds the 65535 bytes a field can hold
+    FieldTooLong {
+        field: AadField,
+        len: usize,
+    },
+    /// `None` on either side means the field was not bound there
+    Mismatch {
+        field: AadField,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+impl fmt::Display for AadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AadError::UnsupportedEncoding(version) => {
+                write!(f, "unsupported AAD encoding version {version}")
+            }
+            AadError::Malformed(reason) => write!(f, "malformed AAD: {reason}"),
+            AadError::FieldTooLong { field, len } => {
+                write!(f, "AAD {field} is {len} bytes, more than {}", u16::MAX)
+            }
+            AadError::Mismatch {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "AAD {field} mismatch: expected {}, got {}",
+                expected.as_deref().unwrap_or("<absent>"),
+                actual.as_deref().unwrap_or("<absent>")
+            ),
+        }
     }
 }
 
+impl std::error::Error for AadError {}
+
 /// Additional Authenticated Data builder
 ///
 /// Per spec: AAD binds version, group root, track label, epoch,
-/// (group_seq, frame_idx), and codec hints
+/// (group_seq, frame_idx), and codec hints. Encoded as length-prefixed
+/// fields (see [`AadFields::encode`]) so field boundaries are unambiguous.
+#[derive(Debug, Clone, Default)]
 pub struct AadBuilder {
-    parts: Vec<Vec<u8>>,
+    fields: AadFields,
 }
 
 impl AadBuilder {
     pub fn new() -> Self {
-        Self { parts: Vec::new() }
+        Self::default()
     }
 
     pub fn version(mut self, version: u8) -> Self {
-        self.parts.push(vec![version]);
+        self.fields.version = Some(version);
         self
     }
 
     pub fn group_root(mut self, root: &str) -> Self {
-        self.parts.push(root.as_bytes().to_vec());
+        self.fields.group_root = Some(root.to_string());
         self
     }
 
     pub fn track_label(mut self, label: &str) -> Self {
-        self.parts.push(label.as_bytes().to_vec());
+        self.fields.track_label = Some(label.to_string());
         self
     }
 
     pub fn epoch(mut self, epoch: u64) -> Self {
-        self.parts.push(epoch.to_be_bytes().to_vec());
+        self.fields.epoch = Some(epoch);
         self
     }
 
     pub fn group_sequence(mut self, seq: u64) -> Self {
-        self.parts.push(seq.to_be_bytes().to_vec());
+        self.fields.group_sequence = Some(seq);
         self
     }
 
     pub fn frame_index(mut self, idx: u64) -> Self {
-        self.parts.push(idx.to_be_bytes().to_vec());
+        self.fields.frame_index = Some(idx);
         self
     }
 
     pub fn keyframe(mut self, is_keyframe: bool) -> Self {
-        self.parts.push(vec![if is_keyframe { 1 } else { 0 }]);
+        self.fields.keyframe = Some(is_keyframe);
         self
     }
 
-    pub fn build(self) -> Vec<u8> {
-        self.parts.concat()
+    /// Codec name from the track's `CodecInfo`
+    pub fn codec(mut self, name: &str) -> Self {
+        self.fields.codec = Some(name.to_string());
+        self
+    }
+
+    /// SVC/simulcast temporal layer id
+    pub fn temporal_layer(mut self, layer: u8) -> Self {
+        self.fields.temporal_layer = Some(layer);
+        self
+    }
+
+    /// SVC/simulcast spatial layer id
+    pub fn spatial_layer(mut self, layer: u8) -> Self {
+        self.fields.spatial_layer = Some(layer);
+        self
+    }
+
+    /// Frame is not referenced by later frames and may be dropped
+    pub fn discardable(mut self, discardable: bool) -> Self {
+        self.fields.discardable = Some(discardable);
+        self
+    }
+
+    /// Fields bound so far, e.g. to [`AadFields::verify`] a received AAD
+    pub fn fields(&self) -> &AadFields {
+        &self.fields
     }
-}
 
-impl Default for AadBuilder {
-    fn default() -> Self {
-        Self::new()
+    pub fn build(self) -> std::result::Result<Vec<u8>, AadError> {
+        self.fields.encode()
     }
 }
 
@@ -291,32 +1251,322 @@ mod tests {
         let pt_gen1 = crypto.decrypt(&ct_gen1, counter_gen1, aad).unwrap();
         assert_eq!(pt_gen1, plaintext);
 
-        // Ciphertexts should differ (different generation keys)
+        // Ciphertexts should differ (different nonces)
         assert_ne!(ct_gen0, ct_gen1);
     }
 
     #[test]
-    fn test_key_caching() {
-        let base_key = [7u8; 32];
-        let mut crypto = MediaCrypto::new(base_key);
+    fn test_replayed_frame_rejected() {
+        let mut crypto = MediaCrypto::new([3u8; 32]);
+        let aad = b"aad";
+
+        let ct = crypto.encrypt(b"frame", 10, aad).unwrap();
+        assert!(crypto.decrypt(&ct, 10, aad).is_ok());
+
+        let err = crypto.decrypt(&ct, 10, aad).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ReplayError>(),
+            Some(&ReplayError::Replayed { frame_counter: 10 })
+        );
+    }
+
+    #[test]
+    fn test_replay_window_accepts_reordering_and_rejects_too_old() {
+        let mut crypto = MediaCrypto::with_replay_window([4u8; 32], 16);
+        let aad = b"aad";
+
+        let frames: Vec<(u32, Vec<u8>)> = [5u32, 20, 30, 12]
+            .iter()
+            .map(|&counter| (counter, crypto.encrypt(b"frame", counter, aad).unwrap()))
+            .collect();
+
+        assert!(crypto.decrypt(&frames[0].1, 5, aad).is_ok());
+        assert!(crypto.decrypt(&frames[2].1, 30, aad).is_ok());
+        // 20 arrives late but is still inside the window
+        assert!(crypto.decrypt(&frames[1].1, 20, aad).is_ok());
+
+        // 12 is 18 behind the highest counter (30) with a window of 16
+        let err = crypto.decrypt(&frames[3].1, 12, aad).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ReplayError>(),
+            Some(&ReplayError::TooOld {
+                frame_counter: 12,
+                highest: 30
+            })
+        );
+    }
+
+    #[test]
+    fn test_replay_window_ignores_failed_authentication() {
+        let mut crypto = MediaCrypto::new([5u8; 32]);
+        let aad = b"aad";
+
+        let ct = crypto.encrypt(b"frame", 7, aad).unwrap();
+        // Forged frame with a far-ahead counter must not slide the window
+        assert!(crypto.decrypt(&ct, 1_000_000, aad).is_err());
+        assert!(crypto.decrypt(&ct, 7, aad).is_ok());
+    }
+
+    #[test]
+    fn test_replay_window_across_generation_rollover() {
+        let mut crypto = MediaCrypto::new([6u8; 32]);
+        let aad = b"aad";
+
+        let last_gen0 = 0x00_FF_FF_FF;
+        let first_gen1 = 0x01_00_00_00;
+        let ct0 = crypto.encrypt(b"frame", last_gen0, aad).unwrap();
+        let ct1 = crypto.encrypt(b"frame", first_gen1, aad).unwrap();
+
+        assert!(crypto.decrypt(&ct1, first_gen1, aad).is_ok());
+        // Previous generation's last frame is one behind, not 2^24 ahead
+        assert!(crypto.decrypt(&ct0, last_gen0, aad).is_ok());
+
+        let err = crypto.decrypt(&ct1, first_gen1, aad).unwrap_err();
+        assert!(err.downcast_ref::<ReplayError>().is_some());
+    }
+
+    #[test]
+    fn test_media_session_expires_on_epoch_change() {
+        let watch = EpochWatch::new(3);
+        let mut sender = MediaSession::new([8u8; 32], 3, watch.clone());
+        let mut receiver = MediaSession::new([8u8; 32], 3, watch.clone());
+
+        let (counter, ct) = sender
+            .encrypt_next(b"frame", |_| Ok(b"aad".to_vec()))
+            .unwrap();
+        assert_eq!(
+            receiver.decrypt(&ct, counter, b"aad").unwrap().plaintext,
+            b"frame"
+        );
+        // Replay state lives across calls on the same session
+        assert!(receiver.decrypt(&ct, counter, b"aad").is_err());
+
+        watch.set(4);
+        assert!(!sender.is_current());
+        let err = sender
+            .encrypt_next(b"frame", |_| Ok(b"aad".to_vec()))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SessionExpired>(),
+            Some(&SessionExpired {
+                session_epoch: 3,
+                current_epoch: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_key_ring_accepts_previous_epoch_within_grace() {
+        let mut ring = MediaKeyRing::with_retention(3, [20u8; 32], 2, Duration::from_secs(5));
+        let mut old_sender = MediaCrypto::new([20u8; 32]);
+        let in_flight = old_sender.encrypt_sframe(b"old", b"meta").unwrap();
+
+        ring.rotate_at(4, [21u8; 32].into(), 1_000);
+        assert_eq!(ring.current_epoch(), 4);
+        let fresh = ring.current_mut().encrypt_sframe(b"new", b"meta").unwrap();
+
+        let mut receiver = MediaKeyRing::with_retention(3, [20u8; 32], 2, Duration::from_secs(5));
+        receiver.rotate_at(4, [21u8; 32].into(), 1_000);
+        let decrypted = receiver
+            .decrypt_sframe_at(&in_flight, b"meta", 2_000)
+            .unwrap();
+        assert_eq!(
+            decrypted,
+            DecryptedFrame {
+                epoch: 3,
+                plaintext: b"old".to_vec()
+            }
+        );
+        assert_eq!(
+            receiver
+                .decrypt_sframe_at(&fresh, b"meta", 2_000)
+                .unwrap()
+                .epoch,
+            4
+        );
+
+        // Past the grace period the old key is gone
+        let late = old_sender.encrypt_sframe(b"late", b"meta").unwrap();
+        assert!(receiver.decrypt_sframe_at(&late, b"meta", 6_000).is_err());
+        assert_eq!(receiver.epoch_for_key_id(old_sender.key_id()), None);
+    }
+
+    #[test]
+    fn test_key_ring_trial_decrypt_reports_epoch_and_bounds_retention() {
+        let mut ring = MediaKeyRing::with_retention(1, [30u8; 32], 1, Duration::from_secs(60));
+        let mut epoch1 = MediaCrypto::new([30u8; 32]);
+        let mut epoch2 = MediaCrypto::new([31u8; 32]);
+        let ct1 = epoch1.encrypt(b"one", 7, b"aad").unwrap();
+        let ct2 = epoch2.encrypt(b"two", 7, b"aad").unwrap();
+
+        ring.rotate_at(2, [31u8; 32].into(), 0);
+        ring.rotate_at(3, [32u8; 32].into(), 0);
+        // Stale rotations are ignored
+        ring.rotate_at(2, [99u8; 32].into(), 0);
+        assert_eq!(ring.current_epoch(), 3);
+
+        let decrypted = ring
+            .decrypt_with_at(|crypto| crypto.decrypt(&ct2, 7, b"aad"), 10)
+            .unwrap();
+        assert_eq!(decrypted.epoch, 2);
+        assert_eq!(decrypted.plaintext, b"two");
+
+        // Only one previous epoch is kept, so epoch 1 no longer decrypts
+        assert!(ring
+            .decrypt_with_at(|crypto| crypto.decrypt(&ct1, 7, b"aad"), 10)
+            .is_err());
+    }
+
+    #[test]
+    fn test_media_session_follows_shared_ring_rotation() {
+        let watch = EpochWatch::new(5);
+        let keys = Rc::new(RefCell::new(MediaKeyRing::new(5, [40u8; 32])));
+        let mut publisher = MediaSession::new([40u8; 32], 5, watch.clone());
+        let mut receiver = MediaSession::with_keys(keys.clone(), watch.clone());
+
+        let before = publisher.encrypt_sframe(b"before", b"meta").unwrap();
+        watch.set(6);
+        keys.borrow_mut().rotate(6, [41u8; 32]);
+        assert!(receiver.is_current());
+        assert!(!publisher.is_current());
+
+        let decrypted = receiver.decrypt_sframe(&before, b"meta").unwrap();
+        assert_eq!(decrypted.epoch, 5);
+        assert_eq!(decrypted.plaintext, b"before");
+    }
+
+    #[test]
+    fn test_encrypt_next_allocates_unique_counters() {
+        let mut sender = MediaCrypto::new([9u8; 32]);
+        let mut receiver = MediaCrypto::new([9u8; 32]);
+
+        let (c0, ct0) = sender
+            .encrypt_next(b"one", |counter| Ok(counter.to_be_bytes().to_vec()))
+            .unwrap();
+        let (c1, ct1) = sender
+            .encrypt_next(b"two", |counter| Ok(counter.to_be_bytes().to_vec()))
+            .unwrap();
+        assert_eq!((c0, c1), (0, 1));
+
+        assert_eq!(
+            receiver.decrypt(&ct0, c0, &c0.to_be_bytes()).unwrap(),
+            b"one"
+        );
+        assert_eq!(
+            receiver.decrypt(&ct1, c1, &c1.to_be_bytes()).unwrap(),
+            b"two"
+        );
+
+        // A manually chosen counter is never re-allocated
+        sender.encrypt(b"manual", 40, b"aad").unwrap();
+        let (c2, _) = sender.encrypt_next(b"three", |_| Ok(Vec::new())).unwrap();
+        assert_eq!(c2, 41);
+    }
+
+    #[test]
+    fn test_instances_over_one_key_share_counters() {
+        let mut first = MediaCrypto::new([14u8; 32]);
+        let (c0, _) = first.encrypt_next(b"one", |_| Ok(Vec::new())).unwrap();
+        drop(first);
+
+        // A second session, or one rebuilt after the first was dropped,
+        // continues where the key left off
+        let mut second = MediaCrypto::new([14u8; 32]);
+        let (c1, _) = second.encrypt_next(b"two", |_| Ok(Vec::new())).unwrap();
+        assert_eq!((c0, c1), (0, 1));
+
+        // A failing AAD builder encrypts nothing and uses up no counter
+        let err = second
+            .encrypt_next(b"three", |_| Err(anyhow!("no AAD")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no AAD");
+        let (c2, _) = second.encrypt_next(b"three", |_| Ok(Vec::new())).unwrap();
+        assert_eq!(c2, 2);
+    }
 
-        let plaintext = b"cached";
-        let aad = b"test";
+    #[test]
+    fn test_frame_counter_rolls_generation_and_exhausts() {
+        let mut counter = FrameCounter::new();
+        counter.skip_past(0x00_FF_FF_FE);
+        assert_eq!(counter.generation(), Some(0));
+        assert_eq!(counter.allocate(), Ok(0x00_FF_FF_FF));
+        assert_eq!(counter.generation(), Some(1));
+        assert_eq!(counter.allocate(), Ok(0x01_00_00_00));
+
+        counter.skip_past(u32::MAX - 1);
+        assert_eq!(counter.allocate(), Ok(u32::MAX));
+        assert_eq!(counter.generation(), None);
+        assert_eq!(counter.allocate(), Err(CounterExhausted));
+
+        let mut crypto = MediaCrypto::new([10u8; 32]);
+        crypto.encrypt(b"last", u32::MAX, b"aad").unwrap();
+        let err = crypto
+            .encrypt_next(b"more", |_| Ok(Vec::new()))
+            .unwrap_err();
+        assert!(err.downcast_ref::<CounterExhausted>().is_some());
+    }
 
-        // Encrypt twice with same generation - should use cached key
-        let counter1 = 0x05_00_00_01; // gen=5
-        let counter2 = 0x05_00_00_02; // gen=5
+    #[test]
+    fn test_sframe_roundtrip_recovers_counter_from_header() {
+        let mut sender = MediaCrypto::new([11u8; 32]);
+        let mut receiver = MediaCrypto::new([11u8; 32]);
+        let metadata = b"marmot/root|label|epoch";
+
+        sender.encrypt(b"skip", 0x01_00_00_00, b"aad").unwrap();
+        let frame = sender.encrypt_sframe(b"opus", metadata).unwrap();
+
+        let (header, _) = SframeHeader::parse(&frame).unwrap();
+        assert_eq!(header.kid, sender.key_id());
+        assert_eq!(header.ctr, 0x01_00_00_01);
+        assert_eq!(receiver.decrypt_sframe(&frame, metadata).unwrap(), b"opus");
+    }
 
-        crypto.encrypt(plaintext, counter1, aad).unwrap();
-        assert_eq!(crypto.key_cache.len(), 1);
+    #[test]
+    fn test_key_id_is_bound_to_the_suite() {
+        let base = MediaBaseKey::new([15u8; 32]);
+        let aes128 = MediaCrypto::with_suite(base.clone(), CipherSuite::Aes128Gcm);
+        let mut chacha = MediaCrypto::with_suite(base.clone(), CipherSuite::ChaCha20Poly1305);
+
+        assert_eq!(aes128.key_id(), base.key_id(CipherSuite::Aes128Gcm));
+        assert_eq!(chacha.key_id(), base.key_id(CipherSuite::ChaCha20Poly1305));
+        assert_ne!(aes128.key_id(), chacha.key_id());
+
+        // Each suite has its own counter under its own KID
+        let frame = chacha.encrypt_sframe(b"opus", b"meta").unwrap();
+        let mut aes_sender = MediaCrypto::with_suite(base, CipherSuite::Aes128Gcm);
+        let aes_frame = aes_sender.encrypt_sframe(b"opus", b"meta").unwrap();
+        assert_eq!(SframeHeader::parse(&frame).unwrap().0.ctr, 0);
+        assert_eq!(SframeHeader::parse(&aes_frame).unwrap().0.ctr, 0);
+    }
 
-        crypto.encrypt(plaintext, counter2, aad).unwrap();
-        assert_eq!(crypto.key_cache.len(), 1); // Still cached
+    #[test]
+    fn test_sframe_rejects_foreign_kid_and_tampered_header() {
+        let mut sender = MediaCrypto::new([12u8; 32]);
+        let mut other = MediaCrypto::new([13u8; 32]);
+        let frame = sender.encrypt_sframe(b"opus", b"meta").unwrap();
+
+        assert_ne!(sender.key_id(), other.key_id());
+        assert!(other.decrypt_sframe(&frame, b"meta").is_err());
+
+        // Header is authenticated: rewriting the counter breaks the tag
+        let (header, header_len) = SframeHeader::parse(&frame).unwrap();
+        let mut tampered = SframeHeader::new(header.kid, header.ctr + 1).encode();
+        tampered.extend_from_slice(&frame[header_len..]);
+        let mut receiver = MediaCrypto::new([12u8; 32]);
+        assert!(receiver.decrypt_sframe(&tampered, b"meta").is_err());
+        assert!(receiver.decrypt_sframe(&frame, b"wrong").is_err());
+        assert_eq!(receiver.decrypt_sframe(&frame, b"meta").unwrap(), b"opus");
+    }
 
-        // Different generation should create new cache entry
-        let counter3 = 0x06_00_00_01; // gen=6
-        crypto.encrypt(plaintext, counter3, aad).unwrap();
-        assert_eq!(crypto.key_cache.len(), 2);
+    #[test]
+    fn test_keys_follow_the_sframe_schedule_of_the_kid() {
+        let base_key = MediaBaseKey::new([7u8; 32]);
+        for suite in CipherSuite::ALL {
+            let crypto = MediaCrypto::with_suite(base_key.clone(), suite);
+            let expected = SframeKeys::derive(base_key.as_bytes(), crypto.key_id(), suite);
+            assert_eq!(crypto.keys.key, expected.key, "{}", suite.name());
+            assert_eq!(crypto.keys.salt, expected.salt, "{}", suite.name());
+        }
     }
 
     #[test]
@@ -329,7 +1579,8 @@ mod tests {
             .group_sequence(100)
             .frame_index(42)
             .keyframe(true)
-            .build();
+            .build()
+            .unwrap();
 
         assert!(!aad.is_empty());
         assert!(aad.len() > 10); // Should contain all components
@@ -343,10 +1594,166 @@ mod tests {
             .group_sequence(100)
             .frame_index(42)
             .keyframe(true)
-            .build();
+            .build()
+            .unwrap();
         assert_eq!(aad, aad2);
     }
 
+    #[test]
+    fn test_aad_encoding_is_unambiguous() {
+        let a = AadBuilder::new()
+            .group_root("ab")
+            .track_label("c")
+            .build()
+            .unwrap();
+        let b = AadBuilder::new()
+            .group_root("a")
+            .track_label("bc")
+            .build()
+            .unwrap();
+        assert_ne!(a, b);
+
+        // Field order in the builder does not change the encoding
+        let c = AadBuilder::new()
+            .track_label("c")
+            .group_root("ab")
+            .build()
+            .unwrap();
+        assert_eq!(a, c);
+    }
+
+    #[test]
+    fn test_aad_parse_roundtrip_with_codec_hints() {
+        let builder = AadBuilder::new()
+            .version(1)
+            .group_root("marmot/abc123")
+            .track_label("track001")
+            .epoch(5)
+            .group_sequence(100)
+            .frame_index(42)
+            .keyframe(false)
+            .codec("vp8")
+            .temporal_layer(2)
+            .spatial_layer(1)
+            .discardable(true);
+        let expected = builder.fields().clone();
+        let aad = builder.build().unwrap();
+
+        assert_eq!(AadFields::parse(&aad).unwrap(), expected);
+        assert_eq!(expected.verify(&aad), Ok(()));
+    }
+
+    #[test]
+    fn test_aad_verify_reports_mismatched_field() {
+        let expected = AadBuilder::new()
+            .group_root("marmot/abc")
+            .epoch(5)
+            .frame_index(42);
+        let received = AadBuilder::new()
+            .group_root("marmot/abc")
+            .epoch(6)
+            .frame_index(42)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            expected.fields().verify(&received),
+            Err(AadError::Mismatch {
+                field: AadField::Epoch,
+                expected: Some("5".into()),
+                actual: Some("6".into()),
+            })
+        );
+
+        let missing = AadBuilder::new()
+            .group_root("marmot/abc")
+            .epoch(5)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            expected.fields().verify(&missing),
+            Err(AadError::Mismatch {
+                field: AadField::FrameIndex,
+                actual: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_aad_rejects_oversized_fields() {
+        let label = "x".repeat(usize::from(u16::MAX) + 1);
+        assert_eq!(
+            AadBuilder::new().epoch(1).track_label(&label).build(),
+            Err(AadError::FieldTooLong {
+                field: AadField::TrackLabel,
+                len: label.len(),
+            })
+        );
+        let longest = &label[1..];
+        let aad = AadBuilder::new().track_label(longest).build().unwrap();
+        assert_eq!(
+            AadFields::parse(&aad).unwrap().track_label.as_deref(),
+            Some(longest)
+        );
+    }
+
+    #[test]
+    fn test_aad_parse_rejects_malformed_input() {
+        assert_eq!(
+            AadFields::parse(&[9]),
+            Err(AadError::UnsupportedEncoding(9))
+        );
+        let aad = AadBuilder::new().epoch(1).build().unwrap();
+        assert!(AadFields::parse(&aad[..aad.len() - 1]).is_err());
+
+        // Repeated field
+        let mut repeated = aad.clone();
+        repeated.extend_from_slice(&aad[1..]);
+        assert!(AadFields::parse(&repeated).is_err());
+    }
+
+    #[test]
+    fn test_cipher_suites_roundtrip_and_do_not_interoperate() {
+        let base_key = [50u8; 32];
+        let mut ciphertexts = Vec::new();
+        for suite in CipherSuite::ALL {
+            let mut sender = MediaCrypto::with_suite(base_key, suite);
+            let mut receiver = MediaCrypto::with_suite(base_key, suite);
+            let frame = sender.encrypt_sframe(b"frame", b"meta").unwrap();
+            assert_eq!(receiver.decrypt_sframe(&frame, b"meta").unwrap(), b"frame");
+            ciphertexts.push(frame);
+        }
+        // Same base key, different suite: distinct keys, so no cross-decrypt
+        let mut aes128 = MediaCrypto::with_suite(base_key, CipherSuite::Aes128Gcm);
+        assert!(aes128.decrypt_sframe(&ciphertexts[1], b"meta").is_err());
+        assert!(aes128.decrypt_sframe(&ciphertexts[2], b"meta").is_err());
+    }
+
+    #[test]
+    fn test_key_ring_keeps_suite_across_rotation() {
+        let mut ring = MediaKeyRing::with_suite(1, [60u8; 32], CipherSuite::ChaCha20Poly1305);
+        ring.rotate(2, [61u8; 32]);
+        assert_eq!(
+            ring.current_mut().cipher_suite(),
+            CipherSuite::ChaCha20Poly1305
+        );
+    }
+
+    #[test]
+    fn test_media_base_key_is_redacted_and_zeroized() {
+        let mut key = MediaBaseKey::new([0xabu8; 32]);
+        let debug = format!("{key:?}");
+        assert!(!debug.contains("ab, ab") && !debug.contains("abab"));
+        assert!(debug.contains("redacted"));
+
+        assert!(MediaBaseKey::from_slice(&[1u8; 31]).is_err());
+        assert_eq!(key, MediaBaseKey::new([0xabu8; 32]));
+        assert_ne!(key, MediaBaseKey::new([0xacu8; 32]));
+        key.zeroize();
+        assert_eq!(key.as_bytes(), &[0u8; 32]);
+    }
+
     #[test]
     fn test_different_base_keys_produce_different_ciphertexts() {
         let base_key1 = [1u8; 32];
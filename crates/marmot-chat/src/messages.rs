@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::cipher_suite::CipherSuite;
+
 #[derive(Clone, Debug)]
 pub struct WrapperFrame {
     pub bytes: Vec<u8>,
@@ -10,7 +12,9 @@ pub struct WrapperFrame {
 pub enum WrapperKind {
     Application { author: String, content: String },
     Directory(DirectoryMessage),
+    RootAnnouncement(RootAnnouncement),
     Commit,
+    Proposal,
 }
 
 impl WrapperKind {
@@ -18,7 +22,9 @@ impl WrapperKind {
         match self {
             WrapperKind::Application { .. } => "application",
             WrapperKind::Directory(_) => "directory",
+            WrapperKind::RootAnnouncement(_) => "root-announcement",
             WrapperKind::Commit => "commit",
+            WrapperKind::Proposal => "proposal",
         }
     }
 
@@ -30,7 +36,15 @@ impl WrapperKind {
             WrapperKind::Directory(dir) => {
                 format!("directory: {} tracks from {}", dir.tracks.len(), dir.sender)
             }
+            WrapperKind::RootAnnouncement(announcement) => {
+                format!(
+                    "root announcement: {} roots from {}",
+                    announcement.roots.len(),
+                    announcement.sender
+                )
+            }
             WrapperKind::Commit => "commit".to_string(),
+            WrapperKind::Proposal => "proposal".to_string(),
         }
     }
 }
@@ -46,6 +60,18 @@ pub struct DirectoryMessage {
     pub tracks: Vec<TrackEntry>,
 }
 
+/// Root announcement: MLS application message listing the MoQ roots the
+/// sender can use, old root first, while the group migrates between roots
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RootAnnouncement {
+    /// Sender's public key (hex-encoded Nostr npub)
+    pub sender: String,
+    /// Epoch the announcement was made at
+    pub epoch: u64,
+    /// MoQ roots the sender can reach
+    pub roots: Vec<String>,
+}
+
 /// Individual media track entry in the directory
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TrackEntry {
@@ -56,6 +82,9 @@ pub struct TrackEntry {
     pub kind: TrackKind,
     /// Codec configuration
     pub codec: CodecInfo,
+    /// Media AEAD chosen by the publisher; subscribers must use the same
+    #[serde(default, skip_serializing_if = "CipherSuite::is_default")]
+    pub cipher_suite: CipherSuite,
     /// Optional simulcast layers
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub simulcast: Vec<SimulcastLayer>,
@@ -70,6 +99,26 @@ pub enum TrackKind {
     Screen,
 }
 
+impl TrackKind {
+    /// Wire name, also used as the `kind` input to track label derivation
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackKind::Audio => "audio",
+            TrackKind::Video => "video",
+            TrackKind::Screen => "screen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "audio" => Some(TrackKind::Audio),
+            "video" => Some(TrackKind::Video),
+            "screen" => Some(TrackKind::Screen),
+            _ => None,
+        }
+    }
+}
+
 /// Codec configuration (minimal RTP-ish/hang-ish fields)
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
@@ -119,6 +168,7 @@ mod tests {
                         channels: Some(2),
                         params: vec![],
                     },
+                    cipher_suite: CipherSuite::default(),
                     simulcast: vec![],
                 },
                 TrackEntry {
@@ -130,6 +180,7 @@ mod tests {
                         channels: None,
                         params: vec![],
                     },
+                    cipher_suite: CipherSuite::default(),
                     simulcast: vec![],
                 },
             ],
@@ -182,6 +233,31 @@ mod tests {
         assert_eq!(deserialized, codec);
     }
 
+    #[test]
+    fn test_track_entry_cipher_suite() {
+        let mut track = TrackEntry {
+            label: "track003".to_string(),
+            kind: TrackKind::Audio,
+            codec: CodecInfo {
+                name: "opus".to_string(),
+                clock_rate: None,
+                channels: None,
+                params: vec![],
+            },
+            cipher_suite: CipherSuite::default(),
+            simulcast: vec![],
+        };
+        // Default suite is omitted, and entries without one parse as default
+        let json = serde_json::to_string(&track).unwrap();
+        assert!(!json.contains("cipher_suite"));
+        assert_eq!(serde_json::from_str::<TrackEntry>(&json).unwrap(), track);
+
+        track.cipher_suite = CipherSuite::ChaCha20Poly1305;
+        let json = serde_json::to_string(&track).unwrap();
+        assert!(json.contains("\"cipher_suite\":\"chacha20-poly1305\""));
+        assert_eq!(serde_json::from_str::<TrackEntry>(&json).unwrap(), track);
+    }
+
     #[test]
     fn test_simulcast_layer() {
         let layer = SimulcastLayer {
@@ -198,6 +274,29 @@ mod tests {
         assert_eq!(deserialized, layer);
     }
 
+    #[test]
+    fn test_root_announcement_is_not_a_directory() {
+        let announcement = RootAnnouncement {
+            sender: "abc123".to_string(),
+            epoch: 3,
+            roots: vec!["marmot/legacy".to_string(), "marmot/exported".to_string()],
+        };
+        let json = serde_json::to_string(&announcement).unwrap();
+        assert!(serde_json::from_str::<DirectoryMessage>(&json).is_err());
+        assert_eq!(
+            serde_json::from_str::<RootAnnouncement>(&json).unwrap(),
+            announcement
+        );
+
+        let directory = DirectoryMessage {
+            sender: "abc123".to_string(),
+            epoch: 3,
+            tracks: vec![],
+        };
+        let json = serde_json::to_string(&directory).unwrap();
+        assert!(serde_json::from_str::<RootAnnouncement>(&json).is_err());
+    }
+
     #[test]
     fn test_empty_directory() {
         let directory = DirectoryMessage {
@@ -0,0 +1,12 @@
+//! Native implementations of the controller's transport services
+//!
+//! The controller state is single-threaded (`Rc`/`RefCell`), so everything
+//! here runs on the current thread: use these services from inside a tokio
+//! [`LocalSet`](tokio::task::LocalSet), and run
+//! [`drive`](crate::controller::drive) there to step the controllers.
+
+mod moq;
+mod nostr;
+
+pub use moq::NativeMoqService;
+pub use nostr::NativeNostrService;
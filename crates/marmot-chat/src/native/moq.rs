@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+use moq_lite::{BroadcastProducer, OriginConsumer, OriginProducer, Track, TrackProducer};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::controller::services::{MoqListener, MoqService};
+
+/// Track every participant publishes wrappers on, as in the browser bridge
+const TRACK_NAME: &str = "wrappers";
+/// Wait before re-subscribing to a peer whose broadcast is not up yet
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// [`MoqService`] over a native MoQ client
+///
+/// Uses the same layout as the browser bridge: each participant publishes a
+/// broadcast at `<session>/wrappers/<pubkey>` carrying a `wrappers` track
+/// with one group per wrapper, and subscribes to the same path for every
+/// peer. Must be used from inside a tokio `LocalSet`.
+pub struct NativeMoqService {
+    client: moq_native::Client,
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Bumped by every connect and shutdown so stale tasks stand down
+    generation: u64,
+    listener: Option<Rc<dyn MoqListener>>,
+    connection: Option<Connection>,
+    /// Wrappers published before the session came up
+    pending: VecDeque<Vec<u8>>,
+    /// Peer subscriptions, including ones requested before connecting
+    peers: HashMap<String, Option<JoinHandle<()>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Connection {
+    base: String,
+    own_pubkey: String,
+    track: TrackProducer,
+    subscribe: OriginConsumer,
+    // Dropping these ends our broadcast and the session
+    _broadcast: BroadcastProducer,
+    _publish: OriginProducer,
+    _session: moq_lite::Session,
+}
+
+impl NativeMoqService {
+    pub fn new(client: moq_native::Client) -> Self {
+        Self {
+            client,
+            inner: Rc::new(RefCell::new(Inner::default())),
+        }
+    }
+
+    /// Build the QUIC client from `config`
+    pub fn with_config(config: moq_native::ClientConfig) -> Result<Self> {
+        Ok(Self::new(config.init().context("init MoQ client")?))
+    }
+
+    async fn establish(
+        client: moq_native::Client,
+        url: &str,
+        session: &str,
+        own_pubkey: &str,
+    ) -> Result<Connection> {
+        let url = Url::parse(url).context("parse MoQ relay url")?;
+        let transport = client.connect(url).await.context("connect MoQ relay")?;
+
+        let base = format!("{session}/{TRACK_NAME}");
+        let mut broadcast = BroadcastProducer::new();
+        let track = broadcast.create_track(Track {
+            name: TRACK_NAME.to_string(),
+            priority: 0,
+        });
+        let publish = OriginProducer::new();
+        publish.publish_broadcast(format!("{base}/{own_pubkey}"), broadcast.consume());
+        let subscribe = OriginProducer::new();
+        let moq_session =
+            moq_lite::Session::connect(transport, publish.consume(), subscribe.clone())
+                .await
+                .context("MoQ handshake")?;
+
+        Ok(Connection {
+            base,
+            own_pubkey: own_pubkey.to_string(),
+            track,
+            subscribe: subscribe.consume(),
+            _broadcast: broadcast,
+            _publish: publish,
+            _session: moq_session,
+        })
+    }
+
+    fn start_peer(inner: &Rc<RefCell<Inner>>, peer_pubkey: &str) {
+        let state = &mut *inner.borrow_mut();
+        let Some(connection) = state.connection.as_ref() else {
+            // Subscribed once the session is up
+            state.peers.entry(peer_pubkey.to_string()).or_insert(None);
+            return;
+        };
+        if peer_pubkey == connection.own_pubkey {
+            return;
+        }
+        if matches!(state.peers.get(peer_pubkey), Some(Some(_))) {
+            return;
+        }
+        let path = format!("{}/{peer_pubkey}", connection.base);
+        let origin = connection.subscribe.clone();
+        let generation = state.generation;
+        let task =
+            tokio::task::spawn_local(Self::consume_peer(inner.clone(), generation, origin, path));
+        state.peers.insert(peer_pubkey.to_string(), Some(task));
+    }
+
+    /// Forward a peer's wrappers to the listener, re-subscribing until the
+    /// subscription is dropped
+    async fn consume_peer(
+        inner: Rc<RefCell<Inner>>,
+        generation: u64,
+        mut origin: OriginConsumer,
+        path: String,
+    ) {
+        let track = Track {
+            name: TRACK_NAME.to_string(),
+            priority: 0,
+        };
+        loop {
+            if let Some(broadcast) = origin.consume_broadcast(&path) {
+                let mut consumer = broadcast.subscribe_track(&track);
+                debug!("[moq] subscribed to {path}");
+                loop {
+                    let mut group = match consumer.next_group().await {
+                        Ok(Some(group)) => group,
+                        Ok(None) => break,
+                        Err(err) => {
+                            debug!("[moq] peer track {path} ended: {err}");
+                            break;
+                        }
+                    };
+                    while let Ok(Some(frame)) = group.read_frame().await {
+                        let listener = {
+                            let state = inner.borrow();
+                            if state.generation != generation {
+                                return;
+                            }
+                            state.listener.clone()
+                        };
+                        if let Some(listener) = listener {
+                            listener.on_frame(frame.to_vec());
+                        }
+                    }
+                }
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            if inner.borrow().generation != generation {
+                return;
+            }
+        }
+    }
+
+    fn write(connection: &mut Connection, bytes: &[u8]) {
+        let mut group = connection.track.append_group();
+        group.write_frame(Bytes::copy_from_slice(bytes));
+        group.finish();
+    }
+}
+
+impl MoqService for NativeMoqService {
+    fn connect(
+        &self,
+        url: &str,
+        session: &str,
+        own_pubkey: &str,
+        peer_pubkeys: &[String],
+        listener: Box<dyn MoqListener>,
+    ) {
+        let generation = {
+            let mut state = self.inner.borrow_mut();
+            state.generation += 1;
+            state.listener = Some(Rc::from(listener));
+            for peer in peer_pubkeys {
+                state.peers.entry(peer.clone()).or_insert(None);
+            }
+            state.generation
+        };
+
+        let inner = self.inner.clone();
+        let client = self.client.clone();
+        let url = url.to_string();
+        let session = session.to_string();
+        let own_pubkey = own_pubkey.to_string();
+        let task = tokio::task::spawn_local(async move {
+            let result = Self::establish(client, &url, &session, &own_pubkey).await;
+            let listener = {
+                let state = inner.borrow();
+                if state.generation != generation {
+                    return;
+                }
+                state.listener.clone()
+            };
+            match result {
+                Ok(mut connection) => {
+                    let peers: Vec<String> = {
+                        let mut state = inner.borrow_mut();
+                        while let Some(bytes) = state.pending.pop_front() {
+                            Self::write(&mut connection, &bytes);
+                        }
+                        state.connection = Some(connection);
+                        state.peers.keys().cloned().collect()
+                    };
+                    for peer in peers {
+                        Self::start_peer(&inner, &peer);
+                    }
+                    if let Some(listener) = listener {
+                        listener.on_ready();
+                    }
+                }
+                Err(err) => {
+                    warn!("[moq] connect failed: {err:#}");
+                    if let Some(listener) = listener {
+                        listener.on_error(format!("{err:#}"));
+                    }
+                }
+            }
+        });
+        self.inner.borrow_mut().tasks.push(task);
+    }
+
+    fn subscribe_to_peer(&self, peer_pubkey: &str) {
+        Self::start_peer(&self.inner, peer_pubkey);
+    }
+
+    fn unsubscribe_from_peer(&self, peer_pubkey: &str) {
+        if let Some(Some(task)) = self.inner.borrow_mut().peers.remove(peer_pubkey) {
+            task.abort();
+        }
+    }
+
+    fn publish_wrapper(&self, bytes: &[u8]) {
+        let state = &mut *self.inner.borrow_mut();
+        match state.connection.as_mut() {
+            Some(connection) => Self::write(connection, bytes),
+            None => state.pending.push_back(bytes.to_vec()),
+        }
+    }
+
+    fn shutdown(&self) {
+        let mut state = self.inner.borrow_mut();
+        state.generation += 1;
+        for task in state.tasks.drain(..) {
+            task.abort();
+        }
+        for task in state.peers.drain().filter_map(|(_, task)| task) {
+            task.abort();
+        }
+        state.connection = None;
+        state.pending.clear();
+        state.listener = None;
+    }
+}
@@ -0,0 +1,485 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use nostr::{JsonUtil, Keys, SecretKey, Timestamp};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::controller::events::{RelayHealth, RelayState, SessionRole};
+use crate::controller::handshake_wire::{self, RelayMessage, SeenEvents};
+use crate::controller::key_packages;
+use crate::controller::reconnect::Backoff;
+use crate::controller::services::{
+    HandshakeConnectParams, HandshakeListener, HandshakeMessage, KeyPackageListener, NostrService,
+};
+
+/// Give up waiting for relays that never finish a key package lookup
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// [`NostrService`] over native websockets
+///
+/// Speaks the same handshake format as the browser client. Every message is
+/// published to all relays and incoming events are de-duplicated, so any one
+/// reachable relay is enough. Messages sent before `connect` or while a relay
+/// is down are buffered. Relays that drop are retried with jittered
+/// exponential backoff and resubscribed from shortly before the drop; the
+/// listener hears of each change. Key package lookups ask every relay and
+/// answer once all of them sent `EOSE`, or after [`FETCH_TIMEOUT`]. Welcomes
+/// also go to the invitee's inbox relays over one-off connections. Must be
+/// used from inside a tokio `LocalSet`.
+pub struct NativeNostrService {
+    extra_relays: Vec<String>,
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Bumped by every connect and shutdown so stale relay tasks stand down
+    generation: u64,
+    channel: Option<Channel>,
+    listener: Option<Rc<dyn HandshakeListener>>,
+    /// Outbound queue per relay, drained once its socket is open
+    relays: Vec<UnboundedSender<String>>,
+    /// Urls of `relays`, in the same order
+    urls: Vec<String>,
+    /// Messages sent before `connect`
+    pending: VecDeque<HandshakeMessage>,
+    /// Rumors already handled, as every relay echoes their wraps
+    seen: SeenEvents,
+    /// Key package `EVENT`s and deletions published before `connect`
+    pending_key_packages: Vec<String>,
+    /// Key package lookups by pubkey
+    fetches: HashMap<String, Fetch>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Fetch {
+    listeners: Vec<Box<dyn KeyPackageListener>>,
+    events: Vec<String>,
+    /// Urls of the relays yet to send `EOSE`
+    waiting: HashSet<String>,
+    timeout: JoinHandle<()>,
+}
+
+struct Channel {
+    session: String,
+    role: SessionRole,
+    keys: Keys,
+}
+
+impl NativeNostrService {
+    /// Use only the relay named in the connect parameters
+    pub fn new() -> Self {
+        Self::with_relays(Vec::new())
+    }
+
+    /// Also publish to and read from `relays`
+    pub fn with_relays(relays: Vec<String>) -> Self {
+        Self {
+            extra_relays: relays,
+            inner: Rc::new(RefCell::new(Inner::default())),
+        }
+    }
+
+    fn publish(state: &Inner, message: &HandshakeMessage) {
+        let Some(channel) = state.channel.as_ref() else {
+            return;
+        };
+        let event = match handshake_wire::build_handshake_event(
+            &channel.keys,
+            &channel.session,
+            channel.role,
+            message,
+        ) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("[nostr] {err:#}");
+                return;
+            }
+        };
+        let text = handshake_wire::event_message(&event);
+        Self::broadcast(state, &text);
+        let connected: Vec<&str> = state.urls.iter().map(String::as_str).collect();
+        for url in handshake_wire::unconnected_inbox_relays(message, &connected) {
+            tokio::task::spawn_local(Self::publish_once(url.to_string(), text.clone()));
+        }
+    }
+
+    /// Hand one event to a relay we do not otherwise talk to
+    async fn publish_once(url: String, text: String) {
+        let mut socket = match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                warn!("[nostr] failed to connect to inbox relay {url}: {err}");
+                return;
+            }
+        };
+        if let Err(err) = socket.send(Message::Text(text)).await {
+            warn!("[nostr] send to inbox relay {url} failed: {err}");
+            return;
+        }
+        // Give the relay a chance to answer `OK` before hanging up
+        let _ = tokio::time::timeout(FETCH_TIMEOUT, socket.next()).await;
+        let _ = socket.close(None).await;
+    }
+
+    /// Keep the relay at `url` connected until the service moves on
+    async fn run_relay(
+        inner: Rc<RefCell<Inner>>,
+        generation: u64,
+        url: String,
+        pubkey: String,
+        mut outbound: UnboundedReceiver<String>,
+    ) {
+        let mut backoff = Backoff::default();
+        let mut dropped_at = None;
+        // Taken off `outbound` but not known to be sent; a socket that
+        // drops meanwhile leaves it for the next one, and relays ignore
+        // an event they already have
+        let mut unsent: Option<String> = None;
+        loop {
+            Self::report(
+                &inner,
+                generation,
+                backoff.health(&url, RelayState::Connecting, None),
+            );
+            match tokio_tungstenite::connect_async(url.as_str()).await {
+                Ok((socket, _)) => {
+                    debug!("[nostr] connected to {url}");
+                    backoff.reset();
+                    Self::report(
+                        &inner,
+                        generation,
+                        backoff.health(&url, RelayState::Connected, None),
+                    );
+                    let (mut sink, mut stream) = socket.split();
+                    let since = dropped_at.map(handshake_wire::resume_since);
+                    let mut requests = vec![handshake_wire::subscription_request(&pubkey, since)];
+                    // Lookups this relay had not answered died with the old
+                    // socket
+                    if dropped_at.is_some() {
+                        requests.extend(Self::unanswered_fetches(&inner, &url));
+                    }
+
+                    let writer = async {
+                        for request in requests {
+                            if let Err(err) = sink.send(Message::Text(request)).await {
+                                warn!("[nostr] subscribe on {url} failed: {err}");
+                                return;
+                            }
+                        }
+                        loop {
+                            if unsent.is_none() {
+                                unsent = outbound.next().await;
+                            }
+                            let Some(text) = unsent.clone() else {
+                                return;
+                            };
+                            if let Err(err) = sink.send(Message::Text(text)).await {
+                                warn!("[nostr] send to {url} failed: {err}");
+                                return;
+                            }
+                            unsent = None;
+                        }
+                    };
+                    let reader = async {
+                        while let Some(message) = stream.next().await {
+                            match message {
+                                Ok(Message::Text(text)) => {
+                                    Self::deliver(&inner, generation, &url, &text)
+                                }
+                                Ok(Message::Close(_)) => break,
+                                Ok(_) => {}
+                                Err(err) => {
+                                    warn!("[nostr] read from {url} failed: {err}");
+                                    break;
+                                }
+                            }
+                        }
+                    };
+                    tokio::select! {
+                        _ = writer => {}
+                        _ = reader => {}
+                    }
+                    debug!("[nostr] disconnected from {url}");
+                    dropped_at = Some(Timestamp::now());
+                }
+                Err(err) => warn!("[nostr] failed to connect to {url}: {err}"),
+            }
+            if inner.borrow().generation != generation {
+                return;
+            }
+            let delay = backoff.next_delay_ms();
+            Self::report(
+                &inner,
+                generation,
+                backoff.health(&url, RelayState::Reconnecting, Some(delay)),
+            );
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+    }
+
+    /// Tell the listener how a relay is doing
+    fn report(inner: &Rc<RefCell<Inner>>, generation: u64, health: RelayHealth) {
+        let listener = {
+            let state = inner.borrow();
+            if state.generation != generation {
+                return;
+            }
+            state.listener.clone()
+        };
+        if let Some(listener) = listener {
+            listener.on_relay_health(health);
+        }
+    }
+
+    fn broadcast(state: &Inner, text: &str) {
+        for relay in &state.relays {
+            let _ = relay.unbounded_send(text.to_string());
+        }
+    }
+
+    /// Broadcast an `EVENT`, or hold it until `connect`
+    fn publish_event(&self, text: String) {
+        let state = &mut *self.inner.borrow_mut();
+        if state.channel.is_none() {
+            state.pending_key_packages.push(text);
+            return;
+        }
+        Self::broadcast(state, &text);
+    }
+
+    /// Ask every relay for the key packages of `pubkey`
+    fn send_fetch(state: &mut Inner, pubkey: &str) {
+        let relays = state.urls.iter().cloned().collect();
+        if let Some(fetch) = state.fetches.get_mut(pubkey) {
+            fetch.waiting = relays;
+            fetch.events.clear();
+        }
+        Self::broadcast(state, &key_packages::fetch_request(pubkey));
+    }
+
+    /// Lookups the relay at `url` has yet to answer, as `REQ`s
+    fn unanswered_fetches(inner: &Rc<RefCell<Inner>>, url: &str) -> Vec<String> {
+        inner
+            .borrow()
+            .fetches
+            .iter()
+            .filter(|(_, fetch)| fetch.waiting.contains(url))
+            .map(|(pubkey, _)| key_packages::fetch_request(pubkey))
+            .collect()
+    }
+
+    fn finish_fetch(inner: &Rc<RefCell<Inner>>, pubkey: &str) {
+        let fetch = {
+            let mut state = inner.borrow_mut();
+            let Some(fetch) = state.fetches.remove(pubkey) else {
+                return;
+            };
+            Self::broadcast(&state, &key_packages::close_request(pubkey));
+            fetch
+        };
+        fetch.timeout.abort();
+        for listener in fetch.listeners {
+            listener.on_key_packages(pubkey.to_string(), fetch.events.clone());
+        }
+    }
+
+    fn deliver(inner: &Rc<RefCell<Inner>>, generation: u64, url: &str, text: &str) {
+        if inner.borrow().generation != generation {
+            return;
+        }
+        let event = match handshake_wire::parse_relay_message(text) {
+            Some(RelayMessage::Event {
+                subscription,
+                event,
+            }) => match key_packages::fetched_pubkey(&subscription) {
+                Some(pubkey) => {
+                    let mut state = inner.borrow_mut();
+                    if let Some(fetch) = state.fetches.get_mut(pubkey) {
+                        let event_json = event.as_json();
+                        if !fetch.events.contains(&event_json) {
+                            fetch.events.push(event_json);
+                        }
+                    }
+                    return;
+                }
+                None => event,
+            },
+            Some(RelayMessage::EndOfStoredEvents(subscription)) => {
+                let Some(pubkey) = key_packages::fetched_pubkey(&subscription) else {
+                    return;
+                };
+                let done = match inner.borrow_mut().fetches.get_mut(pubkey) {
+                    Some(fetch) => {
+                        fetch.waiting.remove(url);
+                        fetch.waiting.is_empty()
+                    }
+                    None => false,
+                };
+                if done {
+                    Self::finish_fetch(inner, pubkey);
+                }
+                return;
+            }
+            None => return,
+        };
+        let (listener, inbound) = {
+            let mut state = inner.borrow_mut();
+            let Some(channel) = state.channel.as_ref() else {
+                return;
+            };
+            let Some(inbound) = handshake_wire::decode_handshake_event(&channel.keys, &event)
+            else {
+                return;
+            };
+            // Deduplicated by the rumor id, known only once the wrap checked
+            // out: a forged event claiming a real wrap's id must not hide it
+            if !inbound.is_for(&channel.session, channel.role) || !state.seen.insert(inbound.id) {
+                return;
+            }
+            (state.listener.clone(), inbound)
+        };
+        if let Some(listener) = listener {
+            listener.on_message(inbound.message);
+        }
+    }
+}
+
+impl Default for NativeNostrService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NostrService for NativeNostrService {
+    fn connect(&self, params: HandshakeConnectParams, listener: Box<dyn HandshakeListener>) {
+        let keys = match SecretKey::from_hex(params.secret_hex.expose()).map(Keys::new) {
+            Ok(keys) => keys,
+            Err(err) => {
+                log::error!("invalid handshake secret: {err}");
+                return;
+            }
+        };
+        let mut urls = vec![params.url.clone()];
+        for relay in params.relays.iter().chain(&self.extra_relays) {
+            if !urls.contains(relay) {
+                urls.push(relay.clone());
+            }
+        }
+
+        let state = &mut *self.inner.borrow_mut();
+        state.generation += 1;
+        for task in state.tasks.drain(..) {
+            task.abort();
+        }
+        state.relays.clear();
+        state.urls.clear();
+        state.channel = Some(Channel {
+            session: params.session.clone(),
+            role: params.role,
+            keys: keys.clone(),
+        });
+        state.listener = Some(Rc::from(listener));
+        state.seen.clear();
+        let pubkey = keys.public_key().to_hex();
+        for url in urls {
+            let (tx, rx) = unbounded();
+            state.relays.push(tx);
+            state.urls.push(url.clone());
+            state.tasks.push(tokio::task::spawn_local(Self::run_relay(
+                self.inner.clone(),
+                state.generation,
+                url,
+                pubkey.clone(),
+                rx,
+            )));
+        }
+        while let Some(message) = state.pending.pop_front() {
+            Self::publish(state, &message);
+        }
+        for text in std::mem::take(&mut state.pending_key_packages) {
+            Self::broadcast(state, &text);
+        }
+        // Lookups made earlier start over on the new relays
+        let fetches: Vec<String> = state.fetches.keys().cloned().collect();
+        for pubkey in fetches {
+            Self::send_fetch(state, &pubkey);
+        }
+    }
+
+    fn send(&self, payload: HandshakeMessage) {
+        let state = &mut *self.inner.borrow_mut();
+        if state.channel.is_none() {
+            state.pending.push_back(payload);
+            return;
+        }
+        Self::publish(state, &payload);
+    }
+
+    fn publish_key_package(&self, event_json: String) {
+        match key_packages::publish_message(&event_json) {
+            Some(text) => self.publish_event(text),
+            None => warn!("[nostr] refusing to publish a malformed key package"),
+        }
+    }
+
+    fn delete_key_packages(&self, deletion_json: String) {
+        match key_packages::deletion_message(&deletion_json) {
+            Some(text) => self.publish_event(text),
+            None => warn!("[nostr] refusing to publish a malformed deletion"),
+        }
+    }
+
+    fn fetch_key_packages(&self, pubkey: &str, listener: Box<dyn KeyPackageListener>) {
+        let state = &mut *self.inner.borrow_mut();
+        if let Some(fetch) = state.fetches.get_mut(pubkey) {
+            fetch.listeners.push(listener);
+            return;
+        }
+        let inner = self.inner.clone();
+        let owner = pubkey.to_string();
+        let timeout = tokio::task::spawn_local(async move {
+            tokio::time::sleep(FETCH_TIMEOUT).await;
+            debug!("[nostr] key package lookup for {owner} timed out");
+            Self::finish_fetch(&inner, &owner);
+        });
+        state.fetches.insert(
+            pubkey.to_string(),
+            Fetch {
+                listeners: vec![listener],
+                events: Vec::new(),
+                waiting: HashSet::new(),
+                timeout,
+            },
+        );
+        // Before `connect` there are no relays; it sends the lookup then
+        if state.channel.is_some() {
+            Self::send_fetch(state, pubkey);
+        }
+    }
+
+    fn shutdown(&self) {
+        let fetches: Vec<String> = self.inner.borrow().fetches.keys().cloned().collect();
+        for pubkey in fetches {
+            Self::finish_fetch(&self.inner, &pubkey);
+        }
+        let state = &mut *self.inner.borrow_mut();
+        state.generation += 1;
+        for task in state.tasks.drain(..) {
+            task.abort();
+        }
+        state.relays.clear();
+        state.urls.clear();
+        state.channel = None;
+        state.listener = None;
+        state.pending.clear();
+        state.pending_key_packages.clear();
+        state.seen.clear();
+    }
+}
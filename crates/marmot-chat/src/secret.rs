@@ -0,0 +1,72 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// String holding secret material, such as a Nostr secret key in hex
+///
+/// Wiped on drop and redacted in `Debug`. Serializes as a plain string so
+/// session parameters keep their wire format.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// Borrow the secret; avoid copying it into longer-lived strings
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let secret = SecretString::from("deadbeef");
+        assert_eq!(format!("{secret:?}"), "SecretString(<redacted>)");
+        assert_eq!(secret.expose(), "deadbeef");
+    }
+
+    #[test]
+    fn test_serializes_as_plain_string() {
+        let secret = SecretString::from("deadbeef");
+        let json = serde_json::to_string(&secret).unwrap();
+        assert_eq!(json, "\"deadbeef\"");
+        assert_eq!(serde_json::from_str::<SecretString>(&json).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_zeroize_clears_contents() {
+        let mut secret = SecretString::from("deadbeef");
+        secret.zeroize();
+        assert!(secret.is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! SFrame (RFC 9605) frame format and key schedule
+//!
+//! Frames are the section 4.3 header (KID and CTR) followed by the AEAD
+//! output, authenticated over the header plus caller metadata. Each KID's
+//! key and salt follow section 4.4, from the Marmot media base key in
+//! [`crate::media_crypto`]:
+//!
+//! - the KID is `HKDF-Expand(base, "sframe-kid" || suite label, 8)`,
+//!   naming one (sender, track, epoch) base key under one suite; RFC 9605
+//!   leaves KID assignment to the application
+//! - CTR stays below 2^32, since [`crate::media_crypto::FrameCounter`]
+//!   hands out 32-bit counters
+//! - suites use their RFC 9605 ids; ChaCha20-Poly1305 has none and takes
+//!   one from the private use range (see [`CipherSuite::sframe_id`])
+
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use sha2::{Sha256, Sha512};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::cipher_suite::CipherSuite;
+
+const SFRAME_KEY_LABEL: &[u8] = b"SFrame 1.0 Secret key ";
+const SFRAME_SALT_LABEL: &[u8] = b"SFrame 1.0 Secret salt ";
+
+/// AEAD key and nonce salt of one KID
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub(crate) struct SframeKeys {
+    pub(crate) key: Vec<u8>,
+    pub(crate) salt: [u8; 12],
+}
+
+impl SframeKeys {
+    /// Derive the key and salt for `kid` (RFC 9605, section 4.4)
+    ///
+    /// ```text
+    /// secret = HKDF-Extract("", base_key)
+    /// key    = HKDF-Expand(secret, "SFrame 1.0 Secret key " || KID || suite, Nk)
+    /// salt   = HKDF-Expand(secret, "SFrame 1.0 Secret salt " || KID || suite, Nn)
+    /// ```
+    ///
+    /// with KID as a 64-bit and the suite id as a 16-bit big-endian integer.
+    pub(crate) fn derive(base_key: &[u8], kid: u64, suite: CipherSuite) -> Self {
+        let suffix = [&kid.to_be_bytes()[..], &suite.sframe_id().to_be_bytes()].concat();
+        let key_info = [SFRAME_KEY_LABEL, suffix.as_slice()];
+        let salt_info = [SFRAME_SALT_LABEL, suffix.as_slice()];
+        let mut keys = Self {
+            key: vec![0u8; suite.key_len()],
+            salt: [0u8; 12],
+        };
+        let expanded = match suite {
+            CipherSuite::Aes256Gcm => {
+                let hkdf = Hkdf::<Sha512>::new(None, base_key);
+                hkdf.expand_multi_info(&key_info, &mut keys.key)
+                    .and_then(|()| hkdf.expand_multi_info(&salt_info, &mut keys.salt))
+            }
+            CipherSuite::Aes128Gcm | CipherSuite::ChaCha20Poly1305 => {
+                let hkdf = Hkdf::<Sha256>::new(None, base_key);
+                hkdf.expand_multi_info(&key_info, &mut keys.key)
+                    .and_then(|()| hkdf.expand_multi_info(&salt_info, &mut keys.salt))
+            }
+        };
+        expanded.expect("AEAD key and salt are valid HKDF output lengths");
+        keys
+    }
+
+    /// Nonce for `ctr`: the salt XOR CTR, big-endian over its last bytes
+    pub(crate) fn nonce(&self, ctr: u32) -> [u8; 12] {
+        let mut nonce = self.salt;
+        for (i, byte) in ctr.to_be_bytes().iter().enumerate() {
+            nonce[8 + i] ^= byte;
+        }
+        nonce
+    }
+}
+
+/// SFrame frame header (RFC 9605, section 4.3)
+///
+/// ```text
+///  0 1 2 3 4 5 6 7
+/// +-+-+-+-+-+-+-+-+------------------+------------------+
+/// |X|  K  |Y|  C  |  KID (K+1 bytes) |  CTR (C+1 bytes) |
+/// +-+-+-+-+-+-+-+-+------------------+------------------+
+/// ```
+///
+/// Values below 8 are packed into the config byte; larger values set the
+/// extension flag and store the minimal big-endian encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SframeHeader {
+    pub kid: u64,
+    pub ctr: u64,
+}
+
+impl SframeHeader {
+    pub fn new(kid: u64, ctr: u64) -> Self {
+        Self { kid, ctr }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kid_bits, kid_bytes) = encode_field(self.kid);
+        let (ctr_bits, ctr_bytes) = encode_field(self.ctr);
+        let mut out = Vec::with_capacity(1 + kid_bytes.len() + ctr_bytes.len());
+        out.push((kid_bits << 4) | ctr_bits);
+        out.extend_from_slice(&kid_bytes);
+        out.extend_from_slice(&ctr_bytes);
+        out
+    }
+
+    /// Parse the header at the start of `frame`
+    ///
+    /// Returns the header and its encoded length; the ciphertext follows.
+    pub fn parse(frame: &[u8]) -> Result<(Self, usize)> {
+        let config = *frame.first().ok_or_else(|| anyhow!("empty SFrame"))?;
+        let mut offset = 1;
+        let kid = decode_field(frame, &mut offset, config >> 4)?;
+        let ctr = decode_field(frame, &mut offset, config & 0x0f)?;
+        Ok((Self { kid, ctr }, offset))
+    }
+}
+
+/// Encode one header field as its 4 config bits plus trailing bytes
+fn encode_field(value: u64) -> (u8, Vec<u8>) {
+    if value < 8 {
+        return (value as u8, Vec::new());
+    }
+    let len = 8 - (value.leading_zeros() / 8) as usize;
+    let bytes = value.to_be_bytes()[8 - len..].to_vec();
+    (0b1000 | (len as u8 - 1), bytes)
+}
+
+fn decode_field(frame: &[u8], offset: &mut usize, bits: u8) -> Result<u64> {
+    if bits & 0b1000 == 0 {
+        return Ok(u64::from(bits & 0b0111));
+    }
+    let len = usize::from(bits & 0b0111) + 1;
+    let bytes = frame
+        .get(*offset..*offset + len)
+        .ok_or_else(|| anyhow!("truncated SFrame header"))?;
+    *offset += len;
+    Ok(bytes
+        .iter()
+        .fold(0u64, |value, byte| (value << 8) | u64::from(*byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_encoding_matches_rfc9605() {
+        let cases: &[(u64, u64, &str)] = &[
+            (0, 0, "00"),
+            (0, 7, "07"),
+            (7, 0, "70"),
+            (0, 8, "0808"),
+            (0, 0xff, "08ff"),
+            (0, 0x100, "090100"),
+            (8, 0, "8008"),
+            (0xffff, 1, "91ffff"),
+        ];
+        for (kid, ctr, expected) in cases {
+            let header = SframeHeader::new(*kid, *ctr);
+            assert_eq!(
+                hex::encode(header.encode()),
+                *expected,
+                "kid={kid} ctr={ctr}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let values = [
+            0u64,
+            1,
+            7,
+            8,
+            0xff,
+            0x100,
+            0x01_00_00_00,
+            u32::MAX as u64,
+            u64::MAX,
+        ];
+        for kid in values {
+            for ctr in values {
+                let header = SframeHeader::new(kid, ctr);
+                let mut frame = header.encode();
+                let header_len = frame.len();
+                frame.extend_from_slice(b"ciphertext");
+
+                let (parsed, len) = SframeHeader::parse(&frame).unwrap();
+                assert_eq!(parsed, header);
+                assert_eq!(len, header_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_schedule_matches_rfc9605() {
+        // Inputs of RFC 9605 appendix C.4
+        let kid = 0x123;
+        let keys = SframeKeys::derive(
+            &hex::decode("000102030405060708090a0b0c0d0e0f").unwrap(),
+            kid,
+            CipherSuite::Aes128Gcm,
+        );
+        assert_eq!(hex::encode(&keys.key), "d34f547f4ca4f9a7447006fe7fcbf768");
+        assert_eq!(hex::encode(keys.salt), "75234edefe07819026751816");
+
+        let base_key: Vec<u8> = (0u8..32).collect();
+        let keys = SframeKeys::derive(&base_key, kid, CipherSuite::Aes256Gcm);
+        assert_eq!(
+            hex::encode(&keys.key),
+            "54a4c5a0c415fc919c499de837b22415d20457b59c3e9b244709c41928f73319"
+        );
+        assert_eq!(hex::encode(keys.salt), "1003aaf9c69dee9802144441");
+    }
+
+    #[test]
+    fn test_nonce_xors_counter_into_salt() {
+        let keys = SframeKeys {
+            key: vec![0u8; 16],
+            salt: [0xff; 12],
+        };
+        assert_eq!(
+            hex::encode(keys.nonce(0x0102_0304)),
+            "fffffffffffffffffefdfcfb"
+        );
+    }
+
+    #[test]
+    fn test_truncated_header_rejected() {
+        assert!(SframeHeader::parse(&[]).is_err());
+        // X=1, K=3 announces a 4-byte KID but only 2 bytes follow
+        assert!(SframeHeader::parse(&[0xb0, 0x01, 0x02]).is_err());
+    }
+}
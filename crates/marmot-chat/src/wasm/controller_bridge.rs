@@ -29,6 +29,7 @@ use openmls_traits::storage::StorageProvider;
 use crate::controller::events::{ChatEvent, SessionParams, SessionRole};
 use crate::controller::services::{IdentityService, MoqListener, MoqService, NostrService};
 use crate::controller::{ChatController, ControllerConfig};
+use crate::secret::SecretString;
 
 use super::identity::{js_error, MOQ_BRIDGE_KEY};
 
@@ -158,8 +159,9 @@ fn parse_public_keys(keys: &[String]) -> Result<Vec<PublicKey>, JsValue> {
 
 #[wasm_bindgen]
 pub fn create_identity(secret_hex: String) -> Result<u32, JsValue> {
-    let secret =
-        SecretKey::from_hex(&secret_hex).map_err(|e| js_error(format!("invalid secret: {e}")))?;
+    let secret_hex = SecretString::from(secret_hex);
+    let secret = SecretKey::from_hex(secret_hex.expose())
+        .map_err(|e| js_error(format!("invalid secret: {e}")))?;
     let keys = Keys::new(secret);
     let identity = LegacyIdentity {
         keys,
@@ -384,33 +386,33 @@ pub fn create_group(
 #[wasm_bindgen]
 pub fn accept_welcome(identity_id: u32, welcome_json: String) -> Result<JsValue, JsValue> {
     with_identity(identity_id, |identity| {
-        let welcome_unsigned = UnsignedEvent::from_json(welcome_json.as_bytes())
+        let mut welcome_unsigned = UnsignedEvent::from_json(welcome_json.as_bytes())
             .map_err(|e| js_error(format!("invalid welcome event: {e}")))?;
-
-        identity
+        welcome_unsigned.ensure_id();
+        // Handed over directly rather than in a gift wrap, so the rumor's
+        // own id records it
+        let welcome_id = welcome_unsigned
+            .id
+            .ok_or_else(|| js_error("welcome without id"))?;
+
+        let welcome = identity
             .mdk
-            .process_welcome(&EventId::all_zeros(), &welcome_unsigned)
+            .process_welcome(&welcome_id, &welcome_unsigned)
             .map_err(|e| js_error(format!("failed to process welcome: {e}")))?;
 
-        let mut accepted_group: Option<Group> = None;
-
-        if let Ok(mut welcomes) = identity.mdk.get_pending_welcomes() {
-            for welcome in welcomes.iter() {
-                if let Err(err) = identity.mdk.accept_welcome(welcome) {
-                    return Err(js_error(format!("failed to accept welcome: {err}")));
-                }
-            }
-            if let Some(latest) = welcomes.pop() {
-                if let Ok(groups) = identity.mdk.get_groups() {
-                    accepted_group = groups
-                        .into_iter()
-                        .find(|g| g.mls_group_id == latest.mls_group_id);
-                }
-            }
-        }
+        // Only this welcome: others stay pending until picked
+        identity
+            .mdk
+            .accept_welcome(&welcome)
+            .map_err(|e| js_error(format!("failed to accept welcome: {e}")))?;
 
-        let group =
-            accepted_group.ok_or_else(|| js_error("accepted welcome but group not found"))?;
+        let group: Group = identity
+            .mdk
+            .get_groups()
+            .map_err(|e| js_error(format!("failed to load groups: {e}")))?
+            .into_iter()
+            .find(|g| g.mls_group_id == welcome.mls_group_id)
+            .ok_or_else(|| js_error("accepted welcome but group not found"))?;
 
         let resp = AcceptWelcomeResult {
             group_id_hex: hex::encode(group.mls_group_id.as_slice()),
@@ -0,0 +1,258 @@
+// =====================================================
+// Multi-group controller
+// =====================================================
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use js_sys::Function;
+use serde_wasm_bindgen as swb;
+
+use crate::controller::events::{GroupEvent, SessionParams};
+use crate::controller::services::IdentityService;
+use crate::controller::{ChatController, ChatHub};
+use crate::messages::TrackKind;
+
+use super::identity::{js_error, parse_cipher_suite, WasmMediaSession};
+use super::moq_bridge::JsMoqService;
+use super::nostr_client::JsNostrService;
+
+/// One identity in several groups over a single handshake connection
+///
+/// Events reach the callback with `session_id` and `group_id_hex` next to
+/// the usual event fields.
+#[wasm_bindgen]
+pub struct WasmChatHub {
+    hub: RefCell<ChatHub>,
+    nostr: JsNostrService,
+    _callback: Rc<Function>,
+}
+
+#[wasm_bindgen]
+impl WasmChatHub {
+    #[wasm_bindgen(constructor)]
+    pub fn new(secret_hex: String, callback: JsValue) -> Result<WasmChatHub, JsValue> {
+        let callback_fn: Function = callback
+            .dyn_into()
+            .map_err(|_| js_error("callback must be a function"))?;
+        let callback_rc = Rc::new(callback_fn);
+        let identity = IdentityService::create(&secret_hex).map_err(js_error)?;
+
+        let callback_emit = callback_rc.clone();
+        let event_callback = Rc::new(move |event: GroupEvent| {
+            if let Ok(value) = swb::to_value(&event) {
+                let _ = callback_emit.call1(&JsValue::NULL, &value);
+            }
+        });
+
+        Ok(Self {
+            hub: RefCell::new(ChatHub::new(identity, event_callback)),
+            nostr: JsNostrService::new(),
+            _callback: callback_rc,
+        })
+    }
+
+    #[wasm_bindgen(js_name = publicKey)]
+    pub fn public_key(&self) -> String {
+        self.hub.borrow().identity().public_key_hex()
+    }
+
+    /// Start a session with the same parameters `WasmChatController.start` takes
+    pub fn join(&self, session: JsValue) -> Result<(), JsValue> {
+        let params: SessionParams = swb::from_value(session)
+            .map_err(|err| js_error(format!("invalid session params: {err}")))?;
+        self.hub
+            .borrow_mut()
+            .join(
+                params,
+                Rc::new(self.nostr.share()),
+                Rc::new(JsMoqService::new()),
+            )
+            .map_err(js_error)
+    }
+
+    pub fn sessions(&self) -> Vec<String> {
+        self.hub.borrow().sessions()
+    }
+
+    /// Ids of the groups this identity belongs to
+    pub fn groups(&self) -> Result<Vec<String>, JsValue> {
+        self.hub.borrow().identity().list_groups().map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = sendMessage)]
+    pub fn send_message(&self, session_id: String, content: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.send_text(content))
+    }
+
+    #[wasm_bindgen(js_name = rotateEpoch)]
+    pub fn rotate_epoch(&self, session_id: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.rotate_epoch())
+    }
+
+    #[wasm_bindgen(js_name = rotateMoqRoot)]
+    pub fn rotate_moq_root(&self, session_id: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.rotate_moq_root())
+    }
+
+    #[wasm_bindgen(js_name = inviteMember)]
+    pub fn invite_member(
+        &self,
+        session_id: String,
+        pubkey: String,
+        is_admin: bool,
+    ) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.invite_member(pubkey, is_admin)
+        })
+    }
+
+    #[wasm_bindgen(js_name = removeMember)]
+    pub fn remove_member(&self, session_id: String, pubkey: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.remove_member(pubkey))
+    }
+
+    /// Promote or demote a member (admins only)
+    #[wasm_bindgen(js_name = setMemberAdmin)]
+    pub fn set_member_admin(
+        &self,
+        session_id: String,
+        pubkey: String,
+        is_admin: bool,
+    ) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.set_member_admin(pubkey, is_admin)
+        })
+    }
+
+    #[wasm_bindgen(js_name = leaveGroup)]
+    pub fn leave_group(&self, session_id: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.leave_group())
+    }
+
+    #[wasm_bindgen(js_name = acceptWelcome)]
+    pub fn accept_welcome(&self, session_id: String, welcome_id: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.accept_welcome(welcome_id)
+        })
+    }
+
+    /// Rename the group and/or change its description (admins only)
+    #[wasm_bindgen(js_name = updateGroupMetadata)]
+    pub fn update_group_metadata(
+        &self,
+        session_id: String,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.update_group_metadata(name, description)
+        })
+    }
+
+    /// Encrypt a group image and commit its reference (admins only)
+    /// Returns the ciphertext for the caller to upload
+    #[wasm_bindgen(js_name = setGroupImage)]
+    pub fn set_group_image(&self, session_id: String, image: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.with_group(&session_id, |controller| controller.set_group_image(&image))?
+            .map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = clearGroupImage)]
+    pub fn clear_group_image(&self, session_id: String) -> Result<(), JsValue> {
+        self.with_group(&session_id, |controller| controller.clear_group_image())
+    }
+
+    /// Decrypt a downloaded group image blob against the group context
+    #[wasm_bindgen(js_name = decryptGroupImage)]
+    pub fn decrypt_group_image(
+        &self,
+        session_id: String,
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.decrypt_group_image(&ciphertext)
+        })?
+        .map_err(js_error)
+    }
+
+    /// Media session for a sender's track in one group, as
+    /// `WasmChatController.createMediaSession`
+    #[wasm_bindgen(js_name = createMediaSession)]
+    pub fn create_media_session(
+        &self,
+        session_id: String,
+        sender_pubkey: String,
+        track_label: String,
+        cipher_suite: Option<String>,
+    ) -> Result<WasmMediaSession, JsValue> {
+        let suite = parse_cipher_suite(cipher_suite)?;
+        self.with_group(&session_id, |controller| {
+            controller.create_media_session(&sender_pubkey, &track_label, suite)
+        })?
+        .map(WasmMediaSession::new)
+        .map_err(js_error)
+    }
+
+    /// Exporter-based label for a sender's track at the group's current epoch
+    /// - kind: "audio", "video" or "screen"
+    #[wasm_bindgen(js_name = deriveTrackLabel)]
+    pub fn derive_track_label(
+        &self,
+        session_id: String,
+        sender_pubkey: String,
+        kind: String,
+    ) -> Result<String, JsValue> {
+        let kind = TrackKind::from_name(&kind)
+            .ok_or_else(|| js_error(format!("unknown track kind {kind:?}")))?;
+        self.with_group(&session_id, |controller| {
+            controller
+                .state()
+                .borrow()
+                .identity
+                .derive_track_label(&sender_pubkey, &kind)
+        })?
+        .map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = currentEpoch)]
+    pub fn current_epoch(&self, session_id: String) -> Result<u64, JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.state().borrow().identity.current_epoch()
+        })?
+        .map_err(js_error)
+    }
+
+    /// Group root the session's MoQ connection uses
+    #[wasm_bindgen(js_name = groupRoot)]
+    pub fn group_root(&self, session_id: String) -> Result<String, JsValue> {
+        self.with_group(&session_id, |controller| {
+            controller.state().borrow().active_moq_root()
+        })?
+        .map_err(js_error)
+    }
+
+    /// Shut one session down, keeping the others connected
+    pub fn close(&self, session_id: String) -> bool {
+        self.hub.borrow_mut().close(&session_id)
+    }
+
+    pub fn shutdown(&self) {
+        self.hub.borrow_mut().shutdown();
+    }
+
+    fn with_group<R>(
+        &self,
+        session_id: &str,
+        f: impl FnOnce(&ChatController) -> R,
+    ) -> Result<R, JsValue> {
+        let hub = self.hub.borrow();
+        let controller = hub
+            .group(session_id)
+            .ok_or_else(|| js_error(format!("unknown session {session_id}")))?;
+        Ok(f(controller))
+    }
+}
@@ -11,17 +11,23 @@ use js_sys::{Function, Object, Reflect, Uint8Array};
 use serde_json::{json, Value as JsonValue};
 use serde_wasm_bindgen as swb;
 
+use anyhow::anyhow;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine as _;
 use serde::{Deserialize, Serialize};
 use web_sys::{BinaryType, ErrorEvent, MessageEvent, WebSocket};
 
+use crate::cipher_suite::CipherSuite;
 use crate::controller::events::{ChatEvent, SessionParams, SessionRole};
 use crate::controller::services::{
     HandshakeConnectParams, HandshakeListener, HandshakeMessage, HandshakeMessageBody,
     HandshakeMessageType, IdentityHandle, IdentityService, MoqListener, MoqService, NostrService,
 };
 use crate::controller::{ChatController, ControllerConfig, ControllerState};
+use crate::frame_transform::{CodecKind, FrameTransformer};
+use crate::media_crypto::{AadFields, DecryptedFrame, MediaBaseKey, MediaSession};
+use crate::messages::TrackKind;
+use crate::sframe::SframeHeader;
 
 use super::moq_bridge::JsMoqService;
 use super::nostr_client::JsNostrService;
@@ -34,7 +40,6 @@ use nostr::{JsonUtil, TagKind};
 use openmls::prelude::{KeyPackageBundle, OpenMlsProvider};
 use openmls_traits::storage::StorageProvider;
 
-pub(super) const HANDSHAKE_KIND: u16 = 44501;
 pub(super) const MOQ_BRIDGE_KEY: &str = "__MARMOT_MOQ__";
 
 #[cfg(feature = "panic-hook")]
@@ -65,13 +70,37 @@ impl WasmChatController {
     pub fn start(session: JsValue, callback: JsValue) -> Result<WasmChatController, JsValue> {
         let params: SessionParams = swb::from_value(session)
             .map_err(|err| js_error(format!("invalid session params: {err}")))?;
+        let identity = IdentityService::create(params.secret_hex.expose()).map_err(js_error)?;
+        Self::launch(identity, params, callback)
+    }
+
+    /// Resume a participant from a snapshot made by `exportState`
+    #[wasm_bindgen(js_name = resume)]
+    pub fn resume(
+        snapshot: &[u8],
+        passphrase: String,
+        callback: JsValue,
+    ) -> Result<WasmChatController, JsValue> {
+        let imported = IdentityService::import_state(snapshot, &passphrase).map_err(js_error)?;
+        let params = imported
+            .session
+            .ok_or_else(|| js_error("snapshot has no session parameters"))?;
+        Self::launch(imported.identity, params, callback)
+    }
+
+    fn launch(
+        mut identity: IdentityHandle,
+        params: SessionParams,
+        callback: JsValue,
+    ) -> Result<WasmChatController, JsValue> {
+        if params.persists_snapshots {
+            identity.set_snapshots_persisted();
+        }
         let callback_fn: Function = callback
             .dyn_into()
             .map_err(|_| js_error("callback must be a function"))?;
         let callback_rc = Rc::new(callback_fn);
 
-        let identity = IdentityService::create(&params.secret_hex).map_err(js_error)?;
-
         let (nostr, moq) = build_services(&params)?;
 
         let callback_emit = callback_rc.clone();
@@ -108,128 +137,426 @@ impl WasmChatController {
         self.controller.rotate_epoch();
     }
 
+    #[wasm_bindgen(js_name = rotateMoqRoot)]
+    pub fn rotate_moq_root(&self) {
+        self.controller.rotate_moq_root();
+    }
+
     pub fn shutdown(&self) {
         self.controller.shutdown();
     }
 
+    /// Encrypt identity, group state, pending key packages and session
+    /// under a passphrase; `resume` picks the group back up at its epoch
+    #[wasm_bindgen(js_name = exportState)]
+    pub fn export_state(&self, passphrase: String) -> Result<Vec<u8>, JsValue> {
+        self.state
+            .borrow()
+            .export_state(&passphrase)
+            .map_err(js_error)
+    }
+
     #[wasm_bindgen(js_name = inviteMember)]
     pub fn invite_member(&self, pubkey: String, is_admin: bool) {
         self.controller.invite_member(pubkey, is_admin);
     }
 
+    /// Remove a member from the group (admins only)
+    #[wasm_bindgen(js_name = removeMember)]
+    pub fn remove_member(&self, pubkey: String) {
+        self.controller.remove_member(pubkey);
+    }
+
+    /// Promote or demote a member (admins only)
+    #[wasm_bindgen(js_name = setMemberAdmin)]
+    pub fn set_member_admin(&self, pubkey: String, is_admin: bool) {
+        self.controller.set_member_admin(pubkey, is_admin);
+    }
+
+    /// Leave the group; an admin commits the removal
+    #[wasm_bindgen(js_name = leaveGroup)]
+    pub fn leave_group(&self) {
+        self.controller.leave_group();
+    }
+
+    /// Join the group of a `welcome_pending` event by its welcome id
+    #[wasm_bindgen(js_name = acceptWelcome)]
+    pub fn accept_welcome(&self, welcome_id: String) {
+        self.controller.accept_welcome(welcome_id);
+    }
+
+    /// Rename the group and/or change its description (admins only)
+    #[wasm_bindgen(js_name = updateGroupMetadata)]
+    pub fn update_group_metadata(&self, name: Option<String>, description: Option<String>) {
+        self.controller.update_group_metadata(name, description);
+    }
+
+    /// Encrypt a group image and commit its reference (admins only)
+    /// Returns the ciphertext for the caller to upload
+    #[wasm_bindgen(js_name = setGroupImage)]
+    pub fn set_group_image(&self, image: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.controller.set_group_image(&image).map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = clearGroupImage)]
+    pub fn clear_group_image(&self) {
+        self.controller.clear_group_image();
+    }
+
+    /// Decrypt a downloaded group image blob against the group context
+    #[wasm_bindgen(js_name = decryptGroupImage)]
+    pub fn decrypt_group_image(&self, ciphertext: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.controller
+            .decrypt_group_image(&ciphertext)
+            .map_err(js_error)
+    }
+
     /// Derive media base key for a given sender and track label
-    /// Returns base64-encoded 32-byte key
+    /// Returns an opaque handle; the key bytes never leave WASM memory and
+    /// are wiped when the handle is freed
     #[wasm_bindgen(js_name = deriveMediaBaseKey)]
     pub fn derive_media_base_key(
         &self,
         sender_pubkey: String,
         track_label: String,
-    ) -> Result<String, JsValue> {
-        let base_key = self
-            .state
-            .borrow()
+    ) -> Result<WasmMediaKey, JsValue> {
+        let state = self.state.borrow();
+        let epoch = state.identity.current_epoch().map_err(js_error)?;
+        let key = state
             .identity
             .derive_media_base_key(&sender_pubkey, &track_label)
             .map_err(js_error)?;
+        Ok(WasmMediaKey {
+            key,
+            epoch,
+            sender_pubkey,
+            track_label,
+            state: self.state.clone(),
+        })
+    }
 
-        use base64::engine::general_purpose::STANDARD as BASE64;
-        use base64::Engine as _;
-        Ok(BASE64.encode(base_key))
+    /// Create a long-lived media session for a sender's track
+    /// - sender_pubkey: hex pubkey of the track publisher
+    /// - track_label: label announced for the track
+    /// - cipher_suite: suite from the track's directory entry (e.g.
+    ///   "chacha20-poly1305"); defaults to "aes-128-gcm"
+    /// Returns a handle that keeps its key cache and replay window across
+    /// frames and follows the group's epoch as commits are merged
+    #[wasm_bindgen(js_name = createMediaSession)]
+    pub fn create_media_session(
+        &self,
+        sender_pubkey: String,
+        track_label: String,
+        cipher_suite: Option<String>,
+    ) -> Result<WasmMediaSession, JsValue> {
+        let suite = parse_cipher_suite(cipher_suite)?;
+        let inner = self
+            .state
+            .borrow()
+            .create_media_session(&sender_pubkey, &track_label, suite)
+            .map_err(js_error)?;
+        Ok(WasmMediaSession::new(inner))
     }
 
-    /// Encrypt audio frame
-    /// - base_key_b64: base64-encoded 32-byte base key from deriveMediaBaseKey
-    /// - plaintext: Uint8Array of audio data
-    /// - frame_counter: 32-bit frame counter (u32)
-    /// - aad: Uint8Array additional authenticated data
-    /// Returns Uint8Array of ciphertext
-    #[wasm_bindgen(js_name = encryptAudioFrame)]
-    pub fn encrypt_audio_frame(
+    /// Derive the exporter-based label for a sender's track at the current epoch
+    /// - sender_pubkey: hex pubkey of the track publisher
+    /// - kind: "audio", "video" or "screen"
+    #[wasm_bindgen(js_name = deriveTrackLabel)]
+    pub fn derive_track_label(
         &self,
-        base_key_b64: String,
-        plaintext: &[u8],
-        frame_counter: u32,
-        aad: &[u8],
-    ) -> Result<Vec<u8>, JsValue> {
-        use crate::media_crypto::MediaCrypto;
-        use base64::engine::general_purpose::STANDARD as BASE64;
-        use base64::Engine as _;
+        sender_pubkey: String,
+        kind: String,
+    ) -> Result<String, JsValue> {
+        let kind = TrackKind::from_name(&kind)
+            .ok_or_else(|| js_error(format!("unknown track kind {kind:?}")))?;
+        self.state
+            .borrow()
+            .identity
+            .derive_track_label(&sender_pubkey, &kind)
+            .map_err(js_error)
+    }
 
-        let base_key_bytes = BASE64
-            .decode(&base_key_b64)
-            .map_err(|e| js_error(format!("invalid base key: {e}")))?;
+    /// Get current epoch number
+    #[wasm_bindgen(js_name = currentEpoch)]
+    pub fn current_epoch(&self) -> Result<u64, JsValue> {
+        self.state
+            .borrow()
+            .identity
+            .current_epoch()
+            .map_err(js_error)
+    }
 
-        if base_key_bytes.len() != 32 {
-            return Err(js_error("base key must be 32 bytes"));
-        }
+    /// Get the group root the MoQ connection uses (MoQ path base)
+    #[wasm_bindgen(js_name = groupRoot)]
+    pub fn group_root(&self) -> Result<String, JsValue> {
+        self.state.borrow().active_moq_root().map_err(js_error)
+    }
+}
 
-        let mut base_key = [0u8; 32];
-        base_key.copy_from_slice(&base_key_bytes);
+/// Opaque media base key held in WASM memory
+#[wasm_bindgen]
+pub struct WasmMediaKey {
+    key: MediaBaseKey,
+    epoch: u64,
+    sender_pubkey: String,
+    track_label: String,
+    state: Rc<RefCell<ControllerState>>,
+}
 
-        let mut crypto = MediaCrypto::new(base_key);
-        let ciphertext = crypto
-            .encrypt(plaintext, frame_counter, aad)
-            .map_err(js_error)?;
+#[wasm_bindgen]
+impl WasmMediaKey {
+    /// Epoch the key was exported under
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
 
-        Ok(ciphertext)
+    /// SFrame key id derived from the key (not secret)
+    /// - cipher_suite: as for `createMediaSession`; the KID depends on it
+    #[wasm_bindgen(js_name = keyId)]
+    pub fn key_id(&self, cipher_suite: Option<String>) -> Result<u64, JsValue> {
+        let suite = parse_cipher_suite(cipher_suite)?;
+        Ok(self.key.key_id(suite))
     }
 
-    /// Decrypt audio frame
-    /// - base_key_b64: base64-encoded 32-byte base key from deriveMediaBaseKey
-    /// - ciphertext: Uint8Array of encrypted data
-    /// - frame_counter: 32-bit frame counter (u32)
-    /// - aad: Uint8Array additional authenticated data (must match encryption)
-    /// Returns Uint8Array of plaintext
-    #[wasm_bindgen(js_name = decryptAudioFrame)]
-    pub fn decrypt_audio_frame(
+    /// Start a media session on this key
+    /// - cipher_suite: as for `createMediaSession`
+    /// The session shares the controller's key ring for this sender and
+    /// track, exactly as `createMediaSession` does, so it never restarts a
+    /// frame counter. Fails once the epoch has moved past the key's.
+    #[wasm_bindgen(js_name = createSession)]
+    pub fn create_session(
         &self,
-        base_key_b64: String,
-        ciphertext: &[u8],
-        frame_counter: u32,
-        aad: &[u8],
-    ) -> Result<Vec<u8>, JsValue> {
-        use crate::media_crypto::MediaCrypto;
-        use base64::engine::general_purpose::STANDARD as BASE64;
-        use base64::Engine as _;
+        cipher_suite: Option<String>,
+    ) -> Result<WasmMediaSession, JsValue> {
+        let suite = parse_cipher_suite(cipher_suite)?;
+        let state = self.state.borrow();
+        let current = state.identity.current_epoch().map_err(js_error)?;
+        if current != self.epoch {
+            return Err(js_error(format!(
+                "media key is from epoch {}, group is at {current}",
+                self.epoch
+            )));
+        }
+        let inner = state
+            .create_media_session(&self.sender_pubkey, &self.track_label, suite)
+            .map_err(js_error)?;
+        Ok(WasmMediaSession::new(inner))
+    }
+}
 
-        let base_key_bytes = BASE64
-            .decode(&base_key_b64)
-            .map_err(|e| js_error(format!("invalid base key: {e}")))?;
+pub(super) fn parse_cipher_suite(name: Option<String>) -> Result<CipherSuite, JsValue> {
+    Ok(name
+        .map(|name| CipherSuite::from_name(&name))
+        .transpose()
+        .map_err(js_error)?
+        .unwrap_or_default())
+}
 
-        if base_key_bytes.len() != 32 {
-            return Err(js_error("base key must be 32 bytes"));
+#[wasm_bindgen]
+pub struct WasmMediaSession {
+    inner: MediaSession,
+    transformer: FrameTransformer,
+}
+
+impl WasmMediaSession {
+    pub(super) fn new(inner: MediaSession) -> Self {
+        Self {
+            inner,
+            transformer: FrameTransformer::new(CodecKind::Other),
         }
+    }
+}
 
-        let mut base_key = [0u8; 32];
-        base_key.copy_from_slice(&base_key_bytes);
+#[wasm_bindgen]
+impl WasmMediaSession {
+    /// Epoch the session currently encrypts under
+    pub fn epoch(&self) -> u64 {
+        self.inner.epoch()
+    }
 
-        let mut crypto = MediaCrypto::new(base_key);
-        let plaintext = crypto
-            .decrypt(ciphertext, frame_counter, aad)
-            .map_err(js_error)?;
+    /// Epoch of a still-accepted key, or undefined once it has expired
+    /// - key_id: SFrame key id (see `sframeKeyId`)
+    #[wasm_bindgen(js_name = epochForKeyId)]
+    pub fn epoch_for_key_id(&self, key_id: u64) -> Option<u64> {
+        self.inner.epoch_for_key_id(key_id)
+    }
 
-        Ok(plaintext)
+    /// False if the group has moved past the session's epoch and its keys
+    /// could not be rotated
+    #[wasm_bindgen(js_name = isCurrent)]
+    pub fn is_current(&self) -> bool {
+        self.inner.is_current()
     }
 
-    /// Get current epoch number
-    #[wasm_bindgen(js_name = currentEpoch)]
-    pub fn current_epoch(&self) -> Result<u64, JsValue> {
-        self.state
-            .borrow()
-            .identity
-            .current_epoch()
+    /// SFrame key id carried in headers produced by this session
+    #[wasm_bindgen(js_name = keyId)]
+    pub fn key_id(&self) -> u64 {
+        self.inner.key_id()
+    }
+
+    /// Select the codec used by `encryptCodecFrame`/`decryptCodecFrame`
+    /// - codec: codec name from the track's directory entry (e.g. "vp8")
+    #[wasm_bindgen(js_name = setCodec)]
+    pub fn set_codec(&mut self, codec: String) {
+        self.transformer = FrameTransformer::new(CodecKind::from_name(&codec));
+    }
+
+    /// Encrypt an encoded frame, leaving codec headers in plaintext
+    /// - frame: Uint8Array of encoded media (H.264 in Annex B format)
+    /// - metadata: Uint8Array authenticated alongside the frame
+    /// Returns Uint8Array of the partially encrypted frame plus trailer
+    #[wasm_bindgen(js_name = encryptCodecFrame)]
+    pub fn encrypt_codec_frame(
+        &mut self,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<Vec<u8>, JsValue> {
+        self.inner
+            .encrypt_codec_frame(&self.transformer, frame, metadata)
             .map_err(js_error)
     }
 
-    /// Get group root (MoQ path base)
-    #[wasm_bindgen(js_name = groupRoot)]
-    pub fn group_root(&self) -> Result<String, JsValue> {
-        self.state
-            .borrow()
-            .identity
-            .derive_group_root()
+    /// Decrypt a frame produced by `encryptCodecFrame`
+    /// - frame: Uint8Array of the partially encrypted frame plus trailer
+    /// - metadata: Uint8Array (must match encryption)
+    /// Returns the original encoded frame and the epoch that decrypted it
+    #[wasm_bindgen(js_name = decryptCodecFrame)]
+    pub fn decrypt_codec_frame(
+        &mut self,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<WasmDecryptedFrame, JsValue> {
+        self.inner
+            .decrypt_codec_frame(&self.transformer, frame, metadata)
+            .map(WasmDecryptedFrame::from)
             .map_err(js_error)
     }
+
+    /// Encrypt a frame into SFrame wire format (RFC 9605 header, Marmot
+    /// key schedule)
+    /// - plaintext: Uint8Array of media data
+    /// - metadata: Uint8Array authenticated alongside the SFrame header
+    /// Returns Uint8Array of SFrame header followed by ciphertext
+    #[wasm_bindgen(js_name = encryptFrame)]
+    pub fn encrypt_frame(&mut self, plaintext: &[u8], metadata: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.inner
+            .encrypt_sframe(plaintext, metadata)
+            .map_err(js_error)
+    }
+
+    /// Decrypt an SFrame produced by `encryptFrame`
+    /// - frame: Uint8Array of SFrame header followed by ciphertext
+    /// - metadata: Uint8Array (must match encryption)
+    /// Returns the plaintext and the epoch whose key matched the header
+    #[wasm_bindgen(js_name = decryptFrame)]
+    pub fn decrypt_frame(
+        &mut self,
+        frame: &[u8],
+        metadata: &[u8],
+    ) -> Result<WasmDecryptedFrame, JsValue> {
+        self.inner
+            .decrypt_sframe(frame, metadata)
+            .map(WasmDecryptedFrame::from)
+            .map_err(js_error)
+    }
+
+    /// Encrypt a frame under the next library-allocated frame counter
+    /// - plaintext: Uint8Array of media data
+    /// - build_aad: function (frameCounter: number) => Uint8Array
+    /// Returns Uint8Array of the 4-byte big-endian frame counter followed by
+    /// the ciphertext
+    #[wasm_bindgen(js_name = encryptNext)]
+    pub fn encrypt_next(
+        &mut self,
+        plaintext: &[u8],
+        build_aad: &Function,
+    ) -> Result<Vec<u8>, JsValue> {
+        // A throwing `build_aad` aborts the frame without using a counter
+        let mut aad_error = None;
+        let (frame_counter, ciphertext) = self
+            .inner
+            .encrypt_next(plaintext, |counter| {
+                build_aad
+                    .call1(&JsValue::NULL, &JsValue::from(counter))
+                    .map(|value| Uint8Array::new(&value).to_vec())
+                    .map_err(|err| {
+                        aad_error = Some(err);
+                        anyhow!("build_aad threw")
+                    })
+            })
+            .map_err(|err| aad_error.take().unwrap_or_else(|| js_error(err)))?;
+
+        let mut framed = Vec::with_capacity(4 + ciphertext.len());
+        framed.extend_from_slice(&frame_counter.to_be_bytes());
+        framed.extend_from_slice(&ciphertext);
+        Ok(framed)
+    }
+
+    /// Decrypt a frame
+    /// - ciphertext: Uint8Array of encrypted data
+    /// - frame_counter: 32-bit frame counter (u32)
+    /// - aad: Uint8Array additional authenticated data (must match encryption)
+    /// Returns the plaintext and the epoch that decrypted it
+    pub fn decrypt(
+        &mut self,
+        ciphertext: &[u8],
+        frame_counter: u32,
+        aad: &[u8],
+    ) -> Result<WasmDecryptedFrame, JsValue> {
+        self.inner
+            .decrypt(ciphertext, frame_counter, aad)
+            .map(WasmDecryptedFrame::from)
+            .map_err(js_error)
+    }
+}
+
+/// Result of a media session decrypt
+#[wasm_bindgen]
+pub struct WasmDecryptedFrame {
+    inner: DecryptedFrame,
+}
+
+#[wasm_bindgen]
+impl WasmDecryptedFrame {
+    #[wasm_bindgen(getter)]
+    pub fn plaintext(&self) -> Vec<u8> {
+        self.inner.plaintext.clone()
+    }
+
+    /// Epoch whose key decrypted the frame
+    #[wasm_bindgen(getter)]
+    pub fn epoch(&self) -> u64 {
+        self.inner.epoch
+    }
+}
+
+impl From<DecryptedFrame> for WasmDecryptedFrame {
+    fn from(inner: DecryptedFrame) -> Self {
+        Self { inner }
+    }
+}
+
+/// Key id from an SFrame header, for picking the matching media session
+#[wasm_bindgen(js_name = sframeKeyId)]
+pub fn sframe_key_id(frame: &[u8]) -> Result<u64, JsValue> {
+    SframeHeader::parse(frame)
+        .map(|(header, _)| header.kid)
+        .map_err(js_error)
+}
+
+/// Frame counter from an SFrame header
+#[wasm_bindgen(js_name = sframeCounter)]
+pub fn sframe_counter(frame: &[u8]) -> Result<u64, JsValue> {
+    SframeHeader::parse(frame)
+        .map(|(header, _)| header.ctr)
+        .map_err(js_error)
+}
+
+/// Canonical AAD bytes for the given fields (see `AadFields`)
+#[wasm_bindgen(js_name = encodeAad)]
+pub fn encode_aad(fields: JsValue) -> Result<Vec<u8>, JsValue> {
+    let fields: AadFields = swb::from_value(fields).map_err(js_error)?;
+    fields.encode().map_err(js_error)
 }
 
 pub(super) fn js_error<E: ToString>(err: E) -> JsValue {
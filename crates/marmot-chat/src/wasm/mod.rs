@@ -1,10 +1,12 @@
 mod controller_bridge;
+mod hub;
 mod identity;
 mod moq_bridge;
 mod nostr_client;
 mod wrapper_utils;
 
 pub use controller_bridge::*;
+pub use hub::*;
 pub use identity::*;
 pub use moq_bridge::*;
 pub use nostr_client::*;
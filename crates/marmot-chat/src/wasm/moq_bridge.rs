@@ -220,6 +220,25 @@ impl MoqService for JsMoqService {
         }
     }
 
+    fn unsubscribe_from_peer(&self, peer_pubkey: &str) {
+        let handle = match self.handle.borrow().as_ref() {
+            Some(h) => h.clone(),
+            None => return,
+        };
+
+        match get_bridge_method(&handle, "unsubscribeFromPeer") {
+            Ok(unsubscribe_fn) => {
+                let pubkey_js = JsValue::from_str(peer_pubkey);
+                if let Err(err) = unsubscribe_fn.call1(&handle, &pubkey_js) {
+                    log::error!("unsubscribe_from_peer error: {:?}", err);
+                }
+            }
+            Err(err) => {
+                log::error!("unsubscribe_from_peer method not found: {:?}", err);
+            }
+        }
+    }
+
     fn publish_wrapper(&self, bytes: &[u8]) {
         if !*self.ready.borrow() {
             self.pending.borrow_mut().push(bytes.to_vec());
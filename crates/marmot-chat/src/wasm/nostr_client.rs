@@ -1,424 +1,672 @@
 // =====================================================
-// Nostr service (handshake over websocket)
+// Nostr service (handshake over a pool of websockets)
 // =====================================================
 
 use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::{Rc, Weak};
 
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 
-use js_sys::Object;
-use log::debug;
-use serde_json::{json, Value as JsonValue};
-use web_sys::{BinaryType, ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{BinaryType, MessageEvent, WebSocket};
 
 use nostr::prelude::*;
-use nostr::JsonUtil;
 
-use crate::controller::events::SessionRole;
+use crate::controller::events::{RelayHealth, RelayState, SessionRole};
+use crate::controller::handshake_wire::{self, RelayMessage, SeenEvents};
+use crate::controller::key_packages;
+use crate::controller::reconnect::Backoff;
 use crate::controller::services::{
-    HandshakeConnectParams, HandshakeListener, HandshakeMessage, HandshakeMessageBody,
-    HandshakeMessageType, NostrService,
+    HandshakeConnectParams, HandshakeListener, HandshakeMessage, KeyPackageListener, NostrService,
 };
 
-use super::identity::{js_error, HANDSHAKE_KIND};
+/// Give up waiting for relays that never finish a key package lookup
+const FETCH_TIMEOUT_MS: i32 = 5_000;
+/// Most messages held for a relay that is down; the oldest go first, and
+/// the sessions hear of it
+const OUTBOX_LIMIT: usize = 256;
 
+/// Handshake channel for one session over a pool of relays
+///
+/// Views made with [`JsNostrService::share`] use the same pool, which
+/// grows by every relay a session names. Each session subscribes on every
+/// relay to the gift wraps addressed to its key and wraps are routed by the
+/// session named inside them, welcomes to every invitee session of the
+/// key. Events go to all relays and are de-duplicated on the way in, so
+/// one reachable relay is enough. A relay that drops is retried with
+/// jittered exponential backoff and resubscribed from shortly before it
+/// dropped; every session hears of each change. The pool closes with the
+/// last session. Welcomes also go to the invitee's inbox relays over
+/// throwaway sockets.
 pub(super) struct JsNostrService {
     state: Rc<JsNostrState>,
+    session: RefCell<Option<String>>,
+}
+
+struct SessionChannel {
+    role: SessionRole,
+    keys: Keys,
+    listener: Rc<dyn HandshakeListener>,
+}
+
+/// Key package lookup waiting for the relays' `EOSE`
+#[derive(Default)]
+struct KeyPackageFetch {
+    listeners: Vec<Box<dyn KeyPackageListener>>,
+    events: Vec<String>,
+    /// Relays that sent `EOSE`
+    answered: HashSet<String>,
+    timeout: Option<i32>,
 }
 
+/// One relay of the pool
+struct RelayConn {
+    url: String,
+    socket: Option<WebSocket>,
+    /// Bumped for every socket so handlers of an old one stand down
+    generation: u64,
+    open: bool,
+    /// Messages sent while the socket was not open
+    outbox: VecDeque<String>,
+    /// Messages pushed out of a full `outbox`
+    dropped: u32,
+    backoff: Backoff,
+    /// When the last open socket dropped
+    dropped_at: Option<Timestamp>,
+    /// Pending reconnect timer
+    retry: Option<i32>,
+    /// Handlers of the current socket
+    handlers: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+#[derive(Default)]
 struct JsNostrState {
-    socket: RefCell<Option<WebSocket>>,
-    listener: RefCell<Option<Box<dyn HandshakeListener>>>,
-    keys: RefCell<Option<Keys>>,
-    session: RefCell<Option<String>>,
-    url: RefCell<Option<String>>,
-    role: RefCell<SessionRole>,
-    on_message: RefCell<Option<Closure<dyn FnMut(MessageEvent)>>>,
-    on_open: RefCell<Option<Closure<dyn FnMut(JsValue)>>>,
-    on_error: RefCell<Option<Closure<dyn FnMut(ErrorEvent)>>>,
-    pending: RefCell<VecDeque<HandshakeMessage>>,
+    relays: RefCell<Vec<RelayConn>>,
+    sessions: RefCell<HashMap<String, SessionChannel>>,
+    /// Key package lookups by pubkey
+    fetches: RefCell<HashMap<String, KeyPackageFetch>>,
+    /// Rumors already handled, so relay echoes are dropped
+    seen: RefCell<SeenEvents>,
 }
 
 impl JsNostrService {
     pub(super) fn new() -> Self {
         Self {
-            state: Rc::new(JsNostrState {
-                socket: RefCell::new(None),
-                listener: RefCell::new(None),
-                keys: RefCell::new(None),
-                session: RefCell::new(None),
-                url: RefCell::new(None),
-                role: RefCell::new(SessionRole::Initial),
-                on_message: RefCell::new(None),
-                on_open: RefCell::new(None),
-                on_error: RefCell::new(None),
-                pending: RefCell::new(VecDeque::new()),
-            }),
+            state: Rc::new(JsNostrState::default()),
+            session: RefCell::new(None),
+        }
+    }
+
+    /// Another session channel over the same pool
+    pub(super) fn share(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            session: RefCell::new(None),
         }
     }
 }
 
 impl NostrService for JsNostrService {
     fn connect(&self, params: HandshakeConnectParams, listener: Box<dyn HandshakeListener>) {
-        JsNostrState::connect_rc(self.state.clone(), params, listener);
+        *self.session.borrow_mut() = Some(params.session.clone());
+        JsNostrState::connect_rc(&self.state, params, listener);
     }
 
     fn send(&self, payload: HandshakeMessage) {
-        JsNostrState::send_rc(&self.state, payload);
+        match self.session.borrow().as_ref() {
+            Some(session) => JsNostrState::send_rc(&self.state, session, payload),
+            None => log::error!("handshake send before connect"),
+        }
+    }
+
+    fn publish_key_package(&self, event_json: String) {
+        match key_packages::publish_message(&event_json) {
+            Some(text) => JsNostrState::broadcast(&self.state, &text),
+            None => log::error!("refusing to publish a malformed key package"),
+        }
+    }
+
+    fn delete_key_packages(&self, deletion_json: String) {
+        match key_packages::deletion_message(&deletion_json) {
+            Some(text) => JsNostrState::broadcast(&self.state, &text),
+            None => log::error!("refusing to publish a malformed deletion"),
+        }
+    }
+
+    fn fetch_key_packages(&self, pubkey: &str, listener: Box<dyn KeyPackageListener>) {
+        let first = {
+            let mut fetches = self.state.fetches.borrow_mut();
+            let fetch = fetches.entry(pubkey.to_string()).or_default();
+            fetch.listeners.push(listener);
+            fetch.listeners.len() == 1
+        };
+        // Later callers for the same pubkey share the lookup in flight
+        if !first {
+            return;
+        }
+        let weak = Rc::downgrade(&self.state);
+        let owner = pubkey.to_string();
+        let timeout = set_timeout(FETCH_TIMEOUT_MS, move || {
+            if let Some(state) = weak.upgrade() {
+                log::debug!("key package lookup for {owner} timed out");
+                JsNostrState::finish_fetch(&state, &owner);
+            }
+        });
+        if let Some(fetch) = self.state.fetches.borrow_mut().get_mut(pubkey) {
+            fetch.timeout = timeout;
+        }
+        // Relays still connecting are asked once they open
+        JsNostrState::send_open(&self.state, &key_packages::fetch_request(pubkey));
     }
 
     fn shutdown(&self) {
-        JsNostrState::shutdown_rc(&self.state);
+        if let Some(session) = self.session.borrow_mut().take() {
+            JsNostrState::shutdown_rc(&self.state, &session);
+        }
     }
 }
 
 impl JsNostrState {
     fn connect_rc(
-        state: Rc<JsNostrState>,
+        state: &Rc<JsNostrState>,
         params: HandshakeConnectParams,
         listener: Box<dyn HandshakeListener>,
     ) {
-        *state.listener.borrow_mut() = Some(listener);
-        *state.role.borrow_mut() = params.role;
-        *state.session.borrow_mut() = Some(params.session.clone());
-        *state.url.borrow_mut() = Some(params.url.clone());
-        state.pending.borrow_mut().clear();
-
-        match SecretKey::from_hex(&params.secret_hex).map(Keys::new) {
-            Ok(keys) => {
-                *state.keys.borrow_mut() = Some(keys);
-            }
+        let keys = match SecretKey::from_hex(params.secret_hex.expose()).map(Keys::new) {
+            Ok(keys) => keys,
             Err(err) => {
                 log::error!("invalid handshake secret: {err}");
                 return;
             }
-        }
+        };
+        let pubkey = keys.public_key().to_hex();
+        state.sessions.borrow_mut().insert(
+            params.session.clone(),
+            SessionChannel {
+                role: params.role,
+                keys,
+                listener: Rc::from(listener),
+            },
+        );
+        // Its subscription replays stored wraps, which it has to hear
+        state.seen.borrow_mut().clear();
 
-        match WebSocket::new(&params.url) {
-            Ok(socket) => {
-                let _ = socket.set_binary_type(BinaryType::Arraybuffer);
-                JsNostrState::install_handlers(state.clone(), &socket);
-                *state.socket.borrow_mut() = Some(socket);
+        // Relays not open yet subscribe every session when they open
+        JsNostrState::send_open(state, &handshake_wire::subscription_request(&pubkey, None));
+        let mut added = Vec::new();
+        {
+            let mut relays = state.relays.borrow_mut();
+            for url in std::iter::once(&params.url).chain(&params.relays) {
+                if !relays.iter().any(|relay| relay.url == *url) {
+                    relays.push(RelayConn::new(url));
+                    added.push(url.clone());
+                }
             }
+        }
+        for url in added {
+            JsNostrState::open_relay(state, &url);
+        }
+    }
+
+    fn send_rc(state: &Rc<JsNostrState>, session: &str, payload: HandshakeMessage) {
+        let Some((keys, role)) = state
+            .sessions
+            .borrow()
+            .get(session)
+            .map(|channel| (channel.keys.clone(), channel.role))
+        else {
+            log::error!("handshake session {session} missing");
+            return;
+        };
+        let event = match handshake_wire::build_handshake_event(&keys, session, role, &payload) {
+            Ok(event) => event,
             Err(err) => {
-                log::error!("failed to open handshake websocket: {:?}", err);
+                log::error!("failed to build handshake event: {err:#}");
+                return;
             }
+        };
+        let text = handshake_wire::event_message(&event);
+        JsNostrState::broadcast(state, &text);
+        let urls: Vec<String> = state
+            .relays
+            .borrow()
+            .iter()
+            .map(|relay| relay.url.clone())
+            .collect();
+        let connected: Vec<&str> = urls.iter().map(String::as_str).collect();
+        for url in handshake_wire::unconnected_inbox_relays(&payload, &connected) {
+            JsNostrState::publish_once(url, text.clone());
         }
     }
 
-    fn send_rc(state: &Rc<JsNostrState>, payload: HandshakeMessage) {
-        if !JsNostrState::is_socket_open(state) {
-            state.pending.borrow_mut().push_back(payload);
-            return;
+    /// Send `text` to every relay, holding it for those that are not open
+    fn broadcast(state: &Rc<JsNostrState>, text: &str) {
+        let mut overflowed = Vec::new();
+        for relay in state.relays.borrow_mut().iter_mut() {
+            if !relay.send(text) {
+                if relay.outbox.len() == OUTBOX_LIMIT {
+                    relay.outbox.pop_front();
+                    relay.dropped = relay.dropped.saturating_add(1);
+                    log::warn!(
+                        "handshake relay {} is down; dropped its oldest held message",
+                        relay.url
+                    );
+                    let waiting = if relay.socket.is_some() {
+                        RelayState::Connecting
+                    } else {
+                        RelayState::Reconnecting
+                    };
+                    overflowed.push(relay.health(waiting, None));
+                }
+                relay.outbox.push_back(text.to_string());
+            }
         }
-        if let Err(err) = JsNostrState::send_now(state, &payload) {
-            log::error!("failed to send handshake event: {:?}", err);
-            state.pending.borrow_mut().push_back(payload);
-            return;
+        for health in overflowed {
+            JsNostrState::report(state, health);
         }
-        JsNostrState::flush_pending(state);
     }
 
-    fn is_socket_open(state: &Rc<JsNostrState>) -> bool {
-        match state.socket.borrow().as_ref() {
-            Some(socket) => socket.ready_state() == WebSocket::OPEN,
-            None => false,
+    /// Send `text` to the relays that are open now
+    fn send_open(state: &Rc<JsNostrState>, text: &str) {
+        for relay in state.relays.borrow().iter() {
+            relay.send(text);
         }
     }
 
-    fn send_now(state: &Rc<JsNostrState>, payload: &HandshakeMessage) -> Result<(), JsValue> {
-        let keys = state
-            .keys
-            .borrow()
-            .as_ref()
-            .cloned()
-            .ok_or_else(|| js_error("handshake keys missing"))?;
-        let session = state
-            .session
-            .borrow()
-            .as_ref()
-            .cloned()
-            .ok_or_else(|| js_error("handshake session missing"))?;
-        let socket_ref = state.socket.borrow();
-        let socket = socket_ref
-            .as_ref()
-            .ok_or_else(|| js_error("handshake socket missing"))?;
-        let role = *state.role.borrow();
-
-        let content = handshake_payload(&session, role, payload);
-        let content_json = serde_json::to_string(&content)
-            .map_err(|err| js_error(format!("handshake payload serialize error: {err}")))?;
-        let tags = vec![
-            Tag::custom(TagKind::custom("t"), [session.clone()]),
-            Tag::custom(
-                TagKind::custom("type"),
-                [payload.message_type.as_str().to_string()],
-            ),
-            Tag::custom(TagKind::custom("role"), [role.as_str().to_string()]),
-        ];
-
-        let builder = EventBuilder::new(Kind::from(HANDSHAKE_KIND), content_json).tags(tags);
-        let event = builder
-            .sign_with_keys(&keys)
-            .map_err(|err| js_error(format!("failed to sign handshake event: {err}")))?;
-
-        let outbound = format!("[\"EVENT\",{}]", event.as_json());
-        socket.send_with_str(&outbound)
+    /// Hand one event to a relay we do not otherwise talk to
+    fn publish_once(url: &str, text: String) {
+        let socket = match WebSocket::new(url) {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::warn!("failed to open inbox relay {url}: {:?}", err);
+                return;
+            }
+        };
+        let sender = socket.clone();
+        // Data queued before `close` is still sent
+        let on_open = Closure::once_into_js(move |_: JsValue| {
+            if let Err(err) = sender.send_with_str(&text) {
+                log::warn!("failed to send to inbox relay: {:?}", err);
+            }
+            let _ = sender.close();
+        });
+        socket.set_onopen(Some(on_open.unchecked_ref()));
     }
 
-    fn flush_pending(state: &Rc<JsNostrState>) {
-        loop {
-            if !JsNostrState::is_socket_open(state) {
-                break;
+    /// Open a new socket to the pool relay at `url`
+    fn open_relay(state: &Rc<JsNostrState>, url: &str) {
+        let health = {
+            let mut relays = state.relays.borrow_mut();
+            let Some(relay) = relays.iter_mut().find(|relay| relay.url == url) else {
+                return;
+            };
+            relay.generation += 1;
+            relay.open = false;
+            relay.retry = None;
+            if let Some(old) = relay.socket.take() {
+                detach(&old);
+                let _ = old.close();
             }
-            let message = {
-                let mut queue = state.pending.borrow_mut();
-                queue.pop_front()
+            match WebSocket::new(url) {
+                Ok(socket) => {
+                    let _ = socket.set_binary_type(BinaryType::Arraybuffer);
+                    relay.handlers = install_handlers(state, &socket, url, relay.generation);
+                    relay.socket = Some(socket);
+                    Some(relay.health(RelayState::Connecting, None))
+                }
+                Err(err) => {
+                    log::error!("failed to open handshake relay {url}: {:?}", err);
+                    None
+                }
+            }
+        };
+        match health {
+            Some(health) => JsNostrState::report(state, health),
+            None => JsNostrState::schedule_retry(state, url),
+        }
+    }
+
+    fn on_open(state: &Rc<JsNostrState>, url: &str, generation: u64) {
+        let pubkeys: HashSet<String> = state
+            .sessions
+            .borrow()
+            .values()
+            .map(|channel| channel.keys.public_key().to_hex())
+            .collect();
+        let lookups: Vec<String> = state.fetches.borrow().keys().cloned().collect();
+        let health = {
+            let mut relays = state.relays.borrow_mut();
+            let Some(relay) = relays
+                .iter_mut()
+                .find(|relay| relay.url == url && relay.generation == generation)
+            else {
+                return;
             };
-            let Some(message) = message else {
-                break;
+            relay.open = true;
+            relay.backoff.reset();
+            // Wraps seen before the drop come back too; `seen` drops them
+            let since = relay.dropped_at.map(handshake_wire::resume_since);
+            for pubkey in &pubkeys {
+                relay.send(&handshake_wire::subscription_request(pubkey, since));
+            }
+            for pubkey in &lookups {
+                relay.send(&key_packages::fetch_request(pubkey));
+            }
+            while let Some(text) = relay.outbox.pop_front() {
+                if !relay.send(&text) {
+                    relay.outbox.push_front(text);
+                    break;
+                }
+            }
+            relay.health(RelayState::Connected, None)
+        };
+        JsNostrState::report(state, health);
+    }
+
+    fn on_close(state: &Rc<JsNostrState>, url: &str, generation: u64) {
+        {
+            let mut relays = state.relays.borrow_mut();
+            let Some(relay) = relays
+                .iter_mut()
+                .find(|relay| relay.url == url && relay.generation == generation)
+            else {
+                return;
             };
-            if let Err(err) = JsNostrState::send_now(state, &message) {
-                log::error!("failed to flush handshake event: {:?}", err);
-                state.pending.borrow_mut().push_front(message);
-                break;
+            if relay.open {
+                relay.dropped_at = Some(Timestamp::now());
+            }
+            relay.open = false;
+            if let Some(socket) = relay.socket.take() {
+                detach(&socket);
             }
         }
+        log::warn!("handshake relay {url} disconnected");
+        // Lookups stop waiting on a relay that can no longer answer
+        JsNostrState::settle_fetches(state);
+        JsNostrState::schedule_retry(state, url);
     }
 
-    fn shutdown_rc(state: &Rc<JsNostrState>) {
-        if let Some(socket) = state.socket.borrow_mut().take() {
-            let _ = socket.close();
+    fn schedule_retry(state: &Rc<JsNostrState>, url: &str) {
+        let health = {
+            let mut relays = state.relays.borrow_mut();
+            let Some(relay) = relays.iter_mut().find(|relay| relay.url == url) else {
+                return;
+            };
+            let delay = relay.backoff.next_delay_ms();
+            let weak = Rc::downgrade(state);
+            let target = url.to_string();
+            relay.retry = set_timeout(i32::try_from(delay).unwrap_or(i32::MAX), move || {
+                if let Some(state) = weak.upgrade() {
+                    JsNostrState::open_relay(&state, &target);
+                }
+            });
+            relay.health(RelayState::Reconnecting, Some(delay))
+        };
+        JsNostrState::report(state, health);
+    }
+
+    /// Tell every session how a relay is doing
+    fn report(state: &Rc<JsNostrState>, health: RelayHealth) {
+        let listeners: Vec<Rc<dyn HandshakeListener>> = state
+            .sessions
+            .borrow()
+            .values()
+            .map(|channel| channel.listener.clone())
+            .collect();
+        for listener in listeners {
+            listener.on_relay_health(health.clone());
         }
-        state.listener.borrow_mut().take();
-        state.keys.borrow_mut().take();
-        state.on_message.borrow_mut().take();
-        state.on_open.borrow_mut().take();
-        state.on_error.borrow_mut().take();
-        state.pending.borrow_mut().clear();
     }
 
-    fn install_handlers(state: Rc<JsNostrState>, socket: &WebSocket) {
-        let state_for_message = state.clone();
-        let message_closure =
-            Closure::<dyn FnMut(MessageEvent)>::wrap(Box::new(move |event: MessageEvent| {
-                JsNostrState::handle_message(&state_for_message, event);
-            }));
-        socket.set_onmessage(Some(message_closure.as_ref().unchecked_ref()));
-        *state.on_message.borrow_mut() = Some(message_closure);
-
-        let state_for_open = state.clone();
-        let open_closure = Closure::<dyn FnMut(JsValue)>::wrap(Box::new(move |_| {
-            JsNostrState::send_subscription(&state_for_open);
-        }));
-        socket.set_onopen(Some(open_closure.as_ref().unchecked_ref()));
-        *state.on_open.borrow_mut() = Some(open_closure);
-
-        let error_closure =
-            Closure::<dyn FnMut(ErrorEvent)>::wrap(Box::new(move |event: ErrorEvent| {
-                log::error!("handshake websocket error: {}", event.message());
-            }));
-        socket.set_onerror(Some(error_closure.as_ref().unchecked_ref()));
-        *state.on_error.borrow_mut() = Some(error_closure);
+    /// Finish the lookups that every open relay has answered
+    fn settle_fetches(state: &Rc<JsNostrState>) {
+        let open: Vec<String> = state
+            .relays
+            .borrow()
+            .iter()
+            .filter(|relay| relay.open)
+            .map(|relay| relay.url.clone())
+            .collect();
+        let done: Vec<String> = state
+            .fetches
+            .borrow()
+            .iter()
+            .filter(|(_, fetch)| {
+                !fetch.answered.is_empty() && open.iter().all(|url| fetch.answered.contains(url))
+            })
+            .map(|(pubkey, _)| pubkey.clone())
+            .collect();
+        for pubkey in done {
+            JsNostrState::finish_fetch(state, &pubkey);
+        }
     }
 
-    fn send_subscription(state: &Rc<JsNostrState>) {
-        let socket_borrow = state.socket.borrow();
-        let socket = match socket_borrow.as_ref() {
-            Some(socket) => socket,
-            None => return,
-        };
-        let session_borrow = state.session.borrow();
-        let session = match session_borrow.as_ref() {
-            Some(session) => session,
-            None => return,
+    /// Hand the events collected for `pubkey` to everyone waiting on them
+    fn finish_fetch(state: &Rc<JsNostrState>, pubkey: &str) {
+        let Some(fetch) = state.fetches.borrow_mut().remove(pubkey) else {
+            return;
         };
-        let payload = json!({
-            "kinds": [HANDSHAKE_KIND],
-            "#t": [session],
-            "limit": 50,
-        });
-        let subscription = format!("[\"REQ\",\"marmot-{session}\",{}]", payload);
-        if let Err(err) = socket.send_with_str(&subscription) {
-            log::error!("failed to send handshake subscription: {:?}", err);
-        } else {
-            JsNostrState::flush_pending(state);
+        if let Some(timeout) = fetch.timeout {
+            clear_timeout(timeout);
+        }
+        JsNostrState::send_open(state, &key_packages::close_request(pubkey));
+        for listener in fetch.listeners {
+            listener.on_key_packages(pubkey.to_string(), fetch.events.clone());
         }
     }
 
-    fn handle_message(state: &Rc<JsNostrState>, event: MessageEvent) {
-        let data = match event.data().as_string() {
-            Some(text) => text,
-            None => return,
+    /// Drop one session; the pool closes once no session is left
+    fn shutdown_rc(state: &Rc<JsNostrState>, session: &str) {
+        let Some(channel) = state.sessions.borrow_mut().remove(session) else {
+            return;
         };
-        let parsed: JsonValue = match serde_json::from_str(&data) {
-            Ok(value) => value,
-            Err(err) => {
-                log::warn!("failed to parse handshake message: {err}");
-                return;
+        if !state.sessions.borrow().is_empty() {
+            let pubkey = channel.keys.public_key();
+            // Sessions of the same identity share the subscription
+            let shared = state
+                .sessions
+                .borrow()
+                .values()
+                .any(|other| other.keys.public_key() == pubkey);
+            if !shared {
+                JsNostrState::send_open(state, &handshake_wire::close_request(&pubkey.to_hex()));
             }
-        };
-        let array = match parsed.as_array() {
-            Some(array) if array.len() >= 3 => array,
-            _ => return,
-        };
-        if array.get(0).and_then(|v| v.as_str()) != Some("EVENT") {
             return;
         }
-        let event_value = array[2].clone();
-        let event_json = match serde_json::to_string(&event_value) {
-            Ok(json) => json,
-            Err(_) => return,
-        };
-        let nostr_event = match Event::from_json(&event_json) {
-            Ok(event) => event,
-            Err(err) => {
-                log::warn!("failed to decode nostr event: {err}");
-                return;
+        // Nobody is left to hear late answers; settle lookups with what came in
+        let pending: Vec<String> = state.fetches.borrow().keys().cloned().collect();
+        for pubkey in pending {
+            JsNostrState::finish_fetch(state, &pubkey);
+        }
+        let relays: Vec<RelayConn> = state.relays.borrow_mut().drain(..).collect();
+        for relay in relays {
+            if let Some(retry) = relay.retry {
+                clear_timeout(retry);
+            }
+            if let Some(socket) = relay.socket {
+                detach(&socket);
+                let _ = socket.close();
             }
-        };
-        if nostr_event.kind != Kind::from(HANDSHAKE_KIND) {
-            return;
         }
-        let content = &nostr_event.content;
-        let payload: JsonValue = match serde_json::from_str(content) {
-            Ok(value) => value,
-            Err(err) => {
-                log::warn!("invalid handshake payload: {err}");
+        state.seen.borrow_mut().clear();
+    }
+
+    fn handle_message(state: &Rc<JsNostrState>, url: &str, data: &str) {
+        let event = match handshake_wire::parse_relay_message(data) {
+            Some(RelayMessage::Event {
+                subscription,
+                event,
+            }) => match key_packages::fetched_pubkey(&subscription) {
+                Some(pubkey) => {
+                    if let Some(fetch) = state.fetches.borrow_mut().get_mut(pubkey) {
+                        let event_json = event.as_json();
+                        if !fetch.events.contains(&event_json) {
+                            fetch.events.push(event_json);
+                        }
+                    }
+                    return;
+                }
+                None => event,
+            },
+            Some(RelayMessage::EndOfStoredEvents(subscription)) => {
+                if let Some(pubkey) = key_packages::fetched_pubkey(&subscription) {
+                    if let Some(fetch) = state.fetches.borrow_mut().get_mut(pubkey) {
+                        fetch.answered.insert(url.to_string());
+                    }
+                    JsNostrState::settle_fetches(state);
+                }
                 return;
             }
-        };
-        let session = match state.session.borrow().as_ref() {
-            Some(session) => session.clone(),
             None => return,
         };
-        if payload.get("session").and_then(|v| v.as_str()) != Some(session.as_str()) {
+        // Only the recipient's key opens a wrap, and its `p` tag says which
+        let recipients: Vec<Keys> = state
+            .sessions
+            .borrow()
+            .values()
+            .filter(|channel| {
+                event
+                    .tags
+                    .public_keys()
+                    .any(|pubkey| *pubkey == channel.keys.public_key())
+            })
+            .map(|channel| channel.keys.clone())
+            .collect();
+        let Some((recipient, inbound)) = recipients.iter().find_map(|keys| {
+            handshake_wire::decode_handshake_event(keys, &event)
+                .map(|inbound| (keys.public_key(), inbound))
+        }) else {
             return;
-        }
-        let from_role = match payload
-            .get("from")
-            .and_then(|v| v.as_str())
-            .and_then(SessionRole::from_str)
-        {
-            Some(role) => role,
-            None => return,
         };
-        if from_role == *state.role.borrow() {
+        // Deduplicated by the rumor id, known only once the wrap checked
+        // out: a forged event claiming a real wrap's id must not hide it
+        if !state.seen.borrow_mut().insert(inbound.id) {
             return;
         }
-        let message = match handshake_from_payload(&payload) {
-            Some(message) => message,
-            None => return,
-        };
-        if let Some(listener) = state.listener.borrow().as_ref() {
-            listener.on_message(message);
+        let listeners: Vec<Rc<dyn HandshakeListener>> = state
+            .sessions
+            .borrow()
+            .iter()
+            .filter(|(session, channel)| {
+                channel.keys.public_key() == recipient && inbound.is_for(session, channel.role)
+            })
+            .map(|(_, channel)| channel.listener.clone())
+            .collect();
+        for listener in listeners {
+            listener.on_message(inbound.message.clone());
         }
     }
 }
 
-fn handshake_payload(session: &str, role: SessionRole, message: &HandshakeMessage) -> JsonValue {
-    let mut base = json!({
-        "type": message.message_type.as_str(),
-        "session": session,
-        "from": role.as_str(),
-        "created_at": js_sys::Date::now() as u64 / 1000,
-    });
-    match &message.data {
-        HandshakeMessageBody::None => {}
-        HandshakeMessageBody::Request { pubkey, is_admin } => {
-            if let Some(obj) = base.as_object_mut() {
-                if let Some(pubkey) = pubkey {
-                    obj.insert("pubkey".to_string(), json!(pubkey));
-                }
-                if let Some(is_admin) = is_admin {
-                    obj.insert("isAdmin".to_string(), json!(is_admin));
-                }
-            }
+impl RelayConn {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            socket: None,
+            generation: 0,
+            open: false,
+            outbox: VecDeque::new(),
+            dropped: 0,
+            backoff: Backoff::default(),
+            dropped_at: None,
+            retry: None,
+            handlers: Vec::new(),
         }
-        HandshakeMessageBody::KeyPackage {
-            event,
-            bundle,
-            pubkey,
-        } => {
-            if let Some(obj) = base.as_object_mut() {
-                obj.insert("event".to_string(), json!(event));
-                if let Some(bundle) = bundle {
-                    obj.insert("bundle".to_string(), json!(bundle));
-                }
-                if let Some(pubkey) = pubkey {
-                    obj.insert("pubkey".to_string(), json!(pubkey));
-                }
-            }
+    }
+
+    /// Health of the relay as of now
+    fn health(&self, state: RelayState, retry_in_ms: Option<u64>) -> RelayHealth {
+        RelayHealth {
+            dropped: self.dropped,
+            ..self.backoff.health(&self.url, state, retry_in_ms)
         }
-        HandshakeMessageBody::Welcome {
-            welcome,
-            group_id_hex,
-            recipient,
-        } => {
-            if let Some(obj) = base.as_object_mut() {
-                obj.insert("welcome".to_string(), json!(welcome));
-                if let Some(group) = group_id_hex {
-                    obj.insert("groupIdHex".to_string(), json!(group));
-                }
-                if let Some(recipient) = recipient {
-                    obj.insert("recipient".to_string(), json!(recipient));
-                }
+    }
+
+    /// Send `text` if the socket is open, returning whether it went out
+    fn send(&self, text: &str) -> bool {
+        let Some(socket) = self.socket.as_ref().filter(|_| self.open) else {
+            return false;
+        };
+        match socket.send_with_str(text) {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!("failed to send to handshake relay {}: {:?}", self.url, err);
+                false
             }
         }
     }
-    base
 }
 
-fn handshake_from_payload(payload: &JsonValue) -> Option<HandshakeMessage> {
-    let ty = payload.get("type")?.as_str()?;
-    let message_type = HandshakeMessageType::from_str(ty)?;
-    let data = match message_type {
-        HandshakeMessageType::RequestKeyPackage | HandshakeMessageType::RequestWelcome => {
-            let pubkey = payload
-                .get("pubkey")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
-            let is_admin = payload.get("isAdmin").and_then(|v| v.as_bool());
-            HandshakeMessageBody::Request { pubkey, is_admin }
-        }
-        HandshakeMessageType::KeyPackage => {
-            let event = payload.get("event")?.as_str()?.to_string();
-            let bundle = payload
-                .get("bundle")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
-            let pubkey = payload
-                .get("pubkey")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
-            HandshakeMessageBody::KeyPackage {
-                event,
-                bundle,
-                pubkey,
+/// Callback of a pool socket, given the relay url and socket generation
+type SocketHandler = fn(&Rc<JsNostrState>, &str, u64, JsValue);
+
+/// Wire a socket of the relay at `url` to the pool
+///
+/// The handlers hold the pool weakly and are kept with the relay until its
+/// next socket replaces them.
+fn install_handlers(
+    state: &Rc<JsNostrState>,
+    socket: &WebSocket,
+    url: &str,
+    generation: u64,
+) -> Vec<Closure<dyn FnMut(JsValue)>> {
+    let handler = |on_event: SocketHandler| {
+        let weak: Weak<JsNostrState> = Rc::downgrade(state);
+        let url = url.to_string();
+        Closure::<dyn FnMut(JsValue)>::wrap(Box::new(move |value: JsValue| {
+            if let Some(state) = weak.upgrade() {
+                on_event(&state, &url, generation, value);
             }
+        }))
+    };
+    let on_message = handler(|state, url, generation, value| {
+        let current = state
+            .relays
+            .borrow()
+            .iter()
+            .any(|relay| relay.url == url && relay.generation == generation);
+        if !current {
+            return;
         }
-        HandshakeMessageType::Welcome => {
-            let welcome = payload.get("welcome")?.as_str()?.to_string();
-            let group_id_hex = payload
-                .get("groupIdHex")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
-            let recipient = payload
-                .get("recipient")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string());
-            HandshakeMessageBody::Welcome {
-                welcome,
-                group_id_hex,
-                recipient,
-            }
+        if let Some(data) = value.unchecked_into::<MessageEvent>().data().as_string() {
+            JsNostrState::handle_message(state, url, &data);
         }
-    };
-    Some(HandshakeMessage { message_type, data })
+    });
+    let on_open =
+        handler(|state, url, generation, _| JsNostrState::on_open(state, url, generation));
+    let on_close =
+        handler(|state, url, generation, _| JsNostrState::on_close(state, url, generation));
+    // The close that follows does the work
+    let on_error = handler(|_, url, _, _| log::warn!("handshake relay {url} errored"));
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+    socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    vec![on_message, on_open, on_close, on_error]
+}
+
+fn detach(socket: &WebSocket) {
+    socket.set_onmessage(None);
+    socket.set_onopen(None);
+    socket.set_onclose(None);
+    socket.set_onerror(None);
+}
+
+/// Run `callback` once after `delay_ms`, returning the timer handle
+fn set_timeout(delay_ms: i32, callback: impl FnOnce() + 'static) -> Option<i32> {
+    let window = web_sys::window()?;
+    let callback = Closure::once_into_js(callback);
+    window
+        .set_timeout_with_callback_and_timeout_and_arguments_0(callback.unchecked_ref(), delay_ms)
+        .ok()
+}
+
+fn clear_timeout(handle: i32) {
+    if let Some(window) = web_sys::window() {
+        window.clear_timeout_with_handle(handle);
+    }
 }
 
 // =====================================================
@@ -0,0 +1,668 @@
+//! Whole controller flows over the in-memory relays in `support`
+//!
+//! Each test drives real controllers through create, invite, chat, rotate
+//! and remove, with the relays optionally delaying, reordering, dropping or
+//! duplicating traffic. Invites go through published key packages where
+//! the invitee has one, welcomes nobody asked for wait for the user and
+//! key packages from anyone but the invitee are turned away. Media sessions
+//! come from the controllers' shared key rings.
+#![cfg(not(target_arch = "wasm32"))]
+
+mod support;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use marmot_chat::cipher_suite::CipherSuite;
+use marmot_chat::controller::events::{GroupRootMode, SessionRole};
+use marmot_chat::controller::services::{
+    HandshakeConnectParams, HandshakeListener, HandshakeMessage, HandshakeMessageBody,
+    HandshakeMessageType, IdentityService, KeyPackageOptions, NostrService,
+};
+use marmot_chat::sframe::SframeHeader;
+use support::{pubkey, session, Faults, Network, Peer};
+
+const ALICE: &str = "a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1";
+const BOB: &str = "b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2b2";
+const CAROL: &str = "c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3c3";
+const DAVE: &str = "d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4d4";
+const ERIN: &str = "e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5e5";
+const FRANK: &str = "f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6f6";
+const MALLORY: &str = "0909090909090909090909090909090909090909090909090909090909090909";
+const SESSION: &str = "flow-session";
+const OTHER_SESSION: &str = "other-session";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Listener for a bare handshake client that only talks
+struct Deaf;
+
+impl HandshakeListener for Deaf {
+    fn on_message(&self, _message: HandshakeMessage) {}
+}
+
+fn assert_no_errors(peers: &[&Peer]) {
+    for peer in peers {
+        assert_eq!(
+            peer.errors(),
+            Vec::<String>::new(),
+            "errors for {}",
+            peer.pubkey
+        );
+    }
+}
+
+/// Alice creates the group with Bob and both come up on MoQ
+fn start_pair(network: &Network) -> (Peer, Peer) {
+    let alice = network.join(session(
+        SessionRole::Initial,
+        ALICE,
+        SESSION,
+        vec![pubkey(BOB)],
+    ));
+    let bob = network.join(session(
+        SessionRole::Invitee,
+        BOB,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    network.settle();
+    assert!(alice.is_ready(), "alice connected");
+    assert!(bob.is_ready(), "bob connected");
+    assert_no_errors(&[&alice, &bob]);
+    (alice, bob)
+}
+
+/// Create, chat, rotate, invite a third member and remove one, checking
+/// who hears what at every step
+fn run_group_flow(network: &Network) {
+    let (alice, bob) = start_pair(network);
+    assert_eq!(alice.roster(), bob.roster());
+
+    alice.controller.send_text("hello bob".to_string());
+    network.settle();
+    assert_eq!(bob.received_from(&alice), vec!["hello bob"]);
+
+    bob.controller.rotate_epoch();
+    network.settle();
+    bob.controller.send_text("rotated".to_string());
+    network.settle();
+    assert_eq!(alice.received_from(&bob), vec!["rotated"]);
+
+    let carol = network.join(session(
+        SessionRole::Invitee,
+        CAROL,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    alice.controller.invite_member(carol.pubkey.clone(), false);
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+    let mut everyone = vec![
+        alice.pubkey.clone(),
+        bob.pubkey.clone(),
+        carol.pubkey.clone(),
+    ];
+    everyone.sort();
+    for peer in [&alice, &bob, &carol] {
+        assert_eq!(peer.roster(), everyone, "roster of {}", peer.pubkey);
+    }
+
+    carol.controller.send_text("hi all".to_string());
+    network.settle();
+    assert_eq!(alice.received_from(&carol), vec!["hi all"]);
+    assert_eq!(bob.received_from(&carol), vec!["hi all"]);
+
+    alice.controller.remove_member(bob.pubkey.clone());
+    network.settle();
+    assert!(bob.has_left(), "bob saw his removal");
+    alice.controller.send_text("bye bob".to_string());
+    network.settle();
+    assert_eq!(carol.received_from(&alice), vec!["bye bob"]);
+    assert_eq!(bob.received_from(&alice), vec!["hello bob"]);
+
+    assert_no_errors(&[&alice, &bob, &carol]);
+}
+
+#[test]
+fn test_group_flow_over_clean_relays() -> Result<()> {
+    let network = Network::new();
+    run_group_flow(&network);
+    Ok(())
+}
+
+#[test]
+fn test_group_flow_survives_delay_reordering_and_duplicates() -> Result<()> {
+    let faults = Faults {
+        delay: 0..=4,
+        reorder: true,
+        loss: 0.0,
+        duplicate: 0.3,
+    };
+    for seed in 1..=4 {
+        let network = Network::with_seed(seed);
+        network.set_nostr_faults(faults.clone());
+        network.set_moq_faults(faults.clone());
+        run_group_flow(&network);
+        assert!(network.duplicated() > 0, "seed {seed} duplicated nothing");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_lost_messages_do_not_stall_the_group() -> Result<()> {
+    let network = Network::with_seed(7);
+    let (alice, bob) = start_pair(&network);
+
+    network.set_moq_faults(Faults {
+        loss: 0.5,
+        ..Faults::none()
+    });
+    let sent: Vec<String> = (0..10).map(|index| format!("lossy {index}")).collect();
+    for content in &sent {
+        alice.controller.send_text(content.clone());
+    }
+    network.settle();
+    let received = bob.received_from(&alice);
+    assert_eq!(received.len(), sent.len() - network.dropped());
+    assert!(received.iter().all(|content| sent.contains(content)));
+
+    // Later messages still decrypt past the gaps
+    network.set_moq_faults(Faults::none());
+    alice.controller.send_text("after the gap".to_string());
+    network.settle();
+    assert_eq!(
+        bob.received_from(&alice).last().map(String::as_str),
+        Some("after the gap")
+    );
+    assert_no_errors(&[&alice, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_leaving_member_stays_until_the_removal_is_committed() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+
+    bob.controller.leave_group();
+    network.settle();
+    assert!(bob.has_left(), "bob saw his leave committed");
+    assert_eq!(alice.roster(), vec![alice.pubkey.clone()]);
+
+    alice.controller.send_text("bye bob".to_string());
+    network.settle();
+    assert!(bob.received_from(&alice).is_empty());
+    assert_no_errors(&[&alice, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_sole_admin_leaving_hands_admin_over_first() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+    assert_eq!(bob.admins(), vec![alice.pubkey.clone()]);
+
+    // Nobody else could commit alice's leave, so bob becomes admin first
+    alice.controller.leave_group();
+    network.settle();
+    assert!(alice.has_left(), "alice saw her leave committed");
+    assert_eq!(bob.roster(), vec![bob.pubkey.clone()]);
+    assert_eq!(bob.admins(), vec![bob.pubkey.clone()]);
+    assert_no_errors(&[&alice, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_admin_invitee_is_promoted_after_joining() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+    let carol = network.join(session(
+        SessionRole::Invitee,
+        CAROL,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    alice.controller.invite_member(carol.pubkey.clone(), true);
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+
+    let mut admins = vec![alice.pubkey.clone(), carol.pubkey.clone()];
+    admins.sort();
+    for peer in [&alice, &bob, &carol] {
+        assert_eq!(peer.admins(), admins, "admins of {}", peer.pubkey);
+    }
+    assert_no_errors(&[&alice, &bob, &carol]);
+    Ok(())
+}
+
+#[test]
+fn test_non_admin_group_image_is_refused_before_encrypting() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+
+    let err = bob
+        .controller
+        .set_group_image(b"not an admin")
+        .expect_err("bob is not an admin");
+    assert!(err.to_string().contains("only admins"), "{err:#}");
+    assert!(alice.controller.set_group_image(b"avatar").is_ok());
+    network.settle();
+    assert_no_errors(&[&alice, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_offline_member_is_added_from_published_key_package() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+
+    // Carol published a key package at some point and is offline now
+    let carol_identity = IdentityService::create(CAROL)?;
+    let published = carol_identity.create_key_package_with(
+        &["wss://moq.test".to_string()],
+        &KeyPackageOptions::published(now()),
+    )?;
+    network
+        .nostr_client()
+        .publish_key_package(published.event_json);
+
+    alice.controller.invite_member(pubkey(CAROL), false);
+    network.settle();
+    assert!(
+        alice.roster().contains(&pubkey(CAROL)),
+        "carol added offline"
+    );
+
+    // Back online, she picks up the welcome left for her
+    let carol = network.join_as(
+        carol_identity,
+        session(SessionRole::Invitee, CAROL, SESSION, vec![pubkey(ALICE)]),
+    );
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+    assert_eq!(carol.roster(), alice.roster());
+
+    alice.controller.send_text("welcome carol".to_string());
+    network.settle();
+    assert_eq!(carol.received_from(&alice), vec!["welcome carol"]);
+    assert_eq!(bob.received_from(&alice), vec!["welcome carol"]);
+    assert_no_errors(&[&alice, &bob, &carol]);
+    Ok(())
+}
+
+#[test]
+fn test_expired_key_package_falls_back_to_asking() -> Result<()> {
+    let network = Network::new();
+    let (alice, _bob) = start_pair(&network);
+
+    let carol_identity = IdentityService::create(CAROL)?;
+    let stale = carol_identity.create_key_package_with(
+        &["wss://moq.test".to_string()],
+        &KeyPackageOptions {
+            last_resort: false,
+            expires_at: Some(now() - 1),
+        },
+    )?;
+    network.nostr_client().publish_key_package(stale.event_json);
+
+    alice.controller.invite_member(pubkey(CAROL), false);
+    network.settle();
+    assert!(
+        !alice.roster().contains(&pubkey(CAROL)),
+        "expired key package used"
+    );
+
+    // The direct request waits on the relay until Carol shows up
+    let carol = network.join_as(
+        carol_identity,
+        session(SessionRole::Invitee, CAROL, SESSION, vec![pubkey(ALICE)]),
+    );
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+    assert!(alice.roster().contains(&pubkey(CAROL)));
+    assert_no_errors(&[&alice, &carol]);
+    Ok(())
+}
+
+#[test]
+fn test_unrequested_welcomes_wait_for_the_user() -> Result<()> {
+    let network = Network::new();
+    let (alice, _bob) = start_pair(&network);
+    let erin = network.join(session(
+        SessionRole::Initial,
+        ERIN,
+        OTHER_SESSION,
+        vec![pubkey(FRANK)],
+    ));
+    let _frank = network.join(session(
+        SessionRole::Invitee,
+        FRANK,
+        OTHER_SESSION,
+        vec![pubkey(ERIN)],
+    ));
+    network.settle();
+
+    // Both groups add Dave from his published key package while he is away
+    let dave_identity = IdentityService::create(DAVE)?;
+    let published = dave_identity.create_key_package_with(
+        &["wss://nostr.test".to_string()],
+        &KeyPackageOptions::last_resort(now()),
+    )?;
+    network
+        .nostr_client()
+        .publish_key_package(published.event_json);
+    alice.controller.invite_member(pubkey(DAVE), false);
+    erin.controller.invite_member(pubkey(DAVE), false);
+    network.settle();
+
+    // Opening the app without an invite, he gets to pick a group
+    let dave = network.join_as(
+        dave_identity.clone(),
+        session(SessionRole::Invitee, DAVE, "dave-session", Vec::new()),
+    );
+    network.settle();
+    let pending = dave.pending_welcomes();
+    assert_eq!(pending.len(), 2, "one welcome per group");
+    assert!(!dave.is_ready(), "joined without being asked");
+    let from_erin = pending
+        .iter()
+        .find(|welcome| welcome.welcomer == erin.pubkey)
+        .expect("welcome from erin");
+
+    dave.controller.accept_welcome(from_erin.welcome_id.clone());
+    network.settle();
+    assert!(dave.is_ready(), "dave connected");
+    assert_eq!(dave.roster(), erin.roster());
+    let still_pending = dave_identity.pending_welcomes()?;
+    assert_eq!(still_pending.len(), 1);
+    assert_eq!(still_pending[0].welcomer, alice.pubkey);
+
+    erin.controller.send_text("hi dave".to_string());
+    network.settle();
+    assert_eq!(dave.received_from(&erin), vec!["hi dave"]);
+    assert_no_errors(&[&alice, &erin, &dave]);
+    Ok(())
+}
+
+#[test]
+fn test_key_packages_only_from_invitees_who_signed_them() -> Result<()> {
+    let network = Network::new();
+    let (alice, _bob) = start_pair(&network);
+    alice.controller.invite_member(pubkey(CAROL), false);
+    network.settle();
+
+    // Mallory is in the session but nobody invited her
+    let mallory = network.join(session(
+        SessionRole::Invitee,
+        MALLORY,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    network.settle();
+    assert!(!mallory.is_ready(), "uninvited key package taken");
+
+    // Carol's key passing off Mallory's key package as Carol's
+    let forged = IdentityService::create(MALLORY)?.create_key_package_with(
+        &["wss://nostr.test".to_string()],
+        &KeyPackageOptions::published(now()),
+    )?;
+    let impostor = network.nostr_client();
+    impostor.connect(
+        HandshakeConnectParams {
+            url: "wss://nostr.test".to_string(),
+            relays: Vec::new(),
+            session: SESSION.to_string(),
+            role: SessionRole::Invitee,
+            secret_hex: CAROL.into(),
+        },
+        Box::new(Deaf),
+    );
+    impostor.send(HandshakeMessage {
+        message_type: HandshakeMessageType::KeyPackage,
+        peer: pubkey(ALICE),
+        data: HandshakeMessageBody::KeyPackage {
+            event: forged.event_json,
+            pubkey: Some(pubkey(CAROL)),
+        },
+    });
+    network.settle();
+    impostor.shutdown();
+    assert!(!alice.roster().contains(&pubkey(MALLORY)));
+    assert!(!alice.roster().contains(&pubkey(CAROL)));
+
+    // The invite still stands for the real Carol
+    let carol = network.join(session(
+        SessionRole::Invitee,
+        CAROL,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+    assert_eq!(carol.roster(), alice.roster());
+    assert_no_errors(&[&alice, &mallory, &carol]);
+    Ok(())
+}
+
+#[test]
+fn test_restarted_controller_resumes_its_stored_group() -> Result<()> {
+    let network = Network::new();
+    let identity = IdentityService::create(ALICE)?;
+    let alice = network.join_as(
+        identity.clone(),
+        session(SessionRole::Initial, ALICE, SESSION, vec![pubkey(BOB)]),
+    );
+    let bob = network.join(session(
+        SessionRole::Invitee,
+        BOB,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    network.settle();
+    assert!(alice.is_ready() && bob.is_ready(), "pair connected");
+    bob.controller.rotate_epoch();
+    network.settle();
+
+    // A new controller over the same store comes back at the stored epoch
+    // without asking anyone for a key package or welcome
+    alice.controller.shutdown();
+    network.settle();
+    let mut params = session(SessionRole::Initial, ALICE, SESSION, vec![pubkey(BOB)]);
+    params.group_id_hex = identity.group_id_hex();
+    let restarted = network.join_as(identity.new_scope(), params);
+    network.settle();
+    assert!(restarted.is_ready(), "alice resumed");
+    assert_eq!(restarted.roster(), bob.roster());
+
+    bob.controller.send_text("welcome back".to_string());
+    network.settle();
+    assert_eq!(restarted.received_from(&bob), vec!["welcome back"]);
+    restarted.controller.send_text("thanks".to_string());
+    network.settle();
+    assert_eq!(bob.received_from(&restarted), vec!["thanks"]);
+    assert_no_errors(&[&restarted, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_root_migration_survives_commits() -> Result<()> {
+    let network = Network::new();
+    let migrating = |role, secret, peer| {
+        let mut params = session(role, secret, SESSION, vec![pubkey(peer)]);
+        params.root_mode = GroupRootMode::Migrate;
+        params
+    };
+    let alice = network.join(migrating(SessionRole::Initial, ALICE, BOB));
+    let bob = network.join(migrating(SessionRole::Invitee, BOB, ALICE));
+    network.settle();
+    assert!(alice.is_ready() && bob.is_ready(), "pair connected");
+
+    // Announcements from before a commit still count after it
+    bob.controller.rotate_epoch();
+    network.settle();
+    bob.controller.send_text("after the move".to_string());
+    network.settle();
+    assert_eq!(alice.received_from(&bob), vec!["after the move"]);
+
+    // Once moved, commits leave the root alone
+    let moved = alice.controller.session().moq_root;
+    alice.controller.rotate_epoch();
+    network.settle();
+    assert_eq!(alice.controller.session().moq_root, moved);
+    assert_eq!(bob.controller.session().moq_root, moved);
+    alice.controller.send_text("next root".to_string());
+    network.settle();
+    assert_eq!(bob.received_from(&alice), vec!["next root"]);
+    assert_no_errors(&[&alice, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_moq_root_stays_pinned_until_an_admin_rotates_it() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+    let root = alice.controller.session().moq_root;
+    assert!(root.is_some(), "alice picked a root");
+    assert_eq!(bob.controller.session().moq_root, root, "bob joined on it");
+
+    // Commits and new members leave the root where it is
+    bob.controller.rotate_epoch();
+    network.settle();
+    let carol = network.join(session(
+        SessionRole::Invitee,
+        CAROL,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    alice.controller.invite_member(carol.pubkey.clone(), false);
+    network.settle();
+    assert!(carol.is_ready(), "carol connected");
+    for peer in [&alice, &bob, &carol] {
+        assert_eq!(
+            peer.controller.session().moq_root,
+            root,
+            "root of {}",
+            peer.pubkey
+        );
+    }
+
+    // Only admins rotate it
+    bob.controller.rotate_moq_root();
+    network.settle();
+    assert_eq!(bob.controller.session().moq_root, root);
+    assert!(!bob.errors().is_empty(), "bob was told no");
+
+    // Everyone moves once all members announced the new root
+    alice.controller.rotate_moq_root();
+    network.settle();
+    let rotated = alice.controller.session().moq_root;
+    assert_ne!(rotated, root);
+    for peer in [&bob, &carol] {
+        assert_eq!(
+            peer.controller.session().moq_root,
+            rotated,
+            "root of {}",
+            peer.pubkey
+        );
+        assert!(peer.is_ready(), "{} reconnected", peer.pubkey);
+    }
+    carol.controller.send_text("on the new root".to_string());
+    network.settle();
+    assert_eq!(alice.received_from(&carol), vec!["on the new root"]);
+    assert_eq!(bob.received_from(&carol), vec!["on the new root"]);
+    assert_no_errors(&[&alice, &carol]);
+    Ok(())
+}
+
+#[test]
+fn test_resumed_controller_sends_media_under_fresh_keys() -> Result<()> {
+    let network = Network::new();
+    let identity = IdentityService::create(ALICE)?;
+    let alice = network.join_as(
+        identity.clone(),
+        session(SessionRole::Initial, ALICE, SESSION, vec![pubkey(BOB)]),
+    );
+    let bob = network.join(session(
+        SessionRole::Invitee,
+        BOB,
+        SESSION,
+        vec![pubkey(ALICE)],
+    ));
+    network.settle();
+    assert!(alice.is_ready() && bob.is_ready(), "pair connected");
+    let label = "alice-audio";
+    let suite = CipherSuite::default();
+    let mut publisher = alice
+        .controller
+        .create_media_session(&alice.pubkey, label, suite)?;
+    let before = SframeHeader::parse(&publisher.encrypt_sframe(b"before", b"meta")?)?.0;
+
+    // The snapshot brings back the epoch those frames went out at, but not
+    // the counters behind them
+    let sealed = identity.export_state("pw", None)?;
+    drop(publisher);
+    alice.controller.shutdown();
+    network.settle();
+    let restored = IdentityService::import_state(&sealed, "pw")?.identity;
+    let mut params = session(SessionRole::Initial, ALICE, SESSION, vec![pubkey(BOB)]);
+    params.group_id_hex = restored.group_id_hex();
+    let resumed = network.join_as(restored, params);
+    network.settle();
+    assert!(resumed.is_ready(), "alice resumed");
+
+    let mut publisher = resumed
+        .controller
+        .create_media_session(&resumed.pubkey, label, suite)?;
+    let mut receiver = bob
+        .controller
+        .create_media_session(&resumed.pubkey, label, suite)?;
+    let frame = publisher.encrypt_sframe(b"after", b"meta")?;
+    let after = SframeHeader::parse(&frame)?.0;
+    assert_ne!(after.kid, before.kid, "first frame reused the stored key");
+    assert_eq!(
+        receiver.decrypt_sframe(&frame, b"meta")?.plaintext,
+        b"after"
+    );
+    assert_no_errors(&[&resumed, &bob]);
+    Ok(())
+}
+
+#[test]
+fn test_media_sessions_resume_their_key_ring_within_an_epoch() -> Result<()> {
+    let network = Network::new();
+    let (alice, bob) = start_pair(&network);
+    let label = "alice-audio";
+    let suite = CipherSuite::default();
+
+    let mut publisher = alice
+        .controller
+        .create_media_session(&alice.pubkey, label, suite)?;
+    let mut receiver = bob
+        .controller
+        .create_media_session(&alice.pubkey, label, suite)?;
+    let first = publisher.encrypt_sframe(b"one", b"meta")?;
+    assert_eq!(receiver.decrypt_sframe(&first, b"meta")?.plaintext, b"one");
+
+    // Sessions made again after the old ones are gone pick up the same
+    // counters and replay window
+    drop((publisher, receiver));
+    let mut publisher = alice
+        .controller
+        .create_media_session(&alice.pubkey, label, suite)?;
+    let mut receiver = bob
+        .controller
+        .create_media_session(&alice.pubkey, label, suite)?;
+    let second = publisher.encrypt_sframe(b"two", b"meta")?;
+    assert!(SframeHeader::parse(&second)?.0.ctr > SframeHeader::parse(&first)?.0.ctr);
+    assert!(
+        receiver.decrypt_sframe(&first, b"meta").is_err(),
+        "replayed"
+    );
+    assert_eq!(receiver.decrypt_sframe(&second, b"meta")?.plaintext, b"two");
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Identities and groups built straight on [`IdentityHandle`]
+//!
+//! For tests below the controller: each helper runs a step of the MLS
+//! handshake that controllers would otherwise drive over the relays.
+#![allow(dead_code)]
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use marmot_chat::controller::services::{
+    GroupArtifacts, IdentityHandle, IdentityService, WrapperOutcome,
+};
+use mdk_storage_traits::MdkStorageProvider;
+use sha2::{Digest, Sha256};
+
+/// Relay the key packages name; nothing connects to it
+pub const RELAY: &str = "ws://localhost:8880";
+
+pub fn relays() -> Vec<String> {
+    vec![RELAY.to_string()]
+}
+
+/// Secret key of the test identity called `name`
+pub fn secret(name: &str) -> String {
+    hex::encode(Sha256::digest(name.as_bytes()))
+}
+
+/// In-memory identity called `name`; a name always gives the same keys
+pub fn identity(name: &str) -> Result<IdentityHandle> {
+    IdentityService::create(&secret(name))
+}
+
+/// `creator` starts a group with `invitee`, who accepts the welcome
+pub fn start_group<C: MdkStorageProvider, I: MdkStorageProvider>(
+    creator: &IdentityHandle<C>,
+    invitee: &IdentityHandle<I>,
+    admins: &[String],
+) -> Result<GroupArtifacts> {
+    let key_package = invitee.create_key_package(&relays())?;
+    let artifacts =
+        creator.create_group(&key_package.event_json, &invitee.public_key_hex(), admins)?;
+    invitee.accept_welcome(&artifacts.welcome)?;
+    Ok(artifacts)
+}
+
+/// `admin` adds `member` to its group; `others` apply the commit
+pub fn add_member(
+    admin: &IdentityHandle,
+    member: &IdentityHandle,
+    others: &[&IdentityHandle],
+) -> Result<()> {
+    let key_package = member.create_key_package(&relays())?;
+    let added = admin.add_members(&[key_package.event_json])?;
+    for other in others {
+        other.ingest_wrapper(&added.commit.bytes)?;
+    }
+    member.accept_welcome(&added.welcomes[0].welcome)?;
+    Ok(())
+}
+
+/// Unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Content of an application message, or `None` for any other outcome
+pub fn application_text(outcome: WrapperOutcome) -> Option<String> {
+    match outcome {
+        WrapperOutcome::Application { content, .. } => Some(content),
+        _ => None,
+    }
+}
@@ -0,0 +1,52 @@
+//! The admin set lives in the group context, the same for every member
+
+mod fixtures;
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use fixtures::{add_member, identity, start_group};
+
+#[test]
+fn test_admin_set_travels_in_group_context() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let carol = identity("carol")?;
+    start_group(&alice, &bob, &[bob.public_key_hex()])?;
+    add_member(&alice, &carol, &[&bob])?;
+
+    // Every member reads the same admin set from the group context
+    let initial = alice.group_admins()?;
+    let expected: BTreeSet<String> = [alice.public_key_hex(), bob.public_key_hex()]
+        .into_iter()
+        .collect();
+    assert_eq!(initial, expected);
+    assert_eq!(bob.group_admins()?, initial);
+    assert_eq!(carol.group_admins()?, initial);
+
+    // Promote carol and demote bob in one commit
+    let updated: BTreeSet<String> = [alice.public_key_hex(), carol.public_key_hex()]
+        .into_iter()
+        .collect();
+    let commit = alice.set_group_admins(&updated)?;
+    bob.ingest_wrapper(&commit.bytes)?;
+    carol.ingest_wrapper(&commit.bytes)?;
+
+    assert_eq!(alice.group_admins()?, updated);
+    assert_eq!(bob.group_admins()?, updated);
+    assert_eq!(carol.group_admins()?, updated);
+    assert_eq!(carol.current_epoch()?, alice.current_epoch()?);
+    Ok(())
+}
+
+#[test]
+fn test_invitee_is_admin_only_when_requested() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    start_group(&alice, &bob, &[])?;
+
+    let admins: BTreeSet<String> = [alice.public_key_hex()].into_iter().collect();
+    assert_eq!(alice.group_admins()?, admins);
+    assert_eq!(bob.group_admins()?, admins);
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Group name, description and image changes reach every member
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{identity, relays};
+use marmot_chat::controller::services::GroupProfile;
+use marmot_chat::group_image::{decrypt_group_image, encrypt_group_image};
+
+#[test]
+fn test_group_metadata_and_image_reach_members() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+
+    let profile = GroupProfile {
+        name: "Standup".to_string(),
+        description: "Daily sync".to_string(),
+    };
+    let bob_kp = bob.create_key_package(&relays())?;
+    let artifacts = alice.create_group_with_profile(
+        &bob_kp.event_json,
+        &bob.public_key_hex(),
+        &[],
+        &profile,
+    )?;
+    bob.accept_welcome(&artifacts.welcome)?;
+
+    let joined = bob.group_metadata()?;
+    assert_eq!(joined.name, "Standup");
+    assert_eq!(joined.description, "Daily sync");
+    assert!(joined.image.is_none());
+
+    let rename = alice.update_group_profile(Some("Retro".to_string()), None)?;
+    bob.ingest_wrapper(&rename.bytes)?;
+    let renamed = bob.group_metadata()?;
+    assert_eq!(renamed.name, "Retro");
+    assert_eq!(renamed.description, "Daily sync");
+
+    // Only the reference travels in the commit; the blob goes elsewhere
+    let (blob, image_ref) = encrypt_group_image(b"\x89PNG group avatar")?;
+    let commit = alice.set_group_image(Some(&image_ref))?;
+    bob.ingest_wrapper(&commit.bytes)?;
+    let bob_ref = bob
+        .group_metadata()?
+        .image
+        .expect("image reference in group context");
+    assert_eq!(bob_ref.hash_hex(), image_ref.hash_hex());
+    assert_eq!(
+        decrypt_group_image(&blob, &bob_ref)?,
+        b"\x89PNG group avatar"
+    );
+    assert_eq!(bob.current_epoch()?, alice.current_epoch()?);
+    Ok(())
+}
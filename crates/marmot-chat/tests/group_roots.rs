@@ -0,0 +1,63 @@
+//! A group's pinned MoQ root stays put through commits and reaches new
+//! members, restarts and snapshots
+#![cfg(not(target_arch = "wasm32"))]
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{add_member, identity, secret, start_group};
+use marmot_chat::controller::services::IdentityService;
+
+#[test]
+fn test_pinned_root_outlives_commits_and_reaches_joiners() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let carol = identity("carol")?;
+    start_group(&alice, &bob, &[])?;
+    let root = alice.derive_group_root()?;
+    alice.pin_group_root(&root)?;
+
+    let commit = bob.self_update()?;
+    alice.ingest_wrapper(&commit.bytes)?;
+    assert_ne!(alice.derive_group_root()?, root, "exporter root moved on");
+    assert_eq!(alice.pinned_group_root()?, Some(root.clone()));
+
+    // The welcome names the pinned root, not the current epoch's
+    add_member(&alice, &carol, &[&bob])?;
+    assert_eq!(carol.pinned_group_root()?, Some(root));
+    assert_eq!(bob.pinned_group_root()?, None, "bob joined before the pin");
+    Ok(())
+}
+
+#[test]
+fn test_pinned_root_survives_restart_and_snapshots() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("marmot-chat-roots-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let db_path = dir.join("alice.db");
+    let root = "marmot/pinned".to_string();
+
+    let bob = identity("bob")?;
+    let group_id_hex = {
+        let alice = IdentityService::open(&secret("alice"), &db_path)?;
+        let artifacts = start_group(&alice, &bob, &[])?;
+        alice.pin_group_root(&root)?;
+        bob.pin_group_root(&root)?;
+        artifacts.group_id_hex
+    };
+
+    let alice = IdentityService::open(&secret("alice"), &db_path)?;
+    assert!(alice.restore_group(&group_id_hex)?);
+    assert_eq!(alice.pinned_group_root()?, Some(root.clone()));
+
+    let sealed = alice.export_state("pw", None)?;
+    let moved = IdentityService::import_state_to(&sealed, "pw", dir.join("device-b.db"))?.identity;
+    assert_eq!(moved.pinned_group_root()?, Some(root.clone()));
+
+    let sealed = bob.export_state("pw", None)?;
+    let bob = IdentityService::import_state(&sealed, "pw")?.identity;
+    assert_eq!(bob.pinned_group_root()?, Some(root));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
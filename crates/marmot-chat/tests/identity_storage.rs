@@ -0,0 +1,93 @@
+//! SQLite-backed identities keep their groups across restarts
+#![cfg(not(target_arch = "wasm32"))]
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{application_text, identity, secret, start_group};
+use marmot_chat::controller::services::{IdentityService, WrapperOutcome};
+
+#[test]
+fn test_persistent_identity_rejoins_at_same_epoch() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("marmot-chat-storage-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let db_path = dir.join("alice.db");
+    let _ = std::fs::remove_file(&db_path);
+
+    let bob = identity("bob")?;
+    let group_id_hex = {
+        let alice = IdentityService::open(&secret("alice"), &db_path)?;
+        assert!(alice.list_groups()?.is_empty());
+        assert!(alice.publishes_key_packages());
+        let artifacts = start_group(&alice, &bob, &[])?;
+
+        // Move past the welcome epoch so the restart has to restore a commit
+        let commit = bob.self_update()?;
+        alice.ingest_wrapper(&commit.bytes)?;
+        assert_eq!(alice.current_epoch()?, bob.current_epoch()?);
+        artifacts.group_id_hex
+    };
+
+    // Simulate a restart: a fresh handle over the same database
+    let alice = IdentityService::open(&secret("alice"), &db_path)?;
+    assert!(!alice.restore_group("00")?);
+    assert!(alice.restore_group(&group_id_hex)?);
+    assert_eq!(alice.current_epoch()?, bob.current_epoch()?);
+    assert_eq!(alice.derive_group_root()?, bob.derive_group_root()?);
+
+    let message = bob.create_message("still here?")?;
+    assert_eq!(
+        application_text(alice.ingest_wrapper(&message.bytes)?).as_deref(),
+        Some("still here?")
+    );
+    let reply = alice.create_message("yes")?;
+    assert!(matches!(
+        bob.ingest_wrapper(&reply.bytes)?,
+        WrapperOutcome::Application { .. }
+    ));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_restored_group_waits_for_a_commit_before_own_media() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("marmot-chat-media-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let db_path = dir.join("alice.db");
+
+    let bob = identity("bob")?;
+    let (group_id_hex, used) = {
+        let alice = IdentityService::open(&secret("alice"), &db_path)?;
+        let artifacts = start_group(&alice, &bob, &[])?;
+        let used = alice.derive_media_base_key(&alice.public_key_hex(), "audio")?;
+        (artifacts.group_id_hex, used)
+    };
+
+    // Counters did not survive, so the keys of the stored epoch are out
+    let alice = IdentityService::open(&secret("alice"), &db_path)?;
+    assert!(alice.restore_group(&group_id_hex)?);
+    assert!(alice
+        .derive_media_base_key(&alice.public_key_hex(), "audio")
+        .is_err());
+    alice.derive_media_base_key(&bob.public_key_hex(), "audio")?;
+    let sealed = alice.export_state("pw", None)?;
+    let moved = IdentityService::import_state_to(&sealed, "pw", dir.join("device-b.db"))?.identity;
+    assert!(moved
+        .derive_media_base_key(&moved.public_key_hex(), "audio")
+        .is_err());
+
+    let commit = alice.self_update()?;
+    bob.ingest_wrapper(&commit.bytes)?;
+    let fresh = alice.derive_media_base_key(&alice.public_key_hex(), "audio")?;
+    assert_ne!(fresh.as_bytes(), used.as_bytes());
+    assert_eq!(
+        bob.derive_media_base_key(&alice.public_key_hex(), "audio")?
+            .as_bytes(),
+        fresh.as_bytes()
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Members leaving or being removed from a group
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{add_member, identity, start_group};
+use marmot_chat::controller::services::WrapperOutcome;
+
+#[test]
+fn test_removed_member_drops_out_of_roster_and_epoch() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let carol = identity("carol")?;
+    start_group(&alice, &bob, &[])?;
+    add_member(&alice, &carol, &[&bob])?;
+    assert_eq!(bob.list_members()?.len(), 3);
+
+    let removal = alice.remove_members(&[carol.public_key_hex()])?;
+    bob.ingest_wrapper(&removal.bytes)?;
+    let members = bob.list_members()?;
+    assert_eq!(members.len(), 2);
+    assert!(!members.contains(&carol.public_key_hex()));
+    assert_eq!(alice.current_epoch()?, bob.current_epoch()?);
+
+    // Carol can no longer read traffic from the new epoch
+    let message = alice.create_message("carol is gone")?;
+    assert!(!carol
+        .ingest_wrapper(&message.bytes)
+        .is_ok_and(|outcome| matches!(outcome, WrapperOutcome::Application { .. })));
+    Ok(())
+}
+
+#[test]
+fn test_one_admin_commits_a_leave_proposal() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let carol = identity("carol")?;
+    start_group(&alice, &bob, &[bob.public_key_hex()])?;
+    add_member(&alice, &carol, &[&bob])?;
+
+    // Both admins see the proposal; only the lower pubkey commits it
+    let proposal = carol.leave_group()?;
+    let (committer, other) = if alice.public_key_hex() < bob.public_key_hex() {
+        (&alice, &bob)
+    } else {
+        (&bob, &alice)
+    };
+    let commit = match committer.ingest_wrapper(&proposal.bytes)? {
+        WrapperOutcome::ProposalCommitted(commit) => commit,
+        outcome => panic!("expected the lowest admin to commit, got {outcome:?}"),
+    };
+    match other.ingest_wrapper(&proposal.bytes)? {
+        WrapperOutcome::ProposalDeferred(removed) => {
+            assert_eq!(
+                removed.into_iter().collect::<Vec<_>>(),
+                vec![carol.public_key_hex()]
+            );
+        }
+        outcome => panic!("expected the other admin to defer, got {outcome:?}"),
+    }
+
+    // The other admin dropped its own commit and follows the committer's
+    assert!(matches!(
+        other.ingest_wrapper(&commit.bytes)?,
+        WrapperOutcome::Commit
+    ));
+    assert_eq!(other.current_epoch()?, committer.current_epoch()?);
+    assert!(!other.list_members()?.contains(&carol.public_key_hex()));
+    Ok(())
+}
+
+#[test]
+fn test_next_admin_commits_a_leave_the_first_one_dropped() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let carol = identity("carol")?;
+    start_group(&alice, &bob, &[bob.public_key_hex()])?;
+    add_member(&alice, &carol, &[&bob])?;
+
+    // The admin due to commit never sees the proposal; the next one does
+    let proposal = carol.leave_group()?;
+    let (committer, other) = if alice.public_key_hex() < bob.public_key_hex() {
+        (&alice, &bob)
+    } else {
+        (&bob, &alice)
+    };
+    let removed = match other.ingest_wrapper(&proposal.bytes)? {
+        WrapperOutcome::ProposalDeferred(removed) => removed,
+        outcome => panic!("expected the other admin to defer, got {outcome:?}"),
+    };
+    assert_eq!(
+        other.proposal_committers(&removed)?.first(),
+        Some(&committer.public_key_hex())
+    );
+
+    let removed: Vec<String> = removed.into_iter().collect();
+    let commit = other.commit_removals(&removed)?;
+    assert!(matches!(
+        committer.ingest_wrapper(&commit.bytes)?,
+        WrapperOutcome::Commit
+    ));
+    assert_eq!(committer.current_epoch()?, other.current_epoch()?);
+    assert!(!committer.list_members()?.contains(&carol.public_key_hex()));
+    assert!(!other.list_members()?.contains(&carol.public_key_hex()));
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! One identity taking part in several groups through scoped handles
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{application_text, identity, relays};
+
+#[test]
+fn test_one_identity_in_two_groups() -> Result<()> {
+    let alice = identity("alice")?;
+    let dave = identity("dave")?;
+    let bob = identity("bob")?;
+
+    // Both invites land before bob accepts either
+    let bob_room = bob.new_scope();
+    let bob_kp_a = bob.create_key_package(&relays())?;
+    let bob_kp_b = bob_room.create_key_package(&relays())?;
+    let group_a = alice.create_group(&bob_kp_a.event_json, &bob.public_key_hex(), &[])?;
+    let group_b = dave.create_group(&bob_kp_b.event_json, &bob.public_key_hex(), &[])?;
+
+    assert_eq!(
+        bob_room.accept_welcome(&group_b.welcome)?,
+        group_b.group_id_hex
+    );
+    assert_eq!(bob.accept_welcome(&group_a.welcome)?, group_a.group_id_hex);
+    assert_eq!(bob.group_id_hex(), Some(group_a.group_id_hex.clone()));
+    assert_eq!(bob_room.group_id_hex(), Some(group_b.group_id_hex.clone()));
+
+    let mut groups = bob.list_groups()?;
+    groups.sort();
+    let mut expected = vec![group_a.group_id_hex.clone(), group_b.group_id_hex.clone()];
+    expected.sort();
+    assert_eq!(groups, expected);
+
+    // Epochs move independently per group
+    let rotate = alice.self_update()?;
+    bob.ingest_wrapper(&rotate.bytes)?;
+    assert_eq!(bob.current_epoch()?, alice.current_epoch()?);
+    assert_eq!(bob_room.current_epoch()?, dave.current_epoch()?);
+    assert_ne!(bob.current_epoch()?, bob_room.current_epoch()?);
+
+    // A fresh handle on the second group reads the same state
+    let reopened = bob.for_group(&group_b.group_id_hex)?;
+    let hello = dave.create_message("hello room b")?;
+    assert_eq!(
+        application_text(reopened.ingest_wrapper(&hello.bytes)?).as_deref(),
+        Some("hello room b")
+    );
+    assert_ne!(
+        bob.derive_group_root()?,
+        reopened.derive_group_root()?,
+        "groups must not share a MoQ root"
+    );
+    assert!(bob.for_group("00").is_err());
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Native MoQ transport against a local relay
+//!
+//! Start one with `just relay-dev` and run
+//! `cargo test -p marmot-chat --features native --test native_moq -- --ignored`.
+//! `MARMOT_MOQ_RELAY` overrides the relay url.
+#![cfg(all(feature = "native", not(target_arch = "wasm32")))]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use marmot_chat::controller::services::{MoqListener, MoqService};
+use marmot_chat::native::NativeMoqService;
+
+#[derive(Debug)]
+enum Seen {
+    Ready,
+    Frame(Vec<u8>),
+    Error(String),
+}
+
+struct ChannelListener(UnboundedSender<Seen>);
+
+impl MoqListener for ChannelListener {
+    fn on_frame(&self, bytes: Vec<u8>) {
+        let _ = self.0.unbounded_send(Seen::Frame(bytes));
+    }
+
+    fn on_ready(&self) {
+        let _ = self.0.unbounded_send(Seen::Ready);
+    }
+
+    fn on_error(&self, message: String) {
+        let _ = self.0.unbounded_send(Seen::Error(message));
+    }
+
+    fn on_closed(&self) {}
+}
+
+fn relay_url() -> String {
+    std::env::var("MARMOT_MOQ_RELAY").unwrap_or_else(|_| "https://localhost:54943".to_string())
+}
+
+fn service() -> Result<NativeMoqService> {
+    let mut config = moq_native::ClientConfig::default();
+    // relay-dev serves a generated certificate
+    config.tls.disable_verify = Some(true);
+    NativeMoqService::with_config(config)
+}
+
+async fn next(events: &mut UnboundedReceiver<Seen>) -> Result<Seen> {
+    tokio::time::timeout(Duration::from_secs(10), events.next())
+        .await
+        .map_err(|_| anyhow!("timed out waiting for MoQ event"))?
+        .ok_or_else(|| anyhow!("listener dropped"))
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore = "needs a local moq-relay"]
+async fn test_wrappers_reach_subscribed_peer() -> Result<()> {
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+            let session = format!("marmot/native-test-{nanos}");
+            let alice_pub = "aa".repeat(32);
+            let bob_pub = "bb".repeat(32);
+
+            let (alice_tx, mut alice_events) = unbounded();
+            let (bob_tx, mut bob_events) = unbounded();
+            let alice = service()?;
+            let bob = service()?;
+
+            // Published before the session is up: queued, then flushed
+            alice.publish_wrapper(b"early");
+            alice.connect(
+                &relay_url(),
+                &session,
+                &alice_pub,
+                &[bob_pub.clone()],
+                Box::new(ChannelListener(alice_tx)),
+            );
+            bob.connect(
+                &relay_url(),
+                &session,
+                &bob_pub,
+                &[alice_pub.clone()],
+                Box::new(ChannelListener(bob_tx)),
+            );
+            assert!(matches!(next(&mut alice_events).await?, Seen::Ready));
+            assert!(matches!(next(&mut bob_events).await?, Seen::Ready));
+
+            alice.publish_wrapper(b"wrapper-1");
+            // The early wrapper can predate bob's subscription, so it may be missed
+            loop {
+                match next(&mut bob_events).await? {
+                    Seen::Frame(bytes) if bytes == b"wrapper-1" => break,
+                    Seen::Frame(bytes) => assert_eq!(bytes, b"early"),
+                    Seen::Error(message) => return Err(anyhow!("MoQ error: {message}")),
+                    Seen::Ready => {}
+                }
+            }
+
+            bob.unsubscribe_from_peer(&alice_pub);
+            alice.publish_wrapper(b"after-unsubscribe");
+            assert!(
+                tokio::time::timeout(Duration::from_millis(500), bob_events.next())
+                    .await
+                    .is_err(),
+                "no frames after unsubscribing"
+            );
+
+            alice.shutdown();
+            bob.shutdown();
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,221 @@
+//! Native handshake transport against a local Nostr relay
+//!
+//! Start one with `just dev` (or `scripts/lib-nostr.sh`) and run
+//! `cargo test -p marmot-chat --features native --test native_nostr -- --ignored`.
+//! `MARMOT_NOSTR_RELAY` overrides the relay url.
+#![cfg(all(feature = "native", not(target_arch = "wasm32")))]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use marmot_chat::controller::events::SessionRole;
+use marmot_chat::controller::services::{
+    HandshakeConnectParams, HandshakeListener, HandshakeMessage, HandshakeMessageBody,
+    HandshakeMessageType, KeyPackageListener, NostrService,
+};
+use marmot_chat::native::NativeNostrService;
+use nostr::{EventBuilder, JsonUtil, Keys, Kind};
+
+struct ChannelListener(UnboundedSender<HandshakeMessage>);
+
+impl HandshakeListener for ChannelListener {
+    fn on_message(&self, message: HandshakeMessage) {
+        let _ = self.0.unbounded_send(message);
+    }
+}
+
+struct KeyPackageChannel(UnboundedSender<Vec<String>>);
+
+impl KeyPackageListener for KeyPackageChannel {
+    fn on_key_packages(&self, _pubkey: String, events: Vec<String>) {
+        let _ = self.0.unbounded_send(events);
+    }
+}
+
+fn relay_url() -> String {
+    std::env::var("MARMOT_NOSTR_RELAY").unwrap_or_else(|_| "ws://localhost:8880".to_string())
+}
+
+fn session_id() -> Result<String> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok(format!("native-nostr-{nanos}"))
+}
+
+fn connect(
+    service: &NativeNostrService,
+    keys: &Keys,
+    session: &str,
+    role: SessionRole,
+) -> UnboundedReceiver<HandshakeMessage> {
+    let (tx, rx) = unbounded();
+    service.connect(
+        HandshakeConnectParams {
+            url: relay_url(),
+            relays: Vec::new(),
+            session: session.to_string(),
+            role,
+            secret_hex: keys.secret_key().to_secret_hex().into(),
+        },
+        Box::new(ChannelListener(tx)),
+    );
+    rx
+}
+
+/// A key package request to `to`, labelled with `pubkey`
+fn request(to: &Keys, pubkey: &str) -> HandshakeMessage {
+    HandshakeMessage {
+        message_type: HandshakeMessageType::RequestKeyPackage,
+        peer: to.public_key().to_hex(),
+        data: HandshakeMessageBody::Request {
+            pubkey: Some(pubkey.to_string()),
+            is_admin: None,
+        },
+    }
+}
+
+async fn next(messages: &mut UnboundedReceiver<HandshakeMessage>) -> Result<HandshakeMessage> {
+    tokio::time::timeout(Duration::from_secs(10), messages.next())
+        .await
+        .map_err(|_| anyhow!("timed out waiting for handshake message"))?
+        .ok_or_else(|| anyhow!("listener dropped"))
+}
+
+fn request_pubkey(message: &HandshakeMessage) -> Option<&str> {
+    match &message.data {
+        HandshakeMessageBody::Request { pubkey, .. } => pubkey.as_deref(),
+        _ => None,
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore = "needs a local nostr relay"]
+async fn test_handshake_reaches_the_other_role() -> Result<()> {
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            let session = session_id()?;
+            let (initial_keys, invitee_keys) = (Keys::generate(), Keys::generate());
+            let initial = NativeNostrService::new();
+            let invitee = NativeNostrService::new();
+
+            // Sent before connecting: buffered, then published
+            invitee.send(request(&initial_keys, "early"));
+            let mut initial_messages =
+                connect(&initial, &initial_keys, &session, SessionRole::Initial);
+            // Give the subscription a head start so the invitee's events are live
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let mut invitee_messages =
+                connect(&invitee, &invitee_keys, &session, SessionRole::Invitee);
+
+            let message = next(&mut initial_messages).await?;
+            assert!(matches!(
+                message.message_type,
+                HandshakeMessageType::RequestKeyPackage
+            ));
+            assert_eq!(request_pubkey(&message), Some("early"));
+            // The sender comes from the seal, not from the payload
+            assert_eq!(message.peer, invitee_keys.public_key().to_hex());
+
+            initial.send(request(&invitee_keys, "reply"));
+            let message = next(&mut invitee_messages).await?;
+            assert_eq!(request_pubkey(&message), Some("reply"));
+
+            // Neither side hears its own messages
+            assert!(
+                tokio::time::timeout(Duration::from_millis(500), initial_messages.next())
+                    .await
+                    .is_err(),
+                "initial must not receive its own messages"
+            );
+
+            initial.shutdown();
+            invitee.shutdown();
+            Ok(())
+        })
+        .await
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore = "needs a local nostr relay"]
+async fn test_events_from_several_relays_arrive_once() -> Result<()> {
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            let session = session_id()?;
+            // The same relay under a second name stands in for a second relay
+            let mirror = relay_url().replace("localhost", "127.0.0.1");
+            let initial = NativeNostrService::with_relays(vec![mirror.clone()]);
+            let invitee = NativeNostrService::with_relays(vec![mirror]);
+
+            let (initial_keys, invitee_keys) = (Keys::generate(), Keys::generate());
+            let mut initial_messages =
+                connect(&initial, &initial_keys, &session, SessionRole::Initial);
+            let _invitee_messages =
+                connect(&invitee, &invitee_keys, &session, SessionRole::Invitee);
+            tokio::time::sleep(Duration::from_millis(300)).await;
+
+            invitee.send(request(&initial_keys, "once"));
+            let message = next(&mut initial_messages).await?;
+            assert_eq!(request_pubkey(&message), Some("once"));
+            assert!(
+                tokio::time::timeout(Duration::from_millis(500), initial_messages.next())
+                    .await
+                    .is_err(),
+                "duplicate delivery from the second relay"
+            );
+
+            initial.shutdown();
+            invitee.shutdown();
+            Ok(())
+        })
+        .await
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore = "needs a local nostr relay"]
+async fn test_published_key_package_can_be_fetched() -> Result<()> {
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async {
+            let session = session_id()?;
+            let author = Keys::generate();
+            let publisher = NativeNostrService::new();
+            let _ = connect(&publisher, &author, &session, SessionRole::Invitee);
+            let event = EventBuilder::new(Kind::MlsKeyPackage, "key-package")
+                .sign_with_keys(&author)?
+                .as_json();
+            publisher.publish_key_package(event.clone());
+            tokio::time::sleep(Duration::from_millis(300)).await;
+
+            let fetcher = NativeNostrService::new();
+            let _ = connect(&fetcher, &Keys::generate(), &session, SessionRole::Initial);
+            let (tx, mut rx) = unbounded();
+            fetcher.fetch_key_packages(
+                &author.public_key().to_hex(),
+                Box::new(KeyPackageChannel(tx.clone())),
+            );
+            let found = tokio::time::timeout(Duration::from_secs(10), rx.next())
+                .await
+                .map_err(|_| anyhow!("timed out waiting for key packages"))?
+                .ok_or_else(|| anyhow!("listener dropped"))?;
+            assert_eq!(found, vec![event]);
+
+            // Nothing published means an empty answer, not silence
+            fetcher.fetch_key_packages(
+                &Keys::generate().public_key().to_hex(),
+                Box::new(KeyPackageChannel(tx)),
+            );
+            let found = tokio::time::timeout(Duration::from_secs(10), rx.next())
+                .await
+                .map_err(|_| anyhow!("timed out waiting for key packages"))?
+                .ok_or_else(|| anyhow!("listener dropped"))?;
+            assert!(found.is_empty());
+
+            publisher.shutdown();
+            fetcher.shutdown();
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,100 @@
+//! Sealed state snapshots move a participant to another device or store
+#![cfg(not(target_arch = "wasm32"))]
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{application_text, identity, relays, secret, start_group};
+use marmot_chat::controller::services::IdentityService;
+use marmot_chat::controller::snapshot::SnapshotError;
+
+#[test]
+fn test_state_snapshot_moves_participant_between_devices() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("marmot-chat-snapshot-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    let alice = IdentityService::open(&secret("alice"), dir.join("device-a.db"))?;
+    let bob = identity("bob")?;
+    let artifacts = start_group(&alice, &bob, &[])?;
+    let commit = bob.self_update()?;
+    alice.ingest_wrapper(&commit.bytes)?;
+
+    let sealed = alice.export_state("correct horse", None)?;
+    let err = IdentityService::import_state_to(&sealed, "wrong horse", dir.join("bad.db"))
+        .err()
+        .expect("wrong passphrase must fail");
+    assert_eq!(
+        err.downcast_ref::<SnapshotError>(),
+        Some(&SnapshotError::Decrypt)
+    );
+
+    let moved =
+        IdentityService::import_state_to(&sealed, "correct horse", dir.join("device-b.db"))?
+            .identity;
+    assert_eq!(moved.public_key_hex(), alice.public_key_hex());
+    assert_eq!(moved.group_id_hex(), Some(artifacts.group_id_hex));
+    assert_eq!(moved.current_epoch()?, bob.current_epoch()?);
+
+    let message = bob.create_message("new phone?")?;
+    assert_eq!(
+        application_text(moved.ingest_wrapper(&message.bytes)?).as_deref(),
+        Some("new phone?")
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_state_snapshot_moves_in_memory_group() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    let artifacts = start_group(&alice, &bob, &[])?;
+    let commit = alice.self_update()?;
+    bob.ingest_wrapper(&commit.bytes)?;
+
+    let sealed = bob.export_state("pw", None)?;
+    drop(bob);
+    let bob = IdentityService::import_state(&sealed, "pw")?.identity;
+    assert_eq!(bob.group_id_hex(), Some(artifacts.group_id_hex));
+    assert_eq!(bob.current_epoch()?, alice.current_epoch()?);
+
+    let message = alice.create_message("still there?")?;
+    assert_eq!(
+        application_text(bob.ingest_wrapper(&message.bytes)?).as_deref(),
+        Some("still there?")
+    );
+    let reply = bob.create_message("yes")?;
+    assert_eq!(
+        application_text(alice.ingest_wrapper(&reply.bytes)?).as_deref(),
+        Some("yes")
+    );
+
+    // Signing key and path secrets came along: both sides can still commit
+    let commit = bob.self_update()?;
+    alice.ingest_wrapper(&commit.bytes)?;
+    let commit = alice.self_update()?;
+    bob.ingest_wrapper(&commit.bytes)?;
+    assert_eq!(bob.current_epoch()?, alice.current_epoch()?);
+    Ok(())
+}
+
+#[test]
+fn test_state_snapshot_keeps_pending_key_packages() -> Result<()> {
+    let carol = identity("carol")?;
+    let carol_kp = carol.create_key_package(&relays())?;
+    let sealed = carol.export_state("pw", None)?;
+    drop(carol);
+
+    // The welcome lands after the move; the imported key package opens it
+    let carol = IdentityService::import_state(&sealed, "pw")?.identity;
+    let dave = identity("dave")?;
+    let artifacts = dave.create_group(&carol_kp.event_json, &carol.public_key_hex(), &[])?;
+    assert_eq!(
+        carol.accept_welcome(&artifacts.welcome)?,
+        artifacts.group_id_hex
+    );
+    assert_eq!(carol.current_epoch()?, dave.current_epoch()?);
+    Ok(())
+}
@@ -0,0 +1,458 @@
+//! In-memory relays for running several controllers in one test
+//!
+//! [`Network`] stands in for both the Nostr handshake relay and the MoQ
+//! relay. Every delivery goes through one queue on a virtual clock, and
+//! [`Faults`] decide per relay how long deliveries take, whether they may
+//! overtake each other, and how often they are lost or duplicated. Faults
+//! draw from a seeded generator, so a failing seed replays exactly.
+#![allow(dead_code)]
+
+mod moq;
+mod nostr;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use marmot_chat::controller::events::{
+    ChatEvent, GroupRootMode, PendingWelcome, SessionParams, SessionRole,
+};
+use marmot_chat::controller::services::{IdentityHandle, IdentityService};
+use marmot_chat::controller::{run_until_stalled, ChatController, ControllerConfig};
+
+pub use moq::FakeMoq;
+pub use nostr::FakeNostr;
+
+/// Give up on flows that keep producing traffic
+const MAX_STEPS: usize = 10_000;
+
+/// How a relay mistreats the traffic passing through it
+#[derive(Debug, Clone)]
+pub struct Faults {
+    /// Ticks each delivery takes, drawn uniformly
+    pub delay: RangeInclusive<u64>,
+    /// Let later deliveries to a client overtake earlier ones
+    pub reorder: bool,
+    /// Chance that a message is dropped
+    pub loss: f64,
+    /// Chance that a message is delivered twice
+    pub duplicate: f64,
+}
+
+impl Faults {
+    /// Prompt, ordered, exactly-once delivery
+    pub fn none() -> Self {
+        Self {
+            delay: 1..=1,
+            reorder: false,
+            loss: 0.0,
+            duplicate: 0.0,
+        }
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Link {
+    Nostr,
+    Moq,
+}
+
+/// Both relays, shared by every service handle
+#[derive(Clone)]
+pub struct Network {
+    relay: Rc<RefCell<Relay>>,
+}
+
+struct Relay {
+    tick: u64,
+    seq: u64,
+    rng: Rng,
+    nostr_faults: Faults,
+    moq_faults: Faults,
+    in_flight: Vec<InFlight>,
+    /// Bumped whenever a client connects or shuts down, so deliveries
+    /// addressed to an earlier connection are discarded
+    generations: HashMap<usize, u64>,
+    /// Latest delivery tick per client, kept monotonic unless reordering
+    last_delivery: HashMap<usize, u64>,
+    next_client: usize,
+    dropped: usize,
+    duplicated: usize,
+    nostr: nostr::NostrRelay,
+    moq: moq::MoqRelay,
+}
+
+struct InFlight {
+    at: u64,
+    seq: u64,
+    client: usize,
+    generation: u64,
+    deliver: Rc<dyn Fn()>,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self::with_seed(1)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            relay: Rc::new(RefCell::new(Relay {
+                tick: 0,
+                seq: 0,
+                rng: Rng(seed),
+                nostr_faults: Faults::none(),
+                moq_faults: Faults::none(),
+                in_flight: Vec::new(),
+                generations: HashMap::new(),
+                last_delivery: HashMap::new(),
+                next_client: 0,
+                dropped: 0,
+                duplicated: 0,
+                nostr: nostr::NostrRelay::default(),
+                moq: moq::MoqRelay::default(),
+            })),
+        }
+    }
+
+    pub fn set_nostr_faults(&self, faults: Faults) {
+        self.relay.borrow_mut().nostr_faults = faults;
+    }
+
+    pub fn set_moq_faults(&self, faults: Faults) {
+        self.relay.borrow_mut().moq_faults = faults;
+    }
+
+    /// Messages lost to [`Faults::loss`] so far
+    pub fn dropped(&self) -> usize {
+        self.relay.borrow().dropped
+    }
+
+    /// Extra copies sent because of [`Faults::duplicate`] so far
+    pub fn duplicated(&self) -> usize {
+        self.relay.borrow().duplicated
+    }
+
+    /// A fresh handshake connection on the Nostr relay
+    pub fn nostr_client(&self) -> FakeNostr {
+        FakeNostr::new(self.clone(), self.register())
+    }
+
+    /// A fresh connection on the MoQ relay
+    pub fn moq_client(&self) -> FakeMoq {
+        FakeMoq::new(self.clone(), self.register())
+    }
+
+    /// Start a controller for `session` with its own relay connections
+    pub fn join(&self, session: SessionParams) -> Peer {
+        let identity =
+            IdentityService::create(session.secret_hex.expose()).expect("create test identity");
+        self.join_as(identity, session)
+    }
+
+    /// Like [`Network::join`], for an identity prepared by the test
+    pub fn join_as(&self, identity: IdentityHandle, session: SessionParams) -> Peer {
+        let pubkey = identity.public_key_hex();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        let controller = ChatController::new(ControllerConfig {
+            identity,
+            session,
+            nostr: Rc::new(self.nostr_client()),
+            moq: Rc::new(self.moq_client()),
+            callback: Rc::new(move |event| sink.borrow_mut().push(event)),
+        });
+        controller.start();
+        Peer {
+            pubkey,
+            controller,
+            events,
+        }
+    }
+
+    /// Run controllers and deliver traffic until everything is quiet
+    ///
+    /// Panics if traffic is still flowing after a generous number of steps.
+    pub fn settle(&self) {
+        for _ in 0..MAX_STEPS {
+            run_until_stalled();
+            if !self.deliver_next() {
+                return;
+            }
+        }
+        panic!("network still busy after {MAX_STEPS} steps");
+    }
+
+    fn register(&self) -> usize {
+        let relay = &mut *self.relay.borrow_mut();
+        relay.next_client += 1;
+        relay.next_client
+    }
+
+    /// Advance the clock to the next pending delivery and make every
+    /// delivery due by then; false once nothing is in flight
+    fn deliver_next(&self) -> bool {
+        let due: Vec<Rc<dyn Fn()>> = {
+            let relay = &mut *self.relay.borrow_mut();
+            let Some(next) = relay.in_flight.iter().map(|flight| flight.at).min() else {
+                return false;
+            };
+            relay.tick = relay.tick.max(next);
+            let tick = relay.tick;
+            let (mut due, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut relay.in_flight)
+                .into_iter()
+                .partition(|flight| flight.at <= tick);
+            relay.in_flight = rest;
+            due.sort_by_key(|flight| (flight.at, flight.seq));
+            due.into_iter()
+                .filter(|flight| relay.generation(flight.client) == flight.generation)
+                .map(|flight| flight.deliver)
+                .collect()
+        };
+        // Listeners only queue controller operations, so the relay is free
+        for deliver in due {
+            deliver();
+        }
+        true
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    fn generation(&self, client: usize) -> u64 {
+        self.generations.get(&client).copied().unwrap_or_default()
+    }
+
+    fn reset(&mut self, client: usize) {
+        *self.generations.entry(client).or_default() += 1;
+    }
+
+    /// Queue `deliver` for `client` under the faults of `link`
+    ///
+    /// Control signals pass `lossy = false`: they are delayed like data but
+    /// never dropped or repeated.
+    fn send(&mut self, link: Link, client: usize, lossy: bool, deliver: Rc<dyn Fn()>) {
+        let faults = match link {
+            Link::Nostr => self.nostr_faults.clone(),
+            Link::Moq => self.moq_faults.clone(),
+        };
+        if lossy && self.rng.chance(faults.loss) {
+            self.dropped += 1;
+            return;
+        }
+        let copies = if lossy && self.rng.chance(faults.duplicate) {
+            self.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut at = self.tick + self.rng.pick(&faults.delay);
+            if !faults.reorder {
+                let last = self.last_delivery.entry(client).or_default();
+                at = at.max(*last);
+                *last = at;
+            }
+            self.seq += 1;
+            self.in_flight.push(InFlight {
+                at,
+                seq: self.seq,
+                client,
+                generation: self.generation(client),
+                deliver: deliver.clone(),
+            });
+        }
+    }
+}
+
+/// A controller started by [`Network::join`] and the events it emitted
+pub struct Peer {
+    pub pubkey: String,
+    pub controller: ChatController,
+    events: Rc<RefCell<Vec<ChatEvent>>>,
+}
+
+impl Peer {
+    pub fn events(&self) -> Vec<ChatEvent> {
+        self.events.borrow().clone()
+    }
+
+    /// `(author, content)` of every message received from others
+    pub fn received(&self) -> Vec<(String, String)> {
+        self.events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::Message {
+                    author,
+                    content,
+                    local: false,
+                    ..
+                } => Some((author.clone(), content.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Contents received from `author`, in arrival order
+    pub fn received_from(&self, author: &Peer) -> Vec<String> {
+        self.received()
+            .into_iter()
+            .filter(|(from, _)| from == &author.pubkey)
+            .map(|(_, content)| content)
+            .collect()
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        self.events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::Error { message, .. } => Some(message.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the latest `Ready` event reported the MoQ session up
+    pub fn is_ready(&self) -> bool {
+        self.events
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                ChatEvent::Ready { ready } => Some(*ready),
+                _ => None,
+            })
+            .unwrap_or(false)
+    }
+
+    /// Pubkeys in the latest roster, sorted
+    pub fn roster(&self) -> Vec<String> {
+        let mut members: Vec<String> = self
+            .events
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                ChatEvent::Roster { members } => {
+                    Some(members.iter().map(|member| member.pubkey.clone()).collect())
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+        members.sort();
+        members
+    }
+
+    /// Admins in the latest roster, sorted
+    pub fn admins(&self) -> Vec<String> {
+        let mut admins: Vec<String> = self
+            .events
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                ChatEvent::Roster { members } => Some(
+                    members
+                        .iter()
+                        .filter(|member| member.is_admin)
+                        .map(|member| member.pubkey.clone())
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .unwrap_or_default();
+        admins.sort();
+        admins
+    }
+
+    /// Welcomes announced as waiting for the user, in arrival order
+    pub fn pending_welcomes(&self) -> Vec<PendingWelcome> {
+        self.events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::WelcomePending { welcome } => Some(welcome.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether this peer saw itself leave or be removed
+    pub fn has_left(&self) -> bool {
+        self.events.borrow().iter().any(
+            |event| matches!(event, ChatEvent::MemberLeft { pubkey } if pubkey == &self.pubkey),
+        )
+    }
+}
+
+/// Session parameters for one participant of `session_id`
+///
+/// Uses exporter-derived MoQ roots, pinned when the group is created and
+/// kept through commits until an admin rotates them.
+pub fn session(
+    role: SessionRole,
+    secret_hex: &str,
+    session_id: &str,
+    peer_pubkeys: Vec<String>,
+) -> SessionParams {
+    SessionParams {
+        bootstrap_role: role,
+        relay_url: "https://moq.test/anon".to_string(),
+        nostr_url: "wss://nostr.test".to_string(),
+        nostr_relays: Vec::new(),
+        session_id: session_id.to_string(),
+        secret_hex: secret_hex.into(),
+        peer_pubkeys,
+        group_id_hex: None,
+        admin_pubkeys: Vec::new(),
+        local_transport_id: None,
+        moq_root: None,
+        root_mode: GroupRootMode::Exporter,
+        group_name: None,
+        group_description: None,
+        persists_snapshots: false,
+    }
+}
+
+/// Public key for a test secret
+pub fn pubkey(secret_hex: &str) -> String {
+    IdentityService::create(secret_hex)
+        .expect("create test identity")
+        .public_key_hex()
+}
+
+/// SplitMix64, enough to draw faults reproducibly
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    fn pick(&mut self, range: &RangeInclusive<u64>) -> u64 {
+        let span = range.end().saturating_sub(*range.start()) + 1;
+        range.start() + self.next() % span
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+use marmot_chat::controller::services::{MoqListener, MoqService};
+
+use super::{Link, Network, Relay};
+
+/// Media side of the relay: live wrapper fan-out per root path
+///
+/// Like a MoQ track, a subscription only carries wrappers published while
+/// it is active; nothing is stored for later subscribers.
+#[derive(Default)]
+pub(super) struct MoqRelay {
+    clients: HashMap<usize, MoqClient>,
+}
+
+#[derive(Default)]
+struct MoqClient {
+    connection: Option<Connection>,
+    /// Peers to receive wrappers from, including ones named before connecting
+    peers: BTreeSet<String>,
+    /// Wrappers published before connecting
+    queued: Vec<Vec<u8>>,
+}
+
+struct Connection {
+    path: String,
+    pubkey: String,
+    listener: Rc<dyn MoqListener>,
+}
+
+impl Relay {
+    fn publish_wrapper(&mut self, sender: usize, bytes: &[u8]) {
+        let Some(connection) = self
+            .moq
+            .clients
+            .get(&sender)
+            .and_then(|client| client.connection.as_ref())
+        else {
+            return;
+        };
+        let path = connection.path.clone();
+        let from = connection.pubkey.clone();
+        let recipients: Vec<(usize, Rc<dyn MoqListener>)> = self
+            .moq
+            .clients
+            .iter()
+            .filter(|(id, _)| **id != sender)
+            .filter_map(|(id, client)| {
+                let connection = client.connection.as_ref()?;
+                (connection.path == path && client.peers.contains(&from))
+                    .then(|| (*id, connection.listener.clone()))
+            })
+            .collect();
+        for (recipient, listener) in recipients {
+            let bytes = bytes.to_vec();
+            self.send(
+                Link::Moq,
+                recipient,
+                true,
+                Rc::new(move || listener.on_frame(bytes.clone())),
+            );
+        }
+    }
+}
+
+/// [`MoqService`] on the in-memory relay
+pub struct FakeMoq {
+    network: Network,
+    client: usize,
+}
+
+impl FakeMoq {
+    pub(super) fn new(network: Network, client: usize) -> Self {
+        Self { network, client }
+    }
+}
+
+impl MoqService for FakeMoq {
+    fn connect(
+        &self,
+        _url: &str,
+        session: &str,
+        own_pubkey: &str,
+        peer_pubkeys: &[String],
+        listener: Box<dyn MoqListener>,
+    ) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.reset(self.client);
+        let listener: Rc<dyn MoqListener> = Rc::from(listener);
+        let client = relay.moq.clients.entry(self.client).or_default();
+        client.peers.extend(peer_pubkeys.iter().cloned());
+        client.connection = Some(Connection {
+            path: session.to_string(),
+            pubkey: own_pubkey.to_string(),
+            listener: listener.clone(),
+        });
+        let queued = std::mem::take(&mut client.queued);
+        for bytes in queued {
+            relay.publish_wrapper(self.client, &bytes);
+        }
+        relay.send(
+            Link::Moq,
+            self.client,
+            false,
+            Rc::new(move || listener.on_ready()),
+        );
+    }
+
+    fn subscribe_to_peer(&self, peer_pubkey: &str) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        let client = relay.moq.clients.entry(self.client).or_default();
+        client.peers.insert(peer_pubkey.to_string());
+    }
+
+    fn unsubscribe_from_peer(&self, peer_pubkey: &str) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        if let Some(client) = relay.moq.clients.get_mut(&self.client) {
+            client.peers.remove(peer_pubkey);
+        }
+    }
+
+    fn publish_wrapper(&self, bytes: &[u8]) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        let client = relay.moq.clients.entry(self.client).or_default();
+        if client.connection.is_none() {
+            client.queued.push(bytes.to_vec());
+            return;
+        }
+        relay.publish_wrapper(self.client, bytes);
+    }
+
+    fn shutdown(&self) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.reset(self.client);
+        relay.moq.clients.remove(&self.client);
+    }
+}
@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use marmot_chat::controller::events::SessionRole;
+use marmot_chat::controller::services::{
+    HandshakeConnectParams, HandshakeListener, HandshakeMessage, HandshakeMessageBody,
+    IdentityService, KeyPackageListener, NostrService,
+};
+use nostr::{Event, JsonUtil};
+
+use super::{Link, Network, Relay};
+
+/// Handshake side of the relay: stored events, published key packages and
+/// live subscriptions
+#[derive(Default)]
+pub(super) struct NostrRelay {
+    clients: HashMap<usize, Subscription>,
+    /// Every message published, replayed to later subscribers like a
+    /// relay returning stored events
+    history: Vec<Published>,
+    /// Published kind 443 events
+    key_packages: Vec<String>,
+}
+
+struct Subscription {
+    session: String,
+    role: SessionRole,
+    pubkey: String,
+    listener: Rc<dyn HandshakeListener>,
+}
+
+struct Published {
+    /// Session and role of the sender; welcomes carry neither
+    origin: Option<(String, SessionRole)>,
+    to: String,
+    /// As the recipient sees it, with `peer` naming the sender
+    message: HandshakeMessage,
+}
+
+impl Published {
+    fn reaches(&self, subscription: &Subscription) -> bool {
+        if subscription.pubkey != self.to {
+            return false;
+        }
+        match &self.origin {
+            Some((session, from)) => *session == subscription.session && *from != subscription.role,
+            None => subscription.role == SessionRole::Invitee,
+        }
+    }
+}
+
+impl Relay {
+    fn deliver_handshake(&mut self, client: usize, message: HandshakeMessage) {
+        let Some(subscription) = self.nostr.clients.get(&client) else {
+            return;
+        };
+        let listener = subscription.listener.clone();
+        self.send(
+            Link::Nostr,
+            client,
+            true,
+            Rc::new(move || listener.on_message(message.clone())),
+        );
+    }
+}
+
+/// [`NostrService`] on the in-memory relay
+///
+/// Like the gift-wrapped handshakes, each message reaches only the client
+/// whose pubkey it is addressed to, and only if that client is the other
+/// role in the same session; welcomes reach every invitee client of the
+/// pubkey. Messages published before the recipient connected are replayed
+/// on connect.
+pub struct FakeNostr {
+    network: Network,
+    client: usize,
+    /// Messages sent before `connect`
+    pending: RefCell<Vec<HandshakeMessage>>,
+}
+
+impl FakeNostr {
+    pub(super) fn new(network: Network, client: usize) -> Self {
+        Self {
+            network,
+            client,
+            pending: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn publish(&self, relay: &mut Relay, mut message: HandshakeMessage) {
+        let Some(sender) = relay.nostr.clients.get(&self.client) else {
+            return;
+        };
+        let to = std::mem::replace(&mut message.peer, sender.pubkey.clone());
+        let origin = match &mut message.data {
+            // Only the kind 444 rumor crosses the wire
+            HandshakeMessageBody::Welcome {
+                recipient, relays, ..
+            } => {
+                *recipient = Some(to.clone());
+                relays.clear();
+                None
+            }
+            _ => Some((sender.session.clone(), sender.role)),
+        };
+        let published = Published {
+            origin,
+            to,
+            message,
+        };
+        let recipients: Vec<usize> = relay
+            .nostr
+            .clients
+            .iter()
+            .filter(|(_, client)| published.reaches(client))
+            .map(|(id, _)| *id)
+            .collect();
+        for recipient in recipients {
+            relay.deliver_handshake(recipient, published.message.clone());
+        }
+        relay.nostr.history.push(published);
+    }
+}
+
+impl NostrService for FakeNostr {
+    fn connect(&self, params: HandshakeConnectParams, listener: Box<dyn HandshakeListener>) {
+        let pubkey = IdentityService::create(params.secret_hex.expose())
+            .expect("create handshake identity")
+            .public_key_hex();
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.reset(self.client);
+        relay.nostr.clients.insert(
+            self.client,
+            Subscription {
+                session: params.session.clone(),
+                role: params.role,
+                pubkey,
+                listener: Rc::from(listener),
+            },
+        );
+        let subscription = &relay.nostr.clients[&self.client];
+        let stored: Vec<HandshakeMessage> = relay
+            .nostr
+            .history
+            .iter()
+            .filter(|published| published.reaches(subscription))
+            .map(|published| published.message.clone())
+            .collect();
+        for message in stored {
+            relay.deliver_handshake(self.client, message);
+        }
+        for message in self.pending.take() {
+            self.publish(relay, message);
+        }
+    }
+
+    fn send(&self, payload: HandshakeMessage) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        if relay.nostr.clients.contains_key(&self.client) {
+            self.publish(relay, payload);
+        } else {
+            self.pending.borrow_mut().push(payload);
+        }
+    }
+
+    fn publish_key_package(&self, event_json: String) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.nostr.key_packages.push(event_json);
+    }
+
+    /// Drops the key packages the deletion names, if its author made them
+    fn delete_key_packages(&self, deletion_json: String) {
+        let Ok(deletion) = Event::from_json(deletion_json.as_str()) else {
+            return;
+        };
+        let ids: Vec<_> = deletion.tags.event_ids().copied().collect();
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.nostr.key_packages.retain(|event_json| {
+            !Event::from_json(event_json.as_str())
+                .is_ok_and(|event| event.pubkey == deletion.pubkey && ids.contains(&event.id))
+        });
+    }
+
+    /// Answers with every stored key package of `pubkey`, unfiltered like
+    /// a relay would; the answer is delayed but never lost
+    fn fetch_key_packages(&self, pubkey: &str, listener: Box<dyn KeyPackageListener>) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        let events: Vec<String> = relay
+            .nostr
+            .key_packages
+            .iter()
+            .filter(|event_json| {
+                Event::from_json(event_json.as_str())
+                    .is_ok_and(|event| event.pubkey.to_hex() == pubkey)
+            })
+            .cloned()
+            .collect();
+        let pubkey = pubkey.to_string();
+        relay.send(
+            Link::Nostr,
+            self.client,
+            false,
+            Rc::new(move || listener.on_key_packages(pubkey.clone(), events.clone())),
+        );
+    }
+
+    fn shutdown(&self) {
+        let relay = &mut *self.network.relay.borrow_mut();
+        relay.reset(self.client);
+        relay.nostr.clients.remove(&self.client);
+        self.pending.borrow_mut().clear();
+    }
+}
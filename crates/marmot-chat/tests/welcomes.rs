@@ -0,0 +1,101 @@
+//! Welcomes held until accepted, and the key packages they use up
+
+mod fixtures;
+
+use anyhow::Result;
+use fixtures::{identity, now, relays};
+use marmot_chat::controller::services::KeyPackageOptions;
+use nostr::{Event, EventId, JsonUtil, Kind};
+
+/// Ids a kind 5 deletion names
+fn deleted_ids(deletion: Option<String>) -> Vec<EventId> {
+    let deletion = Event::from_json(deletion.expect("deletion").as_str()).unwrap();
+    assert_eq!(deletion.kind, Kind::EventDeletion);
+    deletion.tags.event_ids().copied().collect()
+}
+
+fn event_id(event_json: &str) -> EventId {
+    Event::from_json(event_json).unwrap().id
+}
+
+#[test]
+fn test_pending_welcomes_are_accepted_one_at_a_time() -> Result<()> {
+    let alice = identity("alice")?;
+    let dave = identity("dave")?;
+    let bob = identity("bob")?;
+
+    // One published key package, used by two inviters
+    let published = bob.create_key_package_with(
+        &relays(),
+        &KeyPackageOptions {
+            last_resort: true,
+            expires_at: None,
+        },
+    )?;
+    let group_a = alice.create_group(&published.event_json, &bob.public_key_hex(), &[])?;
+    let group_b = dave.create_group(&published.event_json, &bob.public_key_hex(), &[])?;
+
+    let pending_a = bob
+        .process_welcome(&group_a.welcome)?
+        .expect("pending welcome");
+    let pending_b = bob
+        .process_welcome(&group_b.welcome)?
+        .expect("pending welcome");
+    assert_eq!(pending_a.group_id_hex, group_a.group_id_hex);
+    assert_eq!(pending_b.welcomer, dave.public_key_hex());
+    // Seeing the same welcome again changes nothing
+    assert_eq!(
+        bob.process_welcome(&group_a.welcome)?,
+        Some(pending_a.clone())
+    );
+    assert_eq!(bob.pending_welcomes()?.len(), 2);
+
+    assert_eq!(
+        bob.accept_pending_welcome(&pending_b.welcome_id)?,
+        group_b.group_id_hex
+    );
+    assert_eq!(bob.list_groups()?, vec![group_b.group_id_hex.clone()]);
+    assert_eq!(bob.pending_welcomes()?, vec![pending_a]);
+    assert_eq!(bob.process_welcome(&group_b.welcome)?, None);
+    assert!(bob.accept_pending_welcome(&pending_b.welcome_id).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_replaced_and_used_key_packages_are_withdrawn() -> Result<()> {
+    let alice = identity("alice")?;
+    let bob = identity("bob")?;
+    assert!(
+        !bob.publishes_key_packages(),
+        "in-memory keys would be lost"
+    );
+    // Unless the host keeps snapshots of them
+    let mut kept = identity("carol")?;
+    kept.set_snapshots_persisted();
+    assert!(kept.publishes_key_packages());
+    assert!(kept.new_scope().publishes_key_packages());
+
+    // A new last-resort key package replaces the previous one
+    let first = bob.create_key_package_with(&relays(), &KeyPackageOptions::last_resort(now()))?;
+    assert_eq!(bob.key_package_deletion()?, None);
+    let second = bob.create_key_package_with(&relays(), &KeyPackageOptions::last_resort(now()))?;
+    assert_eq!(
+        deleted_ids(bob.key_package_deletion()?),
+        vec![event_id(&first.event_json)]
+    );
+    assert!(bob.export_key_package_bundle(&first.event_json).is_err());
+
+    // A one-shot key package is withdrawn once a welcome used it; the
+    // last-resort one stays
+    let one_shot = bob.create_key_package_with(&relays(), &KeyPackageOptions::published(now()))?;
+    let group = alice.create_group(&one_shot.event_json, &bob.public_key_hex(), &[])?;
+    bob.process_welcome(&group.welcome)?
+        .expect("pending welcome");
+    assert_eq!(
+        deleted_ids(bob.key_package_deletion()?),
+        vec![event_id(&one_shot.event_json)]
+    );
+    assert!(bob.export_key_package_bundle(&second.event_json).is_ok());
+    assert_eq!(bob.key_package_deletion()?, None);
+    Ok(())
+}